@@ -0,0 +1,423 @@
+//! Reports to a central collector, so one server can watch the addresses of many sites
+//!
+//! After every check the client POSTs a [`Report`] to `<url>/api/v1/reports` with its site token,
+//! the server side is in `server`
+use std::net::IpAddr;
+
+use serde_json::{Value, json};
+
+use crate::{
+    config::Config,
+    history, http,
+    ip_check::{IpCheckError, IpFamily, Lookup, ProviderResult},
+    notifier,
+};
+
+/// The path reports are POSTed to, below the collector URL
+pub const REPORT_PATH: &str = "/api/v1/reports";
+/// The check intervals a report may give, up to a week
+pub const CHECK_INTERVAL_RANGE: std::ops::RangeInclusive<u64> = 1..=7 * 24 * 60;
+
+/// The `collector` settings from the config
+#[derive(Debug, Clone)]
+pub struct CollectorSettings {
+    /// The base URL of the server, like `https://collector.example.com:8470`
+    pub url: String,
+    /// The name the server knows this site by
+    pub site_id: String,
+    /// The secret the server gave the site
+    pub token: String,
+}
+
+impl CollectorSettings {
+    /// Reads the settings from `{"url": "https://...", "siteId": "home", "token": "..."}`
+    ///
+    /// # Arguments
+    /// * `value: &Value` - The `collector` value of the config
+    ///
+    /// # Returns
+    /// * `Result<Option<CollectorSettings>, String>` - None if reporting is off, Err if a setting is missing
+    pub fn from_value(value: &Value) -> Result<Option<Self>, String> {
+        if value.is_null() || value.get("enabled").and_then(|v| v.as_bool()) == Some(false) {
+            return Ok(None);
+        }
+
+        let setting = |key: &str| {
+            value
+                .get(key)
+                .and_then(|v| v.as_str())
+                .filter(|v| !v.is_empty())
+                .map(|v| v.to_string())
+                .ok_or_else(|| format!("collector needs a {:?}", key))
+        };
+
+        Ok(Some(CollectorSettings {
+            url: setting("url")?.trim_end_matches('/').to_string(),
+            site_id: setting("siteId")?,
+            token: setting("token")?,
+        }))
+    }
+}
+
+/// What one provider answered during a check
+#[derive(Debug, Clone)]
+pub struct ProviderReport {
+    pub provider: String,
+    pub ip: Option<IpAddr>,
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+/// The outcome of looking up one family
+#[derive(Debug, Clone)]
+pub struct FamilyReport {
+    pub family: IpFamily,
+    /// The agreed upon address, None if the lookup failed
+    pub ip: Option<IpAddr>,
+    /// Why there is no address
+    pub error: Option<String>,
+    pub providers: Vec<ProviderReport>,
+}
+
+impl FamilyReport {
+    /// Sums up a check the way it is reported
+    ///
+    /// # Arguments
+    /// * `family: IpFamily` - The family that was looked up
+    /// * `results: &[ProviderResult]` - The answer of each provider
+    /// * `outcome: &Result<Lookup, IpCheckError>` - The agreed upon address, or why there wasn't one
+    ///
+    /// # Returns
+    /// * `FamilyReport` - The check, ready to be sent
+    pub fn new(
+        family: IpFamily,
+        results: &[ProviderResult],
+        outcome: &Result<Lookup, IpCheckError>,
+    ) -> Self {
+        FamilyReport {
+            family,
+            ip: outcome.as_ref().ok().map(|lookup| lookup.ip),
+            error: outcome.as_ref().err().map(|e| e.to_string()),
+            providers: results
+                .iter()
+                .map(|result| ProviderReport {
+                    provider: result.provider.clone(),
+                    ip: result.result.as_ref().ok().map(|lookup| lookup.ip),
+                    latency_ms: result.latency.as_millis() as u64,
+                    error: result.result.as_ref().err().map(|e| e.to_string()),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Everything a site tells the server after a check
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub site_id: String,
+    /// The name of the machine that checked
+    pub hostname: String,
+    /// How often the site checks, so the server knows when to expect the next report
+    pub check_interval_minutes: u64,
+    pub sequential_failures: u32,
+    pub families: Vec<FamilyReport>,
+}
+
+impl Report {
+    /// The report as it is sent
+    pub fn to_json(&self) -> Value {
+        json!({
+            "siteId": self.site_id,
+            "hostname": self.hostname,
+            "version": env!("CARGO_PKG_VERSION"),
+            "checkIntervalMinutes": self.check_interval_minutes,
+            "sequentialFailures": self.sequential_failures,
+            "families": self.families.iter().map(|family| json!({
+                "family": history::family_name(family.family),
+                "ip": family.ip.map(|ip| ip.to_string()),
+                "error": family.error,
+                "providers": family.providers.iter().map(|provider| json!({
+                    "provider": provider.provider,
+                    "ip": provider.ip.map(|ip| ip.to_string()),
+                    "latencyMs": provider.latency_ms,
+                    "error": provider.error,
+                })).collect::<Vec<Value>>(),
+            })).collect::<Vec<Value>>(),
+        })
+    }
+
+    /// Reads a report sent by a client, checking everything the server relies on
+    ///
+    /// # Arguments
+    /// * `value: &Value` - The body of the request
+    ///
+    /// # Returns
+    /// * `Result<Report, String>` - Err saying what is wrong with the report
+    pub fn from_json(value: &Value) -> Result<Self, String> {
+        let site_id = value
+            .get("siteId")
+            .and_then(|v| v.as_str())
+            .filter(|v| !v.is_empty())
+            .ok_or("report needs a \"siteId\"")?;
+
+        let families = value
+            .get("families")
+            .and_then(|v| v.as_array())
+            .ok_or("report needs a \"families\" array")?
+            .iter()
+            .map(family_from_json)
+            .collect::<Result<Vec<FamilyReport>, String>>()?;
+
+        Ok(Report {
+            site_id: site_id.to_string(),
+            hostname: value
+                .get("hostname")
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_string(),
+            check_interval_minutes: value
+                .get("checkIntervalMinutes")
+                .and_then(|v| v.as_u64())
+                .filter(|minutes| CHECK_INTERVAL_RANGE.contains(minutes))
+                .ok_or_else(|| {
+                    format!(
+                        "report needs a \"checkIntervalMinutes\" number from {} to {}",
+                        CHECK_INTERVAL_RANGE.start(),
+                        CHECK_INTERVAL_RANGE.end()
+                    )
+                })?,
+            sequential_failures: value
+                .get("sequentialFailures")
+                .and_then(|v| v.as_u64())
+                .unwrap_or(0) as u32,
+            families,
+        })
+    }
+}
+
+/// Reads an optional address, Err if it is there but isn't one
+fn optional_ip(value: &Value, key: &str) -> Result<Option<IpAddr>, String> {
+    match value.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(ip) => ip
+            .as_str()
+            .and_then(|ip| ip.parse().ok())
+            .map(Some)
+            .ok_or_else(|| format!("{:?} is not an IP address: {}", key, ip)),
+    }
+}
+
+/// Reads an optional error message
+fn optional_error(value: &Value) -> Option<String> {
+    value
+        .get("error")
+        .and_then(|v| v.as_str())
+        .map(|v| v.to_string())
+}
+
+/// Reads one entry of the `families` array
+fn family_from_json(value: &Value) -> Result<FamilyReport, String> {
+    let family = match value.get("family").and_then(|v| v.as_str()) {
+        Some("ipv4") => IpFamily::V4,
+        Some("ipv6") => IpFamily::V6,
+        other => return Err(format!("unknown family {:?}", other.unwrap_or_default())),
+    };
+    let ip = optional_ip(value, "ip")?;
+    if ip.is_some_and(|ip| !family.matches(&ip)) {
+        return Err(format!(
+            "{} report holds a different family's address",
+            family
+        ));
+    }
+
+    let providers = value
+        .get("providers")
+        .and_then(|v| v.as_array())
+        .map(|providers| {
+            providers
+                .iter()
+                .map(|provider| {
+                    Ok(ProviderReport {
+                        provider: provider
+                            .get("provider")
+                            .and_then(|v| v.as_str())
+                            .ok_or("provider result needs a \"provider\"")?
+                            .to_string(),
+                        ip: optional_ip(provider, "ip")?,
+                        latency_ms: provider
+                            .get("latencyMs")
+                            .and_then(|v| v.as_u64())
+                            .unwrap_or(0),
+                        error: optional_error(provider),
+                    })
+                })
+                .collect::<Result<Vec<ProviderReport>, String>>()
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    Ok(FamilyReport {
+        family,
+        ip,
+        // A report without an address has to say why
+        error: optional_error(value).or_else(|| ip.is_none().then(|| "no address".to_string())),
+        providers,
+    })
+}
+
+/// Sends the outcome of a check to the collector, logging instead of failing so the loop keeps going
+///
+/// # Arguments
+/// * `config: &Config` - The config holding the `collector` settings
+/// * `families: Vec<FamilyReport>` - The lookups of the check
+/// * `sequential_failures: u32` - The failure count after the check
+pub fn report(config: &Config, families: Vec<FamilyReport>, sequential_failures: u32) {
+    let settings = match CollectorSettings::from_value(&config.collector) {
+        Ok(Some(settings)) => settings,
+        Ok(None) => return,
+        Err(e) => {
+            eprintln!("Skipping collector report: {}", e);
+            return;
+        }
+    };
+
+    let report = Report {
+        site_id: settings.site_id,
+        hostname: notifier::hostname(),
+        // The server refuses intervals outside the range, a site checking more often still reports
+        check_interval_minutes: config
+            .check_interval_minutes
+            .clamp(*CHECK_INTERVAL_RANGE.start(), *CHECK_INTERVAL_RANGE.end()),
+        sequential_failures,
+        families,
+    };
+
+    let url = format!("{}{}", settings.url, REPORT_PATH);
+    match http::agent()
+        .post(&url)
+        .set("Authorization", &format!("Bearer {}", settings.token))
+        .set("Content-Type", "application/json")
+        .send_string(&report.to_json().to_string())
+    {
+        Ok(_) => println!("Reported to collector {}.", settings.url),
+        Err(e) => eprintln!(
+            "Could not report to collector {}: {}",
+            settings.url,
+            http::describe_error(e)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(families: Value) -> Value {
+        json!({
+            "siteId": "home",
+            "hostname": "router",
+            "checkIntervalMinutes": 15,
+            "sequentialFailures": 2,
+            "families": families,
+        })
+    }
+
+    #[test]
+    fn reads_what_to_json_writes() {
+        let sent = Report {
+            site_id: "home".to_string(),
+            hostname: "router".to_string(),
+            check_interval_minutes: 15,
+            sequential_failures: 0,
+            families: vec![
+                FamilyReport {
+                    family: IpFamily::V4,
+                    ip: Some("203.0.113.7".parse().unwrap()),
+                    error: None,
+                    providers: vec![ProviderReport {
+                        provider: "ipify".to_string(),
+                        ip: Some("203.0.113.7".parse().unwrap()),
+                        latency_ms: 42,
+                        error: None,
+                    }],
+                },
+                FamilyReport {
+                    family: IpFamily::V6,
+                    ip: None,
+                    error: Some("no route".to_string()),
+                    providers: Vec::new(),
+                },
+            ],
+        };
+
+        let received = Report::from_json(&sent.to_json()).unwrap();
+
+        assert_eq!(received.site_id, "home");
+        assert_eq!(received.check_interval_minutes, 15);
+        assert_eq!(received.families.len(), 2);
+        assert_eq!(received.families[0].ip, sent.families[0].ip);
+        assert_eq!(received.families[0].providers[0].latency_ms, 42);
+        assert_eq!(received.families[1].error.as_deref(), Some("no route"));
+    }
+
+    #[test]
+    fn family_without_an_address_says_why() {
+        let received = Report::from_json(&report(json!([{"family": "ipv6"}]))).unwrap();
+
+        assert_eq!(received.families[0].error.as_deref(), Some("no address"));
+    }
+
+    #[test]
+    fn address_of_the_other_family_is_refused() {
+        let error = Report::from_json(&report(json!([{"family": "ipv4", "ip": "2001:db8::7"}])))
+            .unwrap_err();
+
+        assert_eq!(error, "IPv4 report holds a different family's address");
+    }
+
+    #[test]
+    fn bad_addresses_are_refused() {
+        for families in [
+            json!([{"family": "ipv4", "ip": "203.0.113.256"}]),
+            json!([{"family": "ipv4", "ip": 7}]),
+            json!([{"family": "ipv4", "ip": "203.0.113.7", "providers": [{"provider": "ipify", "ip": "nope"}]}]),
+        ] {
+            let error = Report::from_json(&report(families)).unwrap_err();
+            assert!(error.contains("is not an IP address"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn check_interval_has_to_be_in_range() {
+        for minutes in [
+            json!(0),
+            json!(10081),
+            json!(u64::MAX),
+            json!(-5),
+            json!("15"),
+        ] {
+            let mut value = report(json!([]));
+            value["checkIntervalMinutes"] = minutes;
+            assert!(Report::from_json(&value).is_err());
+        }
+
+        let mut value = report(json!([]));
+        value["checkIntervalMinutes"] = json!(10080);
+        assert_eq!(
+            Report::from_json(&value).unwrap().check_interval_minutes,
+            10080
+        );
+    }
+
+    #[test]
+    fn unknown_family_and_missing_site_are_refused() {
+        assert_eq!(
+            Report::from_json(&report(json!([{"family": "ipx"}]))).unwrap_err(),
+            "unknown family \"ipx\""
+        );
+
+        let mut value = report(json!([]));
+        value["siteId"] = json!("");
+        assert!(Report::from_json(&value).is_err());
+    }
+}
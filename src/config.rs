@@ -1,3 +1,7 @@
+use serde_json::Value;
+
+use crate::ip_check::{IpFamily, Provider};
+
 /// Configuration structure for the application
 /// Holds email settings, recipient info, check interval, and IP address.
 /// 
@@ -8,7 +12,19 @@
 /// * `email_smtp_port`: The SMTP port for the email service.
 /// * `recipient_address`: The email address of the recipient who will receive notifications.
 /// * `check_interval_minutes`: The interval in minutes to check for IP changes.
-/// * `ip_address`: The last known IP address.
+/// * `ipv4_address`: The last known IPv4 address.
+/// * `ipv6_address`: The last known IPv6 address.
+/// * `track_ipv4`: Whether the IPv4 address is monitored.
+/// * `track_ipv6`: Whether the IPv6 address is monitored.
+/// * `ip_providers`: The providers queried for the public IP.
+/// * `provider_quorum`: How many providers have to agree on the IP.
+/// * `notifiers`: The notification channels, see `notifier::from_value`.
+/// * `mqtt`: The MQTT broker settings, see `mqtt::MqttSettings`.
+/// * `hooks`: Commands run on events, see `hooks::Hook`.
+/// * `dns_updaters`: The DNS records kept pointed at the address, see `ddns::from_value`.
+/// * `dns_drift`: The names whose published records are compared to the address, see `drift::DriftSettings`.
+/// * `collector`: The central server reports are sent to, see `collector::CollectorSettings`.
+/// * `server`: The settings used when running as the central server, see `server::ServerSettings`.
 #[derive(Debug, Clone)]
 pub struct Config {
     /// The email address used to send notifications.
@@ -25,17 +41,42 @@ pub struct Config {
     pub recipient_address: String,
     /// The interval in minutes to check for IP changes.
     pub check_interval_minutes: u64,
-    /// The last known IP address.
-    pub ip_address: String,
+    /// The last known IPv4 address.
+    pub ipv4_address: String,
+    /// The last known IPv6 address.
+    pub ipv6_address: String,
+    /// Whether the IPv4 address is monitored.
+    pub track_ipv4: bool,
+    /// Whether the IPv6 address is monitored.
+    pub track_ipv6: bool,
     /// The number of sequential failures in checking the IP address.
     pub sequential_failures: u32,
     /// The threshold of sequential failures before sending an alert email.
     pub failure_threshold: u32,
+    /// Whether the alert for the current run of failures went out.
+    pub failure_alert_sent: bool,
+    /// The providers queried for the public IP.
+    pub ip_providers: Vec<Provider>,
+    /// How many providers have to agree before an IP is accepted.
+    pub provider_quorum: usize,
+    /// The notification channel entries, each picked by its `type` field.
+    pub notifiers: Vec<Value>,
+    /// The MQTT broker settings, Null if MQTT isn't configured.
+    pub mqtt: Value,
+    /// Commands run on events.
+    pub hooks: Vec<Value>,
+    /// The dynamic DNS entries, each picked by its `type` field.
+    pub dns_updaters: Vec<Value>,
+    /// The DNS drift check settings, Null if it isn't configured.
+    pub dns_drift: Value,
+    /// The collector reports are sent to, Null if reporting isn't configured.
+    pub collector: Value,
+    /// The collector server settings, Null if they aren't configured.
+    pub server: Value,
 }
 
 impl Config {
     /// Creates a new `Config` instance with the provided parameters.
-    /// The `ip_address` field is initialized to an empty string.
     /// # Parameters
     /// * `email_address`: The email address used to send notifications.
     /// * `email_password`: The password or app-specific password for the email account.
@@ -43,9 +84,23 @@ impl Config {
     /// * `email_smtp_port`: The SMTP port for the email service.
     /// * `recipient_address`: The email address of the recipient who will receive notifications.
     /// * `check_interval_minutes`: The interval in minutes to check for IP changes.
-    /// * `ip_address`: The last known IP address.
+    /// * `ipv4_address`: The last known IPv4 address.
+    /// * `ipv6_address`: The last known IPv6 address.
+    /// * `track_ipv4`: Whether the IPv4 address is monitored.
+    /// * `track_ipv6`: Whether the IPv6 address is monitored.
+    /// * `failure_alert_sent`: Whether the failure alert went out.
+    /// * `ip_providers`: The providers queried for the public IP.
+    /// * `provider_quorum`: How many providers have to agree on the IP.
+    /// * `notifiers`: The notification channels.
+    /// * `mqtt`: The MQTT broker settings.
+    /// * `hooks`: Commands run on events.
+    /// * `dns_updaters`: The dynamic DNS entries.
+    /// * `dns_drift`: The DNS drift check settings.
+    /// * `collector`: The collector reports are sent to.
+    /// * `server`: The collector server settings.
     /// # Returns
     /// * `Config` - A new instance of the `Config` struct.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         email_address: String,
         username: String,
@@ -54,9 +109,22 @@ impl Config {
         email_smtp_port: u16,
         recipient_address: String,
         check_interval_minutes: u64,
-        ip_address: String,
+        ipv4_address: String,
+        ipv6_address: String,
+        track_ipv4: bool,
+        track_ipv6: bool,
         sequential_failures: u32,
         failure_threshold: u32,
+        failure_alert_sent: bool,
+        ip_providers: Vec<Provider>,
+        provider_quorum: usize,
+        notifiers: Vec<Value>,
+        mqtt: Value,
+        hooks: Vec<Value>,
+        dns_updaters: Vec<Value>,
+        dns_drift: Value,
+        collector: Value,
+        server: Value,
     ) -> Self {
         Config {
             email_address,
@@ -66,9 +134,22 @@ impl Config {
             email_smtp_port,
             recipient_address,
             check_interval_minutes,
-            ip_address,
+            ipv4_address,
+            ipv6_address,
+            track_ipv4,
+            track_ipv6,
             sequential_failures,
             failure_threshold,
+            failure_alert_sent,
+            ip_providers,
+            provider_quorum,
+            notifiers,
+            mqtt,
+            hooks,
+            dns_updaters,
+            dns_drift,
+            collector,
+            server,
         }
     }
 
@@ -81,7 +162,40 @@ impl Config {
         println!("SMTP Port: {}", self.email_smtp_port);
         println!("Recipient Address: {}", self.recipient_address);
         println!("Check Interval (minutes): {}", self.check_interval_minutes);
-        println!("Last Known IP Address: {}", self.ip_address);
+        println!("Last Known IPv4 Address: {}", self.ipv4_address);
+        println!("Last Known IPv6 Address: {}", self.ipv6_address);
+        println!("Track IPv4: {}", self.track_ipv4);
+        println!("Track IPv6: {}", self.track_ipv6);
+        println!(
+            "IP Providers: {}",
+            self.ip_providers
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+        println!("Provider Quorum: {}", self.provider_quorum);
+        println!(
+            "Notifiers: {}",
+            self.notifiers
+                .iter()
+                .map(|n| n.get("type").and_then(|t| t.as_str()).unwrap_or("?"))
+                .collect::<Vec<&str>>()
+                .join(", ")
+        );
+        println!("MQTT: {}", self.mqtt);
+        println!("Hooks: {}", Value::from(self.hooks.clone()));
+        println!(
+            "DNS Updaters: {}",
+            self.dns_updaters
+                .iter()
+                .map(|n| n.get("type").and_then(|t| t.as_str()).unwrap_or("?"))
+                .collect::<Vec<&str>>()
+                .join(", ")
+        );
+        println!("DNS Drift: {}", self.dns_drift);
+        println!("Collector: {}", self.collector);
+        println!("Server: {}", self.server);
     }
 
     /// Converts the `Config` instance to a JSON value.
@@ -96,7 +210,64 @@ impl Config {
             "emailSMTPPort": self.email_smtp_port,
             "recipientAddress": self.recipient_address,
             "checkIntervalMinutes": self.check_interval_minutes,
-            "ipAddress": self.ip_address,
+            "ipv4Address": self.ipv4_address,
+            "ipv6Address": self.ipv6_address,
+            "trackIPv4": self.track_ipv4,
+            "trackIPv6": self.track_ipv6,
+            "ipProviders": self.ip_providers.iter().map(|p| p.to_value()).collect::<Vec<_>>(),
+            "providerQuorum": self.provider_quorum,
+            "notifiers": self.notifiers,
+            "mqtt": self.mqtt,
+            "hooks": self.hooks,
+            "dnsUpdaters": self.dns_updaters,
+            "dnsDrift": self.dns_drift,
+            "collector": self.collector,
+            "server": self.server,
         })
     }
+
+    /// Returns the address families that are monitored
+    ///
+    /// # Returns
+    /// * `Vec<IpFamily>` - IPv4 first, then IPv6
+    pub fn tracked_families(&self) -> Vec<IpFamily> {
+        let mut families = Vec::new();
+
+        if self.track_ipv4 {
+            families.push(IpFamily::V4);
+        }
+        if self.track_ipv6 {
+            families.push(IpFamily::V6);
+        }
+
+        families
+    }
+
+    /// Returns the last known address of the given family
+    ///
+    /// # Arguments
+    /// * `family`: The address family
+    ///
+    /// # Returns
+    /// * `&str` - The last known address, may be empty
+    pub fn last_ip(&self, family: IpFamily) -> &str {
+        match family {
+            IpFamily::V4 => &self.ipv4_address,
+            IpFamily::V6 => &self.ipv6_address,
+        }
+    }
+
+    /// Returns the config key holding the last known address of the given family
+    ///
+    /// # Arguments
+    /// * `family`: The address family
+    ///
+    /// # Returns
+    /// * `&'static str` - The JSON key
+    pub fn ip_address_key(family: IpFamily) -> &'static str {
+        match family {
+            IpFamily::V4 => "ipv4Address",
+            IpFamily::V6 => "ipv6Address",
+        }
+    }
 }
\ No newline at end of file
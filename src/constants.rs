@@ -7,12 +7,24 @@ static PROJ_DIRS: OnceCell<ProjectDirs> = OnceCell::new();
 
 // Client
 pub const LOOP_TIME_SECONDS: u64 = 120;
+pub const HTTP_CONNECT_TIMEOUT_SECONDS: u64 = 5;
+pub const HTTP_READ_TIMEOUT_SECONDS: u64 = 10;
+pub const DNS_TIMEOUT_SECONDS: u64 = 5;
+pub const STUN_TIMEOUT_SECONDS: u64 = 3;
+pub const GATEWAY_TIMEOUT_SECONDS: u64 = 3;
+pub const MQTT_TIMEOUT_SECONDS: u64 = 5;
+pub const HOOK_TIMEOUT_SECONDS: u64 = 30;
+pub const HOOK_OUTPUT_GRACE_MILLIS: u64 = 500;
+pub const USER_AGENT: &str = concat!("public_ip_notifier/", env!("CARGO_PKG_VERSION"));
 
 //Server
 pub const DOWN_SAMPLE_POINTS: u16 = 40;
 pub const OUTLIER_THRESHOLD: f64 = 0.5;
 pub const DO_INTERPOLATION: bool = true;
 pub const INTERPOLATION_STEPS: u16 = 64;
+pub const SERVER_MAX_REPORT_BYTES: u64 = 64 * 1024;
+pub const SERVER_HEARTBEAT_CHECK_SECONDS: u64 = 30;
+pub const SERVER_MAX_REQUESTS: usize = 64;
 
 pub fn setup() {
     PROJ_DIRS
@@ -42,7 +54,7 @@ pub fn get_config_dir() -> String {
 
     proj_dir.config_dir();
 
-    let config_dir = ProjectDirs::config_dir(&proj_dir).to_str().unwrap();
+    let config_dir = ProjectDirs::config_dir(proj_dir).to_str().unwrap();
 
     config_dir.to_string()
 }
@@ -57,7 +69,7 @@ pub fn get_data_dir() -> String {
 
     proj_dir.data_dir();
 
-    let data_dir = ProjectDirs::data_dir(&proj_dir).to_str().unwrap();
+    let data_dir = ProjectDirs::data_dir(proj_dir).to_str().unwrap();
 
     data_dir.to_string()
 } 
@@ -65,3 +77,18 @@ pub fn get_data_dir() -> String {
 pub fn get_db_path() -> String {
     format!("{}/database.sqlite", get_data_dir())
 }
+
+/// Returns the path to the dynamic DNS state, what each updater last set and any backoff
+pub fn get_ddns_state_path() -> String {
+    format!("{}/ddns_state.json", get_data_dir())
+}
+
+/// Returns the path to the collector server database, the reports of every site
+pub fn get_server_db_path() -> String {
+    format!("{}/server.sqlite", get_data_dir())
+}
+
+/// Returns the path to the DNS drift state, which names were stale and when they were last checked
+pub fn get_drift_state_path() -> String {
+    format!("{}/drift_state.json", get_data_dir())
+}
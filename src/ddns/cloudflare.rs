@@ -0,0 +1,371 @@
+//! Updates A and AAAA records through the Cloudflare v4 API
+use std::net::IpAddr;
+
+use serde_json::{Value, json};
+
+use crate::{http, ip_check::IpFamily};
+
+use super::{
+    Backoff, DnsUpdater, UpdateError, names, read_json_answer, record_type, setting, update_each,
+};
+
+/// Where the Cloudflare API lives unless the entry points somewhere else
+pub const DEFAULT_API_URL: &str = "https://api.cloudflare.com/client/v4";
+/// The error code of a token Cloudflare doesn't accept
+const AUTHENTICATION_ERROR: i64 = 10000;
+
+/// Keeps records in one Cloudflare zone pointed at the public address
+pub struct CloudflareUpdater {
+    /// The zone the records are in, from the zone's overview page
+    pub zone_id: String,
+    /// An API token with DNS edit permission for the zone
+    pub api_token: String,
+    /// The full record names, like `home.example.com`
+    pub records: Vec<String>,
+    /// Whether traffic goes through Cloudflare's proxy
+    pub proxied: bool,
+    /// The TTL in seconds, 1 means automatic
+    pub ttl: u64,
+    /// The API server
+    pub api_url: String,
+}
+
+impl CloudflareUpdater {
+    /// Reads the updater from
+    /// `{"type": "cloudflare", "zoneId": "...", "apiToken": "...", "records": ["home.example.com"], "proxied": false, "ttl": 1}`
+    ///
+    /// # Arguments
+    /// * `entry: &Value` - The updater entry
+    ///
+    /// # Returns
+    /// * `Result<CloudflareUpdater, String>` - Err if a setting is missing
+    pub fn from_value(entry: &Value) -> Result<Self, String> {
+        Ok(CloudflareUpdater {
+            zone_id: setting(entry, "zoneId", "Cloudflare")?,
+            api_token: setting(entry, "apiToken", "Cloudflare")?,
+            records: names(entry, "records", "Cloudflare")?,
+            proxied: entry
+                .get("proxied")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false),
+            ttl: entry.get("ttl").and_then(|v| v.as_u64()).unwrap_or(1),
+            api_url: setting(entry, "apiUrl", "Cloudflare")
+                .unwrap_or_else(|_| DEFAULT_API_URL.to_string())
+                .trim_end_matches('/')
+                .to_string(),
+        })
+    }
+
+    /// Sends one API request and unwraps the `result` of the response envelope
+    ///
+    /// # Arguments
+    /// * `method: &str` - The HTTP method
+    /// * `path: &str` - The path below the zone
+    /// * `query: &[(&str, &str)]` - Query parameters
+    /// * `body: Option<Value>` - The JSON body, if any
+    ///
+    /// # Returns
+    /// * `Result<Value, UpdateError>` - The result, Err with Cloudflare's own error messages if it failed
+    fn call(
+        &self,
+        method: &str,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Option<Value>,
+    ) -> Result<Value, UpdateError> {
+        let mut request = http::agent()
+            .request(
+                method,
+                &format!("{}/zones/{}{}", self.api_url, self.zone_id, path),
+            )
+            .set("Authorization", &format!("Bearer {}", self.api_token));
+
+        for (name, value) in query {
+            request = request.query(name, value);
+        }
+
+        let response = match body {
+            Some(body) => request
+                .set("Content-Type", "application/json")
+                .send_string(&body.to_string()),
+            None => request.call(),
+        };
+
+        // Errors come back with the same envelope, so both are read the same way
+        let (status, envelope) = read_json_answer(response, "Cloudflare")?;
+
+        if envelope.get("success").and_then(|v| v.as_bool()) == Some(true) {
+            return Ok(envelope.get("result").cloned().unwrap_or(Value::Null));
+        }
+
+        let errors = envelope
+            .get("errors")
+            .and_then(|v| v.as_array())
+            .cloned()
+            .unwrap_or_default();
+        let messages: Vec<String> = errors
+            .iter()
+            .map(|error| {
+                format!(
+                    "{} (code {})",
+                    error
+                        .get("message")
+                        .and_then(|v| v.as_str())
+                        .unwrap_or("unknown error"),
+                    error.get("code").unwrap_or(&Value::Null)
+                )
+            })
+            .collect();
+
+        // A wrong or underprivileged token won't start working on its own
+        let rejected = status == 401
+            || status == 403
+            || errors.iter().any(|error| {
+                error.get("code").and_then(|v| v.as_i64()) == Some(AUTHENTICATION_ERROR)
+            });
+
+        Err(UpdateError {
+            message: if messages.is_empty() {
+                "Cloudflare API request failed".to_string()
+            } else {
+                messages.join(", ")
+            },
+            backoff: if rejected {
+                Backoff::UntilConfigChanges
+            } else {
+                Backoff::NextCheck
+            },
+        })
+    }
+
+    /// Points one record at the address, creating it if the zone doesn't have it yet
+    ///
+    /// # Arguments
+    /// * `name: &str` - The full record name
+    /// * `record_type: &str` - A or AAAA
+    /// * `ip: IpAddr` - The new address
+    ///
+    /// # Returns
+    /// * `Result<String, UpdateError>` - What was done
+    fn update_record(
+        &self,
+        name: &str,
+        record_type: &str,
+        ip: IpAddr,
+    ) -> Result<String, UpdateError> {
+        let existing = self.call(
+            "GET",
+            "/dns_records",
+            &[("type", record_type), ("name", name)],
+            None,
+        )?;
+        let content = ip.to_string();
+
+        match existing.as_array().and_then(|records| records.first()) {
+            Some(record) => {
+                if record.get("content").and_then(|v| v.as_str()) == Some(content.as_str())
+                    && record.get("proxied").and_then(|v| v.as_bool()) == Some(self.proxied)
+                    && record.get("ttl").and_then(|v| v.as_u64()) == Some(self.ttl)
+                {
+                    return Ok("already up to date".to_string());
+                }
+
+                let id = record
+                    .get("id")
+                    .and_then(|v| v.as_str())
+                    .ok_or_else(|| "Cloudflare returned a record without an ID".to_string())?;
+
+                // The record keeps its ID, name and type, the rest follows the entry
+                self.call(
+                    "PATCH",
+                    &format!("/dns_records/{}", id),
+                    &[],
+                    Some(json!({ "content": content, "proxied": self.proxied, "ttl": self.ttl })),
+                )?;
+                Ok("updated".to_string())
+            }
+            None => {
+                self.call(
+                    "POST",
+                    "/dns_records",
+                    &[],
+                    Some(json!({
+                        "type": record_type,
+                        "name": name,
+                        "content": content,
+                        "proxied": self.proxied,
+                        "ttl": self.ttl,
+                    })),
+                )?;
+                Ok("created".to_string())
+            }
+        }
+    }
+}
+
+impl DnsUpdater for CloudflareUpdater {
+    fn name(&self) -> String {
+        format!("Cloudflare ({})", self.records.join(", "))
+    }
+
+    fn update(&self, family: IpFamily, ip: IpAddr) -> Result<String, UpdateError> {
+        let record_type = record_type(family);
+
+        update_each(record_type, &self.records, |name| {
+            self.update_record(name, record_type, ip)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::mock;
+
+    fn updater(api_url: &str, records: &[&str]) -> CloudflareUpdater {
+        CloudflareUpdater::from_value(&json!({
+            "type": "cloudflare",
+            "zoneId": "zone123",
+            "apiToken": "cf-token",
+            "records": records,
+            "proxied": true,
+            "ttl": 300,
+            "apiUrl": api_url,
+        }))
+        .unwrap()
+    }
+
+    fn success(result: Value) -> (u16, String) {
+        (
+            200,
+            json!({"success": true, "errors": [], "result": result}).to_string(),
+        )
+    }
+
+    #[test]
+    fn patches_existing_records_and_creates_missing_ones() {
+        let server = mock::serve(|request| match request.method.as_str() {
+            "GET" if request.url.contains("name=home.example.com") => {
+                success(json!([{"id": "rec1", "content": "192.0.2.1", "proxied": false}]))
+            }
+            "GET" => success(json!([])),
+            _ => success(json!({"id": "rec2"})),
+        });
+
+        let outcome = updater(&server.url, &["home.example.com", "new.example.com"])
+            .update(IpFamily::V4, "203.0.113.7".parse().unwrap())
+            .unwrap();
+        assert_eq!(
+            outcome,
+            "A home.example.com updated; A new.example.com created"
+        );
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        for request in &requests {
+            assert_eq!(request.header("Authorization"), Some("Bearer cf-token"));
+        }
+        assert_eq!(
+            requests[0].url,
+            "/zones/zone123/dns_records?type=A&name=home.example.com"
+        );
+        assert_eq!(requests[1].method, "PATCH");
+        assert_eq!(requests[1].url, "/zones/zone123/dns_records/rec1");
+        // The proxy and TTL of the entry apply to existing records too
+        assert_eq!(
+            requests[1].json(),
+            json!({"content": "203.0.113.7", "proxied": true, "ttl": 300})
+        );
+        assert_eq!(
+            requests[2].url,
+            "/zones/zone123/dns_records?type=A&name=new.example.com"
+        );
+        assert_eq!(requests[3].method, "POST");
+        assert_eq!(requests[3].url, "/zones/zone123/dns_records");
+        assert_eq!(
+            requests[3].json(),
+            json!({
+                "type": "A",
+                "name": "new.example.com",
+                "content": "203.0.113.7",
+                "proxied": true,
+                "ttl": 300,
+            })
+        );
+    }
+
+    #[test]
+    fn record_holding_the_address_is_left_alone() {
+        let server = mock::serve(|_| {
+            success(json!([{"id": "rec1", "content": "2001:db8::7", "proxied": true, "ttl": 300}]))
+        });
+
+        let outcome = updater(&server.url, &["home.example.com"])
+            .update(IpFamily::V6, "2001:db8::7".parse().unwrap())
+            .unwrap();
+
+        assert_eq!(outcome, "AAAA home.example.com already up to date");
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].url.contains("type=AAAA"));
+    }
+
+    #[test]
+    fn api_errors_are_reported_from_the_envelope() {
+        let server = mock::serve(|_| {
+            (
+                403,
+                json!({
+                    "success": false,
+                    "errors": [{"code": 10000, "message": "Authentication error"}],
+                    "result": null,
+                })
+                .to_string(),
+            )
+        });
+
+        let error = updater(&server.url, &["home.example.com"])
+            .update(IpFamily::V4, "203.0.113.7".parse().unwrap())
+            .unwrap_err();
+
+        assert_eq!(
+            error.message,
+            "A home.example.com failed: Authentication error (code 10000)"
+        );
+        assert!(matches!(error.backoff, Backoff::UntilConfigChanges));
+    }
+
+    #[test]
+    fn record_with_other_settings_is_patched() {
+        let server = mock::serve(|request| match request.method.as_str() {
+            "GET" => success(
+                json!([{"id": "rec1", "content": "203.0.113.7", "proxied": false, "ttl": 1}]),
+            ),
+            _ => success(json!({"id": "rec1"})),
+        });
+
+        let outcome = updater(&server.url, &["home.example.com"])
+            .update(IpFamily::V4, "203.0.113.7".parse().unwrap())
+            .unwrap();
+
+        assert_eq!(outcome, "A home.example.com updated");
+        assert_eq!(server.requests()[1].method, "PATCH");
+    }
+
+    #[test]
+    fn server_errors_are_retried_on_the_next_check() {
+        let server = mock::serve(|_| {
+            (
+                500,
+                json!({"success": false, "errors": [{"code": 1000, "message": "Internal error"}]})
+                    .to_string(),
+            )
+        });
+
+        let error = updater(&server.url, &["home.example.com"])
+            .update(IpFamily::V4, "203.0.113.7".parse().unwrap())
+            .unwrap_err();
+
+        assert!(matches!(error.backoff, Backoff::NextCheck));
+    }
+}
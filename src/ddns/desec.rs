@@ -0,0 +1,139 @@
+//! Updates deSEC (dedyn.io) names through its dyndns2 style update endpoint
+use std::net::IpAddr;
+
+use serde_json::Value;
+
+use crate::{http, ip_check::IpFamily};
+
+use super::{Backoff, DnsUpdater, UpdateError, dyndns2, names, read_answer, setting};
+
+/// Where the deSEC update endpoint lives unless the entry points somewhere else
+pub const DEFAULT_API_URL: &str = "https://update.dedyn.io";
+
+/// Keeps deSEC names pointed at the public address
+pub struct DesecUpdater {
+    /// A token of the account owning the names
+    pub token: String,
+    /// The full names, like `myhome.dedyn.io`
+    pub hostnames: Vec<String>,
+    /// The update server
+    pub api_url: String,
+}
+
+impl DesecUpdater {
+    /// Reads the updater from `{"type": "desec", "token": "...", "hostnames": ["myhome.dedyn.io"]}`
+    ///
+    /// # Arguments
+    /// * `entry: &Value` - The updater entry
+    ///
+    /// # Returns
+    /// * `Result<DesecUpdater, String>` - Err if a setting is missing
+    pub fn from_value(entry: &Value) -> Result<Self, String> {
+        Ok(DesecUpdater {
+            token: setting(entry, "token", "deSEC")?,
+            hostnames: names(entry, "hostnames", "deSEC")?,
+            api_url: setting(entry, "apiUrl", "deSEC")
+                .unwrap_or_else(|_| DEFAULT_API_URL.to_string())
+                .trim_end_matches('/')
+                .to_string(),
+        })
+    }
+}
+
+impl DnsUpdater for DesecUpdater {
+    fn name(&self) -> String {
+        format!("deSEC ({})", self.hostnames.join(", "))
+    }
+
+    fn update(&self, family: IpFamily, ip: IpAddr) -> Result<String, UpdateError> {
+        // deSEC clears the family that isn't sent, so the other one is explicitly kept
+        let (set, keep) = match family {
+            IpFamily::V4 => ("myipv4", "myipv6"),
+            IpFamily::V6 => ("myipv6", "myipv4"),
+        };
+
+        let response = http::agent()
+            .get(&format!("{}/", self.api_url))
+            .set("Authorization", &format!("Token {}", self.token))
+            .query("hostname", &self.hostnames.join(","))
+            .query(set, &ip.to_string())
+            .query(keep, "preserve")
+            .call();
+
+        // Too many updates are answered with 429, which read_answer turns into a wait
+        let (status, body) = read_answer(response)?;
+
+        // A bad token is answered in JSON rather than with badauth
+        if status == 401 || status == 403 {
+            return Err(UpdateError {
+                message: format!("deSEC rejected the token (HTTP status {})", status),
+                backoff: Backoff::UntilConfigChanges,
+            });
+        }
+
+        dyndns2::parse_answer(family, &self.hostnames, status, &body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::http::mock;
+
+    fn updater(api_url: &str) -> DesecUpdater {
+        DesecUpdater::from_value(&json!({
+            "type": "desec",
+            "token": "desec-token",
+            "hostnames": ["myhome.dedyn.io"],
+            "apiUrl": api_url,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn sets_one_family_and_preserves_the_other() {
+        let server = mock::serve(|_| (200, "good".to_string()));
+
+        let outcome = updater(&server.url)
+            .update(IpFamily::V4, "203.0.113.7".parse().unwrap())
+            .unwrap();
+
+        assert_eq!(outcome, "A myhome.dedyn.io updated");
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0].header("Authorization"),
+            Some("Token desec-token")
+        );
+        assert_eq!(
+            requests[0].url,
+            "/?hostname=myhome.dedyn.io&myipv4=203.0.113.7&myipv6=preserve"
+        );
+    }
+
+    #[test]
+    fn rejected_token_waits_for_the_config_to_change() {
+        let server = mock::serve(|_| (401, json!({"detail": "Invalid token."}).to_string()));
+
+        let error = updater(&server.url)
+            .update(IpFamily::V6, "2001:db8::7".parse().unwrap())
+            .unwrap_err();
+
+        assert_eq!(error.message, "deSEC rejected the token (HTTP status 401)");
+        assert!(matches!(error.backoff, Backoff::UntilConfigChanges));
+        assert!(server.requests()[0].url.contains("&myipv4=preserve"));
+    }
+
+    #[test]
+    fn rate_limit_is_waited_out() {
+        let server = mock::serve(|_| (429, json!({"detail": "Slow down."}).to_string()));
+
+        let error = updater(&server.url)
+            .update(IpFamily::V4, "203.0.113.7".parse().unwrap())
+            .unwrap_err();
+
+        assert!(matches!(error.backoff, Backoff::Wait(_)));
+    }
+}
@@ -0,0 +1,146 @@
+//! Updates DuckDNS subdomains, which are always A and AAAA records of `<name>.duckdns.org`
+use std::net::IpAddr;
+
+use serde_json::Value;
+
+use crate::{http, ip_check::IpFamily};
+
+use super::{Backoff, DnsUpdater, UpdateError, names, read_answer, setting};
+
+/// Where the DuckDNS API lives unless the entry points somewhere else
+pub const DEFAULT_API_URL: &str = "https://www.duckdns.org";
+
+/// Keeps DuckDNS subdomains pointed at the public address
+pub struct DuckDnsUpdater {
+    /// The account token from the DuckDNS page
+    pub token: String,
+    /// The subdomains without `.duckdns.org`, sent in one request
+    pub domains: Vec<String>,
+    /// The API server
+    pub api_url: String,
+}
+
+impl DuckDnsUpdater {
+    /// Reads the updater from `{"type": "duckdns", "token": "...", "domains": ["myhome"]}`
+    ///
+    /// # Arguments
+    /// * `entry: &Value` - The updater entry
+    ///
+    /// # Returns
+    /// * `Result<DuckDnsUpdater, String>` - Err if a setting is missing
+    pub fn from_value(entry: &Value) -> Result<Self, String> {
+        Ok(DuckDnsUpdater {
+            token: setting(entry, "token", "DuckDNS")?,
+            // Full names are accepted too, the API only wants the subdomain
+            domains: names(entry, "domains", "DuckDNS")?
+                .into_iter()
+                .map(|domain| domain.trim_end_matches(".duckdns.org").to_string())
+                .collect(),
+            api_url: setting(entry, "apiUrl", "DuckDNS")
+                .unwrap_or_else(|_| DEFAULT_API_URL.to_string())
+                .trim_end_matches('/')
+                .to_string(),
+        })
+    }
+}
+
+impl DnsUpdater for DuckDnsUpdater {
+    fn name(&self) -> String {
+        format!("DuckDNS ({})", self.domains.join(", "))
+    }
+
+    fn update(&self, family: IpFamily, ip: IpAddr) -> Result<String, UpdateError> {
+        // Without an address DuckDNS would use the one the request came from, which may be the other family
+        let ip_key = match family {
+            IpFamily::V4 => "ip",
+            IpFamily::V6 => "ipv6",
+        };
+
+        let response = http::agent()
+            .get(&format!("{}/update", self.api_url))
+            .query("domains", &self.domains.join(","))
+            .query("token", &self.token)
+            .query(ip_key, &ip.to_string())
+            .call();
+
+        let (status, body) = read_answer(response)?;
+
+        // The answer is just OK or KO, KO meaning the token or a domain is wrong
+        match body.lines().next().unwrap_or_default().trim() {
+            "OK" => Ok("updated".to_string()),
+            "KO" => Err(UpdateError {
+                message: "DuckDNS rejected the token or a domain".to_string(),
+                backoff: Backoff::UntilConfigChanges,
+            }),
+            answer => Err(format!(
+                "unexpected DuckDNS answer with HTTP status {}: {:.200}",
+                status, answer
+            )
+            .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::http::mock;
+
+    fn updater(api_url: &str) -> DuckDnsUpdater {
+        DuckDnsUpdater::from_value(&json!({
+            "type": "duckdns",
+            "token": "duck-token",
+            "domains": ["myhome", "cabin.duckdns.org"],
+            "apiUrl": api_url,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn sends_every_domain_with_the_family_address() {
+        let server = mock::serve(|_| (200, "OK".to_string()));
+
+        let outcome = updater(&server.url)
+            .update(IpFamily::V6, "2001:db8::7".parse().unwrap())
+            .unwrap();
+
+        assert_eq!(outcome, "updated");
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "GET");
+        assert_eq!(
+            requests[0].url,
+            "/update?domains=myhome%2Ccabin&token=duck-token&ipv6=2001%3Adb8%3A%3A7"
+        );
+    }
+
+    #[test]
+    fn ko_waits_for_the_config_to_change() {
+        let server = mock::serve(|_| (200, "KO".to_string()));
+
+        let error = updater(&server.url)
+            .update(IpFamily::V4, "203.0.113.7".parse().unwrap())
+            .unwrap_err();
+
+        assert_eq!(error.message, "DuckDNS rejected the token or a domain");
+        assert!(matches!(error.backoff, Backoff::UntilConfigChanges));
+        assert!(server.requests()[0].url.ends_with("&ip=203.0.113.7"));
+    }
+
+    #[test]
+    fn anything_else_is_retried() {
+        let server = mock::serve(|_| (502, "Bad Gateway".to_string()));
+
+        let error = updater(&server.url)
+            .update(IpFamily::V4, "203.0.113.7".parse().unwrap())
+            .unwrap_err();
+
+        assert_eq!(
+            error.message,
+            "unexpected DuckDNS answer with HTTP status 502: Bad Gateway"
+        );
+        assert!(matches!(error.backoff, Backoff::NextCheck));
+    }
+}
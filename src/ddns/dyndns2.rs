@@ -0,0 +1,251 @@
+//! The dyndns2 update protocol, spoken by DynDNS, No-IP and many others
+use std::{net::IpAddr, time::Duration};
+
+use serde_json::Value;
+
+use crate::{http, ip_check::IpFamily};
+
+use super::{
+    Backoff, DnsUpdater, UpdateError, names, read_answer, record_type, setting, update_each,
+};
+
+/// How long the protocol asks clients to wait after a server side problem
+const SERVER_ERROR_WAIT: Duration = Duration::from_secs(30 * 60);
+
+/// Updates host names through a dyndns2 compatible server
+pub struct Dyndns2Updater {
+    /// The server, like `https://members.dyndns.org` or `https://dynupdate.no-ip.com`
+    pub server: String,
+    /// The update path, `/nic/update` unless the provider differs
+    pub path: String,
+    pub username: String,
+    pub password: String,
+    /// The host names to point at the address, sent in one request
+    pub hostnames: Vec<String>,
+}
+
+impl Dyndns2Updater {
+    /// Reads the updater from
+    /// `{"type": "dyndns2", "server": "https://...", "path": "/nic/update", "username": "...", "password": "...", "hostnames": ["home.example.com"]}`
+    ///
+    /// # Arguments
+    /// * `entry: &Value` - The updater entry
+    ///
+    /// # Returns
+    /// * `Result<Dyndns2Updater, String>` - Err if a setting is missing
+    pub fn from_value(entry: &Value) -> Result<Self, String> {
+        Ok(Dyndns2Updater {
+            server: setting(entry, "server", "dyndns2")?
+                .trim_end_matches('/')
+                .to_string(),
+            path: setting(entry, "path", "dyndns2").unwrap_or_else(|_| "/nic/update".to_string()),
+            username: setting(entry, "username", "dyndns2")?,
+            password: setting(entry, "password", "dyndns2")?,
+            hostnames: names(entry, "hostnames", "dyndns2")?,
+        })
+    }
+}
+
+/// What one line of the answer means
+///
+/// # Arguments
+/// * `line: &str` - A line like `good 203.0.113.9` or `badauth`
+///
+/// # Returns
+/// * `Result<&'static str, (String, Backoff)>` - What was done, or why it failed and when to retry
+pub fn parse_code(line: &str) -> Result<&'static str, (String, Backoff)> {
+    let code = line.split_whitespace().next().unwrap_or_default();
+
+    let (reason, backoff) = match code {
+        "good" => return Ok("updated"),
+        "nochg" => return Ok("already up to date"),
+        // The request can't ever work as configured, repeating it can get the account blocked
+        "badauth" => ("bad username or password", Backoff::UntilConfigChanges),
+        "!donator" => (
+            "the account can't use this feature",
+            Backoff::UntilConfigChanges,
+        ),
+        "notfqdn" => (
+            "the host name isn't fully qualified",
+            Backoff::UntilConfigChanges,
+        ),
+        "nohost" => (
+            "the host name doesn't exist in this account",
+            Backoff::UntilConfigChanges,
+        ),
+        "!yours" => (
+            "the host name belongs to another account",
+            Backoff::UntilConfigChanges,
+        ),
+        "numhost" => (
+            "too many host names in one request",
+            Backoff::UntilConfigChanges,
+        ),
+        "abuse" => (
+            "the host name is blocked for abuse",
+            Backoff::UntilConfigChanges,
+        ),
+        "badagent" => ("the client was rejected", Backoff::UntilConfigChanges),
+        // Problems on the provider's side, the protocol asks for a pause
+        "dnserr" => (
+            "DNS error at the provider",
+            Backoff::Wait(SERVER_ERROR_WAIT),
+        ),
+        "911" => (
+            "the provider has a problem or is in maintenance",
+            Backoff::Wait(SERVER_ERROR_WAIT),
+        ),
+        "" => ("empty answer", Backoff::NextCheck),
+        _ => ("unknown answer", Backoff::NextCheck),
+    };
+
+    Err((format!("{} ({})", reason, line.trim()), backoff))
+}
+
+/// Reads the answer to an update of several host names
+///
+/// # Arguments
+/// * `family: IpFamily` - The family that was updated
+/// * `hostnames: &[String]` - The host names, in the order they were sent
+/// * `status: u16` - The HTTP status
+/// * `body: &str` - The answer, one line per host name
+///
+/// # Returns
+/// * `Result<String, UpdateError>` - What was done per host name, Err with the longest backoff if any failed
+pub fn parse_answer(
+    family: IpFamily,
+    hostnames: &[String],
+    status: u16,
+    body: &str,
+) -> Result<String, UpdateError> {
+    // Some providers send the code with an error status, like badauth with 401
+    if status >= 400 && body.trim().is_empty() {
+        return Err(format!("HTTP status {}", status).into());
+    }
+
+    let lines: Vec<&str> = body
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    let mut i = 0;
+
+    update_each(record_type(family), hostnames, |_| {
+        // A single answer for several host names applies to all of them
+        let line = lines.get(i).or(lines.last()).copied().unwrap_or_default();
+        i += 1;
+
+        parse_code(line)
+            .map(|outcome| outcome.to_string())
+            .map_err(|(message, backoff)| UpdateError { message, backoff })
+    })
+}
+
+impl DnsUpdater for Dyndns2Updater {
+    fn name(&self) -> String {
+        format!("dyndns2 {} ({})", self.server, self.hostnames.join(", "))
+    }
+
+    fn update(&self, family: IpFamily, ip: IpAddr) -> Result<String, UpdateError> {
+        let response = http::agent()
+            .get(&format!("{}{}", self.server, self.path))
+            .set(
+                "Authorization",
+                &http::basic_auth(&self.username, &self.password),
+            )
+            .query("hostname", &self.hostnames.join(","))
+            .query("myip", &ip.to_string())
+            .call();
+
+        let (status, body) = read_answer(response)?;
+
+        parse_answer(family, &self.hostnames, status, &body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::http::mock;
+
+    #[test]
+    fn codes_map_to_outcome_or_backoff() {
+        assert_eq!(parse_code("good 203.0.113.9"), Ok("updated"));
+        assert_eq!(parse_code("nochg 203.0.113.9"), Ok("already up to date"));
+
+        let backoff = |line: &str| parse_code(line).unwrap_err().1;
+        assert_eq!(backoff("badauth"), Backoff::UntilConfigChanges);
+        assert_eq!(backoff("nohost"), Backoff::UntilConfigChanges);
+        assert_eq!(backoff("abuse"), Backoff::UntilConfigChanges);
+        assert_eq!(backoff("911"), Backoff::Wait(SERVER_ERROR_WAIT));
+        assert_eq!(backoff(""), Backoff::NextCheck);
+        assert_eq!(backoff("whatever"), Backoff::NextCheck);
+
+        let (message, _) = parse_code("badauth").unwrap_err();
+        assert_eq!(message, "bad username or password (badauth)");
+    }
+
+    #[test]
+    fn answer_lines_follow_the_host_names() {
+        let hostnames = vec!["a.example.com".to_string(), "b.example.com".to_string()];
+
+        let outcome = parse_answer(
+            IpFamily::V4,
+            &hostnames,
+            200,
+            "good 203.0.113.9\nnochg 203.0.113.9\n",
+        )
+        .unwrap();
+        assert_eq!(
+            outcome,
+            "A a.example.com updated; A b.example.com already up to date"
+        );
+
+        // The worst backoff wins when the host names fail differently
+        let error = parse_answer(IpFamily::V6, &hostnames, 200, "911\nnohost\n").unwrap_err();
+        assert_eq!(error.backoff, Backoff::UntilConfigChanges);
+        assert!(error.message.contains("AAAA b.example.com failed"));
+
+        // One answer covers every host name
+        let error = parse_answer(IpFamily::V4, &hostnames, 401, "badauth").unwrap_err();
+        assert_eq!(error.backoff, Backoff::UntilConfigChanges);
+        assert_eq!(error.message.matches("bad username").count(), 2);
+
+        let error = parse_answer(IpFamily::V4, &hostnames, 500, "").unwrap_err();
+        assert_eq!(error.message, "HTTP status 500");
+    }
+
+    #[test]
+    fn sends_one_authenticated_request_for_all_host_names() {
+        let server = mock::serve(|_| (200, "good 203.0.113.9\ngood 203.0.113.9".to_string()));
+        let updater = Dyndns2Updater::from_value(&json!({
+            "type": "dyndns2",
+            "server": format!("{}/", server.url),
+            "username": "user",
+            "password": "pass",
+            "hostnames": ["a.example.com", "b.example.com"],
+        }))
+        .unwrap();
+
+        let outcome = updater
+            .update(IpFamily::V4, "203.0.113.9".parse().unwrap())
+            .unwrap();
+        assert_eq!(outcome, "A a.example.com updated; A b.example.com updated");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "GET");
+        assert!(requests[0].url.starts_with("/nic/update?"));
+        assert!(
+            requests[0]
+                .url
+                .contains("hostname=a.example.com%2Cb.example.com")
+        );
+        assert!(requests[0].url.contains("myip=203.0.113.9"));
+        assert_eq!(
+            requests[0].header("Authorization"),
+            Some(http::basic_auth("user", "pass").as_str())
+        );
+    }
+}
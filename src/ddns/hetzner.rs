@@ -0,0 +1,325 @@
+//! Updates A and AAAA records through the Hetzner DNS API
+use std::net::IpAddr;
+
+use serde_json::{Value, json};
+
+use crate::{http, ip_check::IpFamily};
+
+use super::{
+    Backoff, DnsUpdater, UpdateError, names, read_json_answer, record_type, relative_name, setting,
+    update_each,
+};
+
+/// Where the Hetzner DNS API lives unless the entry points somewhere else
+pub const DEFAULT_API_URL: &str = "https://dns.hetzner.com/api/v1";
+
+/// Keeps records in one Hetzner DNS zone pointed at the public address
+pub struct HetznerUpdater {
+    /// An API token from the DNS console
+    pub api_token: String,
+    /// The zone the records are in, like `example.com`
+    pub zone: String,
+    /// The full record names, like `home.example.com`
+    pub records: Vec<String>,
+    /// The TTL in seconds
+    pub ttl: u64,
+    /// The API server
+    pub api_url: String,
+}
+
+impl HetznerUpdater {
+    /// Reads the updater from
+    /// `{"type": "hetzner", "apiToken": "...", "zone": "example.com", "records": ["home.example.com"], "ttl": 300}`
+    ///
+    /// # Arguments
+    /// * `entry: &Value` - The updater entry
+    ///
+    /// # Returns
+    /// * `Result<HetznerUpdater, String>` - Err if a setting is missing or a record is outside the zone
+    pub fn from_value(entry: &Value) -> Result<Self, String> {
+        let zone = setting(entry, "zone", "Hetzner")?
+            .trim_end_matches('.')
+            .to_lowercase();
+        let records = names(entry, "records", "Hetzner")?;
+
+        if let Some(outside) = records
+            .iter()
+            .find(|record| relative_name(record, &zone).is_none())
+        {
+            return Err(format!("{} is not in the zone {}", outside, zone));
+        }
+
+        Ok(HetznerUpdater {
+            api_token: setting(entry, "apiToken", "Hetzner")?,
+            zone,
+            records,
+            ttl: entry.get("ttl").and_then(|v| v.as_u64()).unwrap_or(300),
+            api_url: setting(entry, "apiUrl", "Hetzner")
+                .unwrap_or_else(|_| DEFAULT_API_URL.to_string())
+                .trim_end_matches('/')
+                .to_string(),
+        })
+    }
+
+    /// Sends one API request
+    ///
+    /// # Arguments
+    /// * `method: &str` - The HTTP method
+    /// * `path: &str` - The path below the API root
+    /// * `query: &[(&str, &str)]` - Query parameters
+    /// * `body: Option<Value>` - The JSON body, if any
+    ///
+    /// # Returns
+    /// * `Result<Value, UpdateError>` - The answer, Err with Hetzner's error message if it failed
+    fn call(
+        &self,
+        method: &str,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Option<Value>,
+    ) -> Result<Value, UpdateError> {
+        let mut request = http::agent()
+            .request(method, &format!("{}{}", self.api_url, path))
+            .set("Auth-API-Token", &self.api_token);
+
+        for (name, value) in query {
+            request = request.query(name, value);
+        }
+
+        let response = match body {
+            Some(body) => request
+                .set("Content-Type", "application/json")
+                .send_string(&body.to_string()),
+            None => request.call(),
+        };
+
+        let (status, answer) = read_json_answer(response, "Hetzner")?;
+
+        if status < 400 {
+            return Ok(answer);
+        }
+
+        // Errors come as {"error": {"message": ..., "code": ...}} or just {"message": ...}
+        let message = answer
+            .pointer("/error/message")
+            .or_else(|| answer.get("message"))
+            .and_then(|v| v.as_str())
+            .filter(|message| !message.is_empty())
+            .unwrap_or("Hetzner API request failed");
+
+        Err(UpdateError {
+            message: format!("{} (HTTP status {})", message, status),
+            backoff: match status {
+                401 | 403 => Backoff::UntilConfigChanges,
+                _ => Backoff::NextCheck,
+            },
+        })
+    }
+
+    /// Looks up the ID of the zone
+    fn zone_id(&self) -> Result<String, UpdateError> {
+        let answer = self.call("GET", "/zones", &[("name", &self.zone)], None)?;
+
+        answer
+            .get("zones")
+            .and_then(|v| v.as_array())
+            .and_then(|zones| {
+                zones
+                    .iter()
+                    .find(|zone| zone.get("name").and_then(|v| v.as_str()) == Some(&self.zone))
+            })
+            .and_then(|zone| zone.get("id"))
+            .and_then(|v| v.as_str())
+            .map(|id| id.to_string())
+            .ok_or_else(|| UpdateError {
+                message: format!("the zone {} isn't in this Hetzner account", self.zone),
+                backoff: Backoff::UntilConfigChanges,
+            })
+    }
+
+    /// Points one record at the address, creating it if the zone doesn't have it yet
+    ///
+    /// # Arguments
+    /// * `zone_id: &str` - The zone's ID
+    /// * `existing: &[Value]` - The records in the zone
+    /// * `name: &str` - The full record name
+    /// * `record_type: &str` - A or AAAA
+    /// * `ip: IpAddr` - The new address
+    ///
+    /// # Returns
+    /// * `Result<String, UpdateError>` - What was done
+    fn update_record(
+        &self,
+        zone_id: &str,
+        existing: &[Value],
+        name: &str,
+        record_type: &str,
+        ip: IpAddr,
+    ) -> Result<String, UpdateError> {
+        // Hetzner names records relative to the zone, the apex is @
+        let relative = match relative_name(name, &self.zone).unwrap_or_default() {
+            relative if relative.is_empty() => "@".to_string(),
+            relative => relative,
+        };
+        let content = ip.to_string();
+        let body = json!({
+            "zone_id": zone_id,
+            "type": record_type,
+            "name": relative,
+            "value": content,
+            "ttl": self.ttl,
+        });
+
+        let record = existing.iter().find(|record| {
+            record.get("type").and_then(|v| v.as_str()) == Some(record_type)
+                && record.get("name").and_then(|v| v.as_str()) == Some(relative.as_str())
+        });
+
+        match record {
+            Some(record) => {
+                if record.get("value").and_then(|v| v.as_str()) == Some(content.as_str()) {
+                    return Ok("already up to date".to_string());
+                }
+
+                let id = record
+                    .get("id")
+                    .and_then(|v| v.as_str())
+                    .ok_or_else(|| "Hetzner returned a record without an ID".to_string())?;
+
+                self.call("PUT", &format!("/records/{}", id), &[], Some(body))?;
+                Ok("updated".to_string())
+            }
+            None => {
+                self.call("POST", "/records", &[], Some(body))?;
+                Ok("created".to_string())
+            }
+        }
+    }
+}
+
+impl DnsUpdater for HetznerUpdater {
+    fn name(&self) -> String {
+        format!("Hetzner ({})", self.records.join(", "))
+    }
+
+    fn update(&self, family: IpFamily, ip: IpAddr) -> Result<String, UpdateError> {
+        let record_type = record_type(family);
+
+        // One listing serves every record in the zone
+        let zone_id = self.zone_id()?;
+        let existing = self
+            .call("GET", "/records", &[("zone_id", &zone_id)], None)?
+            .get("records")
+            .and_then(|v| v.as_array())
+            .cloned()
+            .unwrap_or_default();
+
+        update_each(record_type, &self.records, |name| {
+            self.update_record(&zone_id, &existing, name, record_type, ip)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::mock;
+
+    fn updater(api_url: &str, records: &[&str]) -> HetznerUpdater {
+        HetznerUpdater::from_value(&json!({
+            "type": "hetzner",
+            "apiToken": "hz-token",
+            "zone": "example.com",
+            "records": records,
+            "ttl": 120,
+            "apiUrl": api_url,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn looks_up_the_zone_then_updates_and_creates_records() {
+        let server = mock::serve(
+            |request| match (request.method.as_str(), request.url.as_str()) {
+                ("GET", "/zones?name=example.com") => (
+                    200,
+                    json!({"zones": [
+                        {"id": "other", "name": "example.org"},
+                        {"id": "zone1", "name": "example.com"},
+                    ]})
+                    .to_string(),
+                ),
+                ("GET", "/records?zone_id=zone1") => (
+                    200,
+                    json!({"records": [
+                        {"id": "rec1", "type": "A", "name": "home", "value": "192.0.2.1"},
+                        {"id": "rec2", "type": "AAAA", "name": "@", "value": "2001:db8::1"},
+                    ]})
+                    .to_string(),
+                ),
+                _ => (200, json!({"record": {}}).to_string()),
+            },
+        );
+
+        let outcome = updater(&server.url, &["home.example.com", "example.com"])
+            .update(IpFamily::V4, "203.0.113.7".parse().unwrap())
+            .unwrap();
+        assert_eq!(outcome, "A home.example.com updated; A example.com created");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        for request in &requests {
+            assert_eq!(request.header("Auth-API-Token"), Some("hz-token"));
+        }
+        assert_eq!(
+            (requests[2].method.as_str(), requests[2].url.as_str()),
+            ("PUT", "/records/rec1")
+        );
+        assert_eq!(
+            requests[2].json(),
+            json!({"zone_id": "zone1", "type": "A", "name": "home", "value": "203.0.113.7", "ttl": 120})
+        );
+        // The AAAA record at the apex doesn't count for an A update
+        assert_eq!(
+            (requests[3].method.as_str(), requests[3].url.as_str()),
+            ("POST", "/records")
+        );
+        assert_eq!(requests[3].json()["name"], "@");
+    }
+
+    #[test]
+    fn zone_missing_from_the_account_waits_for_the_config_to_change() {
+        let server = mock::serve(|_| (200, json!({"zones": []}).to_string()));
+
+        let error = updater(&server.url, &["home.example.com"])
+            .update(IpFamily::V4, "203.0.113.7".parse().unwrap())
+            .unwrap_err();
+
+        assert_eq!(
+            error.message,
+            "the zone example.com isn't in this Hetzner account"
+        );
+        assert!(matches!(error.backoff, Backoff::UntilConfigChanges));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn api_errors_carry_the_message_and_status() {
+        let server = mock::serve(|_| {
+            (
+                401,
+                json!({"message": "Invalid authentication credentials"}).to_string(),
+            )
+        });
+
+        let error = updater(&server.url, &["home.example.com"])
+            .update(IpFamily::V6, "2001:db8::7".parse().unwrap())
+            .unwrap_err();
+
+        assert_eq!(
+            error.message,
+            "Invalid authentication credentials (HTTP status 401)"
+        );
+        assert!(matches!(error.backoff, Backoff::UntilConfigChanges));
+    }
+}
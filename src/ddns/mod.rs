@@ -0,0 +1,506 @@
+//! Dynamic DNS, the configured records are kept pointed at the public address
+//!
+//! What each updater last set is saved in the data directory, so records are only touched when
+//! they are out of date and failed updates are retried on the next check
+use std::{fmt, fs, net::IpAddr, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+use crate::{config::Config, constants, http, ip_check::IpFamily, json_handler};
+
+pub mod cloudflare;
+pub mod desec;
+pub mod duckdns;
+pub mod dyndns2;
+pub mod hetzner;
+pub mod porkbun;
+pub mod rfc2136;
+
+/// How long to wait after being rate limited when the provider doesn't say
+const RATE_LIMIT_WAIT_SECONDS: u64 = 10 * 60;
+
+/// When a failed update may be tried again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Backoff {
+    /// On the next check
+    NextCheck,
+    /// Not before this much time has passed
+    Wait(Duration),
+    /// Not until the entry in the config is changed, retrying would only make things worse
+    UntilConfigChanges,
+}
+
+impl Backoff {
+    /// Orders backoffs, so the longest one wins when several records fail
+    fn severity(&self) -> u8 {
+        match self {
+            Backoff::NextCheck => 0,
+            Backoff::Wait(_) => 1,
+            Backoff::UntilConfigChanges => 2,
+        }
+    }
+}
+
+/// Why an update failed and when to try again
+#[derive(Debug, Clone)]
+pub struct UpdateError {
+    pub message: String,
+    pub backoff: Backoff,
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for UpdateError {}
+
+impl From<String> for UpdateError {
+    fn from(message: String) -> Self {
+        UpdateError {
+            message,
+            backoff: Backoff::NextCheck,
+        }
+    }
+}
+
+/// A DNS provider whose records can be updated
+pub trait DnsUpdater {
+    /// A short name for logs, like "Cloudflare (home.example.com)"
+    fn name(&self) -> String;
+
+    /// Points the records of the address family at the address
+    ///
+    /// # Arguments
+    /// * `family: IpFamily` - Picks A or AAAA records
+    /// * `ip: IpAddr` - The new address
+    ///
+    /// # Returns
+    /// * `Result<String, UpdateError>` - What was done, like "updated" or "already up to date"
+    fn update(&self, family: IpFamily, ip: IpAddr) -> Result<String, UpdateError>;
+}
+
+/// Reads a setting an updater can't work without
+///
+/// # Arguments
+/// * `entry: &Value` - The updater entry
+/// * `key: &str` - The setting
+/// * `updater: &str` - The updater name, for the error
+///
+/// # Returns
+/// * `Result<String, String>` - Err if the setting is missing or empty
+pub fn setting(entry: &Value, key: &str, updater: &str) -> Result<String, String> {
+    entry
+        .get(key)
+        .and_then(|v| v.as_str())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_string())
+        .ok_or_else(|| format!("{} updater needs a {:?}", updater, key))
+}
+
+/// Reads a list of names an updater needs at least one of
+///
+/// # Arguments
+/// * `entry: &Value` - The updater entry
+/// * `key: &str` - The setting, like `records`
+/// * `updater: &str` - The updater name, for the error
+///
+/// # Returns
+/// * `Result<Vec<String>, String>` - Lower case names without a trailing dot, Err if there are none
+pub fn names(entry: &Value, key: &str, updater: &str) -> Result<Vec<String>, String> {
+    let names: Vec<String> = entry
+        .get(key)
+        .and_then(|v| v.as_array())
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|name| name.as_str())
+                .map(|name| name.trim_end_matches('.').to_lowercase())
+                .filter(|name| !name.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    if names.is_empty() {
+        return Err(format!(
+            "{} updater needs at least one of {:?}",
+            updater, key
+        ));
+    }
+
+    Ok(names)
+}
+
+/// Returns a name relative to its zone, like `home` for `home.example.com`
+///
+/// # Arguments
+/// * `name: &str` - The full name
+/// * `zone: &str` - The zone
+///
+/// # Returns
+/// * `Option<String>` - The relative name, empty for the zone apex, None if the name is outside the zone
+pub fn relative_name(name: &str, zone: &str) -> Option<String> {
+    if name == zone {
+        return Some(String::new());
+    }
+
+    name.strip_suffix(zone)
+        .and_then(|name| name.strip_suffix('.'))
+        .map(|name| name.to_string())
+}
+
+/// Reads the status and body of an API answer, error statuses included since APIs explain them in the body
+///
+/// Rate limiting is turned into a backoff, so the provider isn't asked again too early
+///
+/// # Arguments
+/// * `result: Result<ureq::Response, ureq::Error>` - What the request returned
+///
+/// # Returns
+/// * `Result<(u16, String), UpdateError>` - The status and body, Err if there was no answer or it was rate limited
+pub fn read_answer(
+    result: Result<ureq::Response, ureq::Error>,
+) -> Result<(u16, String), UpdateError> {
+    let response = match result {
+        Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+        Err(e) => return Err(http::describe_error(e).into()),
+    };
+    let status = response.status();
+
+    if status == 429 {
+        let wait = response
+            .header("Retry-After")
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .unwrap_or(RATE_LIMIT_WAIT_SECONDS);
+
+        return Err(UpdateError {
+            message: "rate limited by the provider".to_string(),
+            backoff: Backoff::Wait(Duration::from_secs(wait)),
+        });
+    }
+
+    let body = response.into_string().map_err(|e| e.to_string())?;
+
+    Ok((status, body))
+}
+
+/// Reads a JSON API answer
+///
+/// # Arguments
+/// * `result: Result<ureq::Response, ureq::Error>` - What the request returned
+/// * `provider: &str` - The provider name, for the error
+///
+/// # Returns
+/// * `Result<(u16, Value), UpdateError>` - The status and parsed body, Err if it isn't JSON
+pub fn read_json_answer(
+    result: Result<ureq::Response, ureq::Error>,
+    provider: &str,
+) -> Result<(u16, Value), UpdateError> {
+    let (status, body) = read_answer(result)?;
+
+    let json = serde_json::from_str(&body).map_err(|_| {
+        format!(
+            "{} answered HTTP status {} with something that isn't JSON: {:.200}",
+            provider, status, body
+        )
+    })?;
+
+    Ok((status, json))
+}
+
+/// The record type holding addresses of the family
+pub fn record_type(family: IpFamily) -> &'static str {
+    match family {
+        IpFamily::V4 => "A",
+        IpFamily::V6 => "AAAA",
+    }
+}
+
+/// Updates every record, so one bad name doesn't keep the others stale
+///
+/// # Arguments
+/// * `record_type: &str` - A or AAAA, for the outcome
+/// * `records: &[String]` - The record names
+/// * `update: F` - Updates one record, returning what was done
+///
+/// # Returns
+/// * `Result<String, UpdateError>` - The outcome of every record, Err with the longest backoff if any failed
+pub fn update_each<F>(
+    record_type: &str,
+    records: &[String],
+    mut update: F,
+) -> Result<String, UpdateError>
+where
+    F: FnMut(&str) -> Result<String, UpdateError>,
+{
+    let mut outcomes = Vec::new();
+    let mut worst: Option<Backoff> = None;
+
+    for name in records {
+        match update(name) {
+            Ok(outcome) => outcomes.push(format!("{} {} {}", record_type, name, outcome)),
+            Err(e) => {
+                outcomes.push(format!("{} {} failed: {}", record_type, name, e));
+                if worst
+                    .as_ref()
+                    .is_none_or(|worst| e.backoff.severity() > worst.severity())
+                {
+                    worst = Some(e.backoff);
+                }
+            }
+        }
+    }
+
+    match worst {
+        None => Ok(outcomes.join("; ")),
+        Some(backoff) => Err(UpdateError {
+            message: outcomes.join("; "),
+            backoff,
+        }),
+    }
+}
+
+/// Builds an updater from its config entry
+///
+/// # Arguments
+/// * `entry: &Value` - The entry from the `dnsUpdaters` array, picked by its `type` field
+///
+/// # Returns
+/// * `Result<Box<dyn DnsUpdater>, String>` - Err if the entry is invalid
+pub fn from_value(entry: &Value) -> Result<Box<dyn DnsUpdater>, String> {
+    let updater_type = entry
+        .get("type")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| format!("DNS updater {} has no type", entry))?;
+
+    match updater_type {
+        "cloudflare" => Ok(Box::new(cloudflare::CloudflareUpdater::from_value(entry)?)),
+        "dyndns2" => Ok(Box::new(dyndns2::Dyndns2Updater::from_value(entry)?)),
+        "rfc2136" => Ok(Box::new(rfc2136::Rfc2136Updater::from_value(entry)?)),
+        "duckdns" => Ok(Box::new(duckdns::DuckDnsUpdater::from_value(entry)?)),
+        "desec" => Ok(Box::new(desec::DesecUpdater::from_value(entry)?)),
+        "porkbun" => Ok(Box::new(porkbun::PorkbunUpdater::from_value(entry)?)),
+        "hetzner" => Ok(Box::new(hetzner::HetznerUpdater::from_value(entry)?)),
+        _ => Err(format!("Unknown DNS updater type {:?}", updater_type)),
+    }
+}
+
+/// Builds every enabled updater in the config, invalid entries are logged and skipped
+///
+/// # Arguments
+/// * `config: &Config` - The config holding the `dnsUpdaters` array
+///
+/// # Returns
+/// * `Vec<(String, Box<dyn DnsUpdater>)>` - The key its state is saved under and the updater, in config order
+pub fn from_config(config: &Config) -> Vec<(String, Box<dyn DnsUpdater>)> {
+    config
+        .dns_updaters
+        .iter()
+        // Entries can be switched off without removing them
+        .filter(|entry| entry.get("enabled").and_then(|v| v.as_bool()) != Some(false))
+        .filter_map(|entry| match from_value(entry) {
+            Ok(updater) => Some((state_key(entry), updater)),
+            Err(e) => {
+                eprintln!("Skipping DNS updater: {}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// The key an entry's state is saved under, changing anything in the entry gives a new key
+///
+/// That way editing a broken entry clears its backoff. SHA-256 rather than the std hasher, whose
+/// output may change between Rust releases and would drop the saved state on an upgrade
+fn state_key(entry: &Value) -> String {
+    Sha256::digest(entry.to_string().as_bytes())[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Reads the saved state, a missing or broken file is an empty state
+pub fn read_state() -> Value {
+    fs::read_to_string(constants::get_ddns_state_path())
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .filter(|state: &Value| state.is_object())
+        .unwrap_or_else(|| json!({}))
+}
+
+/// Prints when each updater last succeeded and whether it is backing off
+///
+/// # Arguments
+/// * `config: &Config` - The config holding the `dnsUpdaters` array
+pub fn print_state(config: &Config) {
+    let state = read_state();
+
+    for (key, updater) in from_config(config) {
+        println!("DNS updater {}:", updater.name());
+        let mut updated = false;
+
+        for family in [IpFamily::V4, IpFamily::V6] {
+            let saved = &state[&key][family_key(family)];
+            if let (Some(ip), Some(time)) = (saved["ip"].as_str(), saved["updated"].as_str()) {
+                println!("  {} set to {} at {}", family_key(family), ip, time);
+                updated = true;
+            }
+            if let Some(reason) = blocked(saved) {
+                println!("  {} backing off: {}", family_key(family), reason);
+            }
+        }
+
+        if !updated {
+            println!("  Never updated");
+        }
+    }
+}
+
+/// The config key of a family in the state
+fn family_key(family: IpFamily) -> &'static str {
+    match family {
+        IpFamily::V4 => "ipv4",
+        IpFamily::V6 => "ipv6",
+    }
+}
+
+/// Tells why an updater has to wait, if it does
+///
+/// # Arguments
+/// * `state: &Value` - The state of one family of an updater, so a failing family doesn't hold up the other
+///
+/// # Returns
+/// * `Option<String>` - Why it is skipped, None if it may run
+fn blocked(state: &Value) -> Option<String> {
+    let reason = state.get("blockedReason").and_then(|v| v.as_str())?;
+
+    match state.get("blockedUntil").and_then(|v| v.as_str()) {
+        // Saved without an end, only a config change lifts it
+        None => Some(format!("{}, fix the entry in the config to retry", reason)),
+        Some(until) => {
+            let until = DateTime::parse_from_rfc3339(until).ok()?;
+            (until > Utc::now()).then(|| format!("{}, retrying after {}", reason, until))
+        }
+    }
+}
+
+/// Brings every updater in line with the address, skipping those already up to date or backing off
+///
+/// Meant to run after every successful lookup, so failed updates are retried
+///
+/// # Arguments
+/// * `config: &Config` - The config holding the `dnsUpdaters` array
+/// * `family: IpFamily` - The family that was looked up
+/// * `ip: IpAddr` - The current address
+///
+/// # Returns
+/// * `Vec<String>` - One line per updater that did something or was skipped, for the notification
+pub fn sync(config: &Config, family: IpFamily, ip: IpAddr) -> Vec<String> {
+    let updaters = from_config(config);
+    if updaters.is_empty() {
+        return Vec::new();
+    }
+
+    let saved = read_state();
+    let mut state = json!({});
+    let mut lines = Vec::new();
+
+    for (key, updater) in &updaters {
+        // Entries no longer in the config are dropped from the state
+        let mut entry_state = saved.get(key).cloned().unwrap_or_else(|| json!({}));
+        let family_state = &mut entry_state[family_key(family)];
+
+        if family_state["ip"].as_str() == Some(ip.to_string().as_str()) {
+            state[key] = entry_state;
+            continue;
+        }
+
+        if let Some(reason) = blocked(family_state) {
+            eprintln!("DNS update via {} skipped: {}", updater.name(), reason);
+            lines.push(format!("DNS {}: skipped, {}", updater.name(), reason));
+            state[key] = entry_state;
+            continue;
+        }
+
+        match updater.update(family, ip) {
+            Ok(outcome) => {
+                println!("DNS update via {}: {}", updater.name(), outcome);
+                lines.push(format!("DNS {}: {}", updater.name(), outcome));
+
+                // Replacing the family's state also lifts its backoff
+                *family_state = json!({
+                    "ip": ip.to_string(),
+                    "updated": Utc::now().to_rfc3339(),
+                });
+            }
+            Err(e) => {
+                eprintln!("DNS update via {} failed: {}", updater.name(), e);
+                lines.push(format!("DNS {}: FAILED, {}", updater.name(), e));
+
+                match e.backoff {
+                    Backoff::NextCheck => {}
+                    Backoff::Wait(wait) => {
+                        family_state["blockedReason"] = json!(e.message);
+                        family_state["blockedUntil"] = json!(
+                            (Utc::now() + chrono::Duration::from_std(wait).unwrap_or_default())
+                                .to_rfc3339()
+                        );
+                    }
+                    Backoff::UntilConfigChanges => {
+                        family_state["blockedReason"] = json!(e.message);
+                        family_state["blockedUntil"] = Value::Null;
+                    }
+                }
+            }
+        }
+
+        state[key] = entry_state;
+    }
+
+    json_handler::write_json_from_value(&constants::get_ddns_state_path(), &state);
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_key_is_stable_and_follows_the_entry() {
+        let entry = json!({"type": "duckdns", "records": ["home.example.com"]});
+
+        // Saved state has to survive an upgrade, so the key may never change for the same entry
+        assert_eq!(state_key(&entry), "e782bbb1dd5adf4f");
+        assert_ne!(
+            state_key(&json!({"type": "duckdns", "records": ["away.example.com"]})),
+            state_key(&entry)
+        );
+    }
+
+    #[test]
+    fn backoff_of_one_family_leaves_the_other_alone() {
+        let later = (Utc::now() + chrono::Duration::hours(1)).to_rfc3339();
+        let earlier = (Utc::now() - chrono::Duration::hours(1)).to_rfc3339();
+        let state = json!({
+            "ipv4": {"ip": "192.0.2.1", "updated": earlier},
+            "ipv6": {"blockedReason": "badauth", "blockedUntil": null},
+        });
+
+        assert_eq!(blocked(&state[family_key(IpFamily::V4)]), None);
+        assert_eq!(
+            blocked(&state[family_key(IpFamily::V6)]).as_deref(),
+            Some("badauth, fix the entry in the config to retry")
+        );
+        assert!(
+            blocked(&json!({"blockedReason": "rate limited", "blockedUntil": later})).is_some()
+        );
+        assert_eq!(
+            blocked(&json!({"blockedReason": "rate limited", "blockedUntil": earlier})),
+            None
+        );
+    }
+}
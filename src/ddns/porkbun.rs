@@ -0,0 +1,284 @@
+//! Updates A and AAAA records through the Porkbun v3 API
+use std::net::IpAddr;
+
+use serde_json::{Value, json};
+
+use crate::{http, ip_check::IpFamily};
+
+use super::{
+    Backoff, DnsUpdater, UpdateError, names, read_json_answer, record_type, relative_name, setting,
+    update_each,
+};
+
+/// Where the Porkbun API lives unless the entry points somewhere else
+pub const DEFAULT_API_URL: &str = "https://api.porkbun.com/api/json/v3";
+
+/// Keeps records in one Porkbun domain pointed at the public address
+pub struct PorkbunUpdater {
+    pub api_key: String,
+    pub secret_api_key: String,
+    /// The domain the records are in, like `example.com`
+    pub domain: String,
+    /// The full record names, like `home.example.com`
+    pub records: Vec<String>,
+    /// The TTL in seconds, Porkbun's minimum is 600
+    pub ttl: u64,
+    /// The API server
+    pub api_url: String,
+}
+
+impl PorkbunUpdater {
+    /// Reads the updater from
+    /// `{"type": "porkbun", "apiKey": "pk1_...", "secretApiKey": "sk1_...", "domain": "example.com", "records": ["home.example.com"], "ttl": 600}`
+    ///
+    /// # Arguments
+    /// * `entry: &Value` - The updater entry
+    ///
+    /// # Returns
+    /// * `Result<PorkbunUpdater, String>` - Err if a setting is missing or a record is outside the domain
+    pub fn from_value(entry: &Value) -> Result<Self, String> {
+        let domain = setting(entry, "domain", "Porkbun")?
+            .trim_end_matches('.')
+            .to_lowercase();
+        let records = names(entry, "records", "Porkbun")?;
+
+        if let Some(outside) = records
+            .iter()
+            .find(|record| relative_name(record, &domain).is_none())
+        {
+            return Err(format!("{} is not in the domain {}", outside, domain));
+        }
+
+        Ok(PorkbunUpdater {
+            api_key: setting(entry, "apiKey", "Porkbun")?,
+            secret_api_key: setting(entry, "secretApiKey", "Porkbun")?,
+            domain,
+            records,
+            ttl: entry.get("ttl").and_then(|v| v.as_u64()).unwrap_or(600),
+            api_url: setting(entry, "apiUrl", "Porkbun")
+                .unwrap_or_else(|_| DEFAULT_API_URL.to_string())
+                .trim_end_matches('/')
+                .to_string(),
+        })
+    }
+
+    /// Sends one API request, every call is a POST with the keys in the body
+    ///
+    /// # Arguments
+    /// * `path: &str` - The path below the API root
+    /// * `body: Value` - The request settings, the keys are added
+    ///
+    /// # Returns
+    /// * `Result<Value, UpdateError>` - The answer, Err with Porkbun's message if it failed
+    fn call(&self, path: &str, mut body: Value) -> Result<Value, UpdateError> {
+        body["apikey"] = json!(self.api_key);
+        body["secretapikey"] = json!(self.secret_api_key);
+
+        let response = http::agent()
+            .post(&format!("{}{}", self.api_url, path))
+            .set("Content-Type", "application/json")
+            .send_string(&body.to_string());
+
+        let (status, answer) = read_json_answer(response, "Porkbun")?;
+
+        if answer.get("status").and_then(|v| v.as_str()) == Some("SUCCESS") {
+            return Ok(answer);
+        }
+
+        let message = answer
+            .get("message")
+            .and_then(|v| v.as_str())
+            .unwrap_or("Porkbun API request failed")
+            .to_string();
+
+        // Wrong keys or a domain without API access won't fix themselves
+        Err(UpdateError {
+            backoff: match status {
+                401 | 403 => Backoff::UntilConfigChanges,
+                _ => Backoff::NextCheck,
+            },
+            message,
+        })
+    }
+
+    /// Points one record at the address, creating it if the domain doesn't have it yet
+    ///
+    /// # Arguments
+    /// * `name: &str` - The full record name
+    /// * `record_type: &str` - A or AAAA
+    /// * `ip: IpAddr` - The new address
+    ///
+    /// # Returns
+    /// * `Result<String, UpdateError>` - What was done
+    fn update_record(
+        &self,
+        name: &str,
+        record_type: &str,
+        ip: IpAddr,
+    ) -> Result<String, UpdateError> {
+        // Checked when the entry was read
+        let subdomain = relative_name(name, &self.domain).unwrap_or_default();
+        let content = ip.to_string();
+
+        let existing = self.call(
+            // The apex has no subdomain segment
+            format!(
+                "/dns/retrieveByNameType/{}/{}/{}",
+                self.domain, record_type, subdomain
+            )
+            .trim_end_matches('/'),
+            json!({}),
+        )?;
+        let records = existing
+            .get("records")
+            .and_then(|v| v.as_array())
+            .cloned()
+            .unwrap_or_default();
+
+        if records.is_empty() {
+            self.call(
+                &format!("/dns/create/{}", self.domain),
+                json!({
+                    "name": subdomain,
+                    "type": record_type,
+                    "content": content,
+                    "ttl": self.ttl.to_string(),
+                }),
+            )?;
+            return Ok("created".to_string());
+        }
+
+        if records
+            .iter()
+            .all(|record| record.get("content").and_then(|v| v.as_str()) == Some(content.as_str()))
+        {
+            return Ok("already up to date".to_string());
+        }
+
+        self.call(
+            // The apex has no subdomain segment
+            format!(
+                "/dns/editByNameType/{}/{}/{}",
+                self.domain, record_type, subdomain
+            )
+            .trim_end_matches('/'),
+            json!({ "content": content, "ttl": self.ttl.to_string() }),
+        )?;
+        Ok("updated".to_string())
+    }
+}
+
+impl DnsUpdater for PorkbunUpdater {
+    fn name(&self) -> String {
+        format!("Porkbun ({})", self.records.join(", "))
+    }
+
+    fn update(&self, family: IpFamily, ip: IpAddr) -> Result<String, UpdateError> {
+        let record_type = record_type(family);
+
+        update_each(record_type, &self.records, |name| {
+            self.update_record(name, record_type, ip)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::mock;
+
+    fn updater(api_url: &str, records: &[&str]) -> PorkbunUpdater {
+        PorkbunUpdater::from_value(&json!({
+            "type": "porkbun",
+            "apiKey": "pk1_key",
+            "secretApiKey": "sk1_secret",
+            "domain": "example.com",
+            "records": records,
+            "apiUrl": api_url,
+        }))
+        .unwrap()
+    }
+
+    fn success(records: Value) -> (u16, String) {
+        (
+            200,
+            json!({"status": "SUCCESS", "records": records}).to_string(),
+        )
+    }
+
+    #[test]
+    fn edits_creates_and_skips_records() {
+        let server = mock::serve(|request| match request.url.as_str() {
+            "/dns/retrieveByNameType/example.com/A/home" => {
+                success(json!([{"id": "1", "content": "192.0.2.1"}]))
+            }
+            "/dns/retrieveByNameType/example.com/A" => {
+                success(json!([{"id": "2", "content": "203.0.113.7"}]))
+            }
+            _ => success(json!([])),
+        });
+
+        let outcome = updater(
+            &server.url,
+            &["home.example.com", "example.com", "new.example.com"],
+        )
+        .update(IpFamily::V4, "203.0.113.7".parse().unwrap())
+        .unwrap();
+        assert_eq!(
+            outcome,
+            "A home.example.com updated; A example.com already up to date; A new.example.com created"
+        );
+
+        let requests = server.requests();
+        let calls: Vec<&str> = requests.iter().map(|r| r.url.as_str()).collect();
+        assert_eq!(
+            calls,
+            vec![
+                "/dns/retrieveByNameType/example.com/A/home",
+                "/dns/editByNameType/example.com/A/home",
+                "/dns/retrieveByNameType/example.com/A",
+                "/dns/retrieveByNameType/example.com/A/new",
+                "/dns/create/example.com",
+            ]
+        );
+        // Every call is a POST carrying the keys
+        for request in &requests {
+            assert_eq!(request.method, "POST");
+            assert_eq!(request.json()["apikey"], "pk1_key");
+            assert_eq!(request.json()["secretapikey"], "sk1_secret");
+        }
+        assert_eq!(requests[1].json()["content"], "203.0.113.7");
+        assert_eq!(requests[1].json()["ttl"], "600");
+        assert_eq!(
+            requests[4].json(),
+            json!({
+                "name": "new",
+                "type": "A",
+                "content": "203.0.113.7",
+                "ttl": "600",
+                "apikey": "pk1_key",
+                "secretapikey": "sk1_secret",
+            })
+        );
+    }
+
+    #[test]
+    fn rejected_keys_wait_for_the_config_to_change() {
+        let server = mock::serve(|_| {
+            (
+                403,
+                json!({"status": "ERROR", "message": "Invalid API key. (002)"}).to_string(),
+            )
+        });
+
+        let error = updater(&server.url, &["home.example.com"])
+            .update(IpFamily::V6, "2001:db8::7".parse().unwrap())
+            .unwrap_err();
+
+        assert_eq!(
+            error.message,
+            "AAAA home.example.com failed: Invalid API key. (002)"
+        );
+        assert!(matches!(error.backoff, Backoff::UntilConfigChanges));
+    }
+}
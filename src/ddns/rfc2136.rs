@@ -0,0 +1,714 @@
+//! RFC 2136 dynamic updates signed with TSIG (RFC 8945), for self hosted BIND or Knot zones
+use std::net::IpAddr;
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::{
+    dns::{self, RecordType},
+    ip_check::IpFamily,
+};
+
+use super::{Backoff, DnsUpdater, UpdateError, names, record_type, relative_name, setting};
+
+const OPCODE_UPDATE: u16 = 5 << 11;
+const TYPE_SOA: u16 = 6;
+const TYPE_TSIG: u16 = 250;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+/// How far the clocks of client and server may be apart, the usual value
+const TSIG_FUDGE: u16 = 300;
+/// The only algorithm supported, and the one BIND and Knot generate keys for by default
+pub const HMAC_SHA256: &str = "hmac-sha256";
+
+/// Replaces A or AAAA records in one zone through a DNS UPDATE
+pub struct Rfc2136Updater {
+    /// The primary server of the zone as `host[:port]`
+    pub server: String,
+    /// The zone the records are in, like `example.com`
+    pub zone: String,
+    /// The full record names, like `home.example.com`
+    pub records: Vec<String>,
+    /// The TTL of the new records
+    pub ttl: u32,
+    /// The name of the TSIG key, as in the server config
+    pub key_name: String,
+    /// The key itself
+    pub key_secret: Vec<u8>,
+}
+
+impl Rfc2136Updater {
+    /// Reads the updater from
+    /// `{"type": "rfc2136", "server": "ns1.example.com", "zone": "example.com", "records": ["home.example.com"],
+    /// "ttl": 300, "keyName": "...", "keySecret": "<base64>", "keyAlgorithm": "hmac-sha256"}`
+    ///
+    /// # Arguments
+    /// * `entry: &Value` - The updater entry
+    ///
+    /// # Returns
+    /// * `Result<Rfc2136Updater, String>` - Err if a setting is missing, the secret isn't base64,
+    ///   or a record is outside the zone
+    pub fn from_value(entry: &Value) -> Result<Self, String> {
+        let get = |key: &str| setting(entry, key, "RFC 2136");
+
+        let algorithm = get("keyAlgorithm").unwrap_or_else(|_| HMAC_SHA256.to_string());
+        if !algorithm.eq_ignore_ascii_case(HMAC_SHA256) {
+            return Err(format!(
+                "RFC 2136 updater only supports {}, not {}",
+                HMAC_SHA256, algorithm
+            ));
+        }
+
+        let zone = normalize(&get("zone")?);
+        let records = names(entry, "records", "RFC 2136")?;
+        if let Some(outside) = records
+            .iter()
+            .find(|record| relative_name(record, &zone).is_none())
+        {
+            return Err(format!("{} is not in the zone {}", outside, zone));
+        }
+
+        Ok(Rfc2136Updater {
+            server: get("server")?,
+            zone,
+            records,
+            ttl: entry.get("ttl").and_then(|v| v.as_u64()).unwrap_or(300) as u32,
+            key_name: normalize(&get("keyName")?),
+            key_secret: STANDARD
+                .decode(get("keySecret")?)
+                .map_err(|e| format!("keySecret is not valid base64: {}", e))?,
+        })
+    }
+}
+
+/// Lower case without the trailing dot, the form names are compared and signed in
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+/// Appends a resource record header, the data follows
+fn write_rr(msg: &mut Vec<u8>, name: &str, rr_type: u16, class: u16, ttl: u32, data_len: u16) {
+    dns::encode_name(name, msg);
+    msg.extend_from_slice(&rr_type.to_be_bytes());
+    msg.extend_from_slice(&class.to_be_bytes());
+    msg.extend_from_slice(&ttl.to_be_bytes());
+    msg.extend_from_slice(&data_len.to_be_bytes());
+}
+
+/// Builds the unsigned UPDATE, every record's old set is deleted and the address added
+///
+/// # Arguments
+/// * `id: u16` - The message ID
+/// * `zone: &str` - The zone
+/// * `records: &[String]` - The record names
+/// * `ttl: u32` - The TTL of the new records
+/// * `ip: IpAddr` - The address, picks A or AAAA
+///
+/// # Returns
+/// * `Vec<u8>` - The message in wire format
+pub fn build_update(id: u16, zone: &str, records: &[String], ttl: u32, ip: IpAddr) -> Vec<u8> {
+    let record_type = match ip {
+        IpAddr::V4(_) => RecordType::A,
+        IpAddr::V6(_) => RecordType::Aaaa,
+    };
+    let data = match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+
+    let mut msg = Vec::with_capacity(512);
+    msg.extend_from_slice(&id.to_be_bytes());
+    msg.extend_from_slice(&OPCODE_UPDATE.to_be_bytes());
+    // One zone, no prerequisites, a delete and an add per record, no additional records yet
+    msg.extend_from_slice(&1u16.to_be_bytes());
+    msg.extend_from_slice(&0u16.to_be_bytes());
+    msg.extend_from_slice(&(records.len() as u16 * 2).to_be_bytes());
+    msg.extend_from_slice(&0u16.to_be_bytes());
+
+    // The zone section looks like a question
+    dns::encode_name(zone, &mut msg);
+    msg.extend_from_slice(&TYPE_SOA.to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    for record in records {
+        // Class ANY with no data deletes the whole RRset
+        write_rr(&mut msg, record, record_type.code(), CLASS_ANY, 0, 0);
+        write_rr(
+            &mut msg,
+            record,
+            record_type.code(),
+            CLASS_IN,
+            ttl,
+            data.len() as u16,
+        );
+        msg.extend_from_slice(&data);
+    }
+
+    msg
+}
+
+/// The TSIG values that are signed along with the message
+struct TsigVariables<'a> {
+    key_name: &'a str,
+    time_signed: u64,
+    fudge: u16,
+    error: u16,
+    other: &'a [u8],
+}
+
+impl TsigVariables<'_> {
+    /// Appends the variables in the form they are signed in
+    fn write_signed(&self, buf: &mut Vec<u8>) {
+        dns::encode_name(self.key_name, buf);
+        buf.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        buf.extend_from_slice(&0u32.to_be_bytes());
+        dns::encode_name(HMAC_SHA256, buf);
+        buf.extend_from_slice(&self.time_signed.to_be_bytes()[2..]);
+        buf.extend_from_slice(&self.fudge.to_be_bytes());
+        buf.extend_from_slice(&self.error.to_be_bytes());
+        buf.extend_from_slice(&(self.other.len() as u16).to_be_bytes());
+        buf.extend_from_slice(self.other);
+    }
+}
+
+/// Feeds everything a TSIG MAC covers into an HMAC
+///
+/// # Arguments
+/// * `secret: &[u8]` - The key
+/// * `prior_mac: Option<&[u8]>` - The request MAC, when checking a response
+/// * `msg: &[u8]` - The message without its TSIG record, with the original ID and count
+/// * `vars: &TsigVariables` - The TSIG values
+///
+/// # Returns
+/// * `Hmac<Sha256>` - The HMAC, ready to be finalized or verified
+fn tsig_mac(
+    secret: &[u8],
+    prior_mac: Option<&[u8]>,
+    msg: &[u8],
+    vars: &TsigVariables,
+) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+
+    if let Some(prior_mac) = prior_mac {
+        mac.update(&(prior_mac.len() as u16).to_be_bytes());
+        mac.update(prior_mac);
+    }
+    mac.update(msg);
+
+    let mut signed = Vec::new();
+    vars.write_signed(&mut signed);
+    mac.update(&signed);
+
+    mac
+}
+
+/// Appends a TSIG record to the message
+///
+/// # Arguments
+/// * `msg: &[u8]` - The unsigned message
+/// * `key_name: &str` - The name of the key
+/// * `secret: &[u8]` - The key
+/// * `time_signed: u64` - Seconds since the Unix epoch
+///
+/// # Returns
+/// * `(Vec<u8>, Vec<u8>)` - The signed message and its MAC, needed to check the answer
+pub fn sign(msg: &[u8], key_name: &str, secret: &[u8], time_signed: u64) -> (Vec<u8>, Vec<u8>) {
+    let vars = TsigVariables {
+        key_name,
+        time_signed,
+        fudge: TSIG_FUDGE,
+        error: 0,
+        other: &[],
+    };
+    let mac = tsig_mac(secret, None, msg, &vars)
+        .finalize()
+        .into_bytes()
+        .to_vec();
+    let id = &msg[0..2];
+
+    let mut rdata = Vec::new();
+    dns::encode_name(HMAC_SHA256, &mut rdata);
+    rdata.extend_from_slice(&time_signed.to_be_bytes()[2..]);
+    rdata.extend_from_slice(&TSIG_FUDGE.to_be_bytes());
+    rdata.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+    rdata.extend_from_slice(&mac);
+    rdata.extend_from_slice(id);
+    // No error and no other data
+    rdata.extend_from_slice(&[0, 0, 0, 0]);
+
+    let mut signed = msg.to_vec();
+    let additional = u16::from_be_bytes([msg[10], msg[11]]) + 1;
+    signed[10..12].copy_from_slice(&additional.to_be_bytes());
+    write_rr(
+        &mut signed,
+        key_name,
+        TYPE_TSIG,
+        CLASS_ANY,
+        0,
+        rdata.len() as u16,
+    );
+    signed.extend_from_slice(&rdata);
+
+    (signed, mac)
+}
+
+/// Skips a resource record, returning where the next one starts and its type
+fn skip_rr(msg: &[u8], pos: usize) -> Result<(usize, u16), dns::DnsError> {
+    let (_, next) = dns::read_name(msg, pos)?;
+    let rr_type = dns::read_u16(msg, next)?;
+    let data_len = dns::read_u16(msg, next + 8)? as usize;
+
+    Ok((next + 10 + data_len, rr_type))
+}
+
+/// Checks the answer to an UPDATE, including its TSIG signature
+///
+/// # Arguments
+/// * `answer: &[u8]` - The answer in wire format
+/// * `id: u16` - The ID of the update
+/// * `key_name: &str` - The name of the key
+/// * `secret: &[u8]` - The key
+/// * `request_mac: &[u8]` - The MAC of the update
+///
+/// # Returns
+/// * `Result<(), UpdateError>` - Err with the server's error, or if the signature is missing or wrong
+pub fn check_answer(
+    answer: &[u8],
+    id: u16,
+    key_name: &str,
+    secret: &[u8],
+    request_mac: &[u8],
+) -> Result<(), UpdateError> {
+    let malformed = |e: dns::DnsError| UpdateError::from(e.to_string());
+
+    if dns::read_u16(answer, 0).map_err(malformed)? != id {
+        return Err("answer ID doesn't match the update".to_string().into());
+    }
+    let rcode = (dns::read_u16(answer, 2).map_err(malformed)? & 0x000F) as u8;
+
+    // Skip to the additional section, the TSIG record has to be the last record there
+    let zone_count = dns::read_u16(answer, 4).map_err(malformed)?;
+    let rr_count: usize = (6..=8)
+        .step_by(2)
+        .map(|offset| dns::read_u16(answer, offset).map(|count| count as usize))
+        .sum::<Result<usize, _>>()
+        .map_err(malformed)?;
+    let additional = dns::read_u16(answer, 10).map_err(malformed)? as usize;
+
+    let mut pos = 12;
+    for _ in 0..zone_count {
+        pos = dns::read_name(answer, pos).map_err(malformed)?.1 + 4;
+    }
+    for _ in 0..rr_count + additional.saturating_sub(1) {
+        pos = skip_rr(answer, pos).map_err(malformed)?.0;
+    }
+
+    let tsig = if additional > 0 {
+        let (end, rr_type) = skip_rr(answer, pos).map_err(malformed)?;
+        (rr_type == TYPE_TSIG).then_some((pos, end))
+    } else {
+        None
+    };
+
+    // Servers that reject the key answer without a usable signature, so report that first
+    let Some((tsig_start, tsig_end)) = tsig else {
+        return Err(rcode_error(rcode, None).unwrap_or_else(|| {
+            "the answer isn't signed, the server may not know the key"
+                .to_string()
+                .into()
+        }));
+    };
+
+    // TSIG RDATA: algorithm, time (6), fudge (2), MAC size (2), MAC, original ID (2), error (2), other
+    let (_, rdata) = dns::read_name(answer, tsig_start).map_err(malformed)?;
+    let rdata = rdata + 10;
+    let (algorithm, mut p) = dns::read_name(answer, rdata).map_err(malformed)?;
+    let time = answer
+        .get(p..p + 6)
+        .ok_or_else(|| UpdateError::from("TSIG record is cut off".to_string()))?;
+    let time_signed = time.iter().fold(0u64, |acc, b| acc << 8 | *b as u64);
+    let fudge = dns::read_u16(answer, p + 6).map_err(malformed)?;
+    let mac_len = dns::read_u16(answer, p + 8).map_err(malformed)? as usize;
+    p += 10;
+    let mac = answer
+        .get(p..p + mac_len)
+        .ok_or_else(|| UpdateError::from("TSIG MAC is cut off".to_string()))?;
+    p += mac_len;
+    let original_id = dns::read_u16(answer, p).map_err(malformed)?;
+    let error = dns::read_u16(answer, p + 2).map_err(malformed)?;
+    let other_len = dns::read_u16(answer, p + 4).map_err(malformed)? as usize;
+    let other = answer
+        .get(p + 6..p + 6 + other_len)
+        .ok_or_else(|| UpdateError::from("TSIG other data is cut off".to_string()))?;
+
+    if let Some(e) = rcode_error(rcode, Some(error)) {
+        return Err(e);
+    }
+    if p + 6 + other_len != tsig_end || !algorithm.eq_ignore_ascii_case(HMAC_SHA256) {
+        return Err("the answer's TSIG record is malformed".to_string().into());
+    }
+
+    // The MAC covers the answer without the TSIG record, with the original ID and count
+    let mut unsigned = answer[..tsig_start].to_vec();
+    unsigned[0..2].copy_from_slice(&original_id.to_be_bytes());
+    unsigned[10..12].copy_from_slice(&(additional as u16 - 1).to_be_bytes());
+
+    let vars = TsigVariables {
+        key_name,
+        time_signed,
+        fudge,
+        error,
+        other,
+    };
+    // verify_slice compares in constant time
+    if tsig_mac(secret, Some(request_mac), &unsigned, &vars)
+        .verify_slice(mac)
+        .is_err()
+    {
+        return Err("the answer's TSIG signature is wrong".to_string().into());
+    }
+
+    Ok(())
+}
+
+/// Turns a failing response code, and the TSIG error if there is one, into an error
+fn rcode_error(rcode: u8, tsig_error: Option<u16>) -> Option<UpdateError> {
+    let tsig_reason = match tsig_error {
+        Some(16) => Some("the server rejected the signature (BADSIG), check the key secret"),
+        Some(17) => Some("the server doesn't know the key (BADKEY), check the key name"),
+        Some(18) => Some("the clocks are too far apart (BADTIME)"),
+        _ => None,
+    };
+
+    if rcode == 0 && tsig_reason.is_none() {
+        return None;
+    }
+
+    let (message, backoff) = match (tsig_reason, rcode) {
+        // Wrong keys and zones won't fix themselves
+        (Some(reason), _) => (reason.to_string(), Backoff::UntilConfigChanges),
+        (None, 5) => (
+            "the server refused the update, the key may not be allowed to change these records"
+                .to_string(),
+            Backoff::UntilConfigChanges,
+        ),
+        (None, 9) => (
+            "the server isn't authoritative for the zone or rejected the key (NOTAUTH)".to_string(),
+            Backoff::UntilConfigChanges,
+        ),
+        (None, 10) => (
+            "a record is outside the zone (NOTZONE)".to_string(),
+            Backoff::UntilConfigChanges,
+        ),
+        (None, rcode) => (
+            format!("the server answered {}", dns::rcode_name(rcode)),
+            Backoff::NextCheck,
+        ),
+    };
+
+    Some(UpdateError { message, backoff })
+}
+
+impl DnsUpdater for Rfc2136Updater {
+    fn name(&self) -> String {
+        format!("RFC 2136 {} ({})", self.server, self.records.join(", "))
+    }
+
+    fn update(&self, family: IpFamily, ip: IpAddr) -> Result<String, UpdateError> {
+        // The server is reached over either family, the family of the address doesn't matter
+        let server = dns::resolve_server(&self.server, family)
+            .or_else(|_| dns::resolve_server(&self.server, IpFamily::V4))
+            .or_else(|_| dns::resolve_server(&self.server, IpFamily::V6))
+            .map_err(|e| e.to_string())?;
+
+        let id = dns::new_id();
+        let update = build_update(id, &self.zone, &self.records, self.ttl, ip);
+        let (signed, mac) = sign(
+            &update,
+            &self.key_name,
+            &self.key_secret,
+            chrono::Utc::now().timestamp() as u64,
+        );
+
+        let answer = dns::exchange(server, &signed).map_err(|e| e.to_string())?;
+        check_answer(&answer, id, &self.key_name, &self.key_secret, &mac)?;
+
+        Ok(format!(
+            "{} {} replaced with {}",
+            record_type(family),
+            self.records.join(", "),
+            ip
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, UdpSocket},
+        sync::mpsc,
+        thread,
+    };
+
+    use serde_json::json;
+
+    use super::*;
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+    const RCODE_NOTAUTH: u16 = 9;
+    const TSIG_BADSIG: u16 = 16;
+
+    /// A record of the update section: name, type, class, TTL and data
+    type UpdateRecord = (String, u16, u16, u32, Vec<u8>);
+
+    /// What the stand-in made of an update
+    struct Received {
+        zone: String,
+        records: Vec<UpdateRecord>,
+        key_name: String,
+        /// Whether the TSIG MAC checked out with the server's key
+        signature_valid: bool,
+    }
+
+    /// How the stand-in treats the zone
+    #[derive(Clone, Copy)]
+    enum Zone {
+        /// Served, updates have to be signed with this key
+        Served(&'static [u8]),
+        /// Not served, every update gets an unsigned NOTAUTH
+        NotServed,
+    }
+
+    fn updater(server: &str, secret: &[u8]) -> Rfc2136Updater {
+        Rfc2136Updater::from_value(&json!({
+            "type": "rfc2136",
+            "server": server,
+            "zone": "example.com",
+            "records": ["home.example.com"],
+            "ttl": 300,
+            "keyName": "update-key.",
+            "keySecret": STANDARD.encode(secret),
+        }))
+        .unwrap()
+    }
+
+    /// Appends a TSIG record to a message whose additional count doesn't include it yet
+    fn append_tsig(msg: &mut Vec<u8>, key_name: &str, time_signed: u64, mac: &[u8], error: u16) {
+        let mut rdata = Vec::new();
+        dns::encode_name(HMAC_SHA256, &mut rdata);
+        rdata.extend_from_slice(&time_signed.to_be_bytes()[2..]);
+        rdata.extend_from_slice(&TSIG_FUDGE.to_be_bytes());
+        rdata.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+        rdata.extend_from_slice(mac);
+        rdata.extend_from_slice(&msg[0..2]);
+        rdata.extend_from_slice(&error.to_be_bytes());
+        rdata.extend_from_slice(&[0, 0]);
+
+        let additional = u16::from_be_bytes([msg[10], msg[11]]) + 1;
+        msg[10..12].copy_from_slice(&additional.to_be_bytes());
+        write_rr(msg, key_name, TYPE_TSIG, CLASS_ANY, 0, rdata.len() as u16);
+        msg.extend_from_slice(&rdata);
+    }
+
+    /// Reads an update and answers it the way BIND would
+    fn respond(update: &[u8], zone: Zone) -> (Vec<u8>, Received) {
+        let (zone_name, zone_end) = dns::read_name(update, 12).unwrap();
+        let mut pos = zone_end + 4;
+
+        let mut records = Vec::new();
+        for _ in 0..dns::read_u16(update, 8).unwrap() {
+            let (name, next) = dns::read_name(update, pos).unwrap();
+            let field = |offset| dns::read_u16(update, next + offset).unwrap();
+            let ttl = u32::from_be_bytes(update[next + 4..next + 8].try_into().unwrap());
+            let data_end = next + 10 + field(8) as usize;
+            records.push((
+                name,
+                field(0),
+                field(2),
+                ttl,
+                update[next + 10..data_end].to_vec(),
+            ));
+            pos = data_end;
+        }
+
+        // The TSIG record is the only additional record
+        assert_eq!(dns::read_u16(update, 10).unwrap(), 1);
+        let tsig_start = pos;
+        let (key_name, next) = dns::read_name(update, tsig_start).unwrap();
+        assert_eq!(dns::read_u16(update, next).unwrap(), TYPE_TSIG);
+        let (algorithm, p) = dns::read_name(update, next + 10).unwrap();
+        assert_eq!(algorithm, HMAC_SHA256);
+        let time_signed = update[p..p + 6]
+            .iter()
+            .fold(0u64, |acc, b| acc << 8 | *b as u64);
+        let fudge = dns::read_u16(update, p + 6).unwrap();
+        let mac_len = dns::read_u16(update, p + 8).unwrap() as usize;
+        let request_mac = update[p + 10..p + 10 + mac_len].to_vec();
+
+        let mut unsigned = update[..tsig_start].to_vec();
+        unsigned[10..12].copy_from_slice(&0u16.to_be_bytes());
+        let vars = TsigVariables {
+            key_name: &key_name,
+            time_signed,
+            fudge,
+            error: 0,
+            other: &[],
+        };
+        let signature_valid = match zone {
+            Zone::Served(secret) => tsig_mac(secret, None, &unsigned, &vars)
+                .verify_slice(&request_mac)
+                .is_ok(),
+            Zone::NotServed => false,
+        };
+
+        // Same ID and zone section, QR set on the UPDATE opcode, no other records yet
+        let mut answer = update[0..2].to_vec();
+        let rcode = if signature_valid { 0 } else { RCODE_NOTAUTH };
+        answer.extend_from_slice(&(0x8000 | OPCODE_UPDATE | rcode).to_be_bytes());
+        answer.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+        answer.extend_from_slice(&update[12..zone_end + 4]);
+
+        match zone {
+            Zone::Served(secret) if signature_valid => {
+                let now = chrono::Utc::now().timestamp() as u64;
+                let vars = TsigVariables {
+                    key_name: &key_name,
+                    time_signed: now,
+                    fudge: TSIG_FUDGE,
+                    error: 0,
+                    other: &[],
+                };
+                let mac = tsig_mac(secret, Some(&request_mac), &answer, &vars)
+                    .finalize()
+                    .into_bytes();
+                append_tsig(&mut answer, &key_name, now, &mac, 0);
+            }
+            // A bad signature is answered with an empty MAC, the client's key can't be trusted
+            Zone::Served(_) => append_tsig(&mut answer, &key_name, time_signed, &[], TSIG_BADSIG),
+            Zone::NotServed => {}
+        }
+
+        (
+            answer,
+            Received {
+                zone: zone_name,
+                records,
+                key_name,
+                signature_valid,
+            },
+        )
+    }
+
+    /// Starts a stand-in primary on a loopback UDP port, and the same TCP port, for one update
+    ///
+    /// With `truncate` the UDP answer only says it was truncated, so the update is sent again over TCP
+    fn stand_in(zone: Zone, truncate: bool) -> (String, mpsc::Receiver<Received>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let listener = TcpListener::bind(address).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let tcp_sender = sender.clone();
+
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            let (len, peer) = socket.recv_from(&mut buf).unwrap();
+            let (mut answer, received) = respond(&buf[..len], zone);
+            if truncate {
+                answer.truncate(12);
+                answer[2] |= 0x02;
+                answer[4..12].fill(0);
+            } else {
+                let _ = sender.send(received);
+            }
+            socket.send_to(&answer, peer).unwrap();
+        });
+
+        thread::spawn(move || {
+            let Ok((mut stream, _)) = listener.accept() else {
+                return;
+            };
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut update = vec![0u8; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut update).unwrap();
+
+            let (answer, received) = respond(&update, zone);
+            let _ = tcp_sender.send(received);
+            let mut framed = (answer.len() as u16).to_be_bytes().to_vec();
+            framed.extend_from_slice(&answer);
+            stream.write_all(&framed).unwrap();
+        });
+
+        (address.to_string(), receiver)
+    }
+
+    #[test]
+    fn signed_update_replaces_the_record() {
+        let (server, received) = stand_in(Zone::Served(SECRET), false);
+
+        let outcome = updater(&server, SECRET)
+            .update(IpFamily::V4, "203.0.113.7".parse().unwrap())
+            .unwrap();
+        assert_eq!(outcome, "A home.example.com replaced with 203.0.113.7");
+
+        let received = received.recv().unwrap();
+        assert!(received.signature_valid);
+        assert_eq!(received.zone, "example.com");
+        assert_eq!(received.key_name, "update-key");
+        assert_eq!(
+            received.records,
+            vec![
+                ("home.example.com".to_string(), 1, CLASS_ANY, 0, vec![]),
+                (
+                    "home.example.com".to_string(),
+                    1,
+                    CLASS_IN,
+                    300,
+                    vec![203, 0, 113, 7]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_answer_sends_the_update_again_over_tcp() {
+        let (server, received) = stand_in(Zone::Served(SECRET), true);
+
+        let outcome = updater(&server, SECRET)
+            .update(IpFamily::V6, "2001:db8::7".parse().unwrap())
+            .unwrap();
+
+        assert_eq!(outcome, "AAAA home.example.com replaced with 2001:db8::7");
+        let received = received.recv().unwrap();
+        assert!(received.signature_valid);
+        assert_eq!(received.records[1].1, RecordType::Aaaa.code());
+    }
+
+    #[test]
+    fn wrong_secret_is_rejected_with_badsig() {
+        let (server, received) = stand_in(Zone::Served(SECRET), false);
+
+        let error = updater(&server, b"not the secret the server has")
+            .update(IpFamily::V4, "203.0.113.7".parse().unwrap())
+            .unwrap_err();
+
+        assert!(!received.recv().unwrap().signature_valid);
+        assert!(error.message.contains("BADSIG"), "{}", error.message);
+        assert!(matches!(error.backoff, Backoff::UntilConfigChanges));
+    }
+
+    #[test]
+    fn zone_the_server_doesnt_serve_is_notauth() {
+        let (server, _received) = stand_in(Zone::NotServed, false);
+
+        let error = updater(&server, SECRET)
+            .update(IpFamily::V4, "203.0.113.7".parse().unwrap())
+            .unwrap_err();
+
+        assert!(error.message.contains("NOTAUTH"), "{}", error.message);
+        assert!(matches!(error.backoff, Backoff::UntilConfigChanges));
+    }
+}
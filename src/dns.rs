@@ -0,0 +1,546 @@
+//! Minimal DNS client, just enough to ask a server for A, AAAA and TXT records
+use std::{
+    fmt,
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    time::Duration,
+};
+
+use crate::{constants, ip_check::IpFamily};
+
+/// The record types this client knows how to ask for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    A,
+    Aaaa,
+    Txt,
+}
+
+impl RecordType {
+    /// The numeric type used on the wire
+    pub fn code(&self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Txt => 16,
+            RecordType::Aaaa => 28,
+        }
+    }
+
+    /// The address record type for a family, A for IPv4 and AAAA for IPv6
+    pub fn address(family: IpFamily) -> Self {
+        match family {
+            IpFamily::V4 => RecordType::A,
+            IpFamily::V6 => RecordType::Aaaa,
+        }
+    }
+}
+
+impl fmt::Display for RecordType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordType::A => write!(f, "A"),
+            RecordType::Aaaa => write!(f, "AAAA"),
+            RecordType::Txt => write!(f, "TXT"),
+        }
+    }
+}
+
+/// Everything that can go wrong while talking to a DNS server
+#[derive(Debug, Clone)]
+pub enum DnsError {
+    /// The server couldn't be resolved or reached, or the socket failed
+    Io(String),
+    /// The answer couldn't be parsed
+    Malformed(&'static str),
+    /// The server answered with an error code
+    Rcode(u8),
+}
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsError::Io(e) => write!(f, "{}", e),
+            DnsError::Malformed(e) => write!(f, "Malformed DNS message: {}", e),
+            DnsError::Rcode(code) => write!(f, "DNS server answered with {}", rcode_name(*code)),
+        }
+    }
+}
+
+impl std::error::Error for DnsError {}
+
+impl From<std::io::Error> for DnsError {
+    fn from(e: std::io::Error) -> Self {
+        DnsError::Io(e.to_string())
+    }
+}
+
+/// A single record from the answer section
+#[derive(Debug, Clone)]
+pub struct Record {
+    /// The owner name of the record
+    pub name: String,
+    /// The numeric record type
+    pub record_type: u16,
+    /// The record data, undecoded
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    /// Reads the record as an address, if it is an A or AAAA record
+    pub fn as_ip(&self) -> Option<IpAddr> {
+        match (self.record_type, self.data.len()) {
+            (1, 4) => {
+                let octets: [u8; 4] = self.data[..].try_into().ok()?;
+                Some(IpAddr::V4(Ipv4Addr::from(octets)))
+            }
+            (28, 16) => {
+                let octets: [u8; 16] = self.data[..].try_into().ok()?;
+                Some(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Reads the character strings of a TXT record
+    pub fn txt_strings(&self) -> Vec<String> {
+        let mut strings = Vec::new();
+        let mut pos = 0;
+
+        while pos < self.data.len() {
+            let len = self.data[pos] as usize;
+            let end = (pos + 1 + len).min(self.data.len());
+            strings.push(String::from_utf8_lossy(&self.data[pos + 1..end]).to_string());
+            pos = end;
+        }
+
+        strings
+    }
+}
+
+/// Returns the mnemonic for a response code
+pub fn rcode_name(code: u8) -> String {
+    match code {
+        0 => "NOERROR".to_string(),
+        1 => "FORMERR".to_string(),
+        2 => "SERVFAIL".to_string(),
+        3 => "NXDOMAIN".to_string(),
+        4 => "NOTIMP".to_string(),
+        5 => "REFUSED".to_string(),
+        6 => "YXDOMAIN".to_string(),
+        7 => "YXRRSET".to_string(),
+        8 => "NXRRSET".to_string(),
+        9 => "NOTAUTH".to_string(),
+        10 => "NOTZONE".to_string(),
+        _ => format!("RCODE {}", code),
+    }
+}
+
+/// Finds the address of a DNS server, connecting over the given family
+///
+/// # Arguments
+/// * `server: &str` - A host name or address, optionally followed by `:port`, port 53 is the default
+/// * `family: IpFamily` - The family the server has to be reached over
+///
+/// # Returns
+/// * `Result<SocketAddr, DnsError>` - The first address of the server in that family
+pub fn resolve_server(server: &str, family: IpFamily) -> Result<SocketAddr, DnsError> {
+    Ok(resolve_host(server, 53, family)?)
+}
+
+/// Resolves a `host[:port]` string to the first address of the given family
+///
+/// # Arguments
+/// * `host: &str` - A host name or address, optionally followed by `:port`
+/// * `default_port: u16` - The port used when none is given
+/// * `family: IpFamily` - The family the address has to be in
+///
+/// # Returns
+/// * `std::io::Result<SocketAddr>` - The first address of the host in that family
+pub fn resolve_host(
+    host: &str,
+    default_port: u16,
+    family: IpFamily,
+) -> std::io::Result<SocketAddr> {
+    let addrs: Vec<SocketAddr> = if let Ok(addr) = host.parse::<SocketAddr>() {
+        vec![addr]
+    } else if let Ok(ip) = host.parse::<IpAddr>() {
+        vec![SocketAddr::new(ip, default_port)]
+    } else {
+        match host.rsplit_once(':') {
+            Some((name, port)) if port.parse::<u16>().is_ok() => {
+                (name, port.parse::<u16>().unwrap_or(default_port))
+                    .to_socket_addrs()?
+                    .collect()
+            }
+            _ => (host, default_port).to_socket_addrs()?.collect(),
+        }
+    };
+
+    addrs
+        .into_iter()
+        .find(|addr| family.matches(&addr.ip()))
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} has no {} address", host, family),
+            )
+        })
+}
+
+/// Writes a domain name in wire format
+///
+/// # Arguments
+/// * `name: &str` - The name, a trailing dot is optional
+/// * `buf: &mut Vec<u8>` - Where to write the name
+pub fn encode_name(name: &str, buf: &mut Vec<u8>) {
+    for label in name
+        .trim_end_matches('.')
+        .split('.')
+        .filter(|l| !l.is_empty())
+    {
+        buf.push(label.len().min(63) as u8);
+        buf.extend_from_slice(&label.as_bytes()[..label.len().min(63)]);
+    }
+    buf.push(0);
+}
+
+/// Reads a possibly compressed domain name
+///
+/// # Arguments
+/// * `msg: &[u8]` - The whole message, compression pointers are relative to it
+/// * `pos: usize` - Where the name starts
+///
+/// # Returns
+/// * `Result<(String, usize), DnsError>` - The name and the position right after it
+pub fn read_name(msg: &[u8], mut pos: usize) -> Result<(String, usize), DnsError> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let len = *msg
+            .get(pos)
+            .ok_or(DnsError::Malformed("name runs past the end"))? as usize;
+
+        if len & 0xC0 == 0xC0 {
+            let low = *msg
+                .get(pos + 1)
+                .ok_or(DnsError::Malformed("truncated pointer"))? as usize;
+            end.get_or_insert(pos + 2);
+            pos = ((len & 0x3F) << 8) | low;
+            jumps += 1;
+            if jumps > 64 {
+                return Err(DnsError::Malformed("compression loop"));
+            }
+        } else if len == 0 {
+            let name = if labels.is_empty() {
+                ".".to_string()
+            } else {
+                labels.join(".")
+            };
+            return Ok((name, end.unwrap_or(pos + 1)));
+        } else {
+            let label = msg
+                .get(pos + 1..pos + 1 + len)
+                .ok_or(DnsError::Malformed("label runs past the end"))?;
+            labels.push(String::from_utf8_lossy(label).to_string());
+            pos += 1 + len;
+        }
+    }
+}
+
+/// Reads a big endian u16
+pub fn read_u16(msg: &[u8], pos: usize) -> Result<u16, DnsError> {
+    msg.get(pos..pos + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or(DnsError::Malformed("message is truncated"))
+}
+
+/// Builds a query for a single name and type
+///
+/// # Arguments
+/// * `id: u16` - Message ID, the answer has to carry the same one
+/// * `name: &str` - The name to look up
+/// * `record_type: RecordType` - The type of record wanted
+///
+/// # Returns
+/// * `Vec<u8>` - The query in wire format
+pub fn build_query(id: u16, name: &str, record_type: RecordType) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(64);
+
+    msg.extend_from_slice(&id.to_be_bytes());
+    // Standard query, recursion desired
+    msg.extend_from_slice(&0x0100u16.to_be_bytes());
+    // One question, no answers, authority or additional records
+    msg.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    encode_name(name, &mut msg);
+    msg.extend_from_slice(&record_type.code().to_be_bytes());
+    // Class IN
+    msg.extend_from_slice(&1u16.to_be_bytes());
+
+    msg
+}
+
+/// Parses an answer, checking the ID and response code
+///
+/// # Arguments
+/// * `msg: &[u8]` - The answer in wire format
+/// * `id: u16` - The ID of the query
+///
+/// # Returns
+/// * `Result<Vec<Record>, DnsError>` - The records in the answer section
+pub fn parse_response(msg: &[u8], id: u16) -> Result<Vec<Record>, DnsError> {
+    if read_u16(msg, 0)? != id {
+        return Err(DnsError::Malformed("answer ID doesn't match the query"));
+    }
+
+    let flags = read_u16(msg, 2)?;
+    if flags & 0x8000 == 0 {
+        return Err(DnsError::Malformed("message is not an answer"));
+    }
+
+    let rcode = (flags & 0x000F) as u8;
+    if rcode != 0 {
+        return Err(DnsError::Rcode(rcode));
+    }
+
+    let questions = read_u16(msg, 4)?;
+    let answers = read_u16(msg, 6)?;
+    let mut pos = 12;
+
+    for _ in 0..questions {
+        pos = read_name(msg, pos)?.1 + 4;
+    }
+
+    let mut records = Vec::with_capacity(answers as usize);
+
+    for _ in 0..answers {
+        let (name, next) = read_name(msg, pos)?;
+        let record_type = read_u16(msg, next)?;
+        let data_len = read_u16(msg, next + 8)? as usize;
+        let data = msg
+            .get(next + 10..next + 10 + data_len)
+            .ok_or(DnsError::Malformed("record data runs past the end"))?
+            .to_vec();
+
+        records.push(Record {
+            name,
+            record_type,
+            data,
+        });
+        pos = next + 10 + data_len;
+    }
+
+    Ok(records)
+}
+
+/// Sends a message over UDP, falling back to TCP if the answer was truncated
+///
+/// # Arguments
+/// * `server: SocketAddr` - The DNS server
+/// * `msg: &[u8]` - The message in wire format
+///
+/// # Returns
+/// * `Result<Vec<u8>, DnsError>` - The raw answer
+pub fn exchange(server: SocketAddr, msg: &[u8]) -> Result<Vec<u8>, DnsError> {
+    let timeout = Duration::from_secs(constants::DNS_TIMEOUT_SECONDS);
+    let bind: SocketAddr = if server.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+
+    let socket = UdpSocket::bind(bind)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect(server)?;
+    socket.send(msg)?;
+
+    let mut buf = [0u8; 4096];
+    let len = socket.recv(&mut buf)?;
+
+    // The TC flag means the answer didn't fit, so it has to be asked again over TCP
+    if len > 2 && buf[2] & 0x02 != 0 {
+        return exchange_tcp(server, msg);
+    }
+
+    Ok(buf[..len].to_vec())
+}
+
+/// Sends a message over TCP
+///
+/// # Arguments
+/// * `server: SocketAddr` - The DNS server
+/// * `msg: &[u8]` - The message in wire format
+///
+/// # Returns
+/// * `Result<Vec<u8>, DnsError>` - The raw answer
+pub fn exchange_tcp(server: SocketAddr, msg: &[u8]) -> Result<Vec<u8>, DnsError> {
+    let timeout = Duration::from_secs(constants::DNS_TIMEOUT_SECONDS);
+    let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    // Over TCP every message is prefixed with its length
+    let mut framed = (msg.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(msg);
+    stream.write_all(&framed)?;
+
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let mut answer = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut answer)?;
+
+    Ok(answer)
+}
+
+/// Asks a DNS server for the records of a name
+///
+/// # Arguments
+/// * `server: SocketAddr` - The DNS server
+/// * `name: &str` - The name to look up
+/// * `record_type: RecordType` - The type of record wanted
+///
+/// # Returns
+/// * `Result<Vec<Record>, DnsError>` - The records in the answer section
+pub fn query(
+    server: SocketAddr,
+    name: &str,
+    record_type: RecordType,
+) -> Result<Vec<Record>, DnsError> {
+    let id = new_id();
+    let answer = exchange(server, &build_query(id, name, record_type))?;
+
+    parse_response(&answer, id)
+}
+
+/// Makes a message ID that is hard to guess, without pulling in a random number crate
+pub fn new_id() -> u16 {
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default(),
+    );
+
+    hasher.finish() as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, UdpSocket},
+        thread,
+    };
+
+    use super::*;
+
+    /// Builds an answer to a query, with one TXT record per string
+    fn answer(query: &[u8], id: u16, flags: u16, txt: &[&str]) -> Vec<u8> {
+        let mut msg = id.to_be_bytes().to_vec();
+        msg.extend_from_slice(&flags.to_be_bytes());
+        msg.extend_from_slice(&[0, 1]);
+        msg.extend_from_slice(&(txt.len() as u16).to_be_bytes());
+        msg.extend_from_slice(&[0, 0, 0, 0]);
+        // The question is sent back as it came
+        msg.extend_from_slice(&query[12..]);
+
+        for text in txt {
+            msg.extend_from_slice(&[0xC0, 12]);
+            msg.extend_from_slice(&RecordType::Txt.code().to_be_bytes());
+            msg.extend_from_slice(&[0, 1, 0, 0, 0, 60]);
+            msg.extend_from_slice(&(text.len() as u16 + 1).to_be_bytes());
+            msg.push(text.len() as u8);
+            msg.extend_from_slice(text.as_bytes());
+        }
+
+        msg
+    }
+
+    /// Starts a stub server on a loopback UDP port, and the same TCP port, that answers one query each
+    ///
+    /// The UDP handler gets the query and its ID, the TCP answer is always the full one
+    fn stub<F>(udp: F, tcp_txt: &'static [&'static str]) -> SocketAddr
+    where
+        F: FnOnce(&[u8], u16) -> Vec<u8> + Send + 'static,
+    {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let listener = TcpListener::bind(address).unwrap();
+
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (len, peer) = socket.recv_from(&mut buf).unwrap();
+            let query = &buf[..len];
+            socket
+                .send_to(&udp(query, read_u16(query, 0).unwrap()), peer)
+                .unwrap();
+        });
+
+        thread::spawn(move || {
+            let Ok((mut stream, _)) = listener.accept() else {
+                return;
+            };
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut query = vec![0u8; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut query).unwrap();
+
+            let msg = answer(&query, read_u16(&query, 0).unwrap(), 0x8180, tcp_txt);
+            let mut framed = (msg.len() as u16).to_be_bytes().to_vec();
+            framed.extend_from_slice(&msg);
+            stream.write_all(&framed).unwrap();
+        });
+
+        address
+    }
+
+    #[test]
+    fn reads_txt_answer() {
+        let server = stub(
+            |query, id| answer(query, id, 0x8180, &["203.0.113.7"]),
+            &[],
+        );
+
+        let records = query(server, "o-o.myaddr.l.google.com", RecordType::Txt).unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].name, "o-o.myaddr.l.google.com");
+        assert_eq!(records[0].txt_strings(), vec!["203.0.113.7"]);
+    }
+
+    #[test]
+    fn truncated_answer_is_asked_again_over_tcp() {
+        // The UDP answer is cut short and says so, only TCP has the record
+        let server = stub(|query, id| answer(query, id, 0x8380, &[]), &["198.51.100.4"]);
+
+        let records = query(server, "myip.opendns.com", RecordType::Txt).unwrap();
+
+        assert_eq!(records[0].txt_strings(), vec!["198.51.100.4"]);
+    }
+
+    #[test]
+    fn nxdomain_is_an_rcode_error() {
+        let server = stub(|query, id| answer(query, id, 0x8183, &[]), &[]);
+
+        match query(server, "missing.example.com", RecordType::Txt) {
+            Err(DnsError::Rcode(3)) => {}
+            other => panic!("expected NXDOMAIN, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn answer_with_another_id_is_refused() {
+        let server = stub(
+            |query, id| answer(query, id.wrapping_add(1), 0x8180, &["192.0.2.66"]),
+            &[],
+        );
+
+        match query(server, "o-o.myaddr.l.google.com", RecordType::Txt) {
+            Err(DnsError::Malformed(_)) => {}
+            other => panic!("expected the answer to be refused, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,483 @@
+//! DNS drift, the published A and AAAA records of watched names are compared to the public address
+//!
+//! Catches DNS updates that failed without anyone noticing and records that were changed by hand
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::{IpAddr, SocketAddr},
+};
+
+use chrono::{DateTime, Utc};
+use serde_json::{Value, json};
+
+use crate::{
+    config::Config,
+    constants,
+    dns::{self, DnsError, RecordType},
+    ip_check::IpFamily,
+    json_handler,
+    notifier::Event,
+};
+
+/// The resolver asked unless the settings name another one
+pub const DEFAULT_RESOLVER: &str = "1.1.1.1";
+/// How many checks in a row a name has to be stale before it is reported, answers cached from
+/// before a change would otherwise raise an alert right after every update
+const DEFAULT_CONFIRMATIONS: u64 = 2;
+/// The response code for a name that doesn't exist
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// A watched name and the families it is expected to have records for
+#[derive(Debug, Clone)]
+pub struct WatchedName {
+    pub name: String,
+    /// None means every tracked family
+    pub families: Option<Vec<IpFamily>>,
+}
+
+/// The `dnsDrift` settings from the config
+#[derive(Debug, Clone)]
+pub struct DriftSettings {
+    pub hostnames: Vec<WatchedName>,
+    /// The DNS server asked, `host[:port]`
+    pub resolver: String,
+    /// How often the names are resolved, 0 means on every check
+    pub interval_minutes: u64,
+    /// How many checks in a row a name has to be stale before it is reported
+    pub confirmations: u64,
+}
+
+impl DriftSettings {
+    /// Reads the settings from
+    /// `{"hostnames": ["home.example.com", {"name": "v4.example.com", "families": ["ipv4"]}], "resolver": "1.1.1.1", "intervalMinutes": 60, "confirmations": 2}`
+    ///
+    /// # Arguments
+    /// * `value: &Value` - The `dnsDrift` value of the config
+    ///
+    /// # Returns
+    /// * `Result<Option<DriftSettings>, String>` - None if the check is off, Err if no names are watched
+    pub fn from_value(value: &Value) -> Result<Option<Self>, String> {
+        if value.is_null() || value.get("enabled").and_then(|v| v.as_bool()) == Some(false) {
+            return Ok(None);
+        }
+
+        let hostnames: Vec<WatchedName> = value
+            .get("hostnames")
+            .and_then(|v| v.as_array())
+            .map(|hostnames| hostnames.iter().filter_map(watched_name).collect())
+            .unwrap_or_default();
+
+        if hostnames.is_empty() {
+            return Err("DNS drift check needs at least one of \"hostnames\"".to_string());
+        }
+
+        Ok(Some(DriftSettings {
+            hostnames,
+            resolver: value
+                .get("resolver")
+                .and_then(|v| v.as_str())
+                .filter(|v| !v.is_empty())
+                .unwrap_or(DEFAULT_RESOLVER)
+                .to_string(),
+            interval_minutes: value
+                .get("intervalMinutes")
+                .and_then(|v| v.as_u64())
+                .unwrap_or(0),
+            confirmations: value
+                .get("confirmations")
+                .and_then(|v| v.as_u64())
+                .unwrap_or(DEFAULT_CONFIRMATIONS)
+                .max(1),
+        }))
+    }
+
+    /// Whether a name is expected to have records of the family
+    fn watches(&self, name: &WatchedName, family: IpFamily) -> bool {
+        name.families
+            .as_ref()
+            .is_none_or(|families| families.contains(&family))
+    }
+}
+
+/// Reads one `hostnames` entry, a plain name or `{"name": ..., "families": ["ipv4", "ipv6"]}`
+fn watched_name(entry: &Value) -> Option<WatchedName> {
+    let (name, families) = match entry {
+        Value::String(name) => (name.as_str(), None),
+        Value::Object(_) => (
+            entry.get("name").and_then(|v| v.as_str())?,
+            entry
+                .get("families")
+                .and_then(|v| v.as_array())
+                .map(|families| {
+                    families
+                        .iter()
+                        .filter_map(|family| match family.as_str()? {
+                            "ipv4" => Some(IpFamily::V4),
+                            "ipv6" => Some(IpFamily::V6),
+                            _ => None,
+                        })
+                        .collect()
+                }),
+        ),
+        _ => return None,
+    };
+    let name = name.trim_end_matches('.').to_lowercase();
+
+    (!name.is_empty()).then_some(WatchedName { name, families })
+}
+
+/// The key a family is saved under in the state
+fn family_key(family: IpFamily) -> &'static str {
+    match family {
+        IpFamily::V4 => "ipv4",
+        IpFamily::V6 => "ipv6",
+    }
+}
+
+/// Finds the resolver, over IPv4 if it can be reached that way
+fn resolver_address(resolver: &str) -> Result<SocketAddr, DnsError> {
+    dns::resolve_server(resolver, IpFamily::V4)
+        .or_else(|_| dns::resolve_server(resolver, IpFamily::V6))
+}
+
+/// Looks up the addresses a name publishes for a family
+///
+/// # Arguments
+/// * `server: SocketAddr` - The resolver
+/// * `name: &str` - The name to look up
+/// * `family: IpFamily` - Picks A or AAAA records
+///
+/// # Returns
+/// * `Result<Vec<IpAddr>, DnsError>` - The addresses, empty if the name or its records don't exist
+pub fn resolve(server: SocketAddr, name: &str, family: IpFamily) -> Result<Vec<IpAddr>, DnsError> {
+    match dns::query(server, name, RecordType::address(family)) {
+        // CNAMEs on the way are in the answer too, only the addresses count
+        Ok(records) => Ok(records.iter().filter_map(|record| record.as_ip()).collect()),
+        Err(DnsError::Rcode(RCODE_NXDOMAIN)) => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads the saved state, a missing or broken file is an empty state
+fn read_state() -> Value {
+    fs::read_to_string(constants::get_drift_state_path())
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .filter(|state: &Value| state.is_object())
+        .unwrap_or_else(|| json!({}))
+}
+
+/// Resolves the watched names when it is time and reports those that don't hold the address
+///
+/// A name is stale when its records of the family are missing or hold anything other than the
+/// address. It is only reported once it has been stale for the configured number of checks, and
+/// the same set of stale names is only reported once.
+///
+/// # Arguments
+/// * `config: &Config` - The config holding the `dnsDrift` settings
+/// * `family: IpFamily` - The family that was looked up
+/// * `ip: IpAddr` - The current public address
+///
+/// # Returns
+/// * `Option<Event>` - The drift alert, None if nothing new is stale or the check didn't run
+pub fn check(config: &Config, family: IpFamily, ip: IpAddr) -> Option<Event> {
+    let settings = match DriftSettings::from_value(&config.dns_drift) {
+        Ok(Some(settings)) => settings,
+        Ok(None) => return None,
+        Err(e) => {
+            eprintln!("Skipping DNS drift check: {}", e);
+            return None;
+        }
+    };
+
+    let mut state = read_state();
+    let family_state = state
+        .get(family_key(family))
+        .cloned()
+        .unwrap_or_else(|| json!({}));
+
+    let last_check = family_state
+        .get("lastCheck")
+        .and_then(|v| v.as_str())
+        .and_then(|v| DateTime::parse_from_rfc3339(v).ok());
+    if let Some(last_check) = last_check
+        && Utc::now() < last_check + chrono::Duration::minutes(settings.interval_minutes as i64)
+    {
+        return None;
+    }
+
+    let server = match resolver_address(&settings.resolver) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!(
+                "DNS drift check skipped, resolver {}: {}",
+                settings.resolver, e
+            );
+            return None;
+        }
+    };
+
+    let (family_state, event) = compare(&settings, family, ip, &family_state, |name| {
+        resolve(server, name, family)
+    });
+
+    state[family_key(family)] = family_state;
+    json_handler::write_json_from_value(&constants::get_drift_state_path(), &state);
+
+    event
+}
+
+/// Compares what the watched names resolve to with the address, the part of a check without I/O
+///
+/// # Arguments
+/// * `settings: &DriftSettings` - The watched names and how often they have to be stale
+/// * `family: IpFamily` - The family that was looked up
+/// * `ip: IpAddr` - The current public address
+/// * `family_state: &Value` - The state the last check left for the family
+/// * `lookup: F` - Resolves a name to its addresses of the family
+///
+/// # Returns
+/// * `(Value, Option<Event>)` - The new state of the family, and the drift alert if there is a new one
+pub fn compare<F>(
+    settings: &DriftSettings,
+    family: IpFamily,
+    ip: IpAddr,
+    family_state: &Value,
+    mut lookup: F,
+) -> (Value, Option<Event>)
+where
+    F: FnMut(&str) -> Result<Vec<IpAddr>, DnsError>,
+{
+    let record_type = RecordType::address(family);
+    let old_counts = family_state.get("staleChecks").cloned().unwrap_or_default();
+    let old_alerted: BTreeMap<String, String> = family_state
+        .get("alerted")
+        .and_then(|v| serde_json::from_value(v.clone()).ok())
+        .unwrap_or_default();
+    let mut counts = json!({});
+    let mut confirmed = BTreeMap::new();
+
+    for watched in settings
+        .hostnames
+        .iter()
+        .filter(|watched| settings.watches(watched, family))
+    {
+        let name = &watched.name;
+
+        let addresses = match lookup(name) {
+            Ok(addresses) => addresses,
+            Err(e) => {
+                // A failed lookup says nothing about the records, so nothing changes for the name
+                eprintln!("DNS drift check could not resolve {}: {}", name, e);
+                if let Some(count) = old_counts.get(name) {
+                    counts[name] = count.clone();
+                }
+                if let Some(answer) = old_alerted.get(name) {
+                    confirmed.insert(name.clone(), answer.clone());
+                }
+                continue;
+            }
+        };
+
+        if !addresses.is_empty() && addresses.iter().all(|address| *address == ip) {
+            continue;
+        }
+
+        let answer = if addresses.is_empty() {
+            format!("no {} record", record_type)
+        } else {
+            addresses
+                .iter()
+                .map(|address| address.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        let count = old_counts.get(name).and_then(|v| v.as_u64()).unwrap_or(0) + 1;
+        println!(
+            "{} {} record of {} is stale ({} of {} checks): {}",
+            family, record_type, name, count, settings.confirmations, answer
+        );
+
+        counts[name] = json!(count);
+        if count >= settings.confirmations {
+            confirmed.insert(name.clone(), answer);
+        }
+    }
+
+    let event = if confirmed.is_empty() {
+        if !old_alerted.is_empty() {
+            println!(
+                "{} records of every watched name hold {} again.",
+                family, ip
+            );
+        }
+        None
+    } else if confirmed
+        .iter()
+        .all(|(name, answer)| old_alerted.get(name) == Some(answer))
+    {
+        // Already reported, names that got fixed since aren't worth another alert
+        None
+    } else {
+        eprintln!(
+            "{} records don't hold {}: {}",
+            family,
+            ip,
+            confirmed
+                .keys()
+                .cloned()
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+        Some(Event::DnsDrift {
+            family,
+            expected: ip,
+            stale: confirmed.clone().into_iter().collect(),
+        })
+    };
+
+    let family_state = json!({
+        "lastCheck": Utc::now().to_rfc3339(),
+        "staleChecks": counts,
+        "alerted": confirmed,
+    });
+
+    (family_state, event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBLIC: &str = "203.0.113.8";
+
+    fn settings(confirmations: u64) -> DriftSettings {
+        DriftSettings::from_value(&json!({
+            "hostnames": ["home.example.com", {"name": "v6.example.com.", "families": ["ipv6"]}, "vpn.example.com"],
+            "confirmations": confirmations,
+        }))
+        .unwrap()
+        .unwrap()
+    }
+
+    /// Runs one comparison where the names answer from the table, names not in it fail to resolve
+    fn run(
+        settings: &DriftSettings,
+        state: &Value,
+        answers: &[(&str, &[&str])],
+    ) -> (Value, Option<Event>) {
+        compare(
+            settings,
+            IpFamily::V4,
+            PUBLIC.parse().unwrap(),
+            state,
+            |name| {
+                assert_ne!(name, "v6.example.com", "an IPv6 only name was looked up");
+                answers
+                    .iter()
+                    .find(|(answered, _)| *answered == name)
+                    .map(|(_, addresses)| addresses.iter().map(|a| a.parse().unwrap()).collect())
+                    .ok_or(DnsError::Rcode(2))
+            },
+        )
+    }
+
+    fn stale_names(event: Option<Event>) -> Vec<(String, String)> {
+        match event {
+            Some(Event::DnsDrift { stale, .. }) => stale,
+            other => panic!("expected a drift alert, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stale_name_is_reported_after_the_confirmations_and_only_once() {
+        let settings = settings(2);
+        let answers: &[(&str, &[&str])] = &[
+            ("home.example.com", &["192.0.2.1"]),
+            ("vpn.example.com", &[PUBLIC]),
+        ];
+
+        let (state, event) = run(&settings, &json!({}), answers);
+        assert!(event.is_none());
+        assert_eq!(state["staleChecks"], json!({"home.example.com": 1}));
+
+        let (state, event) = run(&settings, &state, answers);
+        assert_eq!(
+            stale_names(event),
+            [("home.example.com".to_string(), "192.0.2.1".to_string())]
+        );
+        assert_eq!(state["alerted"], json!({"home.example.com": "192.0.2.1"}));
+
+        // Still the same stale answer, no second alert
+        let (state, event) = run(&settings, &state, answers);
+        assert!(event.is_none());
+        assert_eq!(state["staleChecks"], json!({"home.example.com": 3}));
+
+        // A different wrong answer is news
+        let (_, event) = run(
+            &settings,
+            &state,
+            &[("home.example.com", &[]), ("vpn.example.com", &[PUBLIC])],
+        );
+        assert_eq!(
+            stale_names(event),
+            [("home.example.com".to_string(), "no A record".to_string())]
+        );
+    }
+
+    #[test]
+    fn fixed_names_clear_their_state() {
+        let settings = settings(1);
+        let (state, event) = run(
+            &settings,
+            &json!({}),
+            &[
+                ("home.example.com", &["192.0.2.1", PUBLIC]),
+                ("vpn.example.com", &[PUBLIC]),
+            ],
+        );
+        assert_eq!(stale_names(event).len(), 1);
+
+        let (state, event) = run(
+            &settings,
+            &state,
+            &[
+                ("home.example.com", &[PUBLIC]),
+                ("vpn.example.com", &[PUBLIC]),
+            ],
+        );
+
+        assert!(event.is_none());
+        assert_eq!(state["staleChecks"], json!({}));
+        assert_eq!(state["alerted"], json!({}));
+        assert!(state["lastCheck"].is_string());
+    }
+
+    #[test]
+    fn failed_lookup_keeps_the_state_of_the_name() {
+        let settings = settings(2);
+        let stale: &[(&str, &[&str])] = &[
+            ("home.example.com", &["192.0.2.1"]),
+            ("vpn.example.com", &["192.0.2.2"]),
+        ];
+        let (state, _) = run(&settings, &json!({}), stale);
+        let (state, event) = run(&settings, &state, stale);
+        assert_eq!(stale_names(event).len(), 2);
+
+        // vpn can't be resolved this time, it stays counted and reported
+        let (state, event) = run(&settings, &state, &stale[..1]);
+        assert!(event.is_none());
+        assert_eq!(
+            state["staleChecks"],
+            json!({"home.example.com": 3, "vpn.example.com": 2})
+        );
+        assert_eq!(
+            state["alerted"],
+            json!({"home.example.com": "192.0.2.1", "vpn.example.com": "192.0.2.2"})
+        );
+
+        // Once it resolves to the same answer again, that isn't a new alert either
+        let (_, event) = run(&settings, &state, stale);
+        assert!(event.is_none());
+    }
+}
@@ -0,0 +1,650 @@
+//! Asks the local router for its WAN address over UPnP IGD, NAT-PMP or PCP
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crate::{constants, dns, http, ip_check::IpFamily};
+
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+const NAT_PMP_PORT: u16 = 5351;
+
+/// The UPnP services that can report the external address, best first
+const WAN_SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// Everything that can go wrong while asking the router
+#[derive(Debug, Clone)]
+pub enum GatewayError {
+    /// The socket failed or the router didn't answer
+    Io(String),
+    /// No router, or no usable service on it, was found
+    NotFound(String),
+    /// The router answered with something that couldn't be parsed
+    Malformed(String),
+    /// The router answered with an error
+    Refused(String),
+}
+
+impl fmt::Display for GatewayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GatewayError::Io(e) => write!(f, "{}", e),
+            GatewayError::NotFound(e) => write!(f, "{}", e),
+            GatewayError::Malformed(e) => write!(f, "Malformed answer from the router: {}", e),
+            GatewayError::Refused(e) => write!(f, "The router refused: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for GatewayError {}
+
+impl From<std::io::Error> for GatewayError {
+    fn from(e: std::io::Error) -> Self {
+        GatewayError::Io(e.to_string())
+    }
+}
+
+/// Checks if an address is in the carrier-grade NAT range, 100.64.0.0/10
+///
+/// # Arguments
+/// * `ip: &IpAddr` - The address to check
+///
+/// # Returns
+/// * `bool` - True if the address is shared address space from RFC 6598
+pub fn is_cgnat(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            octets[0] == 100 && (octets[1] & 0xC0) == 64
+        }
+        IpAddr::V6(_) => false,
+    }
+}
+
+/// Explains what a router's WAN address means, if it isn't a plain public address
+///
+/// # Arguments
+/// * `ip: &IpAddr` - The WAN address the router reported
+///
+/// # Returns
+/// * `Option<String>` - A warning for CGNAT or private WAN addresses
+pub fn wan_address_warning(ip: &IpAddr) -> Option<String> {
+    if is_cgnat(ip) {
+        Some(format!(
+            "The router's WAN address {} is in carrier-grade NAT space (100.64.0.0/10), the router isn't reachable from the internet",
+            ip
+        ))
+    } else if matches!(ip, IpAddr::V4(v4) if v4.is_private()) {
+        Some(format!(
+            "The router's WAN address {} is a private address, there is another NAT in front of it",
+            ip
+        ))
+    } else {
+        None
+    }
+}
+
+/// Finds the default IPv4 gateway from the kernel routing table
+///
+/// # Returns
+/// * `Option<Ipv4Addr>` - None if there is no default route, or the OS doesn't have `/proc/net/route`
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+
+    // Columns are: interface, destination, gateway, ... all hex in host byte order
+    routes.lines().skip(1).find_map(|line| {
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        if columns.len() < 3 || columns[1] != "00000000" {
+            return None;
+        }
+        let gateway = u32::from_str_radix(columns[2], 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_le_bytes()))
+    })
+}
+
+/// Resolves the configured gateway, falling back to the default route
+fn gateway_addr(gateway: Option<&str>) -> Result<SocketAddr, GatewayError> {
+    match gateway {
+        Some(gateway) => Ok(dns::resolve_host(gateway, NAT_PMP_PORT, IpFamily::V4)?),
+        None => default_gateway()
+            .map(|ip| SocketAddr::new(IpAddr::V4(ip), NAT_PMP_PORT))
+            .ok_or_else(|| {
+                GatewayError::NotFound(
+                    "No default gateway found, set \"gateway\" on the provider".to_string(),
+                )
+            }),
+    }
+}
+
+/// Sends a request to the router over UDP and waits for an answer, doubling the wait each retry
+/// like NAT-PMP and PCP ask for
+fn udp_exchange(
+    socket: &UdpSocket,
+    request: &[u8],
+    accept: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>, GatewayError> {
+    let mut wait = Duration::from_millis(250);
+    let deadline = Instant::now() + Duration::from_secs(constants::GATEWAY_TIMEOUT_SECONDS);
+    let mut buf = [0u8; 1100];
+
+    while Instant::now() < deadline {
+        socket.send(request)?;
+        let round_end = Instant::now() + wait;
+
+        // Stray packets aren't a lost request, keep listening until this round is over
+        while let Some(remaining) = round_end.checked_duration_since(Instant::now()) {
+            socket.set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+
+            match socket.recv(&mut buf) {
+                Ok(len) if accept(&buf[..len]) => return Ok(buf[..len].to_vec()),
+                Ok(_) => {}
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        wait *= 2;
+    }
+
+    Err(GatewayError::Io(format!(
+        "{} didn't answer",
+        socket
+            .peer_addr()
+            .map(|a| a.to_string())
+            .unwrap_or_default()
+    )))
+}
+
+/// Asks the router for its external address with NAT-PMP (RFC 6886)
+///
+/// # Arguments
+/// * `gateway: Option<&str>` - The router, the default gateway is used if not set
+///
+/// # Returns
+/// * `Result<Ipv4Addr, GatewayError>` - The external address
+pub fn nat_pmp_external_address(gateway: Option<&str>) -> Result<Ipv4Addr, GatewayError> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(gateway_addr(gateway)?)?;
+
+    // Version 0, opcode 0 asks for the external address
+    let answer = udp_exchange(&socket, &[0, 0], |msg| {
+        msg.len() >= 2 && msg[0] == 0 && msg[1] == 128
+    })?;
+
+    if answer.len() < 12 {
+        return Err(GatewayError::Malformed(
+            "NAT-PMP answer is too short".to_string(),
+        ));
+    }
+
+    match u16::from_be_bytes([answer[2], answer[3]]) {
+        0 => Ok(Ipv4Addr::new(answer[8], answer[9], answer[10], answer[11])),
+        1 => Err(GatewayError::Refused(
+            "unsupported NAT-PMP version".to_string(),
+        )),
+        2 => Err(GatewayError::Refused("NAT-PMP is disabled".to_string())),
+        3 => Err(GatewayError::Refused(
+            "the router has no external address yet".to_string(),
+        )),
+        code => Err(GatewayError::Refused(format!(
+            "NAT-PMP result code {}",
+            code
+        ))),
+    }
+}
+
+/// Returns the name of a PCP result code
+fn pcp_result_name(code: u8) -> String {
+    match code {
+        1 => "UNSUPP_VERSION".to_string(),
+        2 => "NOT_AUTHORIZED".to_string(),
+        3 => "MALFORMED_REQUEST".to_string(),
+        4 => "UNSUPP_OPCODE".to_string(),
+        5 => "UNSUPP_OPTION".to_string(),
+        6 => "MALFORMED_OPTION".to_string(),
+        7 => "NETWORK_FAILURE".to_string(),
+        8 => "NO_RESOURCES".to_string(),
+        9 => "UNSUPP_PROTOCOL".to_string(),
+        10 => "USER_EX_QUOTA".to_string(),
+        11 => "CANNOT_PROVIDE_EXTERNAL".to_string(),
+        12 => "ADDRESS_MISMATCH".to_string(),
+        13 => "EXCESSIVE_REMOTE_PEERS".to_string(),
+        _ => format!("result code {}", code),
+    }
+}
+
+/// Builds a PCP MAP request (RFC 6887) for the UDP port of the probe socket
+fn build_pcp_map(client: Ipv4Addr, port: u16, nonce: &[u8; 12], lifetime: u32) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(60);
+
+    // Version 2, opcode 1 (MAP), reserved
+    msg.extend_from_slice(&[2, 1, 0, 0]);
+    msg.extend_from_slice(&lifetime.to_be_bytes());
+    msg.extend_from_slice(&client.to_ipv6_mapped().octets());
+    msg.extend_from_slice(nonce);
+    // Protocol 17 (UDP), reserved
+    msg.extend_from_slice(&[17, 0, 0, 0]);
+    msg.extend_from_slice(&port.to_be_bytes());
+    // Any external port and any external IPv4 address
+    msg.extend_from_slice(&0u16.to_be_bytes());
+    msg.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+
+    msg
+}
+
+/// Asks the router for its external address with PCP (RFC 6887)
+///
+/// PCP has no plain "what is my address" request, so a short lived mapping is made for the
+/// probe socket, the assigned external address is read from the answer and the mapping is
+/// deleted again
+///
+/// # Arguments
+/// * `gateway: Option<&str>` - The router, the default gateway is used if not set
+///
+/// # Returns
+/// * `Result<IpAddr, GatewayError>` - The external address
+pub fn pcp_external_address(gateway: Option<&str>) -> Result<IpAddr, GatewayError> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(gateway_addr(gateway)?)?;
+
+    let local = socket.local_addr()?;
+    let client = match local.ip() {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+    };
+
+    let mut nonce = [0u8; 12];
+    for chunk in nonce.chunks_mut(2) {
+        chunk.copy_from_slice(&dns::new_id().to_be_bytes());
+    }
+
+    let request = build_pcp_map(client, local.port(), &nonce, 60);
+    let answer = udp_exchange(&socket, &request, |msg| {
+        msg.len() >= 60 && msg[0] == 2 && msg[1] == 0x81 && msg[24..36] == nonce
+    })?;
+
+    // Removing the mapping is best effort, it expires on its own anyway
+    let _ = socket.send(&build_pcp_map(client, local.port(), &nonce, 0));
+
+    if answer[3] != 0 {
+        return Err(GatewayError::Refused(pcp_result_name(answer[3])));
+    }
+
+    let octets: [u8; 16] = answer[44..60]
+        .try_into()
+        .map_err(|_| GatewayError::Malformed("PCP answer is too short".to_string()))?;
+    let ip = Ipv6Addr::from(octets);
+
+    Ok(ip
+        .to_ipv4_mapped()
+        .map(IpAddr::V4)
+        .unwrap_or(IpAddr::V6(ip)))
+}
+
+/// Finds an Internet Gateway Device with SSDP
+///
+/// # Returns
+/// * `Result<String, GatewayError>` - The URL of the device description
+pub fn ssdp_discover() -> Result<String, GatewayError> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    let deadline = Instant::now() + Duration::from_secs(constants::GATEWAY_TIMEOUT_SECONDS);
+
+    for search_target in [
+        "urn:schemas-upnp-org:device:InternetGatewayDevice:2",
+        "urn:schemas-upnp-org:device:InternetGatewayDevice:1",
+    ] {
+        let request = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {}\r\n\r\n",
+            SSDP_ADDR, search_target
+        );
+        socket.send_to(request.as_bytes(), SSDP_ADDR)?;
+    }
+
+    let mut buf = [0u8; 2048];
+
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        socket.set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => len,
+            Err(e)
+                if e.kind() == std::io::ErrorKind::WouldBlock
+                    || e.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let answer = String::from_utf8_lossy(&buf[..len]);
+        let location = answer.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("location")
+                .then(|| value.trim().to_string())
+        });
+
+        if let Some(location) = location {
+            return Ok(location);
+        }
+    }
+
+    Err(GatewayError::NotFound(
+        "No UPnP Internet Gateway Device answered".to_string(),
+    ))
+}
+
+/// Returns the text between the first `<tag>` and `</tag>`, namespaces prefixes are ignored
+fn xml_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = xml
+        .match_indices('<')
+        .find(|(i, _)| {
+            let rest = &xml[i + 1..];
+            let name_end = rest.find(['>', ' ', '/']).unwrap_or(rest.len());
+            let name = &rest[..name_end];
+            name == tag || name.ends_with(&format!(":{}", tag))
+        })
+        .map(|(i, _)| i)?;
+    let tag_end = open + xml[open..].find('>')?;
+
+    // A self-closing tag like <NewExternalIPAddress/> has no text
+    if xml[..tag_end].ends_with('/') {
+        return Some("");
+    }
+
+    let start = tag_end + 1;
+    let end = start + xml[start..].find("</")?;
+
+    Some(xml[start..end].trim())
+}
+
+/// Joins a possibly relative URL from a device description onto its base
+fn join_url(base: &str, path: &str) -> String {
+    if path.starts_with("http://") || path.starts_with("https://") {
+        return path.to_string();
+    }
+
+    // Everything up to the first slash after the scheme is the origin
+    let origin_end = base
+        .find("://")
+        .and_then(|i| base[i + 3..].find('/').map(|j| i + 3 + j))
+        .unwrap_or(base.len());
+
+    format!("{}/{}", &base[..origin_end], path.trim_start_matches('/'))
+}
+
+/// Finds the control URL of a WAN connection service in a device description
+///
+/// # Arguments
+/// * `location: &str` - Where the description was fetched from
+/// * `description: &str` - The description XML
+///
+/// # Returns
+/// * `Option<(String, String)>` - The service type and its absolute control URL
+pub fn find_wan_service(location: &str, description: &str) -> Option<(String, String)> {
+    let base = xml_text(description, "URLBase")
+        .filter(|b| !b.is_empty())
+        .unwrap_or(location);
+
+    let services: Vec<&str> = description.split("<service>").skip(1).collect();
+
+    WAN_SERVICES.iter().find_map(|wanted| {
+        services.iter().find_map(|service| {
+            let service = &service[..service.find("</service>").unwrap_or(service.len())];
+            (xml_text(service, "serviceType")? == *wanted).then(|| {
+                let control = xml_text(service, "controlURL").unwrap_or_default();
+                (wanted.to_string(), join_url(base, control))
+            })
+        })
+    })
+}
+
+/// Asks the router for its external address over UPnP IGD
+///
+/// # Returns
+/// * `Result<(Ipv4Addr, String), GatewayError>` - The external address and the control URL that was asked
+pub fn upnp_external_address() -> Result<(Ipv4Addr, String), GatewayError> {
+    let location = ssdp_discover()?;
+    let agent = http::agent_for(IpFamily::V4);
+
+    let description = agent
+        .get(&location)
+        .call()
+        .map_err(|e| GatewayError::Io(e.to_string()))?
+        .into_string()?;
+
+    let (service, control_url) = find_wan_service(&location, &description).ok_or_else(|| {
+        GatewayError::NotFound(format!("{} has no WAN connection service", location))
+    })?;
+
+    let body = format!(
+        "<?xml version=\"1.0\"?>\r\n\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:GetExternalIPAddress xmlns:u=\"{}\"></u:GetExternalIPAddress></s:Body>\
+         </s:Envelope>\r\n",
+        service
+    );
+
+    let answer = match agent
+        .post(&control_url)
+        .set("Content-Type", "text/xml; charset=\"utf-8\"")
+        .set(
+            "SOAPAction",
+            &format!("\"{}#GetExternalIPAddress\"", service),
+        )
+        .send_string(&body)
+    {
+        Ok(response) => response.into_string()?,
+        // SOAP faults come back as 500 with the reason in the body
+        Err(ureq::Error::Status(code, response)) => {
+            let fault = response.into_string().unwrap_or_default();
+            let reason = xml_text(&fault, "errorDescription").unwrap_or("no reason given");
+            return Err(GatewayError::Refused(format!("HTTP {}: {}", code, reason)));
+        }
+        Err(e) => return Err(GatewayError::Io(e.to_string())),
+    };
+
+    let ip = xml_text(&answer, "NewExternalIPAddress")
+        .ok_or_else(|| GatewayError::Malformed("answer has no NewExternalIPAddress".to_string()))?;
+    if ip.is_empty() {
+        return Err(GatewayError::Refused(
+            "the router has no external address yet".to_string(),
+        ));
+    }
+
+    ip.parse::<Ipv4Addr>()
+        .map(|ip| (ip, control_url))
+        .map_err(|_| GatewayError::Malformed(format!("{:?} is not an IPv4 address", ip)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread};
+
+    use super::*;
+
+    /// Starts a router stand-in on a loopback port that sends what `answer` returns for every
+    /// request, the requests it got come through the channel once it has been quiet for a while
+    fn router<F>(answer: F) -> (String, mpsc::Receiver<Vec<Vec<u8>>>)
+    where
+        F: Fn(&[u8]) -> Vec<Vec<u8>> + Send + 'static,
+    {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap().to_string();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            socket
+                .set_read_timeout(Some(Duration::from_millis(500)))
+                .unwrap();
+            let mut requests = Vec::new();
+            let mut buf = [0u8; 1100];
+
+            while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+                requests.push(buf[..len].to_vec());
+                for datagram in answer(&buf[..len]) {
+                    socket.send_to(&datagram, peer).unwrap();
+                }
+            }
+            let _ = sender.send(requests);
+        });
+
+        (address, receiver)
+    }
+
+    /// A NAT-PMP external address answer with the result code and address
+    fn nat_pmp_answer(code: u16, ip: [u8; 4]) -> Vec<u8> {
+        let mut msg = vec![0, 128];
+        msg.extend_from_slice(&code.to_be_bytes());
+        msg.extend_from_slice(&1234u32.to_be_bytes());
+        msg.extend_from_slice(&ip);
+        msg
+    }
+
+    #[test]
+    fn cgnat_range_boundaries() {
+        for (ip, expected) in [
+            ("100.63.255.255", false),
+            ("100.64.0.0", true),
+            ("100.100.1.1", true),
+            ("100.127.255.255", true),
+            ("100.128.0.0", false),
+            ("203.0.113.1", false),
+            ("64:ff9b::6440:1", false),
+        ] {
+            assert_eq!(is_cgnat(&ip.parse().unwrap()), expected, "{}", ip);
+        }
+    }
+
+    #[test]
+    fn warns_about_cgnat_and_private_wan_addresses() {
+        let warning = |ip: &str| wan_address_warning(&ip.parse().unwrap());
+
+        assert!(warning("100.64.1.1").unwrap().contains("carrier-grade NAT"));
+        assert!(warning("192.168.1.2").unwrap().contains("private address"));
+        assert!(warning("10.0.0.1").unwrap().contains("private address"));
+        assert_eq!(warning("203.0.113.1"), None);
+        assert_eq!(warning("2001:db8::1"), None);
+    }
+
+    #[test]
+    fn reads_xml_text_with_prefixes_and_self_closing_tags() {
+        let xml =
+            "<s:Body><u:Response><NewExternalIPAddress/><Other>x</Other></u:Response></s:Body>";
+        assert_eq!(xml_text(xml, "NewExternalIPAddress"), Some(""));
+        assert_eq!(xml_text(xml, "Other"), Some("x"));
+
+        let xml = "<u:NewExternalIPAddress> 203.0.113.5 </u:NewExternalIPAddress>";
+        assert_eq!(xml_text(xml, "NewExternalIPAddress"), Some("203.0.113.5"));
+        // A tag whose name only starts with the wanted one doesn't count
+        assert_eq!(xml_text("<URLBaseX>a</URLBaseX>", "URLBase"), None);
+    }
+
+    #[test]
+    fn finds_the_best_wan_service() {
+        let description = "<root><device><serviceList>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType>\
+            <controlURL>http://192.168.1.1:5000/ppp</controlURL></service>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+            <controlURL>/ctl/IPConn</controlURL></service>\
+            </serviceList></device></root>";
+
+        assert_eq!(
+            find_wan_service("http://192.168.1.1:5000/rootDesc.xml", description),
+            Some((
+                "urn:schemas-upnp-org:service:WANIPConnection:1".to_string(),
+                "http://192.168.1.1:5000/ctl/IPConn".to_string()
+            ))
+        );
+
+        let with_base =
+            description.replace("<root>", "<root><URLBase>http://10.0.0.1:80/</URLBase>");
+        assert_eq!(
+            find_wan_service("http://192.168.1.1:5000/rootDesc.xml", &with_base)
+                .unwrap()
+                .1,
+            "http://10.0.0.1:80/ctl/IPConn"
+        );
+        assert_eq!(find_wan_service("http://192.168.1.1/", "<root/>"), None);
+    }
+
+    #[test]
+    fn nat_pmp_reads_the_address_after_stray_packets() {
+        let (gateway, requests) = router(|_| {
+            vec![
+                vec![0, 129, 0, 0],
+                vec![1],
+                nat_pmp_answer(0, [203, 0, 113, 8]),
+            ]
+        });
+
+        let ip = nat_pmp_external_address(Some(&gateway)).unwrap();
+
+        assert_eq!(ip, Ipv4Addr::new(203, 0, 113, 8));
+        // The stray packets didn't make the request go out again
+        assert_eq!(requests.recv().unwrap(), [vec![0, 0]]);
+    }
+
+    #[test]
+    fn nat_pmp_result_codes_are_refusals() {
+        let (gateway, _) = router(|_| vec![nat_pmp_answer(3, [0; 4])]);
+
+        match nat_pmp_external_address(Some(&gateway)) {
+            Err(GatewayError::Refused(reason)) => assert!(reason.contains("no external address")),
+            other => panic!("expected a refusal, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn pcp_reads_the_assigned_address_and_deletes_the_mapping() {
+        let (gateway, requests) = router(|request| {
+            // Lifetime 0 deletes the mapping and isn't answered here
+            if request[4..8] == [0, 0, 0, 0] {
+                return Vec::new();
+            }
+            let mut answer = request.to_vec();
+            answer[1] = 0x81;
+            answer[3] = 0;
+            answer[44..60]
+                .copy_from_slice(&Ipv4Addr::new(198, 51, 100, 4).to_ipv6_mapped().octets());
+            vec![answer]
+        });
+
+        let ip = pcp_external_address(Some(&gateway)).unwrap();
+
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(198, 51, 100, 4)));
+        let requests = requests.recv().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].len(), 60);
+        assert_eq!(requests[0][..2], [2, 1]);
+        assert_eq!(requests[0][4..8], 60u32.to_be_bytes());
+        // The same mapping is deleted: same nonce, lifetime 0
+        assert_eq!(requests[1][24..36], requests[0][24..36]);
+        assert_eq!(requests[1][4..8], [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn pcp_error_codes_are_named() {
+        let (gateway, _) = router(|request| {
+            let mut answer = request.to_vec();
+            answer[1] = 0x81;
+            answer[3] = 2;
+            vec![answer]
+        });
+
+        match pcp_external_address(Some(&gateway)) {
+            Err(GatewayError::Refused(reason)) => assert_eq!(reason, "NOT_AUTHORIZED"),
+            other => panic!("expected a refusal, got {:?}", other),
+        }
+    }
+}
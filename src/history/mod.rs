@@ -0,0 +1,437 @@
+//! The check history, every lookup and event is kept in SQLite at `constants::get_db_path()`
+//!
+//! The schema is versioned with `PRAGMA user_version`, opening the database brings it up to date
+use chrono::{SecondsFormat, Utc};
+use rusqlite::{Connection, params};
+
+use crate::{
+    constants,
+    ip_check::{IpCheckError, IpFamily, Lookup, ProviderResult},
+    notifier::Event,
+};
+
+pub mod report;
+
+/// The schema changes in order, the database's `user_version` is how many have been applied
+///
+/// Released migrations are never edited, changes go into a new one at the end
+const MIGRATIONS: &[&str] = &[
+    // 1: checks, the outcome per provider, and events
+    "CREATE TABLE checks (
+        id INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        family TEXT NOT NULL,
+        ip TEXT,
+        error TEXT
+    );
+    CREATE INDEX checks_timestamp ON checks (timestamp);
+    CREATE TABLE provider_results (
+        id INTEGER PRIMARY KEY,
+        check_id INTEGER NOT NULL REFERENCES checks (id) ON DELETE CASCADE,
+        provider TEXT NOT NULL,
+        ip TEXT,
+        latency_ms INTEGER NOT NULL,
+        error TEXT
+    );
+    CREATE INDEX provider_results_check_id ON provider_results (check_id);
+    CREATE TABLE events (
+        id INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        family TEXT,
+        old_ip TEXT,
+        new_ip TEXT,
+        message TEXT NOT NULL
+    );
+    CREATE INDEX events_timestamp ON events (timestamp);",
+];
+
+/// The time format stored, UTC with a fixed width so the text sorts in time order
+pub fn timestamp_now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// How a family is written in the database
+pub fn family_name(family: IpFamily) -> &'static str {
+    match family {
+        IpFamily::V4 => "ipv4",
+        IpFamily::V6 => "ipv6",
+    }
+}
+
+/// A connection to the history database
+pub struct History {
+    pub conn: Connection,
+}
+
+impl History {
+    /// Opens the database, creating it and applying any missing migrations
+    ///
+    /// # Arguments
+    /// * `path: &str` - The database file
+    ///
+    /// # Returns
+    /// * `rusqlite::Result<History>` - Err if the file can't be opened or migrated
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let mut conn = Connection::open(path)?;
+        // Other processes, like the history command, may read while a check is written
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        migrate(&mut conn, MIGRATIONS)?;
+
+        Ok(History { conn })
+    }
+
+    /// Opens the database in the data directory
+    pub fn open_default() -> rusqlite::Result<Self> {
+        Self::open(&constants::get_db_path())
+    }
+
+    /// Saves one check, with what every provider answered
+    ///
+    /// # Arguments
+    /// * `family: IpFamily` - The family that was looked up
+    /// * `results: &[ProviderResult]` - The answer of each provider
+    /// * `outcome: &Result<Lookup, IpCheckError>` - The agreed upon address, or why there wasn't one
+    ///
+    /// # Returns
+    /// * `rusqlite::Result<i64>` - The ID of the check
+    pub fn record_check(
+        &mut self,
+        family: IpFamily,
+        results: &[ProviderResult],
+        outcome: &Result<Lookup, IpCheckError>,
+    ) -> rusqlite::Result<i64> {
+        let tx = self.conn.transaction()?;
+
+        tx.execute(
+            "INSERT INTO checks (timestamp, family, ip, error) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                timestamp_now(),
+                family_name(family),
+                outcome.as_ref().ok().map(|lookup| lookup.ip.to_string()),
+                outcome.as_ref().err().map(|e| e.to_string()),
+            ],
+        )?;
+        let check_id = tx.last_insert_rowid();
+
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO provider_results (check_id, provider, ip, latency_ms, error)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for result in results {
+                insert.execute(params![
+                    check_id,
+                    result.provider,
+                    result
+                        .result
+                        .as_ref()
+                        .ok()
+                        .map(|lookup| lookup.ip.to_string()),
+                    result.latency.as_millis() as i64,
+                    result.result.as_ref().err().map(|e| e.to_string()),
+                ])?;
+            }
+        }
+
+        tx.commit()?;
+
+        Ok(check_id)
+    }
+
+    /// Saves an event, like an address change or a failure alert
+    ///
+    /// # Arguments
+    /// * `event: &Event` - What happened
+    pub fn record_event(&self, event: &Event) -> rusqlite::Result<()> {
+        let (family, old_ip, new_ip) = event_columns(event);
+
+        self.conn.execute(
+            "INSERT INTO events (timestamp, kind, family, old_ip, new_ip, message)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                timestamp_now(),
+                event.kind(),
+                family,
+                old_ip,
+                new_ip,
+                event.message(),
+            ],
+        )?;
+
+        Ok(())
+    }
+}
+
+/// The family and addresses of an event as they are stored, None where the event has none
+///
+/// # Arguments
+/// * `event: &Event` - The event
+///
+/// # Returns
+/// * `(Option<String>, Option<String>, Option<String>)` - The family, the old and the new address
+pub fn event_columns(event: &Event) -> (Option<String>, Option<String>, Option<String>) {
+    let placeholders = event.placeholders();
+    let get = |name: &str| {
+        placeholders
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.clone())
+            .filter(|value| !value.is_empty())
+    };
+
+    (
+        // Written the same way as in the checks
+        get("family").map(|family| family.to_lowercase()),
+        get("old_ip"),
+        get("new_ip"),
+    )
+}
+
+/// Applies the migrations the database doesn't have yet, each in its own transaction
+///
+/// # Arguments
+/// * `conn: &mut Connection` - The database
+/// * `migrations: &[&str]` - Every schema change of the database, in order
+pub fn migrate(conn: &mut Connection, migrations: &[&str]) -> rusqlite::Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+    if version > migrations.len() {
+        eprintln!(
+            "The database {} is at version {}, newer than this program knows ({}).",
+            conn.path().unwrap_or_default(),
+            version,
+            migrations.len()
+        );
+        return Ok(());
+    }
+
+    for (i, migration) in migrations.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+/// Saves a check, logging instead of failing so the loop keeps going
+///
+/// # Arguments
+/// * `family: IpFamily` - The family that was looked up
+/// * `results: &[ProviderResult]` - The answer of each provider
+/// * `outcome: &Result<Lookup, IpCheckError>` - The agreed upon address, or why there wasn't one
+pub fn log_check(
+    family: IpFamily,
+    results: &[ProviderResult],
+    outcome: &Result<Lookup, IpCheckError>,
+) {
+    if let Err(e) = History::open_default()
+        .and_then(|mut history| history.record_check(family, results, outcome))
+    {
+        eprintln!("Could not save the check to the history: {}", e);
+    }
+}
+
+/// Saves events, logging instead of failing so the loop keeps going
+///
+/// # Arguments
+/// * `events: &[Event]` - What happened during the check
+pub fn log_events(events: &[Event]) {
+    if events.is_empty() {
+        return;
+    }
+
+    let saved = History::open_default().and_then(|history| {
+        events
+            .iter()
+            .try_for_each(|event| history.record_event(event))
+    });
+
+    if let Err(e) = saved {
+        eprintln!("Could not save the events to the history: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// The kind, family, old and new address of a stored event
+    type EventRow = (String, Option<String>, Option<String>, Option<String>);
+
+    fn version(conn: &Connection) -> usize {
+        conn.pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap()
+    }
+
+    fn tables(conn: &Connection) -> Vec<String> {
+        let mut query = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .unwrap();
+        query
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn migrations_apply_once_and_set_the_version() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let migrations = ["CREATE TABLE a (id INTEGER)", "CREATE TABLE b (id INTEGER)"];
+
+        migrate(&mut conn, &migrations[..1]).unwrap();
+        assert_eq!(version(&conn), 1);
+
+        // Running again, and with one more migration, only applies what is missing
+        migrate(&mut conn, &migrations).unwrap();
+        migrate(&mut conn, &migrations).unwrap();
+        assert_eq!(version(&conn), 2);
+        assert_eq!(tables(&conn), ["a", "b"]);
+    }
+
+    #[test]
+    fn newer_database_is_left_alone() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", 5).unwrap();
+
+        migrate(&mut conn, MIGRATIONS).unwrap();
+
+        assert_eq!(version(&conn), 5);
+        assert!(tables(&conn).is_empty());
+    }
+
+    #[test]
+    fn failed_migration_keeps_the_earlier_ones() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        assert!(migrate(&mut conn, &["CREATE TABLE a (id INTEGER)", "NOT SQL"]).is_err());
+
+        assert_eq!(version(&conn), 1);
+        assert_eq!(tables(&conn), ["a"]);
+    }
+
+    #[test]
+    fn records_checks_with_their_provider_answers() {
+        let mut history = History::open(":memory:").unwrap();
+        assert_eq!(version(&history.conn), MIGRATIONS.len());
+
+        let results = vec![
+            ProviderResult {
+                provider: "https://a.example".to_string(),
+                result: Ok(Lookup::new("203.0.113.4".parse().unwrap())),
+                latency: Duration::from_millis(120),
+            },
+            ProviderResult {
+                provider: "https://b.example".to_string(),
+                result: Err(IpCheckError::AllFailed),
+                latency: Duration::from_millis(5000),
+            },
+        ];
+        let check_id = history
+            .record_check(
+                IpFamily::V4,
+                &results,
+                &Ok(Lookup::new("203.0.113.4".parse().unwrap())),
+            )
+            .unwrap();
+        history
+            .record_check(IpFamily::V6, &[], &Err(IpCheckError::NoProviders))
+            .unwrap();
+
+        let checks: Vec<(String, Option<String>, Option<String>)> = history
+            .conn
+            .prepare("SELECT family, ip, error FROM checks ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(checks.len(), 2);
+        assert_eq!(
+            checks[0],
+            ("ipv4".to_string(), Some("203.0.113.4".to_string()), None)
+        );
+        assert_eq!(checks[1].0, "ipv6");
+        assert_eq!(checks[1].1, None);
+        assert!(checks[1].2.is_some());
+
+        let answers: Vec<(String, Option<String>, i64, Option<String>)> = history
+            .conn
+            .prepare(
+                "SELECT provider, ip, latency_ms, error FROM provider_results
+                 WHERE check_id = ?1 ORDER BY id",
+            )
+            .unwrap()
+            .query_map([check_id], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            answers,
+            [
+                (
+                    "https://a.example".to_string(),
+                    Some("203.0.113.4".to_string()),
+                    120,
+                    None
+                ),
+                (
+                    "https://b.example".to_string(),
+                    None,
+                    5000,
+                    Some(IpCheckError::AllFailed.to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn records_events_with_their_addresses() {
+        let history = History::open(":memory:").unwrap();
+
+        history
+            .record_event(&Event::IpChanged {
+                family: IpFamily::V4,
+                old_ip: String::new(),
+                new_ip: "203.0.113.4".parse().unwrap(),
+                details: Vec::new(),
+            })
+            .unwrap();
+        history
+            .record_event(&Event::Recovered { failures: 3 })
+            .unwrap();
+
+        let events: Vec<EventRow> = history
+            .conn
+            .prepare("SELECT kind, family, old_ip, new_ip FROM events ORDER BY id")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+
+        assert_eq!(
+            events,
+            [
+                (
+                    "ip_changed".to_string(),
+                    Some("ipv4".to_string()),
+                    // An empty old address is stored as NULL
+                    None,
+                    Some("203.0.113.4".to_string())
+                ),
+                ("recovered".to_string(), None, None, None),
+            ]
+        );
+    }
+}
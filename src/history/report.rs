@@ -0,0 +1,810 @@
+//! Turns the check log into address leases and outages, for the `history` command
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, SecondsFormat, Utc};
+use rusqlite::{Connection, Params, params};
+use serde_json::{Value, json};
+
+use crate::{
+    notifier::format_duration,
+    stats::{self, Sample},
+};
+
+/// One row of the `checks` table
+#[derive(Debug, Clone)]
+pub struct CheckRow {
+    pub timestamp: DateTime<Utc>,
+    pub family: String,
+    /// The agreed upon address, None if the check failed
+    pub ip: Option<String>,
+    pub error: Option<String>,
+}
+
+/// A stretch of time a family had the same public address
+#[derive(Debug, Clone)]
+pub struct Lease {
+    pub family: String,
+    pub ip: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    /// When the next address was first seen, None while this is still the current address
+    pub replaced: Option<DateTime<Utc>>,
+    /// How many successful checks saw the address
+    pub checks: u64,
+}
+
+impl Lease {
+    /// How long the address was held, up to the next address or the last check that saw it
+    pub fn duration(&self) -> Duration {
+        self.replaced.unwrap_or(self.last_seen) - self.first_seen
+    }
+}
+
+/// A stretch of time lookups of a family failed
+#[derive(Debug, Clone)]
+pub struct Outage {
+    pub family: String,
+    pub start: DateTime<Utc>,
+    /// The last check that failed
+    pub last_failed: DateTime<Utc>,
+    /// When a lookup worked again, None if it hasn't yet
+    pub end: Option<DateTime<Utc>>,
+    /// How many checks failed in a row
+    pub checks: u64,
+    /// The error of the last failed check
+    pub error: String,
+}
+
+impl Outage {
+    /// How long lookups failed, up to the first working check or the last failed one
+    pub fn duration(&self) -> Duration {
+        self.end.unwrap_or(self.last_failed) - self.start
+    }
+}
+
+/// How the report is printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Table,
+    Json,
+    Csv,
+}
+
+/// The options of the `history` command
+#[derive(Debug, Clone)]
+pub struct ReportOptions {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// `ipv4` or `ipv6`, None for both
+    pub family: Option<String>,
+    pub format: Format,
+}
+
+impl ReportOptions {
+    /// Reads `--since <time> --until <time> --family <ipv4|ipv6> --format <table|json|csv>`
+    ///
+    /// # Arguments
+    /// * `args: &[String]` - The arguments after `history`
+    ///
+    /// # Returns
+    /// * `Result<ReportOptions, String>` - Err if an option is unknown or its value is invalid
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut options = ReportOptions {
+            since: None,
+            until: None,
+            family: None,
+            format: Format::Table,
+        };
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} needs a value", arg))?;
+
+            match arg.as_str() {
+                "--since" => options.since = Some(parse_time(value)?),
+                "--until" => options.until = Some(parse_time(value)?),
+                "--family" => match value.as_str() {
+                    "ipv4" | "ipv6" => options.family = Some(value.clone()),
+                    _ => return Err("--family must be ipv4 or ipv6".to_string()),
+                },
+                "--format" => {
+                    options.format = match value.as_str() {
+                        "table" => Format::Table,
+                        "json" => Format::Json,
+                        "csv" => Format::Csv,
+                        _ => return Err("--format must be table, json or csv".to_string()),
+                    }
+                }
+                _ => return Err(format!("Unknown history option {}", arg)),
+            }
+        }
+
+        Ok(options)
+    }
+
+    /// Whether a stretch of time overlaps the requested range
+    fn overlaps(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> bool {
+        self.since.is_none_or(|since| end >= since) && self.until.is_none_or(|until| start <= until)
+    }
+}
+
+/// Reads a point in time, given as RFC 3339, a UTC date or date and time, or an age like `30d`
+///
+/// # Arguments
+/// * `value: &str` - Like `2024-05-01T12:00:00+02:00`, `2024-05-01`, `2024-05-01 12:00` or `12h`
+///
+/// # Returns
+/// * `Result<DateTime<Utc>, String>` - The time, Err if the format isn't known
+pub fn parse_time(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(value, format) {
+            return Ok(time.and_utc());
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc());
+    }
+
+    // An age, counted back from now, the unit is the last character whatever its width
+    let unknown = || {
+        format!(
+            "Unknown time {:?}, use a date like 2024-05-01 or an age like 30d",
+            value
+        )
+    };
+    let (split, unit) = value.char_indices().last().ok_or_else(unknown)?;
+    let amount: i64 = value[..split]
+        .parse()
+        .ok()
+        .filter(|amount| *amount >= 0)
+        .ok_or_else(unknown)?;
+    let age = match unit {
+        'm' => Duration::try_minutes(amount),
+        'h' => Duration::try_hours(amount),
+        'd' => Duration::try_days(amount),
+        'w' => Duration::try_weeks(amount),
+        _ => {
+            return Err(format!(
+                "Unknown time unit in {:?}, use m, h, d or w",
+                value
+            ));
+        }
+    };
+
+    age.and_then(|age| Utc::now().checked_sub_signed(age))
+        .ok_or_else(|| format!("{:?} is too long ago", value))
+}
+
+/// Reads the checks in time order
+///
+/// All of them are read, whether a lease or outage ended only shows in the checks after it
+///
+/// # Arguments
+/// * `conn: &Connection` - The history database
+/// * `family: Option<&str>` - `ipv4` or `ipv6`, None for both
+///
+/// # Returns
+/// * `rusqlite::Result<Vec<CheckRow>>` - The checks, oldest first
+pub fn read_checks(conn: &Connection, family: Option<&str>) -> rusqlite::Result<Vec<CheckRow>> {
+    let mut statement = conn.prepare(
+        "SELECT timestamp, family, ip, error FROM checks
+         WHERE ?1 IS NULL OR family = ?1
+         ORDER BY timestamp, id",
+    )?;
+    let rows = statement.query_map(params![family], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, Option<String>>(2)?,
+            row.get::<_, Option<String>>(3)?,
+        ))
+    })?;
+
+    let mut checks = Vec::new();
+    for row in rows {
+        let (timestamp, family, ip, error) = row?;
+        // A row that can't be read shouldn't hide the rest of the history
+        let Ok(timestamp) = DateTime::parse_from_rfc3339(&timestamp) else {
+            continue;
+        };
+
+        checks.push(CheckRow {
+            timestamp: timestamp.with_timezone(&Utc),
+            family,
+            ip,
+            error,
+        });
+    }
+
+    Ok(checks)
+}
+
+/// Reads the checks as stats samples, with the mean latency of the providers that answered
+///
+/// # Arguments
+/// * `conn: &Connection` - The history database
+/// * `family: Option<&str>` - `ipv4` or `ipv6`, None for both
+///
+/// # Returns
+/// * `rusqlite::Result<Vec<Sample>>` - The samples, oldest first
+pub fn read_samples(conn: &Connection, family: Option<&str>) -> rusqlite::Result<Vec<Sample>> {
+    query_samples(
+        conn,
+        "SELECT checks.timestamp, checks.ip IS NOT NULL,
+                AVG(CASE WHEN provider_results.error IS NULL THEN provider_results.latency_ms END)
+         FROM checks LEFT JOIN provider_results ON provider_results.check_id = checks.id
+         WHERE ?1 IS NULL OR checks.family = ?1
+         GROUP BY checks.id
+         ORDER BY checks.timestamp, checks.id",
+        params![family],
+    )
+}
+
+/// Runs a query of timestamp, success and mean latency rows and reads them as stats samples
+///
+/// The check history and the collector's reports are both read this way
+///
+/// # Arguments
+/// * `conn: &Connection` - The database
+/// * `sql: &str` - The query, selecting the RFC 3339 timestamp, whether it worked and the latency
+/// * `params: P` - The parameters of the query
+///
+/// # Returns
+/// * `rusqlite::Result<Vec<Sample>>` - The samples, rows with an unreadable timestamp are skipped
+pub fn query_samples<P: Params>(
+    conn: &Connection,
+    sql: &str,
+    params: P,
+) -> rusqlite::Result<Vec<Sample>> {
+    let mut statement = conn.prepare(sql)?;
+    let rows = statement.query_map(params, |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, bool>(1)?,
+            row.get::<_, Option<f64>>(2)?,
+        ))
+    })?;
+
+    let mut samples = Vec::new();
+    for row in rows {
+        let (timestamp, success, latency_ms) = row?;
+        let Ok(timestamp) = DateTime::parse_from_rfc3339(&timestamp) else {
+            continue;
+        };
+
+        samples.push(Sample {
+            timestamp: timestamp.with_timezone(&Utc),
+            success,
+            latency_ms,
+        });
+    }
+
+    Ok(samples)
+}
+
+/// Groups the successful checks of each family into leases of the same address
+///
+/// Failed checks in between don't end a lease, the address is the same before and after
+///
+/// # Arguments
+/// * `checks: &[CheckRow]` - The checks, oldest first
+///
+/// # Returns
+/// * `Vec<Lease>` - The leases of every family, oldest first
+pub fn leases(checks: &[CheckRow]) -> Vec<Lease> {
+    let mut leases: Vec<Lease> = Vec::new();
+
+    for check in checks {
+        let Some(ip) = &check.ip else { continue };
+        let current = leases
+            .iter_mut()
+            .rev()
+            .find(|lease| lease.family == check.family);
+
+        match current {
+            Some(lease) if lease.ip == *ip => {
+                lease.last_seen = check.timestamp;
+                lease.checks += 1;
+            }
+            current => {
+                if let Some(lease) = current {
+                    lease.replaced = Some(check.timestamp);
+                }
+                leases.push(Lease {
+                    family: check.family.clone(),
+                    ip: ip.clone(),
+                    first_seen: check.timestamp,
+                    last_seen: check.timestamp,
+                    replaced: None,
+                    checks: 1,
+                });
+            }
+        }
+    }
+
+    leases
+}
+
+/// Groups the failed checks of each family into outages
+///
+/// # Arguments
+/// * `checks: &[CheckRow]` - The checks, oldest first
+///
+/// # Returns
+/// * `Vec<Outage>` - The outages of every family, oldest first
+pub fn outages(checks: &[CheckRow]) -> Vec<Outage> {
+    let mut outages: Vec<Outage> = Vec::new();
+
+    for check in checks {
+        let ongoing = outages
+            .iter_mut()
+            .rev()
+            .find(|outage| outage.family == check.family)
+            .filter(|outage| outage.end.is_none());
+
+        match (&check.ip, ongoing) {
+            (Some(_), Some(outage)) => outage.end = Some(check.timestamp),
+            (Some(_), None) => {}
+            (None, Some(outage)) => {
+                outage.last_failed = check.timestamp;
+                outage.checks += 1;
+                outage.error = check.error.clone().unwrap_or_default();
+            }
+            (None, None) => outages.push(Outage {
+                family: check.family.clone(),
+                start: check.timestamp,
+                last_failed: check.timestamp,
+                end: None,
+                checks: 1,
+                error: check.error.clone().unwrap_or_default(),
+            }),
+        }
+    }
+
+    outages
+}
+
+/// Writes a time the way it is stored
+fn format_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Quotes a CSV field if it has to be
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Prints rows as a table with aligned columns
+fn print_table(header: &[&str], rows: &[Vec<String>]) {
+    let widths: Vec<usize> = (0..header.len())
+        .map(|i| {
+            rows.iter()
+                .map(|row| row[i].chars().count())
+                .chain([header[i].len()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    let line = |cells: Vec<String>| {
+        cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<String>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+
+    println!("{}", line(header.iter().map(|h| h.to_string()).collect()));
+    for row in rows {
+        println!("{}", line(row.clone()));
+    }
+}
+
+/// Builds and prints the report
+///
+/// # Arguments
+/// * `conn: &Connection` - The history database
+/// * `options: &ReportOptions` - The range, family and output format
+pub fn print_report(conn: &Connection, options: &ReportOptions) -> rusqlite::Result<()> {
+    let checks = read_checks(conn, options.family.as_deref())?;
+    let leases: Vec<Lease> = leases(&checks)
+        .into_iter()
+        .filter(|lease| {
+            options.overlaps(lease.first_seen, lease.replaced.unwrap_or(lease.last_seen))
+        })
+        .collect();
+    let outages: Vec<Outage> = outages(&checks)
+        .into_iter()
+        .filter(|outage| options.overlaps(outage.start, outage.end.unwrap_or(outage.last_failed)))
+        .collect();
+
+    match options.format {
+        Format::Json => {
+            let leases: Vec<Value> = leases
+                .iter()
+                .map(|lease| {
+                    json!({
+                        "family": lease.family,
+                        "ip": lease.ip,
+                        "firstSeen": format_time(lease.first_seen),
+                        "lastSeen": format_time(lease.last_seen),
+                        "replaced": lease.replaced.map(format_time),
+                        "durationSeconds": lease.duration().num_seconds(),
+                        "checks": lease.checks,
+                    })
+                })
+                .collect();
+            let outages: Vec<Value> = outages
+                .iter()
+                .map(|outage| {
+                    json!({
+                        "family": outage.family,
+                        "start": format_time(outage.start),
+                        "end": outage.end.map(format_time),
+                        "durationSeconds": outage.duration().num_seconds(),
+                        "failedChecks": outage.checks,
+                        "lastError": outage.error,
+                    })
+                })
+                .collect();
+
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&json!({ "addresses": leases, "outages": outages }))
+                    .unwrap_or_default()
+            );
+        }
+        Format::Csv => {
+            // One table for both, so it opens as a single sheet
+            println!("record,family,ip,start,end,duration_seconds,checks,error");
+            for lease in &leases {
+                println!(
+                    "address,{},{},{},{},{},{},",
+                    lease.family,
+                    lease.ip,
+                    format_time(lease.first_seen),
+                    lease.replaced.map(format_time).unwrap_or_default(),
+                    lease.duration().num_seconds(),
+                    lease.checks
+                );
+            }
+            for outage in &outages {
+                println!(
+                    "outage,{},,{},{},{},{},{}",
+                    outage.family,
+                    format_time(outage.start),
+                    outage.end.map(format_time).unwrap_or_default(),
+                    outage.duration().num_seconds(),
+                    outage.checks,
+                    csv_field(&outage.error)
+                );
+            }
+        }
+        Format::Table => {
+            println!("Addresses");
+            print_table(
+                &[
+                    "Family",
+                    "IP",
+                    "First seen",
+                    "Last seen",
+                    "Held for",
+                    "Checks",
+                ],
+                &leases
+                    .iter()
+                    .map(|lease| {
+                        vec![
+                            lease.family.clone(),
+                            lease.ip.clone(),
+                            format_time(lease.first_seen),
+                            format_time(lease.last_seen),
+                            match lease.replaced {
+                                Some(_) => format_duration(lease.duration()),
+                                None => format!("{} (current)", format_duration(lease.duration())),
+                            },
+                            lease.checks.to_string(),
+                        ]
+                    })
+                    .collect::<Vec<_>>(),
+            );
+
+            println!("\nOutages");
+            print_table(
+                &[
+                    "Family",
+                    "Start",
+                    "End",
+                    "Lasted",
+                    "Failed checks",
+                    "Last error",
+                ],
+                &outages
+                    .iter()
+                    .map(|outage| {
+                        vec![
+                            outage.family.clone(),
+                            format_time(outage.start),
+                            outage
+                                .end
+                                .map(format_time)
+                                .unwrap_or_else(|| "ongoing".to_string()),
+                            format_duration(outage.duration()),
+                            outage.checks.to_string(),
+                            outage.error.clone(),
+                        ]
+                    })
+                    .collect::<Vec<_>>(),
+            );
+
+            let changes = leases.len().saturating_sub(
+                // The first address of each family is where the history starts, not a change
+                leases
+                    .iter()
+                    .map(|lease| &lease.family)
+                    .collect::<std::collections::HashSet<_>>()
+                    .len(),
+            );
+            println!("\nAddress changes: {}, outages: {}", changes, outages.len());
+        }
+    }
+
+    Ok(())
+}
+
+/// Computes and prints the latency and success rate stats of the range
+///
+/// # Arguments
+/// * `conn: &Connection` - The history database
+/// * `options: &ReportOptions` - The range, family and output format
+pub fn print_stats(conn: &Connection, options: &ReportOptions) -> rusqlite::Result<()> {
+    let samples = read_samples(conn, options.family.as_deref())?;
+    // Without a start the range begins at the first check
+    let start = options
+        .since
+        .or_else(|| samples.first().map(|sample| sample.timestamp))
+        .unwrap_or_else(Utc::now);
+    let end = options.until.unwrap_or_else(Utc::now);
+    let stats = stats::compute(&samples, start, end);
+
+    let value = |value: Option<f64>| value.map(|v| format!("{:.1}", v)).unwrap_or_default();
+
+    match options.format {
+        Format::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&stats.to_json()).unwrap_or_default()
+        ),
+        Format::Csv => {
+            println!("timestamp,success_rate,latency_ms");
+            for (success, latency) in stats.success_rate.iter().zip(&stats.latency) {
+                println!(
+                    "{},{},{}",
+                    format_time(success.timestamp),
+                    success.value.map(|v| v.to_string()).unwrap_or_default(),
+                    latency.value.map(|v| v.to_string()).unwrap_or_default()
+                );
+            }
+        }
+        Format::Table => {
+            let summary = &stats.summary;
+            println!("From {} to {}", format_time(start), format_time(end));
+            println!(
+                "Checks: {}, successful: {} ({}%)",
+                summary.checks,
+                summary.successes,
+                value(summary.success_rate.map(|rate| rate * 100.0))
+            );
+            println!(
+                "Latency in ms: min {}, median {}, p95 {}, max {}\n",
+                value(summary.latency_min),
+                value(summary.latency_median),
+                value(summary.latency_p95),
+                value(summary.latency_max)
+            );
+            print_table(
+                &["Time", "Success %", "Latency ms"],
+                &stats
+                    .success_rate
+                    .iter()
+                    .zip(&stats.latency)
+                    .map(|(success, latency)| {
+                        vec![
+                            format_time(success.timestamp),
+                            value(success.value.map(|rate| rate * 100.0)),
+                            value(latency.value),
+                        ]
+                    })
+                    .collect::<Vec<_>>(),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A check `minute` minutes after midnight, failed if `ip` is None
+    fn check(minute: i64, family: &str, ip: Option<&str>) -> CheckRow {
+        CheckRow {
+            timestamp: at(minute),
+            family: family.to_string(),
+            ip: ip.map(str::to_string),
+            error: ip.is_none().then(|| format!("timeout at {}", minute)),
+        }
+    }
+
+    fn at(minute: i64) -> DateTime<Utc> {
+        "2024-05-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap() + Duration::minutes(minute)
+    }
+
+    #[test]
+    fn reads_dates_and_times() {
+        let expected = "2024-05-01T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+
+        assert_eq!(parse_time("2024-05-01T12:00:00+02:00"), Ok(expected));
+        assert_eq!(parse_time("2024-05-01 10:00"), Ok(expected));
+        assert_eq!(parse_time("2024-05-01T10:00:00"), Ok(expected));
+        assert_eq!(parse_time("2024-05-01"), Ok(expected - Duration::hours(10)));
+    }
+
+    #[test]
+    fn reads_ages() {
+        let time = parse_time("2w").unwrap();
+        let age = Utc::now() - time;
+
+        assert!(age >= Duration::weeks(2) && age < Duration::weeks(2) + Duration::minutes(1));
+        assert!(parse_time("0m").is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_units_and_amounts_without_panicking() {
+        for value in ["30x", "30é", "3€", "é", "", "-5d", "d", "1.5h"] {
+            assert!(parse_time(value).is_err(), "{:?} was accepted", value);
+        }
+        assert_eq!(
+            parse_time("30é"),
+            Err("Unknown time unit in \"30é\", use m, h, d or w".to_string())
+        );
+        // Too far back for chrono to count
+        assert!(parse_time("99999999999999w").is_err());
+        assert!(parse_time(&format!("{}m", i64::MAX)).is_err());
+    }
+
+    #[test]
+    fn returning_address_is_a_new_lease() {
+        let checks = [
+            check(0, "ipv4", Some("192.0.2.1")),
+            check(5, "ipv4", Some("192.0.2.1")),
+            check(10, "ipv4", Some("192.0.2.2")),
+            check(15, "ipv4", Some("192.0.2.1")),
+        ];
+
+        let leases = leases(&checks);
+        let summary: Vec<_> = leases
+            .iter()
+            .map(|lease| (lease.ip.as_str(), lease.checks, lease.replaced))
+            .collect();
+
+        assert_eq!(
+            summary,
+            [
+                ("192.0.2.1", 2, Some(at(10))),
+                ("192.0.2.2", 1, Some(at(15))),
+                ("192.0.2.1", 1, None),
+            ]
+        );
+        assert_eq!(leases[0].duration(), Duration::minutes(10));
+        assert_eq!(leases[2].duration(), Duration::zero());
+    }
+
+    #[test]
+    fn failed_checks_do_not_end_a_lease() {
+        let checks = [
+            check(0, "ipv4", Some("192.0.2.1")),
+            check(5, "ipv4", None),
+            check(10, "ipv4", None),
+            check(15, "ipv4", Some("192.0.2.1")),
+        ];
+
+        let leases = leases(&checks);
+        assert_eq!(leases.len(), 1);
+        assert_eq!(leases[0].checks, 2);
+        assert_eq!(leases[0].last_seen, at(15));
+
+        let outages = outages(&checks);
+        assert_eq!(outages.len(), 1);
+        assert_eq!(outages[0].start, at(5));
+        assert_eq!(outages[0].last_failed, at(10));
+        assert_eq!(outages[0].end, Some(at(15)));
+        assert_eq!(outages[0].checks, 2);
+        assert_eq!(outages[0].error, "timeout at 10");
+        assert_eq!(outages[0].duration(), Duration::minutes(10));
+    }
+
+    #[test]
+    fn families_are_followed_separately() {
+        let checks = [
+            check(0, "ipv4", Some("192.0.2.1")),
+            check(0, "ipv6", Some("2001:db8::1")),
+            check(5, "ipv6", None),
+            check(5, "ipv4", Some("192.0.2.1")),
+            check(10, "ipv6", Some("2001:db8::2")),
+            check(10, "ipv4", None),
+        ];
+
+        let leases = leases(&checks);
+        let summary: Vec<_> = leases
+            .iter()
+            .map(|lease| (lease.family.as_str(), lease.ip.as_str(), lease.replaced))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("ipv4", "192.0.2.1", None),
+                ("ipv6", "2001:db8::1", Some(at(10))),
+                ("ipv6", "2001:db8::2", None),
+            ]
+        );
+
+        let outages = outages(&checks);
+        let summary: Vec<_> = outages
+            .iter()
+            .map(|outage| (outage.family.as_str(), outage.start, outage.end))
+            .collect();
+        assert_eq!(
+            summary,
+            [("ipv6", at(5), Some(at(10))), ("ipv4", at(10), None)]
+        );
+    }
+
+    #[test]
+    fn ongoing_outage_lasts_until_the_last_failure() {
+        let checks = [
+            check(0, "ipv4", None),
+            check(5, "ipv4", None),
+            check(20, "ipv4", None),
+        ];
+
+        let outages = outages(&checks);
+
+        assert_eq!(outages.len(), 1);
+        assert_eq!(outages[0].end, None);
+        assert_eq!(outages[0].checks, 3);
+        assert_eq!(outages[0].duration(), Duration::minutes(20));
+        assert!(leases(&checks).is_empty());
+    }
+
+    #[test]
+    fn range_keeps_stretches_that_overlap_it() {
+        let options = ReportOptions::from_args(&[
+            "--since".to_string(),
+            "2024-05-01 00:10".to_string(),
+            "--until".to_string(),
+            "2024-05-01 00:20".to_string(),
+        ])
+        .unwrap();
+
+        // Before, touching the start, across, inside, touching the end and after
+        assert!(!options.overlaps(at(0), at(9)));
+        assert!(options.overlaps(at(0), at(10)));
+        assert!(options.overlaps(at(5), at(25)));
+        assert!(options.overlaps(at(12), at(18)));
+        assert!(options.overlaps(at(20), at(30)));
+        assert!(!options.overlaps(at(21), at(30)));
+
+        let open = ReportOptions::from_args(&[]).unwrap();
+        assert!(open.overlaps(at(0), at(0)));
+    }
+}
@@ -0,0 +1,312 @@
+//! Runs user commands when something happens, so firewalls or VPN configs can follow the address
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::{
+    io::Read,
+    process::{Child, Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde_json::Value;
+
+use crate::{config::Config, constants, notifier::Event};
+
+/// The events a hook runs for unless it lists its own
+pub const DEFAULT_EVENTS: [&str; 7] = [
+    "ip_changed",
+    "failure_threshold",
+    "recovered",
+    "dns_drift",
+    "site_ip_changed",
+    "site_silent",
+    "site_back_online",
+];
+
+/// A command from the `hooks` array of the config
+#[derive(Debug, Clone)]
+pub struct Hook {
+    /// The executable to run
+    pub command: String,
+    /// Arguments passed to the command
+    pub args: Vec<String>,
+    /// How long the command may run before it is killed
+    pub timeout: Duration,
+    /// The event kinds it runs for, see [`Event::kind`]
+    pub events: Vec<String>,
+}
+
+/// What a hook did
+#[derive(Debug, Clone)]
+pub struct HookOutput {
+    /// The exit code, None if it was killed or ended by a signal
+    pub status: Option<i32>,
+    /// Whether it was killed for running too long
+    pub timed_out: bool,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Hook {
+    /// Reads a hook from `{"command": "...", "args": ["..."], "timeout": 30, "events": ["ip_changed"]}`,
+    /// a plain string is taken as the command
+    ///
+    /// # Arguments
+    /// * `value: &Value` - The entry from the `hooks` array
+    ///
+    /// # Returns
+    /// * `Result<Hook, String>` - Err if there is no command
+    pub fn from_value(value: &Value) -> Result<Self, String> {
+        let strings = |key: &str| -> Option<Vec<String>> {
+            value.get(key).and_then(|v| v.as_array()).map(|items| {
+                items
+                    .iter()
+                    .filter_map(|item| item.as_str())
+                    .map(|item| item.to_string())
+                    .collect()
+            })
+        };
+
+        let command = match value {
+            Value::String(command) => Some(command.clone()),
+            _ => value
+                .get("command")
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_string()),
+        }
+        .filter(|command| !command.is_empty())
+        .ok_or_else(|| format!("Hook {} has no command", value))?;
+
+        Ok(Hook {
+            command,
+            args: strings("args").unwrap_or_default(),
+            timeout: Duration::from_secs(
+                value
+                    .get("timeout")
+                    .and_then(|v| v.as_u64())
+                    .unwrap_or(constants::HOOK_TIMEOUT_SECONDS),
+            ),
+            events: strings("events")
+                .unwrap_or_else(|| DEFAULT_EVENTS.iter().map(|e| e.to_string()).collect()),
+        })
+    }
+
+    /// Runs the command with the event in its environment
+    ///
+    /// The command gets `EVENT`, `OLD_IP`, `NEW_IP`, `FAILURES`, `FAMILY` and `HOSTNAME`,
+    /// values that don't apply to the event are empty
+    ///
+    /// # Arguments
+    /// * `event: &Event` - What happened
+    ///
+    /// # Returns
+    /// * `Result<HookOutput, std::io::Error>` - Err if the command couldn't be started
+    pub fn run(&self, event: &Event) -> Result<HookOutput, std::io::Error> {
+        let mut command = Command::new(&self.command);
+        command
+            .args(&self.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        for (name, value) in event.placeholders() {
+            // Only the plain values, the subject and message are for people
+            if !matches!(name, "subject" | "message" | "timestamp") {
+                command.env(name.to_uppercase(), value);
+            }
+        }
+
+        // Its own process group, so whatever it starts can be killed along with it
+        #[cfg(unix)]
+        command.process_group(0);
+
+        let mut child = command.spawn()?;
+
+        // Read both pipes while waiting, a chatty hook would block on a full pipe otherwise
+        let stdout = read_in_background(child.stdout.take());
+        let stderr = read_in_background(child.stderr.take());
+
+        let deadline = Instant::now() + self.timeout;
+        let mut timed_out = false;
+
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                timed_out = true;
+                kill_group(&mut child);
+                break child.wait()?;
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+
+        // Something it left running may still hold the pipes, so don't wait for them forever
+        let grace = Instant::now() + Duration::from_millis(constants::HOOK_OUTPUT_GRACE_MILLIS);
+        let collect = |output: mpsc::Receiver<String>| {
+            output
+                .recv_timeout(grace.saturating_duration_since(Instant::now()))
+                .unwrap_or_default()
+        };
+
+        Ok(HookOutput {
+            status: status.code(),
+            timed_out,
+            stdout: collect(stdout),
+            stderr: collect(stderr),
+        })
+    }
+}
+
+/// Kills the hook and everything else in its process group
+fn kill_group(child: &mut Child) {
+    #[cfg(unix)]
+    // SAFETY: kill only sends a signal, the group ID is the hook's own PID since it leads the group
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+
+    // Also the fallback where there are no process groups, harmless once the group is gone
+    let _ = child.kill();
+}
+
+/// Reads a pipe to the end on another thread
+///
+/// # Arguments
+/// * `pipe: Option<R>` - The pipe, None if it wasn't captured
+///
+/// # Returns
+/// * `mpsc::Receiver<String>` - Gets the output once the pipe is closed
+fn read_in_background<R: Read + Send + 'static>(mut pipe: Option<R>) -> mpsc::Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(read_all(pipe.as_mut()));
+    });
+    receiver
+}
+
+/// Reads a pipe to the end, lossily since hooks may print anything
+fn read_all<R: Read>(pipe: Option<&mut R>) -> String {
+    let mut buf = Vec::new();
+
+    if let Some(pipe) = pipe {
+        let _ = pipe.read_to_end(&mut buf);
+    }
+
+    String::from_utf8_lossy(&buf).trim_end().to_string()
+}
+
+/// Runs every hook that wants the event, one after the other, and logs what they did
+///
+/// # Arguments
+/// * `config: &Config` - The config holding the `hooks` array
+/// * `event: &Event` - What happened
+pub fn run_all(config: &Config, event: &Event) {
+    for entry in &config.hooks {
+        let hook = match Hook::from_value(entry) {
+            Ok(hook) => hook,
+            Err(e) => {
+                eprintln!("Skipping hook: {}", e);
+                continue;
+            }
+        };
+
+        if !hook.events.iter().any(|kind| kind == event.kind()) {
+            continue;
+        }
+
+        match hook.run(event) {
+            Ok(output) => {
+                for line in output.stdout.lines() {
+                    println!("[{}] {}", hook.command, line);
+                }
+                for line in output.stderr.lines() {
+                    eprintln!("[{}] {}", hook.command, line);
+                }
+
+                if output.timed_out {
+                    eprintln!(
+                        "Hook {} was killed after {} seconds",
+                        hook.command,
+                        hook.timeout.as_secs()
+                    );
+                } else if output.status != Some(0) {
+                    eprintln!(
+                        "Hook {} failed with exit code {}",
+                        hook.command,
+                        output
+                            .status
+                            .map(|code| code.to_string())
+                            .unwrap_or_else(|| "none".to_string())
+                    );
+                } else {
+                    println!("Ran hook {} for {}", hook.command, event.kind());
+                }
+            }
+            Err(e) => eprintln!("Could not run hook {}: {}", hook.command, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hook(script: &str, timeout: Duration) -> Hook {
+        Hook {
+            command: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            timeout,
+            events: DEFAULT_EVENTS.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    fn event() -> Event {
+        Event::FailureThreshold {
+            failures: 3,
+            error: "no provider answered".to_string(),
+        }
+    }
+
+    #[test]
+    fn passes_the_event_in_the_environment() {
+        let output = hook(
+            "echo \"$EVENT $FAILURES\"; echo oops >&2",
+            Duration::from_secs(5),
+        )
+        .run(&event())
+        .unwrap();
+
+        assert_eq!(output.status, Some(0));
+        assert!(!output.timed_out);
+        assert_eq!(output.stdout, "failure_threshold 3");
+        assert_eq!(output.stderr, "oops");
+    }
+
+    #[test]
+    fn timeout_kills_the_whole_process_group() {
+        let started = Instant::now();
+        // The background sleep holds stdout open, it has to die with the shell
+        let output = hook("sleep 30 & echo started; sleep 30", Duration::from_secs(1))
+            .run(&event())
+            .unwrap();
+
+        assert!(output.timed_out);
+        assert_eq!(output.status, None);
+        assert_eq!(output.stdout, "started");
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn output_held_open_by_a_leftover_process_is_given_up_on() {
+        let started = Instant::now();
+        // The shell exits at once, but what it left behind keeps the pipe open
+        let output = hook("sleep 3 &", Duration::from_secs(5))
+            .run(&event())
+            .unwrap();
+
+        assert_eq!(output.status, Some(0));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
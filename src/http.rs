@@ -0,0 +1,283 @@
+//! Shared HTTP client, configured once with the timeouts and user agent used everywhere
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    time::Duration,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use once_cell::sync::Lazy;
+use ureq::{Agent, AgentBuilder};
+
+use crate::{constants, ip_check::IpFamily};
+
+static AGENT: Lazy<Agent> = Lazy::new(|| builder().build());
+
+static AGENT_V4: Lazy<Agent> = Lazy::new(|| {
+    builder()
+        .resolver(|netloc: &str| resolve(netloc, IpFamily::V4))
+        .build()
+});
+
+static AGENT_V6: Lazy<Agent> = Lazy::new(|| {
+    builder()
+        .resolver(|netloc: &str| resolve(netloc, IpFamily::V6))
+        .build()
+});
+
+/// Starts an agent with the shared timeouts and user agent
+fn builder() -> AgentBuilder {
+    AgentBuilder::new()
+        .timeout_connect(Duration::from_secs(constants::HTTP_CONNECT_TIMEOUT_SECONDS))
+        .timeout_read(Duration::from_secs(constants::HTTP_READ_TIMEOUT_SECONDS))
+        .timeout_write(Duration::from_secs(constants::HTTP_READ_TIMEOUT_SECONDS))
+        .user_agent(constants::USER_AGENT)
+}
+
+/// Resolves a host, keeping only the addresses of one family
+fn resolve(netloc: &str, family: IpFamily) -> std::io::Result<Vec<SocketAddr>> {
+    Ok(netloc
+        .to_socket_addrs()?
+        .filter(|addr| family.matches(&addr.ip()))
+        .collect())
+}
+
+/// Returns the shared HTTP agent
+///
+/// # Returns
+/// * `Agent` - Cheap to clone, all clones share a connection pool
+pub fn agent() -> Agent {
+    AGENT.clone()
+}
+
+/// Returns an HTTP agent that only connects over the given address family
+///
+/// # Arguments
+/// * `family: IpFamily` - The family every connection has to use
+///
+/// # Returns
+/// * `Agent` - Cheap to clone, all clones share a connection pool
+pub fn agent_for(family: IpFamily) -> Agent {
+    match family {
+        IpFamily::V4 => AGENT_V4.clone(),
+        IpFamily::V6 => AGENT_V6.clone(),
+    }
+}
+
+/// Describes a transport error without repeating the URL, the caller already knows it
+///
+/// # Arguments
+/// * `error: &ureq::Transport` - The error to describe
+///
+/// # Returns
+/// * `String` - The kind of error followed by any details
+pub fn describe_transport(error: &ureq::Transport) -> String {
+    let mut description = error.kind().to_string();
+
+    if let Some(message) = error.message() {
+        description.push_str(&format!(": {}", message));
+    }
+
+    if let Some(source) = std::error::Error::source(error) {
+        description.push_str(&format!(": {}", source));
+    }
+
+    description
+}
+
+/// Describes a failed request, with the start of the body for error statuses since services
+/// usually explain what was wrong there
+///
+/// # Arguments
+/// * `error: ureq::Error` - The error to describe
+///
+/// # Returns
+/// * `String` - The description
+pub fn describe_error(error: ureq::Error) -> String {
+    match error {
+        ureq::Error::Status(code, response) => {
+            let body = response.into_string().unwrap_or_default();
+            let body: String = body.trim().chars().take(200).collect();
+
+            if body.is_empty() {
+                format!("HTTP status {}", code)
+            } else {
+                format!("HTTP status {}: {}", code, body)
+            }
+        }
+        ureq::Error::Transport(e) => describe_transport(&e),
+    }
+}
+
+/// Percent encodes text for use as one segment of a URL path
+///
+/// # Arguments
+/// * `segment: &str` - The text, like a Matrix room ID
+///
+/// # Returns
+/// * `String` - The text with everything but unreserved characters encoded
+pub fn encode_path_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Undoes percent encoding, a `+` is kept as it is
+///
+/// # Arguments
+/// * `text: &str` - A URL part, like a query value or an Apprise token
+///
+/// # Returns
+/// * `String` - The decoded text, bytes that aren't UTF-8 are replaced
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Builds the value of a basic `Authorization` header
+///
+/// # Arguments
+/// * `username: &str` - The username
+/// * `password: &str` - The password
+///
+/// # Returns
+/// * `String` - `Basic` followed by the base64 of `username:password`
+pub fn basic_auth(username: &str, password: &str) -> String {
+    format!(
+        "Basic {}",
+        STANDARD.encode(format!("{}:{}", username, password))
+    )
+}
+
+/// A loopback HTTP server for the tests of everything that talks HTTP
+#[cfg(test)]
+pub mod mock {
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use tiny_http::{Header, Response, Server};
+
+    /// A request the mock received
+    #[derive(Debug, Clone)]
+    pub struct Recorded {
+        pub method: String,
+        /// The path and query
+        pub url: String,
+        pub headers: Vec<(String, String)>,
+        pub body: String,
+    }
+
+    impl Recorded {
+        /// The value of a header, names compared without case
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+
+        /// The body parsed as JSON
+        pub fn json(&self) -> serde_json::Value {
+            serde_json::from_str(&self.body).expect("request body is not JSON")
+        }
+    }
+
+    /// A running mock, stopped when dropped
+    pub struct MockServer {
+        /// The base URL, like `http://127.0.0.1:12345`
+        pub url: String,
+        server: Arc<Server>,
+        requests: Arc<Mutex<Vec<Recorded>>>,
+    }
+
+    impl MockServer {
+        /// Every request received so far, in order
+        pub fn requests(&self) -> Vec<Recorded> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    impl Drop for MockServer {
+        fn drop(&mut self) {
+            self.server.unblock();
+        }
+    }
+
+    /// Starts a mock that answers every request with what the handler returns
+    ///
+    /// # Arguments
+    /// * `handler: F` - Gets each request and returns the status and JSON body of the answer
+    ///
+    /// # Returns
+    /// * `MockServer` - The running mock
+    pub fn serve<F>(mut handler: F) -> MockServer
+    where
+        F: FnMut(&Recorded) -> (u16, String) + Send + 'static,
+    {
+        let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+        let url = format!("http://{}", server.server_addr());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let (listener, log) = (server.clone(), requests.clone());
+        thread::spawn(move || {
+            for mut request in listener.incoming_requests() {
+                let mut body = String::new();
+                let _ = request.as_reader().read_to_string(&mut body);
+                let recorded = Recorded {
+                    method: request.method().to_string(),
+                    url: request.url().to_string(),
+                    headers: request
+                        .headers()
+                        .iter()
+                        .map(|h| (h.field.to_string(), h.value.to_string()))
+                        .collect(),
+                    body,
+                };
+
+                let (status, body) = handler(&recorded);
+                log.lock().unwrap().push(recorded);
+
+                let response = Response::from_string(body)
+                    .with_status_code(status)
+                    .with_header(
+                        Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap(),
+                    );
+                let _ = request.respond(response);
+            }
+        });
+
+        MockServer {
+            url,
+            server,
+            requests,
+        }
+    }
+}
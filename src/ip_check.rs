@@ -1,16 +1,762 @@
-pub fn get_public_ip() -> Result<String, Box<dyn std::error::Error>> {
-    let command = "curl ifconfig.me";
-    let output = std::process::Command::new("sh")
-        .arg("-c")
-        .arg(command)
-        .output()?;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
 
-    let ip = String::from_utf8_lossy(&output.stdout).trim().to_string();
+use serde_json::{Value, json};
 
-    if ip.len() < 7 || ip.len() > 15 {
-        return Err("Invalid IP address length".into());
+use crate::{
+    dns::{self, DnsError, RecordType},
+    gateway::{self, GatewayError},
+    http,
+    stun::{self, StunError},
+};
+
+/// Providers that are queried when none are configured, see [`Provider::from_name`]
+pub const DEFAULT_PROVIDERS: [&str; 3] = ["ifconfig.me", "icanhazip", "ipify"];
+
+/// The address families that can be monitored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    /// Checks if the address belongs to this family
+    ///
+    /// # Arguments
+    /// * `ip: &IpAddr` - The address to check
+    ///
+    /// # Returns
+    /// * `bool` - True if the address is of this family
+    pub fn matches(&self, ip: &IpAddr) -> bool {
+        match self {
+            IpFamily::V4 => ip.is_ipv4(),
+            IpFamily::V6 => ip.is_ipv6(),
+        }
     }
-    
+}
+
+impl fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpFamily::V4 => write!(f, "IPv4"),
+            IpFamily::V6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// Everything that can go wrong while looking up the public IP
+#[derive(Debug, Clone)]
+pub enum IpCheckError {
+    /// The provider answered, but not with a success status code
+    Status { provider: String, code: u16 },
+    /// The request never got an answer (DNS, connect, TLS, timeout...)
+    Transport { provider: String, message: String },
+    /// The DNS provider failed or answered without an address
+    Dns { provider: String, message: String },
+    /// No STUN server answered with a mapped address
+    Stun { provider: String, message: String },
+    /// The router couldn't be found or wouldn't report its WAN address
+    Gateway { provider: String, message: String },
+    /// The provider answered with something that isn't an IP address
+    InvalidAddress { provider: String, body: String },
+    /// The provider answered with an address of the other family
+    WrongFamily {
+        provider: String,
+        family: IpFamily,
+        ip: IpAddr,
+    },
+    /// No providers are configured
+    NoProviders,
+    /// Every provider failed
+    AllFailed,
+    /// Not enough providers agreed on the same IP
+    NoQuorum {
+        ip: IpAddr,
+        agreed: usize,
+        total: usize,
+        quorum: usize,
+    },
+}
+
+impl fmt::Display for IpCheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpCheckError::Status { provider, code } => {
+                write!(f, "{} answered with HTTP status {}", provider, code)
+            }
+            IpCheckError::Transport { provider, message } => {
+                write!(f, "Could not reach {}: {}", provider, message)
+            }
+            IpCheckError::Dns { provider, message } => {
+                write!(f, "DNS lookup with {} failed: {}", provider, message)
+            }
+            IpCheckError::Stun { provider, message } => {
+                write!(f, "STUN lookup with {} failed: {}", provider, message)
+            }
+            IpCheckError::Gateway { provider, message } => {
+                write!(f, "Asking the router with {} failed: {}", provider, message)
+            }
+            IpCheckError::InvalidAddress { provider, body } => {
+                write!(f, "{} returned an invalid IP address: {:?}", provider, body)
+            }
+            IpCheckError::WrongFamily {
+                provider,
+                family,
+                ip,
+            } => write!(
+                f,
+                "{} returned {} when asked for an {} address",
+                provider, ip, family
+            ),
+            IpCheckError::NoProviders => write!(f, "No IP providers are configured"),
+            IpCheckError::AllFailed => write!(f, "Every provider failed"),
+            IpCheckError::NoQuorum {
+                ip,
+                agreed,
+                total,
+                quorum,
+            } => write!(
+                f,
+                "No quorum: {} of {} providers agreed on {}, {} needed",
+                agreed, total, ip, quorum
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IpCheckError {}
+
+/// An address found by a provider, along with anything else it learned on the way
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lookup {
+    /// The public address
+    pub ip: IpAddr,
+    /// Extra human readable facts, like the mapped port a STUN server saw
+    pub details: Vec<String>,
+}
+
+impl Lookup {
+    /// A lookup with nothing to add besides the address
+    pub fn new(ip: IpAddr) -> Self {
+        Lookup {
+            ip,
+            details: Vec::new(),
+        }
+    }
+}
+
+/// The outcome of asking a single provider for the public IP
+#[derive(Debug, Clone)]
+pub struct ProviderResult {
+    /// The provider as it was written in the config
+    pub provider: String,
+    /// What the provider found, or why it failed
+    pub result: Result<Lookup, IpCheckError>,
+    /// How long the provider took to answer or fail
+    pub latency: Duration,
+}
+
+/// What a DNS provider answers with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsAnswer {
+    /// An A or AAAA record, depending on the family being looked up
+    Address,
+    /// A TXT record holding the address as text
+    Txt,
+}
+
+/// A source for the public IP
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Provider {
+    /// A web service that echoes back the address it was called from
+    Http { url: String },
+    /// A DNS server that answers a special name with the address it was queried from
+    Dns {
+        server: String,
+        name: String,
+        answer: DnsAnswer,
+    },
+    /// STUN servers that answer with the address and port the request came from
+    Stun { servers: Vec<String> },
+    /// The router, found with SSDP, asked over UPnP IGD
+    Upnp,
+    /// The router asked over NAT-PMP, the default gateway is used if no gateway is set
+    NatPmp { gateway: Option<String> },
+    /// The router asked over PCP, the default gateway is used if no gateway is set
+    Pcp { gateway: Option<String> },
+}
+
+impl Provider {
+    /// Reads a provider from the config
+    ///
+    /// Strings are either one of the short names (ifconfig.me, icanhazip, ipify, opendns,
+    /// google-dns, stun, upnp, natpmp, pcp), `stun:host[:port]` or a URL. Objects pick the
+    /// provider with their `type` field: `{"type": "http", "url": "..."}`,
+    /// `{"type": "dns", "server": "...", "name": "...", "record": "A" | "TXT"}`,
+    /// `{"type": "stun", "servers": ["host[:port]", ...]}`, `{"type": "upnp"}` or
+    /// `{"type": "natpmp" | "pcp", "gateway": "..."}` where the gateway is optional
+    ///
+    /// # Arguments
+    /// * `value: &Value` - The config entry
+    ///
+    /// # Returns
+    /// * `Option<Provider>` - None if the entry isn't a valid provider
+    pub fn from_value(value: &Value) -> Option<Provider> {
+        if let Some(name) = value.as_str() {
+            return Some(Provider::from_name(name));
+        }
+
+        let field = |key: &str| {
+            value
+                .get(key)
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_string())
+        };
+
+        match field("type")?.as_str() {
+            "http" => Some(Provider::Http { url: field("url")? }),
+            "dns" => Some(Provider::Dns {
+                server: field("server")?,
+                name: field("name")?,
+                answer: match field("record").unwrap_or_default().to_uppercase().as_str() {
+                    "TXT" => DnsAnswer::Txt,
+                    _ => DnsAnswer::Address,
+                },
+            }),
+            "stun" => Some(Provider::Stun {
+                servers: value
+                    .get("servers")?
+                    .as_array()?
+                    .iter()
+                    .filter_map(|v| v.as_str())
+                    .map(|v| v.to_string())
+                    .collect(),
+            }),
+            "upnp" => Some(Provider::Upnp),
+            "natpmp" => Some(Provider::NatPmp {
+                gateway: field("gateway"),
+            }),
+            "pcp" => Some(Provider::Pcp {
+                gateway: field("gateway"),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Maps the short provider names to their providers, anything else is used as a URL
+    ///
+    /// # Arguments
+    /// * `name: &str` - Provider name or custom URL
+    ///
+    /// # Returns
+    /// * `Provider` - The provider
+    pub fn from_name(name: &str) -> Provider {
+        let http = |url: &str| Provider::Http {
+            url: url.to_string(),
+        };
+
+        match name {
+            "ifconfig.me" => http("https://ifconfig.me/ip"),
+            "icanhazip" => http("https://icanhazip.com"),
+            "ipify" => http("https://api.ipify.org"),
+            "opendns" => Provider::Dns {
+                server: "resolver1.opendns.com".to_string(),
+                name: "myip.opendns.com".to_string(),
+                answer: DnsAnswer::Address,
+            },
+            "google-dns" => Provider::Dns {
+                server: "ns1.google.com".to_string(),
+                name: "o-o.myaddr.l.google.com".to_string(),
+                answer: DnsAnswer::Txt,
+            },
+            // Two servers so the NAT behavior can be worked out
+            "stun" => Provider::Stun {
+                servers: vec![
+                    "stun.l.google.com:19302".to_string(),
+                    "stun1.l.google.com:19302".to_string(),
+                ],
+            },
+            "upnp" => Provider::Upnp,
+            "natpmp" => Provider::NatPmp { gateway: None },
+            "pcp" => Provider::Pcp { gateway: None },
+            _ if name.starts_with("stun:") => Provider::Stun {
+                servers: vec![name.trim_start_matches("stun:").to_string()],
+            },
+            _ => http(name),
+        }
+    }
+
+    /// Converts the provider back to its config form
+    ///
+    /// # Returns
+    /// * `Value` - A URL string for HTTP providers, an object for the rest
+    pub fn to_value(&self) -> Value {
+        match self {
+            Provider::Http { url } => Value::String(url.clone()),
+            Provider::Dns {
+                server,
+                name,
+                answer,
+            } => json!({
+                "type": "dns",
+                "server": server,
+                "name": name,
+                "record": match answer {
+                    DnsAnswer::Address => "A",
+                    DnsAnswer::Txt => "TXT",
+                },
+            }),
+            Provider::Stun { servers } => json!({
+                "type": "stun",
+                "servers": servers,
+            }),
+            Provider::Upnp => json!({ "type": "upnp" }),
+            Provider::NatPmp { gateway } => json!({
+                "type": "natpmp",
+                "gateway": gateway,
+            }),
+            Provider::Pcp { gateway } => json!({
+                "type": "pcp",
+                "gateway": gateway,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Provider::Http { url } => write!(f, "{}", url),
+            Provider::Dns { server, name, .. } => write!(f, "dns://{}/{}", server, name),
+            Provider::Stun { servers } => write!(f, "stun:{}", servers.join(",")),
+            Provider::Upnp => write!(f, "upnp"),
+            Provider::NatPmp { gateway } => {
+                write!(
+                    f,
+                    "natpmp:{}",
+                    gateway.as_deref().unwrap_or("default gateway")
+                )
+            }
+            Provider::Pcp { gateway } => {
+                write!(f, "pcp:{}", gateway.as_deref().unwrap_or("default gateway"))
+            }
+        }
+    }
+}
+
+/// Queries a single provider for the public IP of the given family
+///
+/// The connection to the provider is made over that family, so the provider sees and
+/// reports the address of that family
+///
+/// # Arguments
+/// * `provider: &Provider` - The provider to ask
+/// * `family: IpFamily` - The address family to look up
+///
+/// # Returns
+/// * `Result<Lookup, IpCheckError>` - The IP reported by the provider
+pub fn query_provider(provider: &Provider, family: IpFamily) -> Result<Lookup, IpCheckError> {
+    let lookup = match provider {
+        Provider::Http { url } => Lookup::new(query_http(url, family)?),
+        Provider::Dns {
+            server,
+            name,
+            answer,
+        } => Lookup::new(query_dns(server, name, *answer, family).map_err(|e| {
+            IpCheckError::Dns {
+                provider: provider.to_string(),
+                message: e.to_string(),
+            }
+        })?),
+        Provider::Stun { servers } => {
+            query_stun(servers, family).map_err(|e| IpCheckError::Stun {
+                provider: provider.to_string(),
+                message: e.to_string(),
+            })?
+        }
+        Provider::Upnp | Provider::NatPmp { .. } | Provider::Pcp { .. } => {
+            query_gateway(provider, family).map_err(|e| IpCheckError::Gateway {
+                provider: provider.to_string(),
+                message: e.to_string(),
+            })?
+        }
+    };
+
+    if !family.matches(&lookup.ip) {
+        return Err(IpCheckError::WrongFamily {
+            provider: provider.to_string(),
+            family,
+            ip: lookup.ip,
+        });
+    }
+
+    Ok(lookup)
+}
+
+/// Asks a web service for the address it sees
+fn query_http(url: &str, family: IpFamily) -> Result<IpAddr, IpCheckError> {
+    let response = match http::agent_for(family).get(url).call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(code, _)) => {
+            return Err(IpCheckError::Status {
+                provider: url.to_string(),
+                code,
+            });
+        }
+        Err(ureq::Error::Transport(e)) => {
+            return Err(IpCheckError::Transport {
+                provider: url.to_string(),
+                message: http::describe_transport(&e),
+            });
+        }
+    };
+
+    let body = response
+        .into_string()
+        .map_err(|e| IpCheckError::Transport {
+            provider: url.to_string(),
+            message: e.to_string(),
+        })?;
+
+    body.trim()
+        .parse()
+        .map_err(|_| IpCheckError::InvalidAddress {
+            provider: url.to_string(),
+            body: body.trim().to_string(),
+        })
+}
+
+/// Asks a DNS server for the address it sees, the query is sent over the family being looked up
+fn query_dns(
+    server: &str,
+    name: &str,
+    answer: DnsAnswer,
+    family: IpFamily,
+) -> Result<IpAddr, DnsError> {
+    let server = dns::resolve_server(server, family)?;
+
+    let record_type = match answer {
+        DnsAnswer::Address => RecordType::address(family),
+        DnsAnswer::Txt => RecordType::Txt,
+    };
+
+    let records = dns::query(server, name, record_type)?;
+
+    let ip = match answer {
+        DnsAnswer::Address => records.iter().find_map(|r| r.as_ip()),
+        // Resolvers in the path can add extra strings (like the EDNS client subnet), so the
+        // first one that reads as an address wins
+        DnsAnswer::Txt => records
+            .iter()
+            .filter(|r| r.record_type == RecordType::Txt.code())
+            .flat_map(|r| r.txt_strings())
+            .find_map(|s| s.trim().parse::<IpAddr>().ok()),
+    };
+
+    ip.ok_or(DnsError::Malformed("answer holds no address"))
+}
+
+/// Asks STUN servers for the mapped address, noting the mapped ports and how the NAT behaves
+fn query_stun(servers: &[String], family: IpFamily) -> Result<Lookup, StunError> {
+    let report = stun::discover(servers, family)?;
+
+    let mut details: Vec<String> = report
+        .mappings
+        .iter()
+        .map(|(server, mapped)| {
+            format!(
+                "STUN mapped address via {}: {} port {}",
+                server,
+                mapped.ip(),
+                mapped.port()
+            )
+        })
+        .collect();
+    details.push(format!("NAT: {}", report.nat));
+
+    Ok(Lookup {
+        ip: report.mappings[0].1.ip(),
+        details,
+    })
+}
 
-    Ok(ip)
-}
\ No newline at end of file
+/// Asks the router for its WAN address, warning if that address isn't actually public
+fn query_gateway(provider: &Provider, family: IpFamily) -> Result<Lookup, GatewayError> {
+    // Home routers only know their IPv4 WAN address, IPv6 isn't translated
+    if family == IpFamily::V6 {
+        return Err(GatewayError::NotFound(
+            "Routers only report their IPv4 WAN address".to_string(),
+        ));
+    }
+
+    let (ip, source) = match provider {
+        Provider::NatPmp { gateway } => (
+            IpAddr::V4(gateway::nat_pmp_external_address(gateway.as_deref())?),
+            "NAT-PMP".to_string(),
+        ),
+        Provider::Pcp { gateway } => (
+            gateway::pcp_external_address(gateway.as_deref())?,
+            "PCP".to_string(),
+        ),
+        _ => {
+            let (ip, control_url) = gateway::upnp_external_address()?;
+            (IpAddr::V4(ip), format!("UPnP IGD at {}", control_url))
+        }
+    };
+
+    let mut details = vec![format!("Router WAN address via {}: {}", source, ip)];
+    details.extend(gateway::wan_address_warning(&ip));
+
+    Ok(Lookup { ip, details })
+}
+
+/// Queries every provider at the same time and collects their answers
+///
+/// # Arguments
+/// * `providers: &[Provider]` - The providers to ask
+/// * `family: IpFamily` - The address family to look up
+///
+/// # Returns
+/// * `Vec<ProviderResult>` - One result per provider, in the same order
+pub fn query_providers(providers: &[Provider], family: IpFamily) -> Vec<ProviderResult> {
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = providers
+            .iter()
+            .map(|provider| {
+                scope.spawn(move || {
+                    let start = Instant::now();
+                    let result = query_provider(provider, family);
+
+                    ProviderResult {
+                        provider: provider.to_string(),
+                        result,
+                        latency: start.elapsed(),
+                    }
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .zip(providers)
+            .map(|(handle, provider)| {
+                handle.join().unwrap_or_else(|_| ProviderResult {
+                    provider: provider.to_string(),
+                    result: Err(IpCheckError::Transport {
+                        provider: provider.to_string(),
+                        message: "Provider lookup panicked".to_string(),
+                    }),
+                    latency: Duration::ZERO,
+                })
+            })
+            .collect()
+    })
+}
+
+/// Picks the IP that at least `quorum` providers agree on
+///
+/// Providers that failed or returned a different IP are logged
+///
+/// # Arguments
+/// * `results: &[ProviderResult]` - The answers from each provider
+/// * `quorum: usize` - How many providers have to agree
+///
+/// # Returns
+/// * `Result<Lookup, IpCheckError>` - The agreed upon IP, with the details of every provider that agreed
+pub fn find_consensus(results: &[ProviderResult], quorum: usize) -> Result<Lookup, IpCheckError> {
+    let mut votes: HashMap<IpAddr, usize> = HashMap::new();
+
+    for result in results {
+        if let Ok(lookup) = &result.result {
+            *votes.entry(lookup.ip).or_default() += 1;
+        }
+    }
+
+    // The most voted IP, ties go to the lowest address so the pick is stable
+    let winner = votes
+        .iter()
+        .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+        .map(|(ip, count)| (*ip, *count));
+
+    // Log every provider that didn't go along with the most voted IP
+    for result in results {
+        match (&result.result, &winner) {
+            (Err(e), _) => eprintln!("Provider failed: {}", e),
+            (Ok(lookup), Some((winner_ip, _))) if lookup.ip != *winner_ip => eprintln!(
+                "Provider {} disagreed: returned {}, most providers returned {}",
+                result.provider, lookup.ip, winner_ip
+            ),
+            _ => {}
+        }
+    }
+
+    match winner {
+        Some((ip, count)) if count >= quorum => Ok(Lookup {
+            ip,
+            details: results
+                .iter()
+                .filter_map(|r| r.result.as_ref().ok())
+                .filter(|lookup| lookup.ip == ip)
+                .flat_map(|lookup| lookup.details.clone())
+                .collect(),
+        }),
+        Some((ip, agreed)) => Err(IpCheckError::NoQuorum {
+            ip,
+            agreed,
+            total: results.len(),
+            quorum,
+        }),
+        None => Err(IpCheckError::AllFailed),
+    }
+}
+
+/// Asks every provider for the public IP of the given family and votes on the answers
+///
+/// # Arguments
+/// * `providers: &[Provider]` - The providers to ask
+/// * `quorum: usize` - How many providers have to agree
+/// * `family: IpFamily` - The address family to look up
+///
+/// # Returns
+/// * `(Vec<ProviderResult>, Result<Lookup, IpCheckError>)` - What each provider answered, and the agreed upon IP
+pub fn check_public_ip(
+    providers: &[Provider],
+    quorum: usize,
+    family: IpFamily,
+) -> (Vec<ProviderResult>, Result<Lookup, IpCheckError>) {
+    if providers.is_empty() {
+        return (Vec::new(), Err(IpCheckError::NoProviders));
+    }
+
+    let results = query_providers(providers, family);
+    let consensus = find_consensus(&results, quorum);
+
+    (results, consensus)
+}
+
+/// Gets the public IP of the given family by asking every provider and voting on the answers
+///
+/// # Arguments
+/// * `providers: &[Provider]` - The providers to ask
+/// * `quorum: usize` - How many providers have to agree
+/// * `family: IpFamily` - The address family to look up
+///
+/// # Returns
+/// * `Result<Lookup, IpCheckError>` - The agreed upon IP
+pub fn get_public_ip(
+    providers: &[Provider],
+    quorum: usize,
+    family: IpFamily,
+) -> Result<Lookup, IpCheckError> {
+    check_public_ip(providers, quorum, family).1
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::json_handler::ToConfig;
+
+    fn answered(provider: &str, ip: &str) -> ProviderResult {
+        ProviderResult {
+            provider: provider.to_string(),
+            result: Ok(Lookup {
+                ip: ip.parse().unwrap(),
+                details: vec![format!("from {}", provider)],
+            }),
+            latency: Duration::from_millis(10),
+        }
+    }
+
+    fn failed(provider: &str) -> ProviderResult {
+        ProviderResult {
+            provider: provider.to_string(),
+            result: Err(IpCheckError::Transport {
+                provider: provider.to_string(),
+                message: "timed out".to_string(),
+            }),
+            latency: Duration::from_secs(5),
+        }
+    }
+
+    #[test]
+    fn quorum_of_agreeing_providers_wins() {
+        let results = [
+            answered("a", "203.0.113.1"),
+            failed("b"),
+            answered("c", "203.0.113.1"),
+        ];
+
+        let lookup = find_consensus(&results, 2).unwrap();
+
+        assert_eq!(lookup.ip, "203.0.113.1".parse::<IpAddr>().unwrap());
+        assert_eq!(lookup.details, ["from a", "from c"]);
+    }
+
+    #[test]
+    fn disagreement_below_quorum_is_an_error() {
+        let results = [
+            answered("a", "203.0.113.1"),
+            answered("b", "203.0.113.2"),
+            answered("c", "203.0.113.1"),
+        ];
+
+        match find_consensus(&results, 3) {
+            Err(IpCheckError::NoQuorum {
+                ip,
+                agreed,
+                total,
+                quorum,
+            }) => {
+                assert_eq!(ip, "203.0.113.1".parse::<IpAddr>().unwrap());
+                assert_eq!((agreed, total, quorum), (2, 3, 3));
+            }
+            other => panic!("expected no quorum, got {:?}", other),
+        }
+        assert!(matches!(
+            find_consensus(&[failed("a"), failed("b")], 1),
+            Err(IpCheckError::AllFailed)
+        ));
+    }
+
+    #[test]
+    fn ties_go_to_the_lowest_address() {
+        for order in [
+            ["203.0.113.9", "203.0.113.1", "203.0.113.9", "203.0.113.1"],
+            ["203.0.113.1", "203.0.113.9", "203.0.113.1", "203.0.113.9"],
+        ] {
+            let results: Vec<ProviderResult> = order
+                .iter()
+                .enumerate()
+                .map(|(i, ip)| answered(&i.to_string(), ip))
+                .collect();
+
+            let lookup = find_consensus(&results, 2).unwrap();
+
+            assert_eq!(lookup.ip, "203.0.113.1".parse::<IpAddr>().unwrap());
+        }
+    }
+
+    #[test]
+    fn quorum_setting_stays_within_the_providers() {
+        let quorum = |value: Value| {
+            json!({
+                "ipProviders": ["https://a.example", "https://b.example", "https://c.example"],
+                "providerQuorum": value,
+            })
+            .to_config()
+            .provider_quorum
+        };
+
+        assert_eq!(quorum(json!(0)), 1);
+        assert_eq!(quorum(json!(3)), 3);
+        assert_eq!(quorum(json!(7)), 3);
+        assert_eq!(quorum(Value::Null), 2);
+    }
+}
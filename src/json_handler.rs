@@ -7,7 +7,7 @@ use std::{
 
 use serde_json::{json, Value};
 
-use crate::{config::Config, constants::{self, get_config_path}};
+use crate::{config::Config, constants::{self, get_config_path}, ip_check::{self, Provider}, notifier};
 
 
 /// Reads the config json and returns the value of the requested key as `String`
@@ -83,11 +83,9 @@ pub fn read_json_from_ref(key: &str, json: &Value) -> Value {
 /// open_json("random_path/config.json");
 /// ```
 fn open_json(path: &str) -> Value {
-    let json_data: Value;
-
     // Checks to make sure that the JSON file is there, if it isn't it makes it
-    if Path::new(&path).exists() {
-        let mut reader: BufReader<File> = BufReader::new(File::open(&path).unwrap());
+    let json_data: Value = if Path::new(&path).exists() {
+        let mut reader: BufReader<File> = BufReader::new(File::open(path).unwrap());
 
         let mut buffer: Vec<u8> = Vec::new();
 
@@ -97,17 +95,15 @@ fn open_json(path: &str) -> Value {
             .unwrap();
 
         // If the file is a "Resource Not Found" file, return a blank vector
-        if buffer.len() == 0 {
+        if buffer.is_empty() {
             return Value::default();
         }
 
-        json_data = {
-            let file_content: String = fs::read_to_string(&path).expect("File not found");
-            serde_json::from_str::<Value>(&file_content).expect("Error serializing to JSON")
-        };
+        let file_content: String = fs::read_to_string(path).expect("File not found");
+        serde_json::from_str::<Value>(&file_content).expect("Error serializing to JSON")
     } else {
-        json_data = init_json(path);
-    }
+        init_json(path)
+    };
 
     // Returns the json data
     json_data
@@ -131,7 +127,7 @@ pub fn init_json(path: &str) -> Value {
 
     // Creating the JSON file
     fs::write(
-        &path,
+        path,
         serde_json::to_string_pretty(&json_data).expect(
             "Error 
     serializing to JSON",
@@ -206,7 +202,7 @@ pub fn write_nested_json_no_io(mut json: Value, keys: String, value: Value) -> V
     let mut key = String::new();
 
     // Iterates through every char while keeping track of the index
-    for (i, char) in keys.chars().enumerate() {
+    for (i, char) in keys.char_indices() {
         match char {
             // If char is a '.', set json[key] equal to the next nested key
             '.' => {
@@ -233,7 +229,7 @@ pub fn write_nested_json_no_io(mut json: Value, keys: String, value: Value) -> V
                 let i_key = keys.get(i + 1..i + 2).unwrap().parse::<usize>().unwrap();
 
                 // If the key doesn't exist, push the value and set the json equal to the new Vec
-                if json.as_array().unwrap().len() == 0 || json.as_array().unwrap().len() - 1 < i_key
+                if json.as_array().unwrap().is_empty() || json.as_array().unwrap().len() - 1 < i_key
                 {
                     let mut json_vec = json.as_array().unwrap().to_owned();
 
@@ -261,7 +257,7 @@ pub fn write_nested_json_no_io(mut json: Value, keys: String, value: Value) -> V
         // If i is the last character, or if the next character is ']' and i is the second to last character
         // Write the inputted value to the json
         if i == keys.len() - 1
-            || (keys.get(i..i + 1).unwrap() == "]".to_string() && i == keys.len() - 2)
+            || (keys.get(i..i + 1).unwrap() == "]" && i == keys.len() - 2)
         {
             json[key.clone()] = value.clone();
         }
@@ -334,7 +330,7 @@ fn iterate_json_map(json_key: &str, json: &Value) -> Vec<String> {
         if key == json_key {
             entries.push(v.to_string().replace("\"", ""));
         } else if v.is_object() {
-            for val in iterate_json(json_key, &v) {
+            for val in iterate_json(json_key, v) {
                 entries.push(val);
             }
         }
@@ -359,9 +355,37 @@ fn get_default_config() -> Value {
         "emailSMTPPort": 465,
         "recipientAddress": "person@example.com",
         "checkIntervalMinutes": 15,
-        "ipAddress": "127.0.0.1",
+        "ipv4Address": "127.0.0.1",
+        "ipv6Address": "::1",
+        "trackIPv4": true,
+        "trackIPv6": false,
         "sequentialFailures": 0,
         "failureThreshold": 10,
+        "failureAlertSent": false,
+        "ipProviders": ip_check::DEFAULT_PROVIDERS,
+        "providerQuorum": 2,
+        "notifiers": notifier::default_notifiers(),
+        "mqtt": {
+            "enabled": false,
+            "broker": "localhost:1883",
+            "homeAssistant": true,
+        },
+        "hooks": [],
+        "dnsUpdaters": [],
+        "dnsDrift": {
+            "enabled": false,
+            "hostnames": [],
+        },
+        "collector": {
+            "enabled": false,
+            "url": "http://collector.example.com:8470",
+            "siteId": "",
+            "token": "",
+        },
+        "server": {
+            "listen": "0.0.0.0:8470",
+            "sites": [],
+        },
     })
 }
 
@@ -387,9 +411,28 @@ impl ToConfig for serde_json::Value {
         let email_smtp_port = self.get("emailSMTPPort").and_then(|v| v.as_u64()).unwrap_or(587) as u16;
         let recipient_address = self.get("recipientAddress").and_then(|v| v.as_str()).unwrap_or_default().to_string();
         let check_interval_minutes = self.get("checkIntervalMinutes").and_then(|v| v.as_u64()).unwrap_or(5);
-        let ip_address = self.get("ipAddress").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+        // Configs from before IPv6 support only have "ipAddress", which was always IPv4
+        let ipv4_address = self.get("ipv4Address").or_else(|| self.get("ipAddress")).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+        let ipv6_address = self.get("ipv6Address").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+        let track_ipv4 = self.get("trackIPv4").and_then(|v| v.as_bool()).unwrap_or(true);
+        let track_ipv6 = self.get("trackIPv6").and_then(|v| v.as_bool()).unwrap_or(false);
         let sequential_failures = self.get("sequentialFailures").and_then(|v| v.as_u64()).unwrap_or(0) as u32;
         let failure_threshold = self.get("failureThreshold").and_then(|v| v.as_u64()).unwrap_or(3) as u32;
+        let failure_alert_sent = self.get("failureAlertSent").and_then(|v| v.as_bool()).unwrap_or(false);
+        let mut ip_providers: Vec<Provider> = self.get("ipProviders").and_then(|v| v.as_array()).unwrap_or(&Vec::new()).iter().filter_map(Provider::from_value).collect();
+        if ip_providers.is_empty() {
+            ip_providers = ip_check::DEFAULT_PROVIDERS.iter().map(|v| Provider::from_name(v)).collect();
+        }
+        // Defaults to a simple majority of the providers. 0 would take any answer and more than there are providers none
+        let provider_quorum = self.get("providerQuorum").and_then(|v| v.as_u64()).map(|v| v as usize).unwrap_or(ip_providers.len() / 2 + 1).clamp(1, ip_providers.len());
+        // Configs from before notifiers existed only had the email settings
+        let notifiers = self.get("notifiers").and_then(|v| v.as_array()).cloned().unwrap_or_else(notifier::default_notifiers);
+        let mqtt = self.get("mqtt").cloned().unwrap_or(Value::Null);
+        let hooks = self.get("hooks").and_then(|v| v.as_array()).cloned().unwrap_or_default();
+        let dns_updaters = self.get("dnsUpdaters").and_then(|v| v.as_array()).cloned().unwrap_or_default();
+        let dns_drift = self.get("dnsDrift").cloned().unwrap_or(Value::Null);
+        let collector = self.get("collector").cloned().unwrap_or(Value::Null);
+        let server = self.get("server").cloned().unwrap_or(Value::Null);
 
         Config::new(
             email_address,
@@ -399,9 +442,22 @@ impl ToConfig for serde_json::Value {
             email_smtp_port,
             recipient_address,
             check_interval_minutes,
-            ip_address,
+            ipv4_address,
+            ipv6_address,
+            track_ipv4,
+            track_ipv6,
             sequential_failures,
             failure_threshold,
+            failure_alert_sent,
+            ip_providers,
+            provider_quorum,
+            notifiers,
+            mqtt,
+            hooks,
+            dns_updaters,
+            dns_drift,
+            collector,
+            server,
         )
     }
 }
@@ -1,4 +1,17 @@
+pub mod collector;
 pub mod config;
 pub mod constants;
+pub mod ddns;
+pub mod dns;
+pub mod drift;
+pub mod gateway;
+pub mod history;
+pub mod hooks;
+pub mod http;
 pub mod json_handler;
-pub mod ip_check;
\ No newline at end of file
+pub mod ip_check;
+pub mod mqtt;
+pub mod notifier;
+pub mod server;
+pub mod stats;
+pub mod stun;
\ No newline at end of file
@@ -1,11 +1,15 @@
+use std::net::IpAddr;
 use std::thread::sleep;
 use std::time::Duration;
 
-use lettre::transport::smtp::authentication::Credentials;
-use lettre::{Message, SmtpTransport, Transport};
+use public_ip_notifier::collector::{self, FamilyReport};
 use public_ip_notifier::config::Config;
+use public_ip_notifier::ip_check::{IpFamily, Lookup};
 use public_ip_notifier::json_handler::ToConfig;
-use public_ip_notifier::{constants, ip_check, json_handler};
+use public_ip_notifier::notifier::{self, Event};
+use public_ip_notifier::{
+    constants, ddns, drift, history, hooks, ip_check, json_handler, mqtt, server,
+};
 use serde_json::Value;
 
 fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
@@ -29,16 +33,25 @@ fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
                 let value = &cli_args[3];
 
                 match property.as_str() {
-                    "emailSMTPPort" | "checkIntervalMinutes" | "failureThreshold" => {
+                    "emailSMTPPort"
+                    | "checkIntervalMinutes"
+                    | "failureThreshold"
+                    | "providerQuorum" => {
                         // Check if value is a valid u64
                         if value.parse::<u64>().is_err() {
                             eprintln!("Error: {} must be a valid u64 integer.", property);
+                            return Ok(());
                         }
 
                         // Port number check
-                        if property == "email_smtp_port" && (value.parse::<u64>().unwrap() > 65535)
-                        {
-                            eprintln!("Error: email_smtp_port must be between 1 and 65535.");
+                        if property == "emailSMTPPort" && (value.parse::<u64>().unwrap() > 65535) {
+                            eprintln!("Error: emailSMTPPort must be between 1 and 65535.");
+                            return Ok(());
+                        }
+
+                        if property == "providerQuorum" && value.parse::<u64>() == Ok(0) {
+                            eprintln!("Error: providerQuorum must be at least 1.");
+                            return Ok(());
                         }
 
                         // All checks passed
@@ -47,21 +60,110 @@ fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
                             Value::Number(value.parse::<u64>().unwrap().into()),
                         );
                     }
+                    "ipProviders" => {
+                        // Comma separated list of provider names or URLs
+                        let providers: Vec<Value> = value
+                            .split(',')
+                            .map(|p| p.trim())
+                            .filter(|p| !p.is_empty())
+                            .map(|p| Value::String(p.to_string()))
+                            .collect();
+
+                        json_handler::write_config(property, Value::Array(providers));
+                    }
+                    "hooks" | "dnsUpdaters" => match serde_json::from_str::<Value>(value) {
+                        Ok(entries @ Value::Array(_)) => {
+                            json_handler::write_config(property, entries)
+                        }
+                        _ => {
+                            eprintln!("Error: {} must be a JSON array.", property);
+                            return Ok(());
+                        }
+                    },
+                    "mqtt" | "dnsDrift" | "collector" | "server" => {
+                        match serde_json::from_str::<Value>(value) {
+                            Ok(settings @ Value::Object(_)) => {
+                                json_handler::write_config(property, settings)
+                            }
+                            _ => {
+                                eprintln!("Error: {} must be a JSON object.", property);
+                                return Ok(());
+                            }
+                        }
+                    }
+                    "notifiers" => match serde_json::from_str::<Value>(value) {
+                        Ok(notifiers @ Value::Array(_)) => {
+                            json_handler::write_config(property, notifiers)
+                        }
+                        _ => {
+                            eprintln!("Error: {} must be a JSON array.", property);
+                            return Ok(());
+                        }
+                    },
+                    "trackIPv4" | "trackIPv6" => match value.parse::<bool>() {
+                        Ok(track) => json_handler::write_config(property, Value::Bool(track)),
+                        Err(_) => {
+                            eprintln!("Error: {} must be true or false.", property);
+                            return Ok(());
+                        }
+                    },
                     _ => json_handler::write_config(property, Value::String(value.to_string())),
                 }
                 println!("Set {} to {}", property, value);
                 return  Ok(());
             }
+            "history" | "stats" => {
+                let options = match history::report::ReportOptions::from_args(&cli_args[2..]) {
+                    Ok(options) => options,
+                    Err(e) => {
+                        eprintln!("Error: {}\nSee -h for more info.", e);
+                        return Ok(());
+                    }
+                };
+                let history = history::History::open_default()?;
+                if cli_args[1] == "history" {
+                    history::report::print_report(&history.conn, &options)?;
+                } else {
+                    history::report::print_stats(&history.conn, &options)?;
+                }
+            }
+            "server" => {
+                server::run()?;
+            }
             "-p" => {
                 let config =
                     json_handler::read_json_as_value(&constants::get_config_path()).to_config();
                 config.print();
+                ddns::print_state(&config);
             }
             "-t" => {
                 let config =
                     json_handler::read_json_as_value(&constants::get_config_path()).to_config();
-                let ip = ip_check::get_public_ip().unwrap_or("0.0.0.0".into());
-                let _ = send_email(config, ip);
+                let mut addresses = Vec::new();
+                let message = config
+                    .tracked_families()
+                    .into_iter()
+                    .map(|family| {
+                        match ip_check::get_public_ip(
+                            &config.ip_providers,
+                            config.provider_quorum,
+                            family,
+                        ) {
+                            Ok(lookup) => {
+                                addresses.push((family, Some(lookup.ip.to_string())));
+                                format!("{}: {}\n{}", family, lookup.ip, lookup.details.join("\n"))
+                            }
+                            Err(e) => {
+                                addresses.push((family, None));
+                                format!("{}: {}", family, e)
+                            }
+                        }
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n");
+                let event = Event::Test { message };
+                announce(&config, &event);
+                mqtt::publish_check(&config, &addresses, config.sequential_failures, &[event]);
             }
             _ => {}
         }
@@ -73,105 +175,215 @@ fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
         // The config is read in each loop to allow for dynamic changes
         let config = json_handler::read_json_as_value(&constants::get_config_path()).to_config();
 
-        // Get the current public IP
-        let public_ip = match ip_check::get_public_ip() {
-            Ok(ip) => {
+        // Look up every monitored family, a failure in any of them counts as a failed check
+        let mut lookup_error = None;
+        let mut addresses = Vec::new();
+        let mut events = Vec::new();
+        let mut reports = Vec::new();
+
+        for family in config.tracked_families() {
+            let (results, outcome) =
+                ip_check::check_public_ip(&config.ip_providers, config.provider_quorum, family);
+            history::log_check(family, &results, &outcome);
+            reports.push(FamilyReport::new(family, &results, &outcome));
+
+            match outcome {
+                Ok(public_ip) => {
+                    let ip = public_ip.ip;
+                    addresses.push((family, Some(ip.to_string())));
+                    // DNS goes first, so a change notification can say whether the records followed
+                    let dns_updates = ddns::sync(&config, family, ip);
+                    events.extend(check_for_change(&config, family, public_ip, dns_updates));
+
+                    if let Some(event) = drift::check(&config, family, ip) {
+                        announce(&config, &event);
+                        events.push(event);
+                    }
+                }
+                Err(e) => {
+                    addresses.push((family, None));
+                    lookup_error = Some(format!("{}: {}", family, e));
+                }
+            }
+        }
+
+        // A threshold of 0 would never be reached, treat it as alerting on every failure
+        let failure_threshold = config.failure_threshold.max(1);
+
+        let failures = match lookup_error {
+            None => {
                 // Reset sequential failures on success
                 if config.sequential_failures != 0 {
                     json_handler::write_config("sequentialFailures", Value::Number(0.into()));
                 }
 
-                ip
+                // Only worth telling if the failure alert went out
+                if config.failure_alert_sent {
+                    json_handler::write_config("failureAlertSent", Value::Bool(false));
+                    println!(
+                        "Public IP retrieved again after {} failures.",
+                        config.sequential_failures
+                    );
+                    let event = Event::Recovered {
+                        failures: config.sequential_failures,
+                    };
+                    announce(&config, &event);
+                    events.push(event);
+                }
+                0
             }
-            Err(e) => {
+            Some(e) => {
                 let failures = config.sequential_failures + 1;
                 json_handler::write_config("sequentialFailures", Value::Number(failures.into()));
 
-                if failures >= config.failure_threshold {
-                    eprintln!(
-                        "Failed to get public IP {} times. Sending alert email.",
-                        failures
-                    );
-                    let _ = send_email(
-                        config.clone(),
-                        format!(
-                            "Could not retrieve IP, sequential error threshold reached: {}",
-                            failures
-                        ),
-                    );
-                    json_handler::write_config("sequentialFailures", Value::Number(0.into()));
+                // One alert per outage, the count keeps going so the recovery can say how long it lasted
+                if failures >= failure_threshold && !config.failure_alert_sent {
+                    eprintln!("Failed to get public IP {} times. Sending alert.", failures);
+                    json_handler::write_config("failureAlertSent", Value::Bool(true));
+                    let event = Event::FailureThreshold { failures, error: e };
+                    announce(&config, &event);
+                    events.push(event);
                 } else {
                     eprintln!(
-                        "Failed to get public IP {} times. Sequential failures: {}",
+                        "Failed to get public IP: {}. Sequential failures: {}",
                         e, failures
                     );
                 }
-                sleep(Duration::from_secs(config.check_interval_minutes * 60));
-                continue;
+                failures
             }
         };
 
-        // If the IP hasn't changed, wait and check again
-        if public_ip == config.ip_address {
-            println!("IP has not changed.");
-        }
-        // If the IP has changed, update the config and send an email
-        else {
-            println!(
-                "IP has changed! Old: {}, New: {}",
-                config.ip_address, public_ip
-            );
-            json_handler::write_config("ipAddress", Value::String(public_ip.clone()));
-            let _ = send_email(
-                config.clone(),
-                format!("Hello,\nYour public IP has changed to {}.", public_ip),
-            );
-        }
+        history::log_events(&events);
+        mqtt::publish_check(&config, &addresses, failures, &events);
+        collector::report(&config, reports, failures);
 
         // Wait for the specified interval before checking again
-        sleep(Duration::from_secs(&config.check_interval_minutes * 60));
+        sleep(Duration::from_secs(config.check_interval_minutes * 60));
+    }
+}
+
+/// Compares a looked up address with the last known one, saving it and notifying if it changed
+///
+/// # Arguments
+/// * `dns_updates: Vec<String>` - What the DNS updaters did, added to the notification
+/// # Returns
+/// * `Option<Event>` - The change event, None if the address is the same
+fn check_for_change(
+    config: &Config,
+    family: IpFamily,
+    lookup: Lookup,
+    dns_updates: Vec<String>,
+) -> Option<Event> {
+    let last_ip = config.last_ip(family);
+    let public_ip = lookup.ip;
+
+    // Parsed so differently written IPv6 addresses still compare equal
+    if last_ip.parse::<IpAddr>().ok() == Some(public_ip) {
+        println!("{} address has not changed.", family);
+        None
+    }
+    // If the IP has changed, update the config and notify every channel
+    else {
+        println!(
+            "{} address has changed! Old: {}, New: {}",
+            family, last_ip, public_ip
+        );
+        json_handler::write_config(
+            Config::ip_address_key(family),
+            Value::String(public_ip.to_string()),
+        );
+        let mut details = lookup.details;
+        details.extend(dns_updates);
+
+        let event = Event::IpChanged {
+            family,
+            old_ip: last_ip.to_string(),
+            new_ip: public_ip,
+            details,
+        };
+        announce(config, &event);
+        Some(event)
     }
 }
 
-fn send_email(
-    config: Config,
-    message: String,
-) -> std::result::Result<(), Box<dyn std::error::Error>> {
-    // Define the email
-    let email = Message::builder()
-        .from(
-            format!("IP Change Notifier <{}>", config.email_address)
-                .parse()
-                .unwrap_or_else(|_| return "IP Change Notifier <unknown>".parse().unwrap()),
-        )
-        .to(format!("{}", config.recipient_address).parse().unwrap())
-        .subject("Your IP Changed!")
-        .body(format!("{}", message))
-        .unwrap();
-
-    // Set up the SMTP client
-    let creds = Credentials::new(config.username, config.email_password);
-
-    // Open a remote connection to gmail
-    let mailer = SmtpTransport::relay(&config.email_smtp_host)?
-        .port(config.email_smtp_port)
-        .credentials(creds)
-        .build();
-
-    // Send the email
-    match mailer.send(&email) {
-        Ok(_) => println!("Email sent successfully!"),
-        Err(e) => eprintln!("Could not send email: {:?}", e),
-    };
-    Ok(())
+/// Tells every notifier about the event and runs the hooks that want it
+fn announce(config: &Config, event: &Event) {
+    notifier::notify(config, event);
+    hooks::run_all(config, event);
 }
 
 fn help() {
     println!("Display this message: -h");
     println!(
-        "Set the value of something in the config: -c <property> <value>\nemailAddress, username, emailPassword, emailSMTPHost, emailSMTPPort, ipAddress, recipientAddress, failureThreshold, checkIntervalMinutes"
+        "Set the value of something in the config: -c <property> <value>\nemailAddress, username, emailPassword, emailSMTPHost, emailSMTPPort, ipv4Address, ipv6Address, recipientAddress, failureThreshold, checkIntervalMinutes, providerQuorum"
+    );
+    println!(
+        "Set the IP providers (comma separated, ifconfig.me, icanhazip, ipify, opendns, google-dns, stun, upnp, natpmp, pcp or a URL): -c ipProviders <provider,provider,...>"
+    );
+    println!(
+        "Choose which addresses are monitored: -c trackIPv4 <true|false>, -c trackIPv6 <true|false>"
+    );
+    println!(
+        "Set the notification channels (JSON array, for example '[{{\"type\": \"email\"}}]'): -c notifiers <json>"
+    );
+    println!(
+        "Notifier types: email, webhook, slack, discord and teams, the chat ones take the incoming webhook url"
+    );
+    println!(
+        "Telegram notifiers take a botToken and chatId: {{\"type\": \"telegram\", \"botToken\": \"...\", \"chatId\": \"...\"}}"
+    );
+    println!(
+        "Webhook notifiers take a url and optional method, headers and JSON body, with {{{{old_ip}}}}, {{{{new_ip}}}}, {{{{hostname}}}}, {{{{timestamp}}}}, {{{{event}}}}, {{{{family}}}}, {{{{failures}}}}, {{{{stale_names}}}}, {{{{site}}}}, {{{{subject}}}} and {{{{message}}}} filled in"
+    );
+    println!(
+        "Push notifiers: {{\"type\": \"ntfy\", \"url\": \"<topic url>\", \"priority\": 3, \"alertPriority\": 5, \"tags\": [...], \"token\": \"...\"}}, {{\"type\": \"gotify\", \"url\": \"...\", \"appToken\": \"...\", \"priority\": 5, \"alertPriority\": 8}}"
+    );
+    println!(
+        "Matrix notifiers: {{\"type\": \"matrix\", \"homeserver\": \"https://...\", \"accessToken\": \"...\", \"roomId\": \"!room:server\"}}"
+    );
+    println!(
+        "Pushover notifiers: {{\"type\": \"pushover\", \"userKey\": \"...\", \"appToken\": \"...\", \"priority\": 0, \"alertPriority\": 1}}"
+    );
+    println!(
+        "Notifiers can also be Apprise style URLs: pover://user@token, tgram://bottoken/chatid, discord://id/token, mailto://recipient@example.com, json://host/path"
+    );
+    println!(
+        "Publish to MQTT (retained <topicPrefix>/state, <topicPrefix>/events, optional Home Assistant discovery): -c mqtt <json>, for example '{{\"broker\": \"localhost:1883\", \"homeAssistant\": true}}'"
+    );
+    println!(
+        "Run commands on events (ip_changed, failure_threshold, recovered, dns_drift, site_ip_changed, site_silent, site_back_online or test), with OLD_IP, NEW_IP, EVENT, FAILURES, STALE_NAMES, SITE, FAMILY and HOSTNAME set: -c hooks '[{{\"command\": \"/path/to/script\", \"args\": [], \"timeout\": 30, \"events\": [\"ip_changed\"]}}]'"
+    );
+    println!(
+        "Update DNS records when the address changes: -c dnsUpdaters '[{{\"type\": \"cloudflare\", \"zoneId\": \"...\", \"apiToken\": \"...\", \"records\": [\"home.example.com\"], \"proxied\": false, \"ttl\": 1}}]'"
+    );
+    println!(
+        "dyndns2 providers (DynDNS, No-IP and others) work the same: {{\"type\": \"dyndns2\", \"server\": \"https://...\", \"username\": \"...\", \"password\": \"...\", \"hostnames\": [\"home.example.com\"]}}"
+    );
+    println!(
+        "RFC 2136 updates for your own BIND or Knot zone, signed with an hmac-sha256 TSIG key: {{\"type\": \"rfc2136\", \"server\": \"ns1.example.com\", \"zone\": \"example.com\", \"records\": [\"home.example.com\"], \"ttl\": 300, \"keyName\": \"...\", \"keySecret\": \"<base64>\"}}"
+    );
+    println!(
+        "DuckDNS: {{\"type\": \"duckdns\", \"token\": \"...\", \"domains\": [\"myhome\"]}}, deSEC: {{\"type\": \"desec\", \"token\": \"...\", \"hostnames\": [\"myhome.dedyn.io\"]}}"
+    );
+    println!(
+        "Porkbun: {{\"type\": \"porkbun\", \"apiKey\": \"...\", \"secretApiKey\": \"...\", \"domain\": \"example.com\", \"records\": [\"home.example.com\"]}}, Hetzner: {{\"type\": \"hetzner\", \"apiToken\": \"...\", \"zone\": \"example.com\", \"records\": [\"home.example.com\"]}}"
+    );
+    println!(
+        "Alert when names don't resolve to the public address: -c dnsDrift '{{\"hostnames\": [\"home.example.com\", {{\"name\": \"v4.example.com\", \"families\": [\"ipv4\"]}}], \"resolver\": \"1.1.1.1\", \"intervalMinutes\": 60, \"confirmations\": 2}}'"
+    );
+    println!(
+        "Report every check to a collector server: -c collector '{{\"url\": \"http://collector.example.com:8470\", \"siteId\": \"home\", \"token\": \"...\"}}'"
+    );
+    println!(
+        "Run as the collector server for many sites, alerting through the notifiers and hooks when a site's address changes or it misses missedIntervals of its checks: server, with -c server '{{\"listen\": \"0.0.0.0:8470\", \"adminToken\": \"...\", \"missedIntervals\": 3, \"sites\": [{{\"siteId\": \"home\", \"token\": \"...\"}}]}}'"
+    );
+    println!("Print config and the last update of every DNS updater: -p");
+    println!("Send a test notification through every notifier: -t");
+    println!(
+        "Show past addresses and outages: history [--since <time>] [--until <time>] [--family ipv4|ipv6] [--format table|json|csv], times are UTC dates like 2024-05-01, RFC 3339 or ages like 30d"
+    );
+    println!(
+        "Show lookup success rate and latency over time, with the same options: stats [--since <time>] [--until <time>] [--family ipv4|ipv6] [--format table|json|csv]"
     );
-    println!("Print config: -p");
-    println!("Send test email: -t");
     println!("No arguments will run the program normally.");
 }
@@ -0,0 +1,613 @@
+//! Minimal MQTT 3.1.1 client, publishes the public IP state and events for home automation
+use std::{
+    fmt,
+    io::{Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use serde_json::{Value, json};
+
+use crate::{config::Config, constants, dns, ip_check::IpFamily, notifier};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const DISCONNECT: u8 = 0xE0;
+
+/// Everything that can go wrong while talking to the broker
+#[derive(Debug, Clone)]
+pub enum MqttError {
+    /// The broker couldn't be resolved or reached, or the connection dropped
+    Io(String),
+    /// The broker refused the connection, with the CONNACK return code
+    Refused(u8),
+    /// The broker sent something that isn't what was expected
+    Malformed(&'static str),
+}
+
+impl fmt::Display for MqttError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttError::Io(e) => write!(f, "{}", e),
+            MqttError::Refused(code) => write!(
+                f,
+                "MQTT broker refused the connection: {}",
+                match code {
+                    1 => "unacceptable protocol version",
+                    2 => "client ID rejected",
+                    3 => "server unavailable",
+                    4 => "bad username or password",
+                    5 => "not authorized",
+                    _ => "unknown reason",
+                }
+            ),
+            MqttError::Malformed(e) => write!(f, "Malformed MQTT packet: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MqttError {}
+
+impl From<std::io::Error> for MqttError {
+    fn from(e: std::io::Error) -> Self {
+        MqttError::Io(e.to_string())
+    }
+}
+
+/// The `mqtt` object from the config
+#[derive(Debug, Clone)]
+pub struct MqttSettings {
+    /// The broker as `host[:port]`, port 1883 is the default
+    pub broker: String,
+    /// Username, if the broker wants one
+    pub username: Option<String>,
+    /// Password for the username
+    pub password: Option<String>,
+    /// The client ID to connect with
+    pub client_id: String,
+    /// Every topic starts with this, like `public_ip_notifier/myhost`
+    pub topic_prefix: String,
+    /// Whether Home Assistant discovery payloads are published
+    pub home_assistant: bool,
+    /// Where Home Assistant listens for discovery payloads
+    pub discovery_prefix: String,
+}
+
+impl MqttSettings {
+    /// Reads the settings from the `mqtt` object of the config
+    ///
+    /// `{"enabled": true, "broker": "host:1883", "username": "...", "password": "...", "clientId": "...",
+    /// "topicPrefix": "...", "homeAssistant": true, "discoveryPrefix": "homeassistant"}`
+    ///
+    /// # Arguments
+    /// * `value: &Value` - The `mqtt` object, Null if the config has none
+    ///
+    /// # Returns
+    /// * `Result<Option<MqttSettings>, String>` - None if MQTT is off, Err if the broker is missing
+    pub fn from_value(value: &Value) -> Result<Option<Self>, String> {
+        if value.is_null() || value.get("enabled").and_then(|v| v.as_bool()) == Some(false) {
+            return Ok(None);
+        }
+
+        let get = |key: &str| {
+            value
+                .get(key)
+                .and_then(|v| v.as_str())
+                .filter(|v| !v.is_empty())
+                .map(|v| v.to_string())
+        };
+        // Topics can't hold wildcards, and the host name is used in them
+        let host: String = notifier::hostname()
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+
+        Ok(Some(MqttSettings {
+            broker: get("broker").ok_or("MQTT needs a \"broker\"")?,
+            username: get("username"),
+            password: get("password"),
+            client_id: get("clientId").unwrap_or_else(|| format!("public_ip_notifier_{}", host)),
+            topic_prefix: get("topicPrefix")
+                .unwrap_or_else(|| format!("public_ip_notifier/{}", host))
+                .trim_end_matches('/')
+                .to_string(),
+            home_assistant: value
+                .get("homeAssistant")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false),
+            discovery_prefix: get("discoveryPrefix").unwrap_or_else(|| "homeassistant".to_string()),
+        }))
+    }
+
+    /// The retained topic holding the current addresses
+    pub fn state_topic(&self) -> String {
+        format!("{}/state", self.topic_prefix)
+    }
+
+    /// The topic every event is published to
+    pub fn events_topic(&self) -> String {
+        format!("{}/events", self.topic_prefix)
+    }
+}
+
+/// One message to publish
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub topic: String,
+    pub payload: String,
+    /// Whether the broker keeps it for clients that subscribe later
+    pub retain: bool,
+}
+
+/// Appends an MQTT string, a length followed by the UTF-8 bytes
+fn write_string(packet: &mut Vec<u8>, text: &str) {
+    packet.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    packet.extend_from_slice(text.as_bytes());
+}
+
+/// Puts the fixed header in front of the rest of the packet
+fn finish_packet(header: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut remaining = body.len();
+
+    // The remaining length is 7 bits per byte, the top bit says another byte follows
+    loop {
+        let mut byte = (remaining % 128) as u8;
+        remaining /= 128;
+        if remaining > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if remaining == 0 {
+            break;
+        }
+    }
+
+    packet.extend(body);
+    packet
+}
+
+/// Builds a CONNECT packet with a clean session
+///
+/// # Arguments
+/// * `settings: &MqttSettings` - The client ID and credentials
+///
+/// # Returns
+/// * `Vec<u8>` - The packet in wire format
+pub fn build_connect(settings: &MqttSettings) -> Vec<u8> {
+    let mut body = Vec::new();
+    write_string(&mut body, "MQTT");
+    // Protocol level 4 is MQTT 3.1.1
+    body.push(4);
+
+    let mut flags = 0x02;
+    if settings.username.is_some() {
+        flags |= 0x80;
+        if settings.password.is_some() {
+            flags |= 0x40;
+        }
+    }
+    body.push(flags);
+    // Keep alive, the connection never lives this long
+    body.extend_from_slice(&60u16.to_be_bytes());
+
+    write_string(&mut body, &settings.client_id);
+    if let Some(username) = &settings.username {
+        write_string(&mut body, username);
+        if let Some(password) = &settings.password {
+            write_string(&mut body, password);
+        }
+    }
+
+    finish_packet(CONNECT, body)
+}
+
+/// Builds a QoS 1 PUBLISH packet
+///
+/// # Arguments
+/// * `message: &Message` - What to publish
+/// * `packet_id: u16` - The ID the broker acknowledges, must not be 0
+///
+/// # Returns
+/// * `Vec<u8>` - The packet in wire format
+pub fn build_publish(message: &Message, packet_id: u16) -> Vec<u8> {
+    let mut body = Vec::new();
+    write_string(&mut body, &message.topic);
+    body.extend_from_slice(&packet_id.to_be_bytes());
+    body.extend_from_slice(message.payload.as_bytes());
+
+    // QoS 1, so the broker confirms it got the message
+    let mut header = PUBLISH | 0x02;
+    if message.retain {
+        header |= 0x01;
+    }
+
+    finish_packet(header, body)
+}
+
+/// Reads one packet, returning its type byte and body
+fn read_packet(stream: &mut TcpStream) -> Result<(u8, Vec<u8>), MqttError> {
+    let mut header = [0u8; 1];
+    stream.read_exact(&mut header)?;
+
+    let mut remaining = 0usize;
+    for shift in 0..4 {
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte)?;
+        remaining |= ((byte[0] & 0x7F) as usize) << (7 * shift);
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0u8; remaining];
+            stream.read_exact(&mut body)?;
+            return Ok((header[0], body));
+        }
+    }
+
+    Err(MqttError::Malformed(
+        "remaining length is longer than 4 bytes",
+    ))
+}
+
+/// Connects, publishes every message and disconnects
+///
+/// # Arguments
+/// * `settings: &MqttSettings` - The broker and credentials
+/// * `messages: &[Message]` - What to publish, in order
+///
+/// # Returns
+/// * `Result<(), MqttError>` - Ok once the broker acknowledged every message
+pub fn publish(settings: &MqttSettings, messages: &[Message]) -> Result<(), MqttError> {
+    let timeout = Duration::from_secs(constants::MQTT_TIMEOUT_SECONDS);
+    let addr = dns::resolve_host(&settings.broker, 1883, IpFamily::V4)
+        .or_else(|_| dns::resolve_host(&settings.broker, 1883, IpFamily::V6))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    stream.write_all(&build_connect(settings))?;
+    match read_packet(&mut stream)? {
+        (CONNACK, body) if body.len() == 2 => {
+            if body[1] != 0 {
+                return Err(MqttError::Refused(body[1]));
+            }
+        }
+        _ => return Err(MqttError::Malformed("expected a CONNACK")),
+    }
+
+    for (i, message) in messages.iter().enumerate() {
+        let packet_id = i as u16 + 1;
+        stream.write_all(&build_publish(message, packet_id))?;
+
+        // Wait for the PUBACK, skipping anything else the broker sends
+        loop {
+            match read_packet(&mut stream)? {
+                (PUBACK, body)
+                    if body.len() == 2 && u16::from_be_bytes([body[0], body[1]]) == packet_id =>
+                {
+                    break;
+                }
+                (PUBACK, body) if body.len() != 2 => {
+                    return Err(MqttError::Malformed("PUBACK has the wrong length"));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Nothing to do if this fails, every message already arrived
+    let _ = stream.write_all(&[DISCONNECT, 0]);
+
+    Ok(())
+}
+
+/// Builds the retained state message
+///
+/// # Arguments
+/// * `settings: &MqttSettings` - The topics
+/// * `addresses: &[(IpFamily, Option<String>)]` - The current address of each tracked family
+/// * `sequential_failures: u32` - How many checks in a row have failed
+///
+/// # Returns
+/// * `Message` - The message for the state topic
+pub fn state_message(
+    settings: &MqttSettings,
+    addresses: &[(IpFamily, Option<String>)],
+    sequential_failures: u32,
+) -> Message {
+    let mut state = json!({
+        "ipv4": null,
+        "ipv6": null,
+        "sequentialFailures": sequential_failures,
+        "hostname": notifier::hostname(),
+        "updated": chrono::Utc::now().to_rfc3339(),
+    });
+
+    for (family, ip) in addresses {
+        state[family_key(*family)] = json!(ip);
+    }
+
+    Message {
+        topic: settings.state_topic(),
+        payload: state.to_string(),
+        retain: true,
+    }
+}
+
+/// Builds the message for an event, carrying the same values the webhook templates get
+///
+/// # Arguments
+/// * `settings: &MqttSettings` - The topics
+/// * `event: &notifier::Event` - What happened
+///
+/// # Returns
+/// * `Message` - The message for the events topic
+pub fn event_message(settings: &MqttSettings, event: &notifier::Event) -> Message {
+    let payload: serde_json::Map<String, Value> = event
+        .placeholders()
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), Value::String(value)))
+        .collect();
+
+    Message {
+        topic: settings.events_topic(),
+        payload: Value::Object(payload).to_string(),
+        retain: false,
+    }
+}
+
+/// Builds the Home Assistant discovery messages, one sensor per tracked family
+///
+/// # Arguments
+/// * `settings: &MqttSettings` - The topics
+/// * `families: &[IpFamily]` - The tracked families
+///
+/// # Returns
+/// * `Vec<Message>` - The retained discovery messages
+pub fn discovery_messages(settings: &MqttSettings, families: &[IpFamily]) -> Vec<Message> {
+    let node_id = settings
+        .client_id
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+
+    families
+        .iter()
+        .map(|family| {
+            let object_id = format!("public_{}", family_key(*family));
+
+            let payload = json!({
+                "name": format!("Public {} address", family),
+                "unique_id": format!("{}_{}", node_id, object_id),
+                "state_topic": settings.state_topic(),
+                "value_template": format!("{{{{ value_json.{} }}}}", family_key(*family)),
+                "json_attributes_topic": settings.state_topic(),
+                "icon": "mdi:ip-network",
+                "device": {
+                    "identifiers": [node_id],
+                    "name": format!("Public IP Notifier ({})", notifier::hostname()),
+                    "sw_version": env!("CARGO_PKG_VERSION"),
+                },
+            });
+
+            Message {
+                topic: format!(
+                    "{}/sensor/{}/{}/config",
+                    settings.discovery_prefix, node_id, object_id
+                ),
+                payload: payload.to_string(),
+                retain: true,
+            }
+        })
+        .collect()
+}
+
+/// The key of a family in the state message
+fn family_key(family: IpFamily) -> &'static str {
+    match family {
+        IpFamily::V4 => "ipv4",
+        IpFamily::V6 => "ipv6",
+    }
+}
+
+/// Publishes the outcome of one check, logging instead of failing so the loop keeps going
+///
+/// Discovery payloads go first when enabled, then the state, then every event
+///
+/// # Arguments
+/// * `config: &Config` - The config holding the `mqtt` object
+/// * `addresses: &[(IpFamily, Option<String>)]` - The current address of each tracked family, None if the lookup failed
+/// * `sequential_failures: u32` - How many checks in a row have failed
+/// * `events: &[notifier::Event]` - What happened during the check
+pub fn publish_check(
+    config: &Config,
+    addresses: &[(IpFamily, Option<String>)],
+    sequential_failures: u32,
+    events: &[notifier::Event],
+) {
+    let settings = match MqttSettings::from_value(&config.mqtt) {
+        Ok(Some(settings)) => settings,
+        Ok(None) => return,
+        Err(e) => {
+            eprintln!("Skipping MQTT: {}", e);
+            return;
+        }
+    };
+
+    let mut messages = Vec::new();
+    if settings.home_assistant {
+        messages.extend(discovery_messages(&settings, &config.tracked_families()));
+    }
+    messages.push(state_message(&settings, addresses, sequential_failures));
+    messages.extend(events.iter().map(|event| event_message(&settings, event)));
+
+    match publish(&settings, &messages) {
+        Ok(()) => println!(
+            "Published {} messages to MQTT broker {}",
+            messages.len(),
+            settings.broker
+        ),
+        Err(e) => eprintln!(
+            "Could not publish to MQTT broker {}: {}",
+            settings.broker, e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+
+    fn settings(broker: String) -> MqttSettings {
+        MqttSettings {
+            broker,
+            username: Some("user".to_string()),
+            password: Some("secret".to_string()),
+            client_id: "test-client".to_string(),
+            topic_prefix: "public_ip_notifier/test".to_string(),
+            home_assistant: false,
+            discovery_prefix: "homeassistant".to_string(),
+        }
+    }
+
+    fn message(topic: &str, payload: &str, retain: bool) -> Message {
+        Message {
+            topic: topic.to_string(),
+            payload: payload.to_string(),
+            retain,
+        }
+    }
+
+    /// Reads an MQTT string from the front of a body, returning it and the rest
+    fn take_string(body: &[u8]) -> (String, &[u8]) {
+        let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+        (
+            String::from_utf8(body[2..2 + len].to_vec()).unwrap(),
+            &body[2 + len..],
+        )
+    }
+
+    /// The CONNECT body and every PUBLISH the broker got
+    type Session = (Vec<u8>, Vec<Published>);
+
+    /// A PUBLISH as the broker saw it
+    struct Published {
+        header: u8,
+        topic: String,
+        packet_id: u16,
+        payload: String,
+    }
+
+    /// Starts a broker on a loopback port that takes one connection
+    ///
+    /// It answers the CONNECT with the given return code, then acknowledges `messages` PUBLISH
+    /// packets, sending a PINGRESP in front of each PUBACK so the client has to skip it
+    fn broker(return_code: u8, messages: usize) -> (String, thread::JoinHandle<Session>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (header, connect) = read_packet(&mut stream).unwrap();
+            assert_eq!(header, CONNECT);
+            stream.write_all(&[CONNACK, 2, 0, return_code]).unwrap();
+
+            let mut published = Vec::new();
+            if return_code == 0 {
+                for _ in 0..messages {
+                    let (header, body) = read_packet(&mut stream).unwrap();
+                    let (topic, rest) = take_string(&body);
+                    let packet_id = u16::from_be_bytes([rest[0], rest[1]]);
+                    published.push(Published {
+                        header,
+                        topic,
+                        packet_id,
+                        payload: String::from_utf8(rest[2..].to_vec()).unwrap(),
+                    });
+
+                    let [high, low] = packet_id.to_be_bytes();
+                    stream.write_all(&[0xD0, 0]).unwrap();
+                    stream.write_all(&[PUBACK, 2, high, low]).unwrap();
+                }
+
+                let (header, _) = read_packet(&mut stream).unwrap();
+                assert_eq!(header, DISCONNECT);
+            }
+
+            (connect, published)
+        });
+
+        (address, handle)
+    }
+
+    #[test]
+    fn publishes_at_qos_1_and_waits_for_each_puback() {
+        let (address, broker) = broker(0, 2);
+        let messages = [
+            message(
+                "public_ip_notifier/test/state",
+                "{\"ipv4\":\"203.0.113.7\"}",
+                true,
+            ),
+            message("public_ip_notifier/test/events", "changed", false),
+        ];
+
+        publish(&settings(address), &messages).unwrap();
+        let (connect, published) = broker.join().unwrap();
+
+        let (protocol, rest) = take_string(&connect);
+        assert_eq!(protocol, "MQTT");
+        // Level 4, clean session with a username and password, 60 second keep alive
+        assert_eq!(&rest[..4], &[4, 0xC2, 0, 60]);
+        let (client_id, rest) = take_string(&rest[4..]);
+        let (username, rest) = take_string(rest);
+        let (password, rest) = take_string(rest);
+        assert_eq!(
+            (client_id.as_str(), username.as_str(), password.as_str()),
+            ("test-client", "user", "secret")
+        );
+        assert!(rest.is_empty());
+
+        assert_eq!(published.len(), 2);
+        assert_eq!(published[0].header, PUBLISH | 0x02 | 0x01);
+        assert_eq!(published[0].topic, "public_ip_notifier/test/state");
+        assert_eq!(published[0].packet_id, 1);
+        assert_eq!(published[0].payload, "{\"ipv4\":\"203.0.113.7\"}");
+        assert_eq!(published[1].header, PUBLISH | 0x02);
+        assert_eq!(published[1].topic, "public_ip_notifier/test/events");
+        assert_eq!(published[1].packet_id, 2);
+        assert_eq!(published[1].payload, "changed");
+    }
+
+    #[test]
+    fn refused_connection_reports_the_return_code() {
+        let (address, broker) = broker(5, 0);
+
+        let error = publish(
+            &settings(address),
+            &[message("public_ip_notifier/test/events", "changed", false)],
+        )
+        .unwrap_err();
+        broker.join().unwrap();
+
+        assert!(matches!(error, MqttError::Refused(5)));
+        assert!(error.to_string().contains("not authorized"));
+    }
+
+    #[test]
+    fn long_payloads_use_a_multi_byte_remaining_length() {
+        let payload = "x".repeat(200);
+        let packet = build_publish(&message("t", &payload, false), 7);
+
+        // 2 + 1 topic bytes, 2 packet ID bytes and the payload
+        assert_eq!(&packet[..3], &[PUBLISH | 0x02, (205 % 128) | 0x80, 1]);
+        assert_eq!(packet.len(), 3 + 205);
+    }
+}
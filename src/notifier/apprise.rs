@@ -0,0 +1,235 @@
+//! Reads Apprise style URLs, so a whole channel can be configured with one string
+//!
+//! Supported are `pover://user@token[/device]`, `tgram://bottoken/chatid`,
+//! `discord://webhook_id/webhook_token`, `mailto://recipient@example.com` and
+//! `json://host[:port]/path` (or `jsons://` for HTTPS). The query can hold `priority` and
+//! `alertPriority` for Pushover, `username` for Discord, `method` and `+Header=value` for JSON, and
+//! `to` for email
+use std::collections::HashMap;
+
+use serde_json::{Value, json};
+
+use crate::http::percent_decode;
+
+/// Turns an Apprise URL into the notifier entry it stands for
+///
+/// # Arguments
+/// * `url: &str` - The URL, the scheme picks the notifier
+///
+/// # Returns
+/// * `Result<Value, String>` - The entry, Err if the scheme is unknown or a part is missing
+pub fn to_entry(url: &str) -> Result<Value, String> {
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or_else(|| format!("{:?} is not a notifier URL", url))?;
+    let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+    let params: HashMap<String, String> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect();
+    let segments: Vec<String> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(percent_decode)
+        .collect();
+    let missing = |what: &str| format!("{}:// URL needs {}", scheme, what);
+
+    // Each scheme lists the query settings it takes, the rest can't reach the entry
+    let (mut entry, settings): (Value, &[&str]) = match scheme.to_lowercase().as_str() {
+        "pover" => {
+            let (user, token) = segments
+                .first()
+                .and_then(|first| first.split_once('@'))
+                .ok_or_else(|| missing("user@token"))?;
+
+            let mut entry = json!({ "type": "pushover", "userKey": user, "appToken": token });
+            if let Some(device) = segments.get(1) {
+                entry["device"] = Value::String(device.clone());
+            }
+            (entry, &["priority", "alertPriority"])
+        }
+        "tgram" => match segments.as_slice() {
+            [token, chat, ..] => (
+                json!({ "type": "telegram", "botToken": token, "chatId": chat }),
+                &[],
+            ),
+            _ => return Err(missing("a bot token and a chat ID")),
+        },
+        "discord" => match segments.as_slice() {
+            [id, token, ..] => (
+                json!({
+                    "type": "discord",
+                    "url": format!("https://discord.com/api/webhooks/{}/{}", id, token),
+                }),
+                &["username"],
+            ),
+            _ => return Err(missing("a webhook ID and token")),
+        },
+        "mailto" | "mailtos" => {
+            // The SMTP account comes from the config, the URL only says who gets the email
+            let recipient = params
+                .get("to")
+                .or(segments.first())
+                .filter(|recipient| recipient.contains('@'))
+                .ok_or_else(|| missing("a recipient address"))?;
+
+            (
+                json!({ "type": "email", "recipientAddress": recipient }),
+                &[],
+            )
+        }
+        "json" | "jsons" => {
+            let http_scheme = if scheme.eq_ignore_ascii_case("jsons") {
+                "https"
+            } else {
+                "http"
+            };
+            if segments.is_empty() {
+                return Err(missing("a host"));
+            }
+
+            // Apprise passes extra headers as +Name=value
+            let headers: serde_json::Map<String, Value> = params
+                .iter()
+                .filter_map(|(key, value)| {
+                    key.strip_prefix('+')
+                        .map(|name| (name.to_string(), Value::String(value.clone())))
+                })
+                .collect();
+
+            (
+                json!({
+                    "type": "webhook",
+                    "url": format!("{}://{}", http_scheme, path),
+                    "headers": headers,
+                    // The payload Apprise's JSON notifier sends
+                    "body": {
+                        "version": "1.0",
+                        "title": "{{subject}}",
+                        "message": "{{message}}",
+                        "type": "{{event}}",
+                    },
+                }),
+                &["method"],
+            )
+        }
+        _ => return Err(format!("Unknown notifier URL scheme {:?}", scheme)),
+    };
+
+    // Settings like ?priority=1 are passed through to the notifier
+    for key in settings {
+        let Some(value) = params.get(*key) else {
+            continue;
+        };
+        entry[*key] = match value.as_str() {
+            "true" | "yes" => Value::Bool(true),
+            "false" | "no" => Value::Bool(false),
+            _ => value
+                .parse::<i64>()
+                .map(Value::from)
+                .unwrap_or_else(|_| Value::String(value.clone())),
+        };
+    }
+
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pushover_with_device_and_priorities() {
+        assert_eq!(
+            to_entry("pover://user-key@app-token/phone?priority=-1&alertPriority=2").unwrap(),
+            json!({
+                "type": "pushover",
+                "userKey": "user-key",
+                "appToken": "app-token",
+                "device": "phone",
+                "priority": -1,
+                "alertPriority": 2,
+            })
+        );
+        assert!(to_entry("pover://app-token").is_err());
+    }
+
+    #[test]
+    fn telegram_and_discord() {
+        assert_eq!(
+            to_entry("tgram://123:abc/-100200").unwrap(),
+            json!({ "type": "telegram", "botToken": "123:abc", "chatId": "-100200" })
+        );
+        assert_eq!(
+            to_entry("discord://42/hook-token?username=Router").unwrap(),
+            json!({
+                "type": "discord",
+                "url": "https://discord.com/api/webhooks/42/hook-token",
+                "username": "Router",
+            })
+        );
+        assert!(to_entry("discord://42").is_err());
+    }
+
+    #[test]
+    fn email_recipient_from_the_path_or_to() {
+        let entry = json!({ "type": "email", "recipientAddress": "me@example.com" });
+
+        assert_eq!(to_entry("mailto://me@example.com").unwrap(), entry);
+        assert_eq!(
+            to_entry("mailtos://smtp.example.com?to=me%40example.com").unwrap(),
+            entry
+        );
+        assert!(to_entry("mailto://nobody").is_err());
+    }
+
+    #[test]
+    fn json_webhook_with_headers_and_method() {
+        let entry = to_entry("jsons://example.com:8443/hook?+X-Token=secret&method=put").unwrap();
+
+        assert_eq!(entry["type"], "webhook");
+        assert_eq!(entry["url"], "https://example.com:8443/hook");
+        assert_eq!(entry["headers"], json!({ "X-Token": "secret" }));
+        assert_eq!(entry["method"], "put");
+        assert_eq!(entry["body"]["message"], "{{message}}");
+        assert_eq!(
+            to_entry("json://example.com").unwrap()["url"],
+            "http://example.com"
+        );
+    }
+
+    #[test]
+    fn query_cannot_override_the_parsed_target() {
+        let entry = to_entry(
+            "discord://42/hook-token?url=https://evil.example&type=webhook&body=x&priority=2",
+        )
+        .unwrap();
+
+        assert_eq!(
+            entry,
+            json!({ "type": "discord", "url": "https://discord.com/api/webhooks/42/hook-token" })
+        );
+        assert_eq!(
+            to_entry("json://example.com/hook?url=http://other.example&headers=x").unwrap()["url"],
+            "http://example.com/hook"
+        );
+        assert_eq!(
+            to_entry("tgram://123:abc/1?chatId=2&botToken=x").unwrap()["chatId"],
+            "1"
+        );
+    }
+
+    #[test]
+    fn unknown_schemes_are_errors() {
+        assert_eq!(
+            to_entry("gopher://example.com").unwrap_err(),
+            "Unknown notifier URL scheme \"gopher\""
+        );
+        assert!(to_entry("not a url").is_err());
+    }
+}
@@ -0,0 +1,174 @@
+//! Sends events to a Discord webhook as embeds
+use serde_json::{Value, json};
+
+use super::{Event, Notifier, post_json, required_str, truncate};
+
+/// Embed colors, so alerts stand out from routine changes
+const COLOR_CHANGED: u32 = 0xF1C40F;
+const COLOR_ALERT: u32 = 0xE74C3C;
+const COLOR_RECOVERED: u32 = 0x2ECC71;
+const COLOR_TEST: u32 = 0x3498DB;
+
+/// What Discord accepts in an embed, longer messages are refused as a whole
+const FIELD_VALUE_MAX_CHARS: usize = 1024;
+const DESCRIPTION_MAX_CHARS: usize = 4096;
+
+/// Posts to a Discord channel webhook
+pub struct DiscordNotifier {
+    /// The webhook URL, from the channel's integration settings
+    pub url: String,
+    /// Overrides the name the webhook posts as
+    pub username: Option<String>,
+}
+
+impl DiscordNotifier {
+    /// Reads the notifier from `{"type": "discord", "url": "https://discord.com/api/webhooks/...", "username": "..."}`
+    ///
+    /// # Arguments
+    /// * `entry: &Value` - The notifier entry
+    ///
+    /// # Returns
+    /// * `Result<DiscordNotifier, String>` - Err if the url is missing
+    pub fn from_value(entry: &Value) -> Result<Self, String> {
+        Ok(DiscordNotifier {
+            url: required_str(entry, "url", "Discord")?,
+            username: entry
+                .get("username")
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_string()),
+        })
+    }
+}
+
+/// Builds the embed message
+///
+/// # Arguments
+/// * `event: &Event` - What happened
+/// * `username: Option<&str>` - The name to post as
+///
+/// # Returns
+/// * `Value` - The message body
+pub fn message(event: &Event, username: Option<&str>) -> Value {
+    let color = match event {
+        Event::IpChanged { .. } | Event::SiteIpChanged { .. } => COLOR_CHANGED,
+        Event::FailureThreshold { .. } | Event::DnsDrift { .. } | Event::SiteSilent { .. } => {
+            COLOR_ALERT
+        }
+        Event::Recovered { .. } | Event::SiteBackOnline { .. } => COLOR_RECOVERED,
+        Event::Test { .. } => COLOR_TEST,
+    };
+
+    let fields: Vec<Value> = event
+        .facts()
+        .iter()
+        .map(|(label, value)| {
+            json!({
+                "name": label,
+                // The backticks count too
+                "value": format!("`{}`", truncate(&value.replace('`', "'"), FIELD_VALUE_MAX_CHARS - 2)),
+                // Long values like errors get a row of their own
+                "inline": value.len() < 40,
+            })
+        })
+        .collect();
+
+    let mut embed = json!({
+        "title": event.subject(),
+        "color": color,
+        "fields": fields,
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "footer": { "text": "public_ip_notifier" },
+    });
+
+    if let Some(notes) = event.notes() {
+        embed["description"] = Value::String(truncate(&notes, DESCRIPTION_MAX_CHARS));
+    }
+
+    let mut body = json!({ "embeds": [embed] });
+
+    if let Some(username) = username {
+        body["username"] = Value::String(username.to_string());
+    }
+
+    body
+}
+
+impl Notifier for DiscordNotifier {
+    fn name(&self) -> String {
+        "Discord".to_string()
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), Box<dyn std::error::Error>> {
+        post_json(&self.url, &message(event, self.username.as_deref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ip_check::IpFamily;
+
+    #[test]
+    fn changed_address_is_an_embed_with_fields() {
+        let body = message(
+            &Event::IpChanged {
+                family: IpFamily::V4,
+                old_ip: String::new(),
+                new_ip: "203.0.113.8".parse().unwrap(),
+                details: vec!["NAT: endpoint independent".to_string()],
+            },
+            Some("Router"),
+        );
+
+        assert_eq!(body["username"], "Router");
+        let embed = &body["embeds"][0];
+        assert_eq!(embed["title"], "Your IP Changed!");
+        assert_eq!(embed["color"], COLOR_CHANGED);
+        assert_eq!(embed["description"], "NAT: endpoint independent");
+        let fields = embed["fields"].as_array().unwrap();
+        assert!(fields.contains(&json!({"name": "Old IP", "value": "`unknown`", "inline": true})));
+        assert!(
+            fields.contains(&json!({"name": "New IP", "value": "`203.0.113.8`", "inline": true}))
+        );
+    }
+
+    #[test]
+    fn long_error_fits_in_a_field() {
+        let body = message(
+            &Event::FailureThreshold {
+                failures: 3,
+                error: format!("`quoted` {}", "e".repeat(5000)),
+            },
+            None,
+        );
+
+        let embed = &body["embeds"][0];
+        assert_eq!(embed["color"], COLOR_ALERT);
+        assert!(body.get("username").is_none());
+        let error = embed["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|field| field["name"] == "Last error")
+            .unwrap();
+        let value = error["value"].as_str().unwrap();
+        assert_eq!(value.chars().count(), FIELD_VALUE_MAX_CHARS);
+        assert!(value.starts_with("`'quoted' eee"), "{}", value);
+        assert!(value.ends_with("e…`"), "{}", value);
+        assert_eq!(error["inline"], false);
+    }
+
+    #[test]
+    fn long_notes_fit_in_the_description() {
+        let body = message(
+            &Event::Test {
+                message: "n".repeat(10_000),
+            },
+            None,
+        );
+
+        let description = body["embeds"][0]["description"].as_str().unwrap();
+        assert_eq!(description.chars().count(), DESCRIPTION_MAX_CHARS);
+        assert_eq!(body["embeds"][0]["color"], COLOR_TEST);
+    }
+}
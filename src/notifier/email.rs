@@ -0,0 +1,118 @@
+//! Sends events by email over SMTP
+use lettre::{Message, SmtpTransport, Transport, transport::smtp::authentication::Credentials};
+use serde_json::Value;
+
+use crate::config::Config;
+
+use super::{Event, Notifier};
+
+/// Sends emails through the SMTP account in the config
+pub struct EmailNotifier {
+    /// The address the email is sent from
+    pub from: String,
+    /// The SMTP username
+    pub username: String,
+    /// The SMTP password
+    pub password: String,
+    /// The SMTP host
+    pub host: String,
+    /// The SMTP port
+    pub port: u16,
+    /// Who gets the email
+    pub recipient: String,
+}
+
+impl EmailNotifier {
+    /// Builds the notifier from the SMTP settings in the config
+    ///
+    /// The entry can set its own `recipientAddress`, so several people can be emailed
+    ///
+    /// # Arguments
+    /// * `entry: &Value` - The notifier entry
+    /// * `config: &Config` - The config holding the SMTP account
+    ///
+    /// # Returns
+    /// * `EmailNotifier` - The notifier
+    pub fn from_value(entry: &Value, config: &Config) -> Self {
+        EmailNotifier {
+            from: config.email_address.clone(),
+            username: config.username.clone(),
+            password: config.email_password.clone(),
+            host: config.email_smtp_host.clone(),
+            port: config.email_smtp_port,
+            recipient: entry
+                .get("recipientAddress")
+                .and_then(|v| v.as_str())
+                .unwrap_or(&config.recipient_address)
+                .to_string(),
+        }
+    }
+}
+
+impl Notifier for EmailNotifier {
+    fn name(&self) -> String {
+        format!("email ({})", self.recipient)
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), Box<dyn std::error::Error>> {
+        // Define the email
+        // A bad address fails this channel only, the others still get the event
+        let email = Message::builder()
+            .from(format!("IP Change Notifier <{}>", self.from).parse()?)
+            .to(self.recipient.parse()?)
+            .subject(event.subject())
+            .body(event.message())?;
+
+        // Set up the SMTP client
+        let creds = Credentials::new(self.username.clone(), self.password.clone());
+
+        // Open a remote connection to the SMTP server
+        let mailer = SmtpTransport::relay(&self.host)?
+            .port(self.port)
+            .credentials(creds)
+            .build();
+
+        // Send the email
+        mailer.send(&email)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notifier(from: &str, recipient: &str) -> EmailNotifier {
+        EmailNotifier {
+            from: from.to_string(),
+            username: "user".to_string(),
+            password: "secret".to_string(),
+            // Never reached, the addresses are checked first
+            host: "smtp.invalid".to_string(),
+            port: 465,
+            recipient: recipient.to_string(),
+        }
+    }
+
+    #[test]
+    fn invalid_addresses_are_errors_not_panics() {
+        let event = Event::Test {
+            message: "hello".to_string(),
+        };
+
+        for (from, recipient) in [
+            ("", "person@example.com"),
+            ("not an address", "person@example.com"),
+            ("me@example.com", ""),
+            ("me@example.com", "person@"),
+        ] {
+            assert!(
+                notifier(from, recipient).notify(&event).is_err(),
+                "{:?} to {:?} was accepted",
+                from,
+                recipient
+            );
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Sends events to a Gotify server as push notifications
+use serde_json::{Value, json};
+
+use crate::http;
+
+use super::{Event, Notifier, priority, required_str};
+
+/// Pushes to a Gotify application
+pub struct GotifyNotifier {
+    /// The server, like `https://gotify.example.com`
+    pub url: String,
+    /// The application token messages are sent with
+    pub app_token: String,
+    /// Priority for IP changes and tests, 0 to 10
+    pub priority: i64,
+    /// Priority for the failure alert
+    pub alert_priority: i64,
+}
+
+impl GotifyNotifier {
+    /// Reads the notifier from
+    /// `{"type": "gotify", "url": "...", "appToken": "...", "priority": 5, "alertPriority": 8}`
+    ///
+    /// # Arguments
+    /// * `entry: &Value` - The notifier entry
+    ///
+    /// # Returns
+    /// * `Result<GotifyNotifier, String>` - Err if the url or token is missing or a priority is out of range
+    pub fn from_value(entry: &Value) -> Result<Self, String> {
+        Ok(GotifyNotifier {
+            url: required_str(entry, "url", "Gotify")?
+                .trim_end_matches('/')
+                .to_string(),
+            app_token: required_str(entry, "appToken", "Gotify")?,
+            priority: priority(entry, "priority", 5, 0..=10)?,
+            alert_priority: priority(entry, "alertPriority", 8, 0..=10)?,
+        })
+    }
+}
+
+impl Notifier for GotifyNotifier {
+    fn name(&self) -> String {
+        format!("Gotify ({})", self.url)
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), Box<dyn std::error::Error>> {
+        let priority = if event.is_alert() {
+            self.alert_priority
+        } else {
+            self.priority
+        };
+
+        let message = event
+            .facts()
+            .iter()
+            .map(|(label, value)| format!("**{}:** {}", label, value))
+            .chain(event.notes())
+            .collect::<Vec<String>>()
+            .join("  \n");
+
+        http::agent()
+            .post(&format!("{}/message", self.url))
+            .set("X-Gotify-Key", &self.app_token)
+            .set("Content-Type", "application/json")
+            .send_string(
+                &json!({
+                    "title": event.subject(),
+                    "message": message,
+                    "priority": priority,
+                    "extras": {
+                        "client::display": { "contentType": "text/markdown" },
+                    },
+                })
+                .to_string(),
+            )
+            .map_err(http::describe_error)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+//! Sends events to a Matrix room through the client-server API
+use serde_json::{Value, json};
+
+use crate::{dns, http};
+
+use super::{Event, Notifier, required_str};
+
+/// Posts to a Matrix room as a user or bot account
+pub struct MatrixNotifier {
+    /// The homeserver, like `https://matrix.example.org`
+    pub homeserver: String,
+    /// The access token of the account that posts
+    pub access_token: String,
+    /// The room, like `!abcdef:example.org`, the account has to have joined it
+    pub room_id: String,
+}
+
+impl MatrixNotifier {
+    /// Reads the notifier from
+    /// `{"type": "matrix", "homeserver": "...", "accessToken": "...", "roomId": "!...:..."}`
+    ///
+    /// # Arguments
+    /// * `entry: &Value` - The notifier entry
+    ///
+    /// # Returns
+    /// * `Result<MatrixNotifier, String>` - Err if a setting is missing
+    pub fn from_value(entry: &Value) -> Result<Self, String> {
+        Ok(MatrixNotifier {
+            homeserver: required_str(entry, "homeserver", "Matrix")?
+                .trim_end_matches('/')
+                .to_string(),
+            access_token: required_str(entry, "accessToken", "Matrix")?,
+            room_id: required_str(entry, "roomId", "Matrix")?,
+        })
+    }
+}
+
+/// Escapes text for use inside HTML
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Builds the message event, with a plain body for clients that don't render HTML
+///
+/// # Arguments
+/// * `event: &Event` - What happened
+///
+/// # Returns
+/// * `Value` - The `m.room.message` content
+pub fn message(event: &Event) -> Value {
+    let facts = event.facts();
+    let notes = event.notes();
+
+    let mut plain = event.subject();
+    for (label, value) in &facts {
+        plain.push_str(&format!("\n{}: {}", label, value));
+    }
+
+    let mut html = format!("<h4>{}</h4><ul>", escape_html(&event.subject()));
+    for (label, value) in &facts {
+        html.push_str(&format!(
+            "<li><b>{}:</b> <code>{}</code></li>",
+            escape_html(label),
+            escape_html(value)
+        ));
+    }
+    html.push_str("</ul>");
+
+    if let Some(notes) = notes {
+        plain.push_str(&format!("\n\n{}", notes));
+        html.push_str(&format!(
+            "<p>{}</p>",
+            escape_html(&notes).replace('\n', "<br>")
+        ));
+    }
+
+    json!({
+        // Notices are the message type meant for bots, clients don't ping for them by default
+        "msgtype": if event.is_alert() { "m.text" } else { "m.notice" },
+        "body": plain,
+        "format": "org.matrix.custom.html",
+        "formatted_body": html,
+    })
+}
+
+impl Notifier for MatrixNotifier {
+    fn name(&self) -> String {
+        format!("Matrix ({})", self.room_id)
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), Box<dyn std::error::Error>> {
+        // The transaction ID only has to be unique for this token, it lets the server drop retries
+        let txn_id = format!(
+            "{}-{:04x}",
+            chrono::Utc::now().timestamp_millis(),
+            dns::new_id()
+        );
+
+        let url = format!(
+            "{}/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
+            self.homeserver,
+            http::encode_path_segment(&self.room_id),
+            txn_id
+        );
+
+        http::agent()
+            .put(&url)
+            .set("Authorization", &format!("Bearer {}", self.access_token))
+            .set("Content-Type", "application/json")
+            .send_string(&message(event).to_string())
+            .map_err(http::describe_error)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::mock;
+
+    fn notifier(homeserver: &str) -> MatrixNotifier {
+        MatrixNotifier::from_value(&json!({
+            "type": "matrix",
+            "homeserver": format!("{}/", homeserver),
+            "accessToken": "syt_token",
+            "roomId": "!room:example.org",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn puts_the_message_into_the_room() {
+        let server = mock::serve(|_| (200, r#"{"event_id": "$abc"}"#.to_string()));
+
+        notifier(&server.url)
+            .notify(&Event::FailureThreshold {
+                failures: 4,
+                error: "<timeout> & more".to_string(),
+            })
+            .unwrap();
+
+        let request = &server.requests()[0];
+        assert_eq!(request.method, "PUT");
+        assert!(
+            request
+                .url
+                .starts_with("/_matrix/client/v3/rooms/%21room%3Aexample.org/send/m.room.message/"),
+            "{}",
+            request.url
+        );
+        assert_eq!(request.header("Authorization"), Some("Bearer syt_token"));
+
+        let body = request.json();
+        assert_eq!(body["msgtype"], "m.text");
+        assert_eq!(body["format"], "org.matrix.custom.html");
+        assert!(
+            body["body"]
+                .as_str()
+                .unwrap()
+                .contains("Last error: <timeout> & more")
+        );
+        assert!(
+            body["formatted_body"]
+                .as_str()
+                .unwrap()
+                .contains("<code>&lt;timeout&gt; &amp; more</code>")
+        );
+    }
+
+    #[test]
+    fn every_send_has_its_own_transaction() {
+        let server = mock::serve(|_| (200, r#"{"event_id": "$abc"}"#.to_string()));
+        let notifier = notifier(&server.url);
+
+        notifier.notify(&Event::Recovered { failures: 1 }).unwrap();
+        notifier.notify(&Event::Recovered { failures: 1 }).unwrap();
+
+        let requests = server.requests();
+        assert_ne!(requests[0].url, requests[1].url);
+        assert_eq!(requests[0].json()["msgtype"], "m.notice");
+    }
+
+    #[test]
+    fn homeserver_error_is_reported() {
+        let server = mock::serve(|_| {
+            (
+                403,
+                r#"{"errcode": "M_FORBIDDEN", "error": "not in room"}"#.to_string(),
+            )
+        });
+
+        let error = notifier(&server.url)
+            .notify(&Event::Recovered { failures: 1 })
+            .unwrap_err()
+            .to_string();
+
+        assert!(
+            error.contains("403") && error.contains("M_FORBIDDEN"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,633 @@
+//! Notification channels, every enabled channel is told about each event
+use std::{fmt, net::IpAddr, ops::RangeInclusive};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{Value, json};
+
+use crate::{config::Config, http, ip_check::IpFamily};
+
+pub mod apprise;
+pub mod discord;
+pub mod email;
+pub mod gotify;
+pub mod matrix;
+pub mod ntfy;
+pub mod pushover;
+pub mod slack;
+pub mod teams;
+pub mod telegram;
+pub mod webhook;
+
+/// Something worth telling the user about
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// The public address of a family changed
+    IpChanged {
+        family: IpFamily,
+        /// The last known address, may be empty on the first check
+        old_ip: String,
+        new_ip: IpAddr,
+        /// Extra facts from the providers, like the NAT behavior
+        details: Vec<String>,
+    },
+    /// Looking up the public address failed too many times in a row
+    FailureThreshold { failures: u32, error: String },
+    /// Lookups work again after the failure alert was sent
+    Recovered { failures: u32 },
+    /// Published records of watched names don't hold the public address
+    DnsDrift {
+        family: IpFamily,
+        /// The address the records should hold
+        expected: IpAddr,
+        /// The stale names and what they resolve to
+        stale: Vec<(String, String)>,
+    },
+    /// The collector server saw the public address of a site change
+    SiteIpChanged {
+        /// The site ID the address was reported under
+        site: String,
+        family: IpFamily,
+        /// The last reported address
+        old_ip: String,
+        new_ip: IpAddr,
+    },
+    /// The collector server stopped getting reports from a site
+    SiteSilent {
+        site: String,
+        /// When the site last reported
+        last_seen: DateTime<Utc>,
+        /// How many of its check intervals went by without a report
+        missed: u64,
+    },
+    /// A site that went silent reports again
+    SiteBackOnline {
+        site: String,
+        /// How long there was no report
+        silent_for: chrono::Duration,
+    },
+    /// A test notification, sent with -t
+    Test { message: String },
+}
+
+impl Event {
+    /// A short machine friendly name for the kind of event
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::IpChanged { .. } => "ip_changed",
+            Event::FailureThreshold { .. } => "failure_threshold",
+            Event::Recovered { .. } => "recovered",
+            Event::DnsDrift { .. } => "dns_drift",
+            Event::SiteIpChanged { .. } => "site_ip_changed",
+            Event::SiteSilent { .. } => "site_silent",
+            Event::SiteBackOnline { .. } => "site_back_online",
+            Event::Test { .. } => "test",
+        }
+    }
+
+    /// The site the event is about, only the collector server's events have one
+    pub fn site(&self) -> Option<&str> {
+        match self {
+            Event::SiteIpChanged { site, .. }
+            | Event::SiteSilent { site, .. }
+            | Event::SiteBackOnline { site, .. } => Some(site),
+            _ => None,
+        }
+    }
+
+    /// Whether the event needs attention, the notifiers that have priorities raise them
+    pub fn is_alert(&self) -> bool {
+        matches!(
+            self,
+            Event::FailureThreshold { .. } | Event::DnsDrift { .. } | Event::SiteSilent { .. }
+        )
+    }
+
+    /// The values that can be put into templates as `{{name}}`
+    ///
+    /// # Returns
+    /// * `Vec<(&'static str, String)>` - Placeholder names and their values, empty if they don't apply
+    pub fn placeholders(&self) -> Vec<(&'static str, String)> {
+        let (family, old_ip, new_ip, failures, stale_names) = match self {
+            Event::IpChanged {
+                family,
+                old_ip,
+                new_ip,
+                ..
+            }
+            | Event::SiteIpChanged {
+                family,
+                old_ip,
+                new_ip,
+                ..
+            } => (
+                family.to_string(),
+                old_ip.clone(),
+                new_ip.to_string(),
+                String::new(),
+                String::new(),
+            ),
+            Event::FailureThreshold { failures, .. } | Event::Recovered { failures } => (
+                String::new(),
+                String::new(),
+                String::new(),
+                failures.to_string(),
+                String::new(),
+            ),
+            Event::DnsDrift {
+                family,
+                expected,
+                stale,
+            } => (
+                family.to_string(),
+                String::new(),
+                expected.to_string(),
+                String::new(),
+                stale
+                    .iter()
+                    .map(|(name, _)| name.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(","),
+            ),
+            Event::SiteSilent { .. } | Event::SiteBackOnline { .. } | Event::Test { .. } => (
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+            ),
+        };
+
+        vec![
+            ("event", self.kind().to_string()),
+            ("subject", self.subject()),
+            ("message", self.message()),
+            ("family", family),
+            ("old_ip", old_ip),
+            ("new_ip", new_ip),
+            ("failures", failures),
+            ("stale_names", stale_names),
+            ("site", self.site().unwrap_or_default().to_string()),
+            ("hostname", hostname()),
+            ("timestamp", chrono::Utc::now().to_rfc3339()),
+        ]
+    }
+
+    /// Labelled facts about the event, shown as fields by the chat notifiers
+    ///
+    /// # Returns
+    /// * `Vec<(&'static str, String)>` - Labels and values, the host always comes first
+    pub fn facts(&self) -> Vec<(&'static str, String)> {
+        let mut facts = vec![("Host", hostname())];
+        if let Some(site) = self.site() {
+            facts.push(("Site", site.to_string()));
+        }
+
+        match self {
+            Event::IpChanged {
+                family,
+                old_ip,
+                new_ip,
+                ..
+            }
+            | Event::SiteIpChanged {
+                family,
+                old_ip,
+                new_ip,
+                ..
+            } => {
+                facts.push(("Family", family.to_string()));
+                facts.push((
+                    "Old IP",
+                    if old_ip.is_empty() {
+                        "unknown".to_string()
+                    } else {
+                        old_ip.clone()
+                    },
+                ));
+                facts.push(("New IP", new_ip.to_string()));
+            }
+            Event::FailureThreshold { failures, error } => {
+                facts.push(("Sequential failures", failures.to_string()));
+                facts.push(("Last error", error.clone()));
+            }
+            Event::Recovered { failures } => {
+                facts.push(("Failed checks", failures.to_string()));
+            }
+            Event::DnsDrift {
+                family,
+                expected,
+                stale,
+            } => {
+                facts.push(("Family", family.to_string()));
+                facts.push(("Public IP", expected.to_string()));
+                facts.push(("Stale records", stale.len().to_string()));
+            }
+            Event::SiteSilent {
+                last_seen, missed, ..
+            } => {
+                facts.push((
+                    "Last report",
+                    last_seen.to_rfc3339_opts(SecondsFormat::Secs, true),
+                ));
+                facts.push(("Missed intervals", missed.to_string()));
+            }
+            Event::SiteBackOnline { silent_for, .. } => {
+                facts.push(("Silent for", format_duration(*silent_for)));
+            }
+            Event::Test { .. } => {}
+        }
+
+        facts
+    }
+
+    /// Free text that doesn't fit in [`Event::facts`], like the provider details
+    ///
+    /// # Returns
+    /// * `Option<String>` - The text, None if there is nothing to add
+    pub fn notes(&self) -> Option<String> {
+        let notes = match self {
+            Event::IpChanged { details, .. } => details.join("\n"),
+            Event::DnsDrift { stale, .. } => stale_lines(stale),
+            Event::FailureThreshold { .. }
+            | Event::Recovered { .. }
+            | Event::SiteIpChanged { .. }
+            | Event::SiteSilent { .. }
+            | Event::SiteBackOnline { .. } => String::new(),
+            Event::Test { message } => message.clone(),
+        };
+
+        (!notes.is_empty()).then_some(notes)
+    }
+
+    /// A one line summary, used as the email subject and message title
+    pub fn subject(&self) -> String {
+        match self {
+            Event::IpChanged { .. } => "Your IP Changed!".to_string(),
+            Event::FailureThreshold { .. } => "Could not retrieve your IP".to_string(),
+            Event::Recovered { .. } => "Your IP can be retrieved again".to_string(),
+            Event::DnsDrift { .. } => "Your DNS records are out of date".to_string(),
+            Event::SiteIpChanged { site, .. } => format!("The IP of {} changed", site),
+            Event::SiteSilent { site, .. } => format!("{} stopped reporting", site),
+            Event::SiteBackOnline { site, .. } => format!("{} is back online", site),
+            Event::Test { .. } => "IP Change Notifier test".to_string(),
+        }
+    }
+
+    /// The plain text body
+    pub fn message(&self) -> String {
+        match self {
+            Event::IpChanged {
+                family,
+                old_ip,
+                new_ip,
+                details,
+            } => {
+                let mut message = format!(
+                    "Hello,\nYour public {} address has changed from {} to {}.",
+                    family, old_ip, new_ip
+                );
+
+                if !details.is_empty() {
+                    message.push_str(&format!("\n\nDetails:\n{}", details.join("\n")));
+                }
+
+                message
+            }
+            Event::FailureThreshold { failures, error } => format!(
+                "Could not retrieve IP, sequential error threshold reached: {}\nLast error: {}",
+                failures, error
+            ),
+            Event::Recovered { failures } => format!(
+                "The public IP could be retrieved again after {} failed checks.",
+                failures
+            ),
+            Event::DnsDrift {
+                family,
+                expected,
+                stale,
+            } => format!(
+                "Hello,\nThese names don't resolve to your public {} address {}:\n{}",
+                family,
+                expected,
+                stale_lines(stale)
+            ),
+            Event::SiteIpChanged {
+                site,
+                family,
+                old_ip,
+                new_ip,
+            } => format!(
+                "Hello,\nThe public {} address of site {} has changed from {} to {}.",
+                family,
+                site,
+                if old_ip.is_empty() { "unknown" } else { old_ip },
+                new_ip
+            ),
+            Event::SiteSilent {
+                site,
+                last_seen,
+                missed,
+            } => format!(
+                "Hello,\nSite {} has not reported since {}, {} of its check intervals went by without a report.\nIts power or internet connection may be down.",
+                site,
+                last_seen.to_rfc3339_opts(SecondsFormat::Secs, true),
+                missed
+            ),
+            Event::SiteBackOnline { site, silent_for } => format!(
+                "Site {} is reporting again after {} without a report.",
+                site,
+                format_duration(*silent_for)
+            ),
+            Event::Test { message } => message.clone(),
+        }
+    }
+}
+
+/// One line per stale name, saying what it resolves to instead
+fn stale_lines(stale: &[(String, String)]) -> String {
+    stale
+        .iter()
+        .map(|(name, answer)| format!("{} resolves to {}", name, answer))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.subject())
+    }
+}
+
+/// Returns the name of this machine, so the message says which site it came from
+pub fn hostname() -> String {
+    hostname::get()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|_| "unknown host".to_string())
+}
+
+/// Writes a duration like `3d 4h 12m`
+pub fn format_duration(duration: chrono::Duration) -> String {
+    let minutes = duration.num_minutes();
+    let (days, hours, minutes) = (minutes / 1440, minutes / 60 % 24, minutes % 60);
+
+    match (days, hours) {
+        (0, 0) => format!("{}m", minutes),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h {}m", days, hours, minutes),
+    }
+}
+
+/// Fills in every `{{name}}` placeholder in the text
+///
+/// # Arguments
+/// * `template: &str` - The text with placeholders
+/// * `placeholders: &[(&str, String)]` - Names and values from [`Event::placeholders`]
+///
+/// # Returns
+/// * `String` - The text with the placeholders replaced, unknown ones are left alone
+pub fn render(template: &str, placeholders: &[(&str, String)]) -> String {
+    let mut rendered = template.to_string();
+
+    for (name, value) in placeholders {
+        rendered = rendered.replace(&format!("{{{{{}}}}}", name), value);
+    }
+
+    rendered
+}
+
+/// Shortens text to what a service accepts in one field, ending it with `…` if anything was cut
+///
+/// # Arguments
+/// * `text: &str` - The text
+/// * `max_chars: usize` - How many characters the field takes
+///
+/// # Returns
+/// * `String` - The text, at most `max_chars` characters long
+pub fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+
+    let mut shortened: String = text.chars().take(max_chars.saturating_sub(1)).collect();
+    if max_chars > 0 {
+        shortened.push('…');
+    }
+    shortened
+}
+
+/// Reads a setting that a notifier can't work without
+///
+/// # Arguments
+/// * `entry: &Value` - The notifier entry
+/// * `key: &str` - The setting
+/// * `notifier: &str` - The notifier name, for the error
+///
+/// # Returns
+/// * `Result<String, String>` - Err if the setting is missing or empty
+pub fn required_str(entry: &Value, key: &str, notifier: &str) -> Result<String, String> {
+    entry
+        .get(key)
+        .and_then(|v| v.as_str())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_string())
+        .ok_or_else(|| format!("{} notifier needs a {:?}", notifier, key))
+}
+
+/// Reads a priority setting, checking it is within what the service accepts
+///
+/// # Arguments
+/// * `entry: &Value` - The notifier entry
+/// * `key: &str` - The setting
+/// * `default: i64` - Used when the setting is missing
+/// * `range: RangeInclusive<i64>` - The priorities the service knows
+///
+/// # Returns
+/// * `Result<i64, String>` - Err if the setting is out of range
+pub fn priority(
+    entry: &Value,
+    key: &str,
+    default: i64,
+    range: RangeInclusive<i64>,
+) -> Result<i64, String> {
+    match entry.get(key).map(|v| v.as_i64()) {
+        None => Ok(default),
+        Some(Some(priority)) if range.contains(&priority) => Ok(priority),
+        _ => Err(format!(
+            "{} must be a number from {} to {}",
+            key,
+            range.start(),
+            range.end()
+        )),
+    }
+}
+
+/// POSTs a JSON body, the way most chat services take incoming messages
+///
+/// # Arguments
+/// * `url: &str` - Where the body goes
+/// * `body: &Value` - The body
+///
+/// # Returns
+/// * `Result<(), Box<dyn std::error::Error>>` - Err with the status and response if it was refused
+pub fn post_json(url: &str, body: &Value) -> Result<(), Box<dyn std::error::Error>> {
+    http::agent()
+        .post(url)
+        .set("Content-Type", "application/json")
+        .send_string(&body.to_string())
+        .map_err(http::describe_error)?;
+
+    Ok(())
+}
+
+/// A channel that can deliver events
+pub trait Notifier {
+    /// A short name for logs, like "email" or "email (ops@example.com)"
+    fn name(&self) -> String;
+
+    /// Delivers the event
+    ///
+    /// # Arguments
+    /// * `event: &Event` - What happened
+    ///
+    /// # Returns
+    /// * `Result<(), Box<dyn std::error::Error>>` - Ok once the channel accepted the event
+    fn notify(&self, event: &Event) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// The notifiers used when the config has none, so older configs keep getting emails
+pub fn default_notifiers() -> Vec<Value> {
+    vec![json!({ "type": "email" })]
+}
+
+/// Builds a notifier from its config entry
+///
+/// # Arguments
+/// * `entry: &Value` - The entry from the `notifiers` array, picked by its `type` field,
+///   or an Apprise style URL string
+/// * `config: &Config` - The whole config, for shared settings like the SMTP account
+///
+/// # Returns
+/// * `Result<Box<dyn Notifier>, String>` - Err if the entry is invalid
+pub fn from_value(entry: &Value, config: &Config) -> Result<Box<dyn Notifier>, String> {
+    if let Value::String(url) = entry {
+        return from_value(&apprise::to_entry(url)?, config);
+    }
+
+    let notifier_type = entry
+        .get("type")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| format!("Notifier {} has no type", entry))?;
+
+    match notifier_type {
+        "email" => Ok(Box::new(email::EmailNotifier::from_value(entry, config))),
+        "webhook" => Ok(Box::new(webhook::WebhookNotifier::from_value(entry)?)),
+        "slack" => Ok(Box::new(slack::SlackNotifier::from_value(entry)?)),
+        "discord" => Ok(Box::new(discord::DiscordNotifier::from_value(entry)?)),
+        "teams" => Ok(Box::new(teams::TeamsNotifier::from_value(entry)?)),
+        "telegram" => Ok(Box::new(telegram::TelegramNotifier::from_value(entry)?)),
+        "ntfy" => Ok(Box::new(ntfy::NtfyNotifier::from_value(entry)?)),
+        "gotify" => Ok(Box::new(gotify::GotifyNotifier::from_value(entry)?)),
+        "matrix" => Ok(Box::new(matrix::MatrixNotifier::from_value(entry)?)),
+        "pushover" => Ok(Box::new(pushover::PushoverNotifier::from_value(entry)?)),
+        _ => Err(format!("Unknown notifier type {:?}", notifier_type)),
+    }
+}
+
+/// Builds every enabled notifier in the config, invalid entries are logged and skipped
+///
+/// # Arguments
+/// * `config: &Config` - The config holding the `notifiers` array
+///
+/// # Returns
+/// * `Vec<Box<dyn Notifier>>` - The notifiers, in config order
+pub fn from_config(config: &Config) -> Vec<Box<dyn Notifier>> {
+    config
+        .notifiers
+        .iter()
+        .filter_map(|entry| {
+            // URLs are turned into entries first, so ?enabled=false works for them too
+            let entry = match entry {
+                Value::String(url) => apprise::to_entry(url),
+                entry => Ok(entry.clone()),
+            };
+
+            let notifier = entry.and_then(|entry| {
+                // Entries can be switched off without removing them
+                if entry.get("enabled").and_then(|v| v.as_bool()) == Some(false) {
+                    Ok(None)
+                } else {
+                    from_value(&entry, config).map(Some)
+                }
+            });
+
+            notifier.unwrap_or_else(|e| {
+                eprintln!("Skipping notifier: {}", e);
+                None
+            })
+        })
+        .collect()
+}
+
+/// Sends the event through every notifier, one failing doesn't stop the others
+///
+/// # Arguments
+/// * `notifiers: &[Box<dyn Notifier>]` - The channels to use
+/// * `event: &Event` - What happened
+///
+/// # Returns
+/// * `Vec<(String, Result<(), String>)>` - The name and outcome of each channel
+pub fn notify_all(
+    notifiers: &[Box<dyn Notifier>],
+    event: &Event,
+) -> Vec<(String, Result<(), String>)> {
+    notifiers
+        .iter()
+        .map(|notifier| {
+            let result = notifier.notify(event).map_err(|e| e.to_string());
+
+            match &result {
+                Ok(()) => println!("Sent \"{}\" via {}", event, notifier.name()),
+                Err(e) => eprintln!(
+                    "Could not send \"{}\" via {}: {}",
+                    event,
+                    notifier.name(),
+                    e
+                ),
+            }
+
+            (notifier.name(), result)
+        })
+        .collect()
+}
+
+/// Builds the notifiers from the config and sends the event through all of them
+///
+/// # Arguments
+/// * `config: &Config` - The config holding the `notifiers` array
+/// * `event: &Event` - What happened
+///
+/// # Returns
+/// * `Vec<(String, Result<(), String>)>` - The name and outcome of each channel
+pub fn notify(config: &Config, event: &Event) -> Vec<(String, Result<(), String>)> {
+    let notifiers = from_config(config);
+
+    if notifiers.is_empty() {
+        eprintln!("No notifiers are enabled, \"{}\" was not sent", event);
+    }
+
+    notify_all(&notifiers, event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_counts_characters_not_bytes() {
+        assert_eq!(truncate("short", 5), "short");
+        assert_eq!(truncate("longer", 5), "long…");
+        assert_eq!(truncate("ééééé€", 3), "éé…");
+        assert_eq!(truncate("anything", 1), "…");
+        assert_eq!(truncate("anything", 0), "");
+    }
+}
@@ -0,0 +1,99 @@
+//! Sends events to an ntfy topic as push notifications
+use serde_json::Value;
+
+use crate::http;
+
+use super::{Event, Notifier, priority, required_str};
+
+/// Pushes to an ntfy topic
+pub struct NtfyNotifier {
+    /// The topic URL, like `https://ntfy.sh/my-topic`
+    pub url: String,
+    /// Priority for IP changes and tests, 1 (min) to 5 (max)
+    pub priority: i64,
+    /// Priority for the failure alert
+    pub alert_priority: i64,
+    /// Tags added to every message, emoji short codes show as icons
+    pub tags: Vec<String>,
+    /// Access token for protected topics
+    pub token: Option<String>,
+}
+
+impl NtfyNotifier {
+    /// Reads the notifier from
+    /// `{"type": "ntfy", "url": "...", "priority": 3, "alertPriority": 5, "tags": ["..."], "token": "..."}`,
+    /// only the url is required
+    ///
+    /// # Arguments
+    /// * `entry: &Value` - The notifier entry
+    ///
+    /// # Returns
+    /// * `Result<NtfyNotifier, String>` - Err if the url is missing or a priority is out of range
+    pub fn from_value(entry: &Value) -> Result<Self, String> {
+        Ok(NtfyNotifier {
+            url: required_str(entry, "url", "ntfy")?,
+            priority: priority(entry, "priority", 3, 1..=5)?,
+            alert_priority: priority(entry, "alertPriority", 5, 1..=5)?,
+            tags: match entry.get("tags") {
+                Some(Value::Array(tags)) => tags
+                    .iter()
+                    .filter_map(|tag| tag.as_str())
+                    .map(|tag| tag.to_string())
+                    .collect(),
+                Some(Value::String(tags)) => tags
+                    .split(',')
+                    .map(|tag| tag.trim().to_string())
+                    .filter(|tag| !tag.is_empty())
+                    .collect(),
+                _ => Vec::new(),
+            },
+            token: entry
+                .get("token")
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_string()),
+        })
+    }
+}
+
+impl Notifier for NtfyNotifier {
+    fn name(&self) -> String {
+        format!("ntfy ({})", self.url)
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), Box<dyn std::error::Error>> {
+        // The priority follows the same rule as the other notifiers, the tag only picks an emoji
+        let priority = if event.is_alert() {
+            self.alert_priority
+        } else {
+            self.priority
+        };
+        let tag = match event {
+            Event::IpChanged { .. } | Event::SiteIpChanged { .. } => "globe_with_meridians",
+            Event::FailureThreshold { .. } => "rotating_light",
+            Event::DnsDrift { .. } => "warning",
+            Event::SiteSilent { .. } => "electric_plug",
+            Event::Recovered { .. } | Event::SiteBackOnline { .. } => "white_check_mark",
+            Event::Test { .. } => "test_tube",
+        };
+
+        let mut tags = self.tags.clone();
+        tags.push(tag.to_string());
+
+        let mut request = http::agent()
+            .post(&self.url)
+            .set("Title", &event.subject())
+            .set("Priority", &priority.to_string())
+            .set("Tags", &tags.join(","));
+
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", &format!("Bearer {}", token));
+        }
+
+        // The body is the notification text, ntfy takes it as is
+        let body = format!("{}\n\nHost: {}", event.message(), super::hostname());
+
+        request.send_string(&body).map_err(http::describe_error)?;
+
+        Ok(())
+    }
+}
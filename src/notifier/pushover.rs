@@ -0,0 +1,95 @@
+//! Sends events through Pushover
+use serde_json::{Value, json};
+
+use super::{Event, Notifier, post_json, priority, required_str};
+
+/// Where the Pushover API lives unless the entry points somewhere else
+pub const DEFAULT_API_URL: &str = "https://api.pushover.net";
+
+/// Pushes to a Pushover user or group
+pub struct PushoverNotifier {
+    /// The user or group key messages go to
+    pub user_key: String,
+    /// The token of the application sending them
+    pub app_token: String,
+    /// Only push to this device of the user
+    pub device: Option<String>,
+    /// Priority for IP changes and tests, -2 (silent) to 2 (emergency)
+    pub priority: i64,
+    /// Priority for the failure alert
+    pub alert_priority: i64,
+    /// The API server
+    pub api_url: String,
+}
+
+impl PushoverNotifier {
+    /// Reads the notifier from
+    /// `{"type": "pushover", "userKey": "...", "appToken": "...", "device": "...", "priority": 0, "alertPriority": 1}`
+    ///
+    /// # Arguments
+    /// * `entry: &Value` - The notifier entry
+    ///
+    /// # Returns
+    /// * `Result<PushoverNotifier, String>` - Err if a key is missing or a priority is out of range
+    pub fn from_value(entry: &Value) -> Result<Self, String> {
+        Ok(PushoverNotifier {
+            user_key: required_str(entry, "userKey", "Pushover")?,
+            app_token: required_str(entry, "appToken", "Pushover")?,
+            device: entry
+                .get("device")
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_string()),
+            priority: priority(entry, "priority", 0, -2..=2)?,
+            alert_priority: priority(entry, "alertPriority", 1, -2..=2)?,
+            api_url: entry
+                .get("apiUrl")
+                .and_then(|v| v.as_str())
+                .unwrap_or(DEFAULT_API_URL)
+                .trim_end_matches('/')
+                .to_string(),
+        })
+    }
+}
+
+impl Notifier for PushoverNotifier {
+    fn name(&self) -> String {
+        "Pushover".to_string()
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), Box<dyn std::error::Error>> {
+        let priority = if event.is_alert() {
+            self.alert_priority
+        } else {
+            self.priority
+        };
+
+        let message = event
+            .facts()
+            .iter()
+            .map(|(label, value)| format!("<b>{}:</b> {}", label, value))
+            .chain(event.notes())
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let mut body = json!({
+            "token": self.app_token,
+            "user": self.user_key,
+            "title": event.subject(),
+            "message": message,
+            "html": 1,
+            "priority": priority,
+        });
+
+        if let Some(device) = &self.device {
+            body["device"] = Value::String(device.clone());
+        }
+
+        // Emergency messages repeat until acknowledged, every minute for at most an hour
+        if priority == 2 {
+            body["retry"] = json!(60);
+            body["expire"] = json!(3600);
+        }
+
+        post_json(&format!("{}/1/messages.json", self.api_url), &body)
+    }
+}
@@ -0,0 +1,158 @@
+//! Sends events to a Slack incoming webhook as Block Kit messages
+use serde_json::{Value, json};
+
+use super::{Event, Notifier, post_json, required_str, truncate};
+
+/// What Slack accepts in a section, longer messages are refused as a whole
+const SECTION_TEXT_MAX_CHARS: usize = 3000;
+const FIELD_TEXT_MAX_CHARS: usize = 2000;
+
+/// Posts to a Slack incoming webhook
+pub struct SlackNotifier {
+    /// The incoming webhook URL, it already decides the channel
+    pub url: String,
+}
+
+impl SlackNotifier {
+    /// Reads the notifier from `{"type": "slack", "url": "https://hooks.slack.com/services/..."}`
+    ///
+    /// # Arguments
+    /// * `entry: &Value` - The notifier entry
+    ///
+    /// # Returns
+    /// * `Result<SlackNotifier, String>` - Err if the url is missing
+    pub fn from_value(entry: &Value) -> Result<Self, String> {
+        Ok(SlackNotifier {
+            url: required_str(entry, "url", "Slack")?,
+        })
+    }
+}
+
+/// Escapes the characters Slack treats as markup in mrkdwn text
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Builds the Block Kit message, `text` is the fallback shown in notifications
+///
+/// # Arguments
+/// * `event: &Event` - What happened
+///
+/// # Returns
+/// * `Value` - The message body
+pub fn message(event: &Event) -> Value {
+    let fields: Vec<Value> = event
+        .facts()
+        .iter()
+        .map(|(label, value)| {
+            // The label, the line break and the markup count too
+            let room = FIELD_TEXT_MAX_CHARS - label.chars().count() - 5;
+            json!({
+                "type": "mrkdwn",
+                "text": format!("*{}*\n`{}`", label, truncate(&escape(value), room)),
+            })
+        })
+        .collect();
+
+    let mut blocks = vec![
+        json!({
+            "type": "header",
+            "text": { "type": "plain_text", "text": event.subject() },
+        }),
+        json!({ "type": "section", "fields": fields }),
+    ];
+
+    if let Some(notes) = event.notes() {
+        blocks.push(json!({
+            "type": "section",
+            "text": { "type": "mrkdwn", "text": truncate(&escape(&notes), SECTION_TEXT_MAX_CHARS) },
+        }));
+    }
+
+    blocks.push(json!({
+        "type": "context",
+        "elements": [{
+            "type": "mrkdwn",
+            "text": format!("Sent by public_ip_notifier at {}", chrono::Utc::now().to_rfc3339()),
+        }],
+    }));
+
+    json!({
+        "text": event.message(),
+        "blocks": blocks,
+    })
+}
+
+impl Notifier for SlackNotifier {
+    fn name(&self) -> String {
+        "Slack".to_string()
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), Box<dyn std::error::Error>> {
+        post_json(&self.url, &message(event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The text of every block and field, in order
+    fn texts(body: &Value) -> Vec<String> {
+        let mut texts = Vec::new();
+        for block in body["blocks"].as_array().unwrap() {
+            if let Some(text) = block["text"]["text"].as_str() {
+                texts.push(text.to_string());
+            }
+            for field in block["fields"].as_array().into_iter().flatten() {
+                texts.push(field["text"].as_str().unwrap().to_string());
+            }
+        }
+        texts
+    }
+
+    #[test]
+    fn facts_become_escaped_fields() {
+        let event = Event::FailureThreshold {
+            failures: 3,
+            error: "<html> & more".to_string(),
+        };
+
+        let body = message(&event);
+
+        assert_eq!(body["text"], event.message());
+        assert_eq!(body["blocks"][0]["type"], "header");
+        assert_eq!(
+            body["blocks"][0]["text"]["text"],
+            "Could not retrieve your IP"
+        );
+        let texts = texts(&body);
+        assert!(texts.contains(&"*Sequential failures*\n`3`".to_string()));
+        assert!(texts.contains(&"*Last error*\n`&lt;html&gt; &amp; more`".to_string()));
+    }
+
+    #[test]
+    fn long_text_fits_in_sections_and_fields() {
+        let error = message(&Event::FailureThreshold {
+            failures: 3,
+            error: "e".repeat(5000),
+        });
+        let field = texts(&error)
+            .into_iter()
+            .find(|text| text.starts_with("*Last error*"))
+            .unwrap();
+        assert_eq!(field.chars().count(), FIELD_TEXT_MAX_CHARS);
+        assert!(field.ends_with("e…`"), "{}", field);
+
+        let notes = message(&Event::Test {
+            message: "n".repeat(10_000),
+        });
+        let section = &notes["blocks"][2]["text"]["text"];
+        assert_eq!(
+            section.as_str().unwrap().chars().count(),
+            SECTION_TEXT_MAX_CHARS
+        );
+    }
+}
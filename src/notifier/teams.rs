@@ -0,0 +1,139 @@
+//! Sends events to Microsoft Teams as Adaptive Cards
+use serde_json::{Value, json};
+
+use super::{Event, Notifier, post_json, required_str};
+
+/// Posts to a Teams incoming webhook, either a Workflows webhook or an older connector
+pub struct TeamsNotifier {
+    /// The webhook URL
+    pub url: String,
+}
+
+impl TeamsNotifier {
+    /// Reads the notifier from `{"type": "teams", "url": "..."}`
+    ///
+    /// # Arguments
+    /// * `entry: &Value` - The notifier entry
+    ///
+    /// # Returns
+    /// * `Result<TeamsNotifier, String>` - Err if the url is missing
+    pub fn from_value(entry: &Value) -> Result<Self, String> {
+        Ok(TeamsNotifier {
+            url: required_str(entry, "url", "Teams")?,
+        })
+    }
+}
+
+/// Builds the message holding an Adaptive Card, which both kinds of webhook accept
+///
+/// # Arguments
+/// * `event: &Event` - What happened
+///
+/// # Returns
+/// * `Value` - The message body
+pub fn message(event: &Event) -> Value {
+    let facts: Vec<Value> = event
+        .facts()
+        .iter()
+        .map(|(label, value)| json!({ "title": label, "value": value }))
+        .collect();
+
+    let color = match event {
+        Event::FailureThreshold { .. } | Event::DnsDrift { .. } | Event::SiteSilent { .. } => {
+            "Attention"
+        }
+        Event::Recovered { .. } | Event::SiteBackOnline { .. } => "Good",
+        _ => "Accent",
+    };
+
+    let mut body = vec![
+        json!({
+            "type": "TextBlock",
+            "text": event.subject(),
+            "size": "Large",
+            "weight": "Bolder",
+            "color": color,
+            "wrap": true,
+        }),
+        json!({ "type": "FactSet", "facts": facts }),
+    ];
+
+    if let Some(notes) = event.notes() {
+        body.push(json!({
+            "type": "TextBlock",
+            "text": notes,
+            "wrap": true,
+            "isSubtle": true,
+        }));
+    }
+
+    json!({
+        "type": "message",
+        "attachments": [{
+            "contentType": "application/vnd.microsoft.card.adaptive",
+            "content": {
+                "$schema": "http://adaptivecards.io/schemas/adaptive-card.json",
+                "type": "AdaptiveCard",
+                "version": "1.4",
+                "body": body,
+            },
+        }],
+    })
+}
+
+impl Notifier for TeamsNotifier {
+    fn name(&self) -> String {
+        "Teams".to_string()
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), Box<dyn std::error::Error>> {
+        post_json(&self.url, &message(event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_becomes_an_adaptive_card() {
+        let body = message(&Event::Recovered { failures: 4 });
+
+        assert_eq!(body["type"], "message");
+        let card = &body["attachments"][0]["content"];
+        assert_eq!(card["type"], "AdaptiveCard");
+        assert_eq!(
+            card["body"][0]["text"],
+            Event::Recovered { failures: 4 }.subject()
+        );
+        assert_eq!(card["body"][0]["color"], "Good");
+        assert!(
+            card["body"][1]["facts"]
+                .as_array()
+                .unwrap()
+                .contains(&json!({"title": "Failed checks", "value": "4"}))
+        );
+        // Nothing to add, so no notes block
+        assert_eq!(card["body"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn alerts_stand_out_and_notes_follow_the_facts() {
+        let alert = message(&Event::FailureThreshold {
+            failures: 3,
+            error: "timed out".to_string(),
+        });
+        assert_eq!(
+            alert["attachments"][0]["content"]["body"][0]["color"],
+            "Attention"
+        );
+
+        let test = message(&Event::Test {
+            message: "Hello".to_string(),
+        });
+        let body = &test["attachments"][0]["content"]["body"];
+        assert_eq!(body[0]["color"], "Accent");
+        assert_eq!(body[2]["text"], "Hello");
+        assert_eq!(body[2]["isSubtle"], true);
+    }
+}
@@ -0,0 +1,228 @@
+//! Sends events through a Telegram bot, formatted with MarkdownV2
+use serde_json::{Value, json};
+
+use crate::http;
+
+use super::{Event, Notifier, required_str};
+
+/// Where the Bot API lives unless the entry points somewhere else
+pub const DEFAULT_API_URL: &str = "https://api.telegram.org";
+
+/// Sends messages to a chat as a bot
+pub struct TelegramNotifier {
+    /// The token from BotFather
+    pub bot_token: String,
+    /// The chat, group or channel to post in, a number or `@channelname`
+    pub chat_id: String,
+    /// The Bot API server, only changed for self hosted API servers
+    pub api_url: String,
+}
+
+impl TelegramNotifier {
+    /// Reads the notifier from `{"type": "telegram", "botToken": "...", "chatId": "...", "apiUrl": "..."}`
+    ///
+    /// # Arguments
+    /// * `entry: &Value` - The notifier entry
+    ///
+    /// # Returns
+    /// * `Result<TelegramNotifier, String>` - Err if the token or chat is missing
+    pub fn from_value(entry: &Value) -> Result<Self, String> {
+        // Chat IDs are numbers, but are often written as strings
+        let chat_id = match entry.get("chatId") {
+            Some(Value::Number(id)) => id.to_string(),
+            _ => required_str(entry, "chatId", "Telegram")?,
+        };
+
+        Ok(TelegramNotifier {
+            bot_token: required_str(entry, "botToken", "Telegram")?,
+            chat_id,
+            api_url: entry
+                .get("apiUrl")
+                .and_then(|v| v.as_str())
+                .unwrap_or(DEFAULT_API_URL)
+                .trim_end_matches('/')
+                .to_string(),
+        })
+    }
+}
+
+/// Escapes text for MarkdownV2, where every one of these characters is markup
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        if "_*[]()~`>#+-=|{}.!\\".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Escapes text for inside a MarkdownV2 code span, only ` and \ are special there
+fn escape_code(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('`', "\\`")
+}
+
+/// Builds the MarkdownV2 text, a bold title followed by the facts
+///
+/// # Arguments
+/// * `event: &Event` - What happened
+///
+/// # Returns
+/// * `String` - The message text
+pub fn message(event: &Event) -> String {
+    let icon = match event {
+        Event::IpChanged { .. } | Event::SiteIpChanged { .. } => "🔄",
+        Event::FailureThreshold { .. } => "🚨",
+        Event::DnsDrift { .. } => "⚠️",
+        Event::SiteSilent { .. } => "🔌",
+        Event::Recovered { .. } | Event::SiteBackOnline { .. } => "✅",
+        Event::Test { .. } => "🧪",
+    };
+
+    let mut text = format!("{} *{}*\n", icon, escape(&event.subject()));
+
+    for (label, value) in event.facts() {
+        text.push_str(&format!("\n*{}:* `{}`", escape(label), escape_code(&value)));
+    }
+
+    if let Some(notes) = event.notes() {
+        text.push_str(&format!("\n\n{}", escape(&notes)));
+    }
+
+    text
+}
+
+impl Notifier for TelegramNotifier {
+    fn name(&self) -> String {
+        format!("Telegram ({})", self.chat_id)
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), Box<dyn std::error::Error>> {
+        let url = format!("{}/bot{}/sendMessage", self.api_url, self.bot_token);
+        // The token is part of the URL, keep it out of the logs
+        let hide_token = |e: String| e.replace(&self.bot_token, "<token>");
+
+        let body = json!({
+            "chat_id": self.chat_id,
+            "text": message(event),
+            "parse_mode": "MarkdownV2",
+        });
+        let answer = http::agent()
+            .post(&url)
+            .set("Content-Type", "application/json")
+            .send_string(&body.to_string())
+            .map_err(|e| hide_token(http::describe_error(e)))?
+            .into_string()?;
+
+        // The Bot API says whether the message went out in the body, not only in the status
+        let answer: Value = serde_json::from_str(&answer).unwrap_or_default();
+        if answer.get("ok").and_then(|v| v.as_bool()) == Some(false) {
+            return Err(hide_token(format!(
+                "Telegram refused the message: {}",
+                answer
+                    .get("description")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("no description")
+            ))
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{http::mock, ip_check::IpFamily};
+
+    fn notifier(url: &str) -> TelegramNotifier {
+        TelegramNotifier::from_value(&json!({
+            "type": "telegram",
+            "botToken": "123:secret",
+            "chatId": -1001234,
+            "apiUrl": format!("{}/", url),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn sends_escaped_markdown() {
+        let server = mock::serve(|_| (200, r#"{"ok": true, "result": {}}"#.to_string()));
+
+        notifier(&server.url)
+            .notify(&Event::IpChanged {
+                family: IpFamily::V4,
+                old_ip: "203.0.113.7".to_string(),
+                new_ip: "203.0.113.8".parse().unwrap(),
+                details: vec!["Seen by my-router.example.com!".to_string()],
+            })
+            .unwrap();
+
+        let request = &server.requests()[0];
+        assert_eq!(request.url, "/bot123:secret/sendMessage");
+        let body = request.json();
+        assert_eq!(body["chat_id"], "-1001234");
+        assert_eq!(body["parse_mode"], "MarkdownV2");
+
+        let text = body["text"].as_str().unwrap();
+        assert!(text.starts_with("🔄 *Your IP Changed\\!*\n"), "{}", text);
+        // Code spans only escape ` and \\, the address stays readable there
+        assert!(text.contains("*New IP:* `203.0.113.8`"), "{}", text);
+        assert!(text.contains("*Old IP:* `203.0.113.7`"), "{}", text);
+        assert!(
+            text.ends_with("Seen by my\\-router\\.example\\.com\\!"),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn escapes_every_markup_character() {
+        assert_eq!(escape("203.0.113.7"), "203\\.0\\.113\\.7");
+        assert_eq!(escape("my-host!"), "my\\-host\\!");
+        assert_eq!(escape("a_b*c[d]"), "a\\_b\\*c\\[d\\]");
+    }
+
+    #[test]
+    fn refused_message_is_an_error_without_the_token() {
+        let server = mock::serve(|_| {
+            (
+                400,
+                r#"{"ok": false, "error_code": 400, "description": "Bad Request: can't parse entities"}"#
+                    .to_string(),
+            )
+        });
+
+        let error = notifier(&server.url)
+            .notify(&Event::Recovered { failures: 3 })
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("can't parse entities"), "{}", error);
+        assert!(!error.contains("secret"), "{}", error);
+    }
+
+    #[test]
+    fn ok_false_with_a_success_status_is_an_error() {
+        let server = mock::serve(|_| {
+            (
+                200,
+                r#"{"ok": false, "description": "Forbidden: bot was kicked"}"#.to_string(),
+            )
+        });
+
+        let error = notifier(&server.url)
+            .notify(&Event::Recovered { failures: 3 })
+            .unwrap_err()
+            .to_string();
+
+        assert_eq!(
+            error,
+            "Telegram refused the message: Forbidden: bot was kicked"
+        );
+    }
+}
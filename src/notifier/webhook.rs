@@ -0,0 +1,202 @@
+//! Sends events to any HTTP endpoint, with the body built from a JSON template
+use serde_json::{Map, Value, json};
+
+use crate::http;
+
+use super::{Event, Notifier, render, required_str};
+
+/// Calls a URL with a templated JSON body
+pub struct WebhookNotifier {
+    /// Where the request goes, placeholders are filled in here too
+    pub url: String,
+    /// The HTTP method, POST unless configured
+    pub method: String,
+    /// Extra headers, placeholders are filled in the values
+    pub headers: Vec<(String, String)>,
+    /// The body template, every string in it has its placeholders filled in
+    pub body: Value,
+}
+
+impl WebhookNotifier {
+    /// Reads the webhook from its config entry
+    ///
+    /// `{"type": "webhook", "url": "...", "method": "POST", "headers": {"Name": "value"}, "body": {...}}`,
+    /// only the url is required
+    ///
+    /// # Arguments
+    /// * `entry: &Value` - The notifier entry
+    ///
+    /// # Returns
+    /// * `Result<WebhookNotifier, String>` - Err if the url is missing
+    pub fn from_value(entry: &Value) -> Result<Self, String> {
+        let url = required_str(entry, "url", "Webhook")?;
+
+        let headers = entry
+            .get("headers")
+            .and_then(|v| v.as_object())
+            .map(|headers| {
+                headers
+                    .iter()
+                    .map(|(name, value)| {
+                        (name.clone(), value.as_str().unwrap_or_default().to_string())
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(WebhookNotifier {
+            url,
+            method: entry
+                .get("method")
+                .and_then(|v| v.as_str())
+                .unwrap_or("POST")
+                .to_uppercase(),
+            headers,
+            body: entry.get("body").cloned().unwrap_or_else(default_body),
+        })
+    }
+}
+
+/// The body sent when none is configured, every placeholder under its own name
+pub fn default_body() -> Value {
+    json!({
+        "event": "{{event}}",
+        "hostname": "{{hostname}}",
+        "family": "{{family}}",
+        "old_ip": "{{old_ip}}",
+        "new_ip": "{{new_ip}}",
+        "failures": "{{failures}}",
+        "message": "{{message}}",
+        "timestamp": "{{timestamp}}",
+    })
+}
+
+/// Fills in the placeholders of every string in the template, keys included
+///
+/// Working on the parsed JSON means values with quotes or newlines can't break the body
+///
+/// # Arguments
+/// * `template: &Value` - The body template
+/// * `placeholders: &[(&str, String)]` - Names and values from [`Event::placeholders`]
+///
+/// # Returns
+/// * `Value` - The rendered body
+pub fn render_value(template: &Value, placeholders: &[(&str, String)]) -> Value {
+    match template {
+        Value::String(text) => Value::String(render(text, placeholders)),
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|item| render_value(item, placeholders))
+                .collect(),
+        ),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| (render(key, placeholders), render_value(value, placeholders)))
+                .collect::<Map<String, Value>>(),
+        ),
+        other => other.clone(),
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> String {
+        format!("webhook ({} {})", self.method, self.url)
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), Box<dyn std::error::Error>> {
+        let placeholders = event.placeholders();
+        let url = render(&self.url, &placeholders);
+        let body = render_value(&self.body, &placeholders);
+
+        let mut request = http::agent()
+            .request(&self.method, &url)
+            .set("Content-Type", "application/json");
+
+        for (name, value) in &self.headers {
+            request = request.set(name, &render(value, &placeholders));
+        }
+
+        // GET requests carry no body
+        let result = if self.method == "GET" {
+            request.call()
+        } else {
+            request.send_string(&body.to_string())
+        };
+
+        result.map_err(http::describe_error)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::mock;
+
+    #[test]
+    fn renders_the_template_into_json() {
+        let server = mock::serve(|_| (200, "{}".to_string()));
+        let notifier = WebhookNotifier::from_value(&json!({
+            "type": "webhook",
+            "url": format!("{}/hook?event={{{{event}}}}", server.url),
+            "headers": {"X-Event": "{{event}}"},
+            "body": {"text": "{{message}}", "{{event}}": ["{{subject}}"], "count": 3},
+        }))
+        .unwrap();
+        // Quotes, backslashes and newlines would break a body built as text
+        let message = "He said \"hi\"\\\nthen left".to_string();
+
+        notifier
+            .notify(&Event::Test {
+                message: message.clone(),
+            })
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.url, "/hook?event=test");
+        assert_eq!(request.header("X-Event"), Some("test"));
+        assert_eq!(
+            request.json(),
+            json!({"text": message, "test": ["IP Change Notifier test"], "count": 3})
+        );
+    }
+
+    #[test]
+    fn default_body_carries_the_addresses() {
+        let server = mock::serve(|_| (200, "{}".to_string()));
+        let notifier = WebhookNotifier::from_value(&json!({"url": server.url})).unwrap();
+
+        notifier
+            .notify(&Event::IpChanged {
+                family: crate::ip_check::IpFamily::V4,
+                old_ip: "192.0.2.1".to_string(),
+                new_ip: "192.0.2.2".parse().unwrap(),
+                details: Vec::new(),
+            })
+            .unwrap();
+
+        let body = server.requests()[0].json();
+        assert_eq!(body["event"], "ip_changed");
+        assert_eq!(body["old_ip"], "192.0.2.1");
+        assert_eq!(body["new_ip"], "192.0.2.2");
+        assert_eq!(body["family"], "IPv4");
+    }
+
+    #[test]
+    fn error_status_is_reported() {
+        let server = mock::serve(|_| (500, "{\"error\": \"down\"}".to_string()));
+        let notifier = WebhookNotifier::from_value(&json!({"url": server.url})).unwrap();
+
+        let error = notifier
+            .notify(&Event::Recovered { failures: 2 })
+            .unwrap_err();
+
+        assert!(error.to_string().contains("500"), "{}", error);
+    }
+}
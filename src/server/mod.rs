@@ -0,0 +1,818 @@
+//! The collector server, many sites report their checks to it and it alerts on their behalf
+//!
+//! Started with the `server` command. The listen address, the sites and their tokens are in the
+//! `server` object of the config, alerts go through the notifiers and hooks of the same config.
+//! Sites send `Authorization: Bearer <token>` with every report, the sites and stats endpoints
+//! take the `adminToken` instead.
+//!
+//! Sites say how often they check, one that misses `missedIntervals` of them in a row is reported
+//! silent, which is what a site without power or internet looks like from here.
+//!
+//! * `POST /api/v1/reports` - A [`Report`] from a site
+//! * `GET /api/v1/sites` - Every site with its last report and addresses
+//! * `GET /api/v1/sites/<siteId>/stats?since=7d&until=...&family=ipv4` - Success rate and latency of a site
+//! * `GET /health` - Answers while the server runs
+use std::{
+    io::Read,
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde_json::{Value, json};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{
+    collector::{self, Report},
+    config::Config,
+    constants,
+    history::report::ReportOptions,
+    hooks, http,
+    json_handler::{self, ToConfig},
+    notifier::{self, Event},
+    stats,
+};
+
+pub mod store;
+
+use store::Store;
+
+/// The address the server listens on unless the settings name another one
+pub const DEFAULT_LISTEN: &str = "0.0.0.0:8470";
+/// How many check intervals a site may miss before it is reported silent
+const DEFAULT_MISSED_INTERVALS: u64 = 3;
+
+/// A site that may report, and the secret it proves that with
+#[derive(Debug, Clone)]
+pub struct Site {
+    pub site_id: String,
+    pub token: String,
+}
+
+/// The `server` settings from the config
+#[derive(Debug, Clone)]
+pub struct ServerSettings {
+    /// The address and port to listen on, only read when the server starts
+    pub listen: String,
+    /// The token for the site and stats endpoints, None leaves them off
+    pub admin_token: Option<String>,
+    /// How many of its check intervals a site may miss before it is reported silent
+    pub missed_intervals: u64,
+    pub sites: Vec<Site>,
+}
+
+impl ServerSettings {
+    /// Reads the settings from
+    /// `{"listen": "0.0.0.0:8470", "adminToken": "...", "missedIntervals": 3, "sites": [{"siteId": "home", "token": "..."}]}`
+    ///
+    /// # Arguments
+    /// * `value: &Value` - The `server` value of the config
+    ///
+    /// # Returns
+    /// * `Result<ServerSettings, String>` - Err if a site is missing its ID or token, or two share one
+    pub fn from_value(value: &Value) -> Result<Self, String> {
+        let mut sites: Vec<Site> = Vec::new();
+
+        for entry in value
+            .get("sites")
+            .and_then(|v| v.as_array())
+            .into_iter()
+            .flatten()
+        {
+            let setting = |key: &str| {
+                entry
+                    .get(key)
+                    .and_then(|v| v.as_str())
+                    .filter(|v| !v.is_empty())
+                    .map(|v| v.to_string())
+                    .ok_or_else(|| format!("every site needs a {:?}", key))
+            };
+            let site = Site {
+                site_id: setting("siteId")?,
+                token: setting("token")?,
+            };
+
+            if sites.iter().any(|other| other.site_id == site.site_id) {
+                return Err(format!("site {} is listed twice", site.site_id));
+            }
+            if sites.iter().any(|other| other.token == site.token) {
+                return Err(format!(
+                    "site {} shares its token with another site",
+                    site.site_id
+                ));
+            }
+            sites.push(site);
+        }
+
+        Ok(ServerSettings {
+            listen: value
+                .get("listen")
+                .and_then(|v| v.as_str())
+                .filter(|v| !v.is_empty())
+                .unwrap_or(DEFAULT_LISTEN)
+                .to_string(),
+            admin_token: value
+                .get("adminToken")
+                .and_then(|v| v.as_str())
+                .filter(|v| !v.is_empty())
+                .map(|v| v.to_string()),
+            missed_intervals: value
+                .get("missedIntervals")
+                .and_then(|v| v.as_u64())
+                .unwrap_or(DEFAULT_MISSED_INTERVALS)
+                .max(1),
+            sites,
+        })
+    }
+
+    /// Finds the site a token belongs to
+    fn site_for_token(&self, token: &str) -> Option<&Site> {
+        self.sites
+            .iter()
+            .find(|site| constant_time_eq(site.token.as_bytes(), token.as_bytes()))
+    }
+}
+
+/// Compares secrets without the time taken giving away how much of them matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// An answer to a request, always JSON
+struct Reply {
+    status: u16,
+    body: Value,
+}
+
+impl Reply {
+    fn ok(body: Value) -> Self {
+        Reply { status: 200, body }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Self {
+        Reply {
+            status,
+            body: json!({ "error": message.into() }),
+        }
+    }
+}
+
+/// Reads the token of an `Authorization: Bearer <token>` header
+fn bearer_token(request: &Request) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+}
+
+/// Checks the admin token of a request to the site or stats endpoints
+fn check_admin(request: &Request, settings: &ServerSettings) -> Result<(), Reply> {
+    let Some(admin_token) = &settings.admin_token else {
+        return Err(Reply::error(
+            403,
+            "set an adminToken in the server settings to use this endpoint",
+        ));
+    };
+
+    match bearer_token(request) {
+        Some(token) if constant_time_eq(token.as_bytes(), admin_token.as_bytes()) => Ok(()),
+        _ => Err(Reply::error(401, "missing or wrong admin token")),
+    }
+}
+
+/// Locks the store, a request that panicked while holding it doesn't stop the others
+fn lock(store: &Mutex<Store>) -> MutexGuard<'_, Store> {
+    store
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Sends the reply, a client that went away is only logged
+fn respond(request: Request, reply: Reply) {
+    let mut response = Response::from_string(reply.body.to_string()).with_status_code(reply.status);
+
+    if let Ok(header) = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]) {
+        response.add_header(header);
+    }
+    if reply.status == 401
+        && let Ok(header) = Header::from_bytes(&b"WWW-Authenticate"[..], &b"Bearer"[..])
+    {
+        response.add_header(header);
+    }
+
+    if let Err(e) = request.respond(response) {
+        eprintln!("Could not answer a request: {}", e);
+    }
+}
+
+/// Accepts a report from a site, saving it and working out which addresses changed
+///
+/// # Returns
+/// * `Result<Vec<Event>, Reply>` - The change events, Err with the reply for a refused report
+fn receive_report(
+    request: &mut Request,
+    settings: &ServerSettings,
+    store: &Mutex<Store>,
+) -> Result<Vec<Event>, Reply> {
+    let site = bearer_token(request)
+        .and_then(|token| settings.site_for_token(&token).cloned())
+        .ok_or_else(|| Reply::error(401, "missing or unknown site token"))?;
+
+    let too_large = || {
+        Reply::error(
+            413,
+            format!(
+                "reports are limited to {} bytes",
+                constants::SERVER_MAX_REPORT_BYTES
+            ),
+        )
+    };
+    if request
+        .body_length()
+        .is_some_and(|length| length as u64 > constants::SERVER_MAX_REPORT_BYTES)
+    {
+        return Err(too_large());
+    }
+
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(constants::SERVER_MAX_REPORT_BYTES + 1)
+        .read_to_string(&mut body)
+        .map_err(|e| Reply::error(400, format!("could not read the report: {}", e)))?;
+    if body.len() as u64 > constants::SERVER_MAX_REPORT_BYTES {
+        return Err(too_large());
+    }
+
+    let value: Value = serde_json::from_str(&body)
+        .map_err(|e| Reply::error(400, format!("the report is not JSON: {}", e)))?;
+    let report = Report::from_json(&value).map_err(|e| Reply::error(400, e))?;
+
+    if report.site_id != site.site_id {
+        return Err(Reply::error(
+            403,
+            format!("the token doesn't belong to site {}", report.site_id),
+        ));
+    }
+
+    let remote_address = request
+        .remote_addr()
+        .map(|address| address.ip().to_string());
+    // The body is read before the store is locked, so a slow client doesn't hold it
+    let events = lock(store)
+        .record_report(
+            &report,
+            value.get("version").and_then(|v| v.as_str()),
+            remote_address.as_deref(),
+        )
+        .map_err(|e| {
+            eprintln!("Could not save the report of {}: {}", report.site_id, e);
+            Reply::error(500, "could not save the report")
+        })?;
+
+    println!(
+        "Report from {}: {}",
+        report.site_id,
+        report
+            .families
+            .iter()
+            .map(|family| match (family.ip, &family.error) {
+                (Some(ip), _) => format!("{} {}", family.family, ip),
+                (None, error) => format!(
+                    "{} failed ({})",
+                    family.family,
+                    error.as_deref().unwrap_or_default()
+                ),
+            })
+            .collect::<Vec<String>>()
+            .join(", ")
+    );
+
+    Ok(events)
+}
+
+/// Computes the stats of a site, taking the range and family from the query string
+fn site_stats(site_id: &str, query: &str, store: &Mutex<Store>) -> Reply {
+    // The query takes the options of the stats command, without their dashes. A `+` is kept, in
+    // a time it is the offset far more often than an encoded space
+    let args: Vec<String> = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .flat_map(|(key, value)| {
+            [
+                format!("--{}", http::percent_decode(key)),
+                http::percent_decode(value),
+            ]
+        })
+        .collect();
+    let options = match ReportOptions::from_args(&args) {
+        Ok(options) => options,
+        Err(e) => return Reply::error(400, e),
+    };
+
+    let samples = match lock(store).read_samples(site_id, options.family.as_deref()) {
+        Ok(samples) => samples,
+        Err(e) => {
+            eprintln!("Could not read the reports of {}: {}", site_id, e);
+            return Reply::error(500, "could not read the reports");
+        }
+    };
+    if samples.is_empty() {
+        return Reply::error(404, format!("site {} has not reported", site_id));
+    }
+
+    let start = options
+        .since
+        .or_else(|| samples.first().map(|sample| sample.timestamp))
+        .unwrap_or_else(Utc::now);
+    let end = options.until.unwrap_or_else(Utc::now);
+
+    Reply::ok(json!({
+        "siteId": site_id,
+        "start": start.to_rfc3339(),
+        "end": end.to_rfc3339(),
+        "stats": stats::compute(&samples, start, end).to_json(),
+    }))
+}
+
+/// Answers one request
+///
+/// # Returns
+/// * `(Reply, Vec<Event>)` - The reply, and the events to announce once it is sent
+fn handle(
+    request: &mut Request,
+    settings: &ServerSettings,
+    store: &Mutex<Store>,
+) -> (Reply, Vec<Event>) {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    let reply = match (request.method(), segments.as_slice()) {
+        (Method::Get, ["health"]) => Reply::ok(json!({ "status": "ok" })),
+        (Method::Post, _) if path == collector::REPORT_PATH => {
+            return match receive_report(request, settings, store) {
+                Ok(events) => (Reply::ok(json!({ "status": "ok" })), events),
+                Err(reply) => (reply, Vec::new()),
+            };
+        }
+        (Method::Get, ["api", "v1", "sites"]) => match check_admin(request, settings) {
+            Err(reply) => reply,
+            Ok(()) => match lock(store).sites() {
+                Ok(sites) => Reply::ok(json!({
+                    "sites": sites.iter().map(|site| site.to_json()).collect::<Vec<Value>>(),
+                })),
+                Err(e) => {
+                    eprintln!("Could not read the sites: {}", e);
+                    Reply::error(500, "could not read the sites")
+                }
+            },
+        },
+        (Method::Get, ["api", "v1", "sites", site_id, "stats"]) => {
+            match check_admin(request, settings) {
+                Err(reply) => reply,
+                Ok(()) => site_stats(site_id, query, store),
+            }
+        }
+        _ => Reply::error(404, "not found"),
+    };
+
+    (reply, Vec::new())
+}
+
+/// Tells every notifier about the event, runs the hooks that want it and saves it
+fn announce(config: &Config, store: &Mutex<Store>, event: &Event) {
+    notifier::notify(config, event);
+    hooks::run_all(config, event);
+
+    if let Err(e) = lock(store).record_event(event) {
+        eprintln!("Could not save the event: {}", e);
+    }
+}
+
+/// Reports the sites that stopped reporting
+///
+/// Only the configured sites are watched, removing a site from the settings silences it
+///
+/// # Arguments
+/// * `config: &Config` - The config, for the notifiers and hooks
+/// * `settings: &ServerSettings` - The sites and how many intervals they may miss
+/// * `store: &Mutex<Store>` - The collector database
+/// * `started: DateTime<Utc>` - When the server started, a site can't be blamed for reports it missed before
+fn check_heartbeats(
+    config: &Config,
+    settings: &ServerSettings,
+    store: &Mutex<Store>,
+    started: DateTime<Utc>,
+) {
+    // Finding and marking the sites happen under one lock, a report landing in between would
+    // otherwise be followed by a false silent alert
+    let silent: Vec<Event> = {
+        let store = lock(store);
+        let overdue = match store.overdue_sites(settings.missed_intervals, started) {
+            Ok(overdue) => overdue,
+            Err(e) => {
+                eprintln!("Could not check when the sites last reported: {}", e);
+                return;
+            }
+        };
+
+        overdue
+            .into_iter()
+            .filter(|(site_id, _, _)| settings.sites.iter().any(|site| &site.site_id == site_id))
+            .filter_map(|(site_id, last_seen, missed)| {
+                if let Err(e) = store.mark_silent(&site_id) {
+                    // Without the mark the alert would repeat on every check
+                    eprintln!("Could not mark {} as silent: {}", site_id, e);
+                    return None;
+                }
+
+                Some(Event::SiteSilent {
+                    site: site_id,
+                    last_seen,
+                    missed,
+                })
+            })
+            .collect()
+    };
+
+    for event in silent {
+        eprintln!("{}.", event);
+        announce(config, store, &event);
+    }
+}
+
+/// Reads the config and the server settings in it
+fn read_settings() -> Result<(Config, ServerSettings), String> {
+    let config = json_handler::read_json_as_value(&constants::get_config_path()).to_config();
+    let settings = ServerSettings::from_value(&config.server)?;
+
+    Ok((config, settings))
+}
+
+/// Answers one request and announces the events it caused
+///
+/// Runs on a thread of its own, so a client sending its body slowly or a slow notifier only holds
+/// up this request. The config is read again each time, so sites can be added while the server runs
+fn serve(mut request: Request, store: &Mutex<Store>) {
+    let (config, settings) = match read_settings() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Invalid server settings: {}", e);
+            respond(
+                request,
+                Reply::error(500, "the server settings are invalid"),
+            );
+            return;
+        }
+    };
+
+    let (reply, events) = handle(&mut request, &settings, store);
+    respond(request, reply);
+
+    for event in events {
+        println!("{}.", event);
+        announce(&config, store, &event);
+    }
+}
+
+/// Looks for silent sites every [`constants::SERVER_HEARTBEAT_CHECK_SECONDS`], for as long as the server runs
+fn watch_heartbeats(store: &Mutex<Store>, started: DateTime<Utc>) {
+    loop {
+        thread::sleep(Duration::from_secs(
+            constants::SERVER_HEARTBEAT_CHECK_SECONDS,
+        ));
+
+        match read_settings() {
+            Ok((config, settings)) => check_heartbeats(&config, &settings, store, started),
+            Err(e) => eprintln!("Invalid server settings: {}", e),
+        }
+    }
+}
+
+/// Holds one of the [`constants::SERVER_MAX_REQUESTS`] request threads, freed when dropped
+struct RequestSlot(Arc<AtomicUsize>);
+
+impl RequestSlot {
+    /// Takes a slot, None if every one is in use
+    fn take(busy: &Arc<AtomicUsize>) -> Option<Self> {
+        busy.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |taken| {
+            (taken < constants::SERVER_MAX_REQUESTS).then_some(taken + 1)
+        })
+        .ok()
+        .map(|_| RequestSlot(busy.clone()))
+    }
+}
+
+impl Drop for RequestSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Runs the collector server until it is stopped
+///
+/// # Returns
+/// * `Result<(), Box<dyn std::error::Error>>` - Err if the settings are invalid or the address can't be listened on
+pub fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let (_, settings) = read_settings()?;
+
+    let server = Server::http(&settings.listen)
+        .map_err(|e| format!("Could not listen on {}: {}", settings.listen, e))?;
+    let store = Arc::new(Mutex::new(Store::open_default()?));
+
+    if settings.sites.is_empty() {
+        eprintln!("No sites are configured, every report will be refused.");
+    }
+    println!(
+        "Collector listening on {} for {} sites, saving to {}.",
+        settings.listen,
+        settings.sites.len(),
+        constants::get_server_db_path()
+    );
+
+    let started = Utc::now();
+    let watcher_store = store.clone();
+    thread::spawn(move || watch_heartbeats(&watcher_store, started));
+
+    let busy = Arc::new(AtomicUsize::new(0));
+    for request in server.incoming_requests() {
+        // Slow clients can't pile up threads without end, past the limit requests are turned away
+        let Some(slot) = RequestSlot::take(&busy) else {
+            respond(
+                request,
+                Reply::error(503, "too many requests at once, try again later"),
+            );
+            continue;
+        };
+
+        let store = store.clone();
+        thread::spawn(move || {
+            let _slot = slot;
+            serve(request, &store);
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers requests with `handle` on a loopback port, with an in-memory store
+    fn collector(settings: Value) -> (String, Arc<Mutex<Store>>) {
+        let settings = ServerSettings::from_value(&settings).unwrap();
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let store = Arc::new(Mutex::new(Store::open(":memory:").unwrap()));
+        let served = store.clone();
+
+        thread::spawn(move || {
+            for mut request in server.incoming_requests() {
+                let (reply, _) = handle(&mut request, &settings, &served);
+                respond(request, reply);
+            }
+        });
+
+        (url, store)
+    }
+
+    /// Sends a request, with the token as a bearer token, and returns the status and JSON answer
+    fn send(method: &str, url: &str, token: Option<&str>, body: Option<&str>) -> (u16, Value) {
+        let mut request = ureq::request(method, url);
+        if let Some(token) = token {
+            request = request.set("Authorization", &format!("Bearer {}", token));
+        }
+        let response = match body {
+            Some(body) => request.send_string(body),
+            None => request.call(),
+        };
+
+        match response {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => (
+                response.status(),
+                serde_json::from_str(&response.into_string().unwrap()).unwrap(),
+            ),
+            Err(e) => panic!("{} {} failed: {}", method, url, e),
+        }
+    }
+
+    fn sites() -> Value {
+        json!({
+            "adminToken": "admin",
+            "sites": [{"siteId": "home", "token": "home-token"}, {"siteId": "cabin", "token": "cabin-token"}],
+        })
+    }
+
+    fn report(site_id: &str) -> String {
+        json!({
+            "siteId": site_id,
+            "checkIntervalMinutes": 5,
+            "families": [{"family": "ipv4", "ip": "203.0.113.7"}],
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn reads_the_settings_with_defaults() {
+        let settings = ServerSettings::from_value(&json!({
+            "sites": [{"siteId": "home", "token": "a"}, {"siteId": "cabin", "token": "b"}],
+            "missedIntervals": 0,
+        }))
+        .unwrap();
+
+        assert_eq!(settings.listen, DEFAULT_LISTEN);
+        assert_eq!(settings.admin_token, None);
+        // At least one interval, a site can't be late before it was due
+        assert_eq!(settings.missed_intervals, 1);
+        assert_eq!(settings.site_for_token("b").unwrap().site_id, "cabin");
+        assert!(settings.site_for_token("c").is_none());
+        assert!(settings.site_for_token("").is_none());
+    }
+
+    #[test]
+    fn duplicate_sites_and_tokens_are_refused() {
+        let duplicate_site = json!({"sites": [
+            {"siteId": "home", "token": "a"},
+            {"siteId": "home", "token": "b"},
+        ]});
+        let shared_token = json!({"sites": [
+            {"siteId": "home", "token": "a"},
+            {"siteId": "cabin", "token": "a"},
+        ]});
+
+        assert_eq!(
+            ServerSettings::from_value(&duplicate_site).unwrap_err(),
+            "site home is listed twice"
+        );
+        assert_eq!(
+            ServerSettings::from_value(&shared_token).unwrap_err(),
+            "site cabin shares its token with another site"
+        );
+    }
+
+    #[test]
+    fn sites_need_an_id_and_a_token() {
+        let error =
+            ServerSettings::from_value(&json!({"sites": [{"siteId": "home", "token": ""}]}))
+                .unwrap_err();
+
+        assert_eq!(error, "every site needs a \"token\"");
+    }
+
+    #[test]
+    fn silent_site_is_announced_once() {
+        // No notifiers or hooks, the event only goes into the store
+        let config = json!({"notifiers": [], "hooks": []}).to_config();
+        let settings = ServerSettings::from_value(&json!({
+            "sites": [{"siteId": "home", "token": "a"}],
+        }))
+        .unwrap();
+        let store = Mutex::new(Store::open(":memory:").unwrap());
+        let report = Report::from_json(&json!({
+            "siteId": "home",
+            "checkIntervalMinutes": 5,
+            "families": [{"family": "ipv4", "ip": "203.0.113.7"}],
+        }))
+        .unwrap();
+        lock(&store).record_report(&report, None, None).unwrap();
+        lock(&store)
+            .conn
+            .execute(
+                "UPDATE sites SET last_seen = ?1",
+                [(Utc::now() - chrono::Duration::hours(1)).to_rfc3339()],
+            )
+            .unwrap();
+        let started = Utc::now() - chrono::Duration::days(1);
+
+        check_heartbeats(&config, &settings, &store, started);
+        check_heartbeats(&config, &settings, &store, started);
+
+        let kinds: Vec<String> = lock(&store)
+            .conn
+            .prepare("SELECT kind FROM events")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(kinds, vec!["site_silent"]);
+        assert!(lock(&store).sites().unwrap()[0].silent_since.is_some());
+    }
+
+    #[test]
+    fn reports_need_the_token_of_their_site() {
+        let (url, store) = collector(sites());
+        let reports = format!("{}{}", url, collector::REPORT_PATH);
+
+        let (status, body) = send("POST", &reports, None, Some(&report("home")));
+        assert_eq!(status, 401);
+        assert_eq!(body["error"], "missing or unknown site token");
+        assert_eq!(
+            send("POST", &reports, Some("guess"), Some(&report("home"))).0,
+            401
+        );
+
+        let (status, body) = send("POST", &reports, Some("cabin-token"), Some(&report("home")));
+        assert_eq!(status, 403);
+        assert_eq!(body["error"], "the token doesn't belong to site home");
+
+        assert!(lock(&store).sites().unwrap().is_empty());
+        assert_eq!(
+            send("POST", &reports, Some("home-token"), Some(&report("home"))).0,
+            200
+        );
+        assert_eq!(lock(&store).sites().unwrap()[0].site_id, "home");
+    }
+
+    #[test]
+    fn oversized_and_broken_reports_are_refused() {
+        let (url, _) = collector(sites());
+        let reports = format!("{}{}", url, collector::REPORT_PATH);
+        let padding = "x".repeat(constants::SERVER_MAX_REPORT_BYTES as usize);
+        let oversized = json!({"siteId": "home", "padding": padding}).to_string();
+
+        let (status, body) = send("POST", &reports, Some("home-token"), Some(&oversized));
+        assert_eq!(status, 413);
+        assert_eq!(
+            body["error"],
+            format!(
+                "reports are limited to {} bytes",
+                constants::SERVER_MAX_REPORT_BYTES
+            )
+        );
+        assert_eq!(send("POST", &reports, Some("home-token"), Some("{")).0, 400);
+        assert_eq!(
+            send("POST", &reports, Some("home-token"), Some("{}")).0,
+            400
+        );
+    }
+
+    #[test]
+    fn admin_endpoints_need_the_admin_token() {
+        let (url, _) = collector(sites());
+        let sites = format!("{}/api/v1/sites", url);
+
+        assert_eq!(send("GET", &sites, None, None).0, 401);
+        assert_eq!(send("GET", &sites, Some("home-token"), None).0, 401);
+        let (status, body) = send("GET", &sites, Some("admin"), None);
+        assert_eq!(status, 200);
+        assert_eq!(body, json!({"sites": []}));
+        assert_eq!(send("GET", &format!("{}/health", url), None, None).0, 200);
+        assert_eq!(send("GET", &format!("{}/nothing", url), None, None).0, 404);
+
+        // Without an admin token in the settings the endpoints are off
+        let (url, _) = collector(json!({"sites": [{"siteId": "home", "token": "home-token"}]}));
+        let (status, _) = send("GET", &format!("{}/api/v1/sites", url), Some(""), None);
+        assert_eq!(status, 403);
+    }
+
+    #[test]
+    fn stats_read_an_encoded_query() {
+        let (url, _) = collector(sites());
+        send(
+            "POST",
+            &format!("{}{}", url, collector::REPORT_PATH),
+            Some("home-token"),
+            Some(&report("home")),
+        );
+        let stats = |site: &str, query: &str| {
+            send(
+                "GET",
+                &format!("{}/api/v1/sites/{}/stats?{}", url, site, query),
+                Some("admin"),
+                None,
+            )
+        };
+
+        let (status, body) = stats("home", "since=2024-05-01%2000%3A00&family=ipv4");
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(body["start"], "2024-05-01T00:00:00+00:00");
+        assert_eq!(body["stats"]["summary"]["checks"], 1);
+
+        assert_eq!(stats("home", "since=2024-05-01T02%3A00%3A00+02:00").0, 200);
+        assert_eq!(stats("home", "family=ipv5").0, 400);
+        assert_eq!(stats("cabin", "").0, 404);
+    }
+
+    #[test]
+    fn request_slots_run_out_and_come_back() {
+        let busy = Arc::new(AtomicUsize::new(0));
+
+        let slots: Vec<RequestSlot> = (0..constants::SERVER_MAX_REQUESTS)
+            .map(|_| RequestSlot::take(&busy).unwrap())
+            .collect();
+        assert!(RequestSlot::take(&busy).is_none());
+
+        drop(slots);
+        assert_eq!(busy.load(Ordering::SeqCst), 0);
+        assert!(RequestSlot::take(&busy).is_some());
+    }
+}
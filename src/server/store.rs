@@ -0,0 +1,540 @@
+//! The collector database, what every site reported, kept in SQLite at `constants::get_server_db_path()`
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde_json::{Value, json};
+
+use crate::{
+    collector::Report,
+    constants,
+    history::{self, family_name, report, timestamp_now},
+    notifier::Event,
+    stats::Sample,
+};
+
+/// The schema changes in order, the database's `user_version` is how many have been applied
+///
+/// Released migrations are never edited, changes go into a new one at the end
+const MIGRATIONS: &[&str] = &[
+    // 1: sites, their reports with the outcome per provider, and events
+    "CREATE TABLE sites (
+        site_id TEXT PRIMARY KEY,
+        hostname TEXT NOT NULL,
+        version TEXT,
+        check_interval_minutes INTEGER NOT NULL,
+        remote_address TEXT,
+        first_seen TEXT NOT NULL,
+        last_seen TEXT NOT NULL
+    );
+    CREATE TABLE reports (
+        id INTEGER PRIMARY KEY,
+        site_id TEXT NOT NULL REFERENCES sites (site_id) ON DELETE CASCADE,
+        timestamp TEXT NOT NULL,
+        family TEXT NOT NULL,
+        ip TEXT,
+        error TEXT,
+        sequential_failures INTEGER NOT NULL
+    );
+    CREATE INDEX reports_site_timestamp ON reports (site_id, timestamp);
+    CREATE TABLE report_providers (
+        id INTEGER PRIMARY KEY,
+        report_id INTEGER NOT NULL REFERENCES reports (id) ON DELETE CASCADE,
+        provider TEXT NOT NULL,
+        ip TEXT,
+        latency_ms INTEGER NOT NULL,
+        error TEXT
+    );
+    CREATE INDEX report_providers_report_id ON report_providers (report_id);
+    CREATE TABLE events (
+        id INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        site_id TEXT,
+        kind TEXT NOT NULL,
+        family TEXT,
+        old_ip TEXT,
+        new_ip TEXT,
+        message TEXT NOT NULL
+    );
+    CREATE INDEX events_timestamp ON events (timestamp);",
+    // 2: when a site was reported silent, NULL while it reports
+    "ALTER TABLE sites ADD COLUMN silent_since TEXT;",
+];
+
+/// What the server knows about a site
+#[derive(Debug, Clone)]
+pub struct SiteStatus {
+    pub site_id: String,
+    pub hostname: String,
+    pub version: Option<String>,
+    pub check_interval_minutes: u64,
+    /// Where the last report came from
+    pub remote_address: Option<String>,
+    pub first_seen: String,
+    pub last_seen: String,
+    /// When the site was reported silent, None while it reports
+    pub silent_since: Option<String>,
+    /// The last reported address of each family
+    pub addresses: Vec<(String, String)>,
+}
+
+impl SiteStatus {
+    /// The status as it is handed out by the API
+    pub fn to_json(&self) -> Value {
+        let addresses: serde_json::Map<String, Value> = self
+            .addresses
+            .iter()
+            .map(|(family, ip)| (family.clone(), Value::String(ip.clone())))
+            .collect();
+
+        json!({
+            "siteId": self.site_id,
+            "hostname": self.hostname,
+            "version": self.version,
+            "checkIntervalMinutes": self.check_interval_minutes,
+            "remoteAddress": self.remote_address,
+            "firstSeen": self.first_seen,
+            "lastSeen": self.last_seen,
+            "silentSince": self.silent_since,
+            "addresses": addresses,
+        })
+    }
+}
+
+/// A connection to the collector database
+pub struct Store {
+    pub conn: Connection,
+}
+
+impl Store {
+    /// Opens the database, creating it and applying any missing migrations
+    ///
+    /// # Arguments
+    /// * `path: &str` - The database file
+    ///
+    /// # Returns
+    /// * `rusqlite::Result<Store>` - Err if the file can't be opened or migrated
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let mut conn = Connection::open(path)?;
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        history::migrate(&mut conn, MIGRATIONS)?;
+
+        Ok(Store { conn })
+    }
+
+    /// Opens the database in the data directory
+    pub fn open_default() -> rusqlite::Result<Self> {
+        Self::open(&constants::get_server_db_path())
+    }
+
+    /// The last address a site reported for a family
+    fn last_ip(&self, site_id: &str, family: &str) -> rusqlite::Result<Option<String>> {
+        self.conn
+            .query_row(
+                "SELECT ip FROM reports WHERE site_id = ?1 AND family = ?2 AND ip IS NOT NULL
+                 ORDER BY id DESC LIMIT 1",
+                params![site_id, family],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    /// Saves a report and works out which addresses changed since the site's last report, and
+    /// whether the site was reported silent before it
+    ///
+    /// # Arguments
+    /// * `report: &Report` - What the site sent
+    /// * `version: Option<&str>` - The program version the site runs
+    /// * `remote_address: Option<&str>` - Where the report came from
+    ///
+    /// # Returns
+    /// * `rusqlite::Result<Vec<Event>>` - A change event per family whose address differs from the last one,
+    ///   after a back online event if the site was silent
+    pub fn record_report(
+        &mut self,
+        report: &Report,
+        version: Option<&str>,
+        remote_address: Option<&str>,
+    ) -> rusqlite::Result<Vec<Event>> {
+        let now = timestamp_now();
+        let mut events = Vec::new();
+
+        let silent: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT last_seen FROM sites WHERE site_id = ?1 AND silent_since IS NOT NULL",
+                params![report.site_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(last_seen) = silent {
+            events.push(Event::SiteBackOnline {
+                site: report.site_id.clone(),
+                silent_for: DateTime::parse_from_rfc3339(&last_seen)
+                    .map(|last_seen| Utc::now() - last_seen.with_timezone(&Utc))
+                    .unwrap_or_default(),
+            });
+        }
+
+        for family in &report.families {
+            let Some(ip) = family.ip else {
+                continue;
+            };
+            let last_ip = self.last_ip(&report.site_id, family_name(family.family))?;
+
+            // The first address of a site is nothing to tell about
+            if let Some(last_ip) = last_ip
+                && last_ip.parse::<IpAddr>().ok() != Some(ip)
+            {
+                events.push(Event::SiteIpChanged {
+                    site: report.site_id.clone(),
+                    family: family.family,
+                    old_ip: last_ip,
+                    new_ip: ip,
+                });
+            }
+        }
+
+        let tx = self.conn.transaction()?;
+
+        tx.execute(
+            "INSERT INTO sites (site_id, hostname, version, check_interval_minutes, remote_address, first_seen, last_seen)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)
+             ON CONFLICT (site_id) DO UPDATE SET
+                hostname = excluded.hostname,
+                version = excluded.version,
+                check_interval_minutes = excluded.check_interval_minutes,
+                remote_address = excluded.remote_address,
+                last_seen = excluded.last_seen,
+                silent_since = NULL",
+            params![
+                report.site_id,
+                report.hostname,
+                version,
+                report.check_interval_minutes as i64,
+                remote_address,
+                now,
+            ],
+        )?;
+
+        for family in &report.families {
+            tx.execute(
+                "INSERT INTO reports (site_id, timestamp, family, ip, error, sequential_failures)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    report.site_id,
+                    now,
+                    family_name(family.family),
+                    family.ip.map(|ip| ip.to_string()),
+                    family.error,
+                    report.sequential_failures,
+                ],
+            )?;
+            let report_id = tx.last_insert_rowid();
+
+            let mut insert = tx.prepare(
+                "INSERT INTO report_providers (report_id, provider, ip, latency_ms, error)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for provider in &family.providers {
+                insert.execute(params![
+                    report_id,
+                    provider.provider,
+                    provider.ip.map(|ip| ip.to_string()),
+                    provider.latency_ms as i64,
+                    provider.error,
+                ])?;
+            }
+        }
+
+        tx.commit()?;
+
+        Ok(events)
+    }
+
+    /// Saves an event the server sent
+    ///
+    /// # Arguments
+    /// * `event: &Event` - What happened
+    pub fn record_event(&self, event: &Event) -> rusqlite::Result<()> {
+        let (family, old_ip, new_ip) = history::event_columns(event);
+
+        self.conn.execute(
+            "INSERT INTO events (timestamp, site_id, kind, family, old_ip, new_ip, message)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                timestamp_now(),
+                event.site(),
+                event.kind(),
+                family,
+                old_ip,
+                new_ip,
+                event.message(),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Reads every site that has reported, with its last addresses
+    ///
+    /// # Returns
+    /// * `rusqlite::Result<Vec<SiteStatus>>` - The sites, ordered by ID
+    pub fn sites(&self) -> rusqlite::Result<Vec<SiteStatus>> {
+        let mut statement = self.conn.prepare(
+            "SELECT site_id, hostname, version, check_interval_minutes, remote_address, first_seen, last_seen, silent_since
+             FROM sites ORDER BY site_id",
+        )?;
+        let mut sites = statement
+            .query_map([], |row| {
+                Ok(SiteStatus {
+                    site_id: row.get(0)?,
+                    hostname: row.get(1)?,
+                    version: row.get(2)?,
+                    check_interval_minutes: row.get::<_, i64>(3)? as u64,
+                    remote_address: row.get(4)?,
+                    first_seen: row.get(5)?,
+                    last_seen: row.get(6)?,
+                    silent_since: row.get(7)?,
+                    addresses: Vec::new(),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<SiteStatus>>>()?;
+
+        for site in &mut sites {
+            for family in ["ipv4", "ipv6"] {
+                if let Some(ip) = self.last_ip(&site.site_id, family)? {
+                    site.addresses.push((family.to_string(), ip));
+                }
+            }
+        }
+
+        Ok(sites)
+    }
+
+    /// Finds the sites that missed too many of their check intervals and aren't reported silent yet
+    ///
+    /// # Arguments
+    /// * `missed_intervals: u64` - How many intervals may go by without a report
+    /// * `watching_since: DateTime<Utc>` - When the server started, time it was down doesn't count
+    ///
+    /// # Returns
+    /// * `rusqlite::Result<Vec<(String, DateTime<Utc>, u64)>>` - The site IDs, when they last reported and how many intervals they missed
+    pub fn overdue_sites(
+        &self,
+        missed_intervals: u64,
+        watching_since: DateTime<Utc>,
+    ) -> rusqlite::Result<Vec<(String, DateTime<Utc>, u64)>> {
+        let mut statement = self.conn.prepare(
+            "SELECT site_id, last_seen, check_interval_minutes FROM sites WHERE silent_since IS NULL",
+        )?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+            ))
+        })?;
+
+        let now = Utc::now();
+        let mut overdue = Vec::new();
+        for row in rows {
+            let (site_id, last_seen, interval) = row?;
+            let Ok(last_seen) = DateTime::parse_from_rfc3339(&last_seen) else {
+                continue;
+            };
+            let last_seen = last_seen.with_timezone(&Utc);
+            // A site checking continuously is still given a minute, reports bound the interval
+            // but a row written before that could hold anything
+            let Some(interval) = chrono::Duration::try_minutes(interval.max(1)) else {
+                continue;
+            };
+
+            let missed = ((now - last_seen.max(watching_since)).num_seconds()
+                / interval.num_seconds())
+            .max(0) as u64;
+            if missed >= missed_intervals {
+                overdue.push((site_id, last_seen, missed));
+            }
+        }
+
+        Ok(overdue)
+    }
+
+    /// Notes that a site was reported silent, so it is reported once and its return is noticed
+    ///
+    /// # Arguments
+    /// * `site_id: &str` - The site
+    pub fn mark_silent(&self, site_id: &str) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "UPDATE sites SET silent_since = ?1 WHERE site_id = ?2",
+            params![timestamp_now(), site_id],
+        )?;
+
+        Ok(())
+    }
+
+    /// Reads the reports of a site as stats samples, with the mean latency of the providers that answered
+    ///
+    /// # Arguments
+    /// * `site_id: &str` - The site
+    /// * `family: Option<&str>` - `ipv4` or `ipv6`, None for both
+    ///
+    /// # Returns
+    /// * `rusqlite::Result<Vec<Sample>>` - The samples, oldest first
+    pub fn read_samples(
+        &self,
+        site_id: &str,
+        family: Option<&str>,
+    ) -> rusqlite::Result<Vec<Sample>> {
+        report::query_samples(
+            &self.conn,
+            "SELECT reports.timestamp, reports.ip IS NOT NULL,
+                    AVG(CASE WHEN report_providers.error IS NULL THEN report_providers.latency_ms END)
+             FROM reports LEFT JOIN report_providers ON report_providers.report_id = reports.id
+             WHERE reports.site_id = ?1 AND (?2 IS NULL OR reports.family = ?2)
+             GROUP BY reports.id
+             ORDER BY reports.timestamp, reports.id",
+            params![site_id, family],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, SecondsFormat};
+
+    use super::*;
+    use crate::{collector::FamilyReport, ip_check::IpFamily};
+
+    fn store() -> Store {
+        Store::open(":memory:").unwrap()
+    }
+
+    fn report(ipv4: Option<&str>) -> Report {
+        Report {
+            site_id: "home".to_string(),
+            hostname: "router".to_string(),
+            check_interval_minutes: 5,
+            sequential_failures: 0,
+            families: vec![FamilyReport {
+                family: IpFamily::V4,
+                ip: ipv4.map(|ip| ip.parse().unwrap()),
+                error: ipv4.is_none().then(|| "no provider answered".to_string()),
+                providers: Vec::new(),
+            }],
+        }
+    }
+
+    /// Moves the site's last report into the past
+    fn last_seen_ago(store: &Store, ago: Duration) -> DateTime<Utc> {
+        let last_seen = Utc::now() - ago;
+        store
+            .conn
+            .execute(
+                "UPDATE sites SET last_seen = ?1",
+                params![last_seen.to_rfc3339_opts(SecondsFormat::Millis, true)],
+            )
+            .unwrap();
+        last_seen
+    }
+
+    #[test]
+    fn changed_address_is_an_event_but_the_first_one_isnt() {
+        let mut store = store();
+
+        let record = |store: &mut Store, ip| store.record_report(&report(ip), Some("1.1.0"), None);
+        assert!(record(&mut store, Some("203.0.113.7")).unwrap().is_empty());
+        assert!(record(&mut store, Some("203.0.113.7")).unwrap().is_empty());
+        // A failed check has no address to compare
+        assert!(record(&mut store, None).unwrap().is_empty());
+
+        let events = record(&mut store, Some("203.0.113.8")).unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            &events[0],
+            Event::SiteIpChanged { site, family: IpFamily::V4, old_ip, new_ip }
+                if site == "home" && old_ip == "203.0.113.7" && new_ip.to_string() == "203.0.113.8"
+        ));
+
+        let sites = store.sites().unwrap();
+        assert_eq!(sites.len(), 1);
+        assert_eq!(sites[0].version.as_deref(), Some("1.1.0"));
+        assert_eq!(
+            sites[0].addresses,
+            vec![("ipv4".to_string(), "203.0.113.8".to_string())]
+        );
+    }
+
+    #[test]
+    fn silent_site_is_reported_once_and_welcomed_back() {
+        let mut store = store();
+        store
+            .record_report(&report(Some("203.0.113.7")), None, None)
+            .unwrap();
+        let a_day_ago = Utc::now() - Duration::days(1);
+
+        // Reporting on time, then four of its five minute intervals missed
+        assert!(store.overdue_sites(3, a_day_ago).unwrap().is_empty());
+        let last_seen = last_seen_ago(&store, Duration::minutes(21));
+        let overdue = store.overdue_sites(3, a_day_ago).unwrap();
+        assert_eq!(overdue.len(), 1);
+        assert_eq!(overdue[0].0, "home");
+        assert_eq!(
+            overdue[0].1.timestamp_millis(),
+            last_seen.timestamp_millis()
+        );
+        assert_eq!(overdue[0].2, 4);
+
+        // Time the server wasn't watching doesn't count against the site
+        let ten_minutes_ago = Utc::now() - Duration::minutes(10);
+        assert!(store.overdue_sites(3, ten_minutes_ago).unwrap().is_empty());
+
+        store.mark_silent("home").unwrap();
+        assert!(store.overdue_sites(3, a_day_ago).unwrap().is_empty());
+        assert!(store.sites().unwrap()[0].silent_since.is_some());
+
+        let events = store
+            .record_report(&report(Some("203.0.113.7")), None, None)
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            &events[0],
+            Event::SiteBackOnline { site, silent_for }
+                if site == "home" && *silent_for >= Duration::minutes(21)
+        ));
+        assert!(store.sites().unwrap()[0].silent_since.is_none());
+
+        // Back to normal, the next report is nothing special
+        assert!(
+            store
+                .record_report(&report(Some("203.0.113.7")), None, None)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn interval_too_long_to_count_is_skipped() {
+        let mut store = store();
+        store
+            .record_report(&report(Some("203.0.113.7")), None, None)
+            .unwrap();
+        store
+            .conn
+            .execute(
+                "UPDATE sites SET check_interval_minutes = ?1",
+                params![i64::MAX],
+            )
+            .unwrap();
+        last_seen_ago(&store, Duration::days(365));
+
+        assert!(
+            store
+                .overdue_sites(1, Utc::now() - Duration::days(400))
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,417 @@
+//! Statistics and chart ready time series of lookup latency and success rate
+//!
+//! Latency outliers are dropped against the median of their neighbours, the samples are then
+//! downsampled into `DOWN_SAMPLE_POINTS` equal buckets, and with `DO_INTERPOLATION` the buckets
+//! are resampled onto `INTERPOLATION_STEPS` evenly spaced steps so gaps are filled
+use chrono::{DateTime, Duration, Utc};
+use serde_json::{Value, json};
+
+use crate::constants;
+
+/// How many samples on each side an outlier is judged against
+///
+/// Judging against neighbours rather than the whole range keeps a lasting slowdown in the series
+/// while single spikes go
+const OUTLIER_WINDOW: usize = 5;
+
+/// One check, the unit everything here is computed from
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub timestamp: DateTime<Utc>,
+    pub success: bool,
+    /// How long the providers took, None if none of them answered
+    pub latency_ms: Option<f64>,
+}
+
+/// A point of a series, None where there was nothing to compute it from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub timestamp: DateTime<Utc>,
+    pub value: Option<f64>,
+}
+
+/// Summary numbers over a whole range
+#[derive(Debug, Clone, Default)]
+pub struct Summary {
+    pub checks: usize,
+    pub successes: usize,
+    /// From 0 to 1, None without checks
+    pub success_rate: Option<f64>,
+    pub latency_min: Option<f64>,
+    pub latency_median: Option<f64>,
+    pub latency_p95: Option<f64>,
+    pub latency_max: Option<f64>,
+}
+
+/// Everything a chart or report needs about a range
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub summary: Summary,
+    pub latency: Vec<Point>,
+    pub success_rate: Vec<Point>,
+}
+
+/// The value below which a share of the sorted values falls, interpolating between neighbours
+///
+/// # Arguments
+/// * `sorted: &[f64]` - The values, in ascending order
+/// * `q: f64` - The share, from 0 to 1
+///
+/// # Returns
+/// * `Option<f64>` - The quantile, None without values
+pub fn quantile(sorted: &[f64], q: f64) -> Option<f64> {
+    let last = sorted.len().checked_sub(1)?;
+    let position = q.clamp(0.0, 1.0) * last as f64;
+    let (low, high) = (position.floor() as usize, position.ceil() as usize);
+
+    Some(sorted[low] + (sorted[high] - sorted[low]) * (position - low as f64))
+}
+
+/// Drops the values that are more than `threshold` times the median of their neighbours above it
+///
+/// Only high values are dropped, a lookup can't be suspiciously fast
+///
+/// # Arguments
+/// * `values: &[(DateTime<Utc>, f64)]` - The values, in time order
+/// * `threshold: f64` - How far above the median a value may be, as a share of the median
+/// * `window: usize` - How many values on each side make up the neighbourhood
+///
+/// # Returns
+/// * `Vec<(DateTime<Utc>, f64)>` - The values that are kept, in the same order
+pub fn reject_outliers(
+    values: &[(DateTime<Utc>, f64)],
+    threshold: f64,
+    window: usize,
+) -> Vec<(DateTime<Utc>, f64)> {
+    values
+        .iter()
+        .enumerate()
+        .filter(|(i, (_, value))| {
+            let end = (i + window + 1).min(values.len());
+            let mut neighbours: Vec<f64> = values[i.saturating_sub(window)..end]
+                .iter()
+                .map(|(_, value)| *value)
+                .collect();
+            neighbours.sort_by(f64::total_cmp);
+
+            quantile(&neighbours, 0.5).is_none_or(|median| value - median <= threshold * median)
+        })
+        .map(|(_, value)| *value)
+        .collect()
+}
+
+/// The mean of the values, None without values
+fn mean(values: &[f64]) -> Option<f64> {
+    (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+}
+
+/// Splits a range into equal buckets and reduces the values in each one to a point
+///
+/// # Arguments
+/// * `values: &[(DateTime<Utc>, f64)]` - The timestamped values
+/// * `start: DateTime<Utc>` - The start of the range
+/// * `end: DateTime<Utc>` - The end of the range
+/// * `points: usize` - How many buckets the range is split into
+/// * `reduce: F` - Turns the values of a bucket into its value
+///
+/// # Returns
+/// * `Vec<Point>` - One point per bucket at its middle, None for empty buckets
+pub fn downsample<F>(
+    values: &[(DateTime<Utc>, f64)],
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    points: usize,
+    reduce: F,
+) -> Vec<Point>
+where
+    F: Fn(&[f64]) -> Option<f64>,
+{
+    if points == 0 || end < start {
+        return Vec::new();
+    }
+
+    let span = (end - start).num_milliseconds().max(1) as f64;
+    let mut buckets: Vec<Vec<f64>> = vec![Vec::new(); points];
+
+    for (timestamp, value) in values {
+        if *timestamp < start || *timestamp > end {
+            continue;
+        }
+        let offset = (*timestamp - start).num_milliseconds() as f64;
+        // The end itself belongs to the last bucket
+        let bucket = ((offset / span * points as f64) as usize).min(points - 1);
+        buckets[bucket].push(*value);
+    }
+
+    buckets
+        .iter()
+        .enumerate()
+        .map(|(i, bucket)| Point {
+            timestamp: start
+                + Duration::milliseconds(((i as f64 + 0.5) * span / points as f64) as i64),
+            value: reduce(bucket),
+        })
+        .collect()
+}
+
+/// Resamples a series onto evenly spaced steps, bridging empty points linearly
+///
+/// Steps before the first or after the last known value stay empty, they aren't guessed
+///
+/// # Arguments
+/// * `points: &[Point]` - The series, in time order
+/// * `steps: usize` - How many points the result has
+///
+/// # Returns
+/// * `Vec<Point>` - The resampled series, from the first to the last point of the input
+pub fn interpolate(points: &[Point], steps: usize) -> Vec<Point> {
+    let known: Vec<(i64, f64)> = points
+        .iter()
+        .filter_map(|point| Some((point.timestamp.timestamp_millis(), point.value?)))
+        .collect();
+    let (Some(first), Some(last)) = (points.first(), points.last()) else {
+        return Vec::new();
+    };
+    if steps == 0 {
+        return Vec::new();
+    }
+
+    let start = first.timestamp.timestamp_millis();
+    let span = (last.timestamp.timestamp_millis() - start) as f64;
+
+    (0..steps)
+        .map(|i| {
+            let time = if steps == 1 {
+                start
+            } else {
+                start + (span * i as f64 / (steps - 1) as f64) as i64
+            };
+
+            // The known values on both sides of the step
+            let after = known.iter().position(|(t, _)| *t >= time);
+            let value = match after {
+                Some(0) => (known[0].0 == time).then_some(known[0].1),
+                Some(i) => {
+                    let ((t0, v0), (t1, v1)) = (known[i - 1], known[i]);
+                    Some(v0 + (v1 - v0) * (time - t0) as f64 / (t1 - t0) as f64)
+                }
+                None => None,
+            };
+
+            Point {
+                timestamp: DateTime::from_timestamp_millis(time).unwrap_or(first.timestamp),
+                value,
+            }
+        })
+        .collect()
+}
+
+/// Turns a downsampled series into the one handed out, interpolated if that is switched on
+fn finish(points: Vec<Point>) -> Vec<Point> {
+    if constants::DO_INTERPOLATION {
+        interpolate(&points, constants::INTERPOLATION_STEPS as usize)
+    } else {
+        points
+    }
+}
+
+/// Computes the summary and series of a range of checks
+///
+/// # Arguments
+/// * `samples: &[Sample]` - The checks, in any order
+/// * `start: DateTime<Utc>` - The start of the range
+/// * `end: DateTime<Utc>` - The end of the range
+///
+/// # Returns
+/// * `Stats` - The summary over every check, and series of `DOWN_SAMPLE_POINTS` buckets
+pub fn compute(samples: &[Sample], start: DateTime<Utc>, end: DateTime<Utc>) -> Stats {
+    let mut samples: Vec<&Sample> = samples
+        .iter()
+        .filter(|sample| sample.timestamp >= start && sample.timestamp <= end)
+        .collect();
+    // Neighbours are found by position
+    samples.sort_by_key(|sample| sample.timestamp);
+
+    let mut latencies: Vec<f64> = samples.iter().filter_map(|s| s.latency_ms).collect();
+    latencies.sort_by(f64::total_cmp);
+    let successes = samples.iter().filter(|sample| sample.success).count();
+
+    let summary = Summary {
+        checks: samples.len(),
+        successes,
+        success_rate: (!samples.is_empty()).then(|| successes as f64 / samples.len() as f64),
+        latency_min: latencies.first().copied(),
+        latency_median: quantile(&latencies, 0.5),
+        latency_p95: quantile(&latencies, 0.95),
+        latency_max: latencies.last().copied(),
+    };
+
+    let points = constants::DOWN_SAMPLE_POINTS as usize;
+    let latency: Vec<(DateTime<Utc>, f64)> = samples
+        .iter()
+        .filter_map(|sample| Some((sample.timestamp, sample.latency_ms?)))
+        .collect();
+    let success: Vec<(DateTime<Utc>, f64)> = samples
+        .iter()
+        .map(|sample| (sample.timestamp, if sample.success { 1.0 } else { 0.0 }))
+        .collect();
+
+    Stats {
+        summary,
+        latency: finish(downsample(
+            &reject_outliers(&latency, constants::OUTLIER_THRESHOLD, OUTLIER_WINDOW),
+            start,
+            end,
+            points,
+            mean,
+        )),
+        // Successes are 0 or 1, there is nothing to reject
+        success_rate: finish(downsample(&success, start, end, points, mean)),
+    }
+}
+
+impl Stats {
+    /// The stats as JSON, times in RFC 3339 and missing values as null
+    pub fn to_json(&self) -> Value {
+        let series = |points: &[Point]| -> Vec<Value> {
+            points
+                .iter()
+                .map(|point| json!({ "timestamp": point.timestamp.to_rfc3339(), "value": point.value }))
+                .collect()
+        };
+
+        json!({
+            "summary": {
+                "checks": self.summary.checks,
+                "successes": self.summary.successes,
+                "successRate": self.summary.success_rate,
+                "latencyMinMs": self.summary.latency_min,
+                "latencyMedianMs": self.summary.latency_median,
+                "latencyP95Ms": self.summary.latency_p95,
+                "latencyMaxMs": self.summary.latency_max,
+            },
+            "latencyMs": series(&self.latency),
+            "successRate": series(&self.success_rate),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(minute: i64) -> DateTime<Utc> {
+        "2024-05-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap() + Duration::minutes(minute)
+    }
+
+    fn point(minute: i64, value: Option<f64>) -> Point {
+        Point {
+            timestamp: at(minute),
+            value,
+        }
+    }
+
+    #[test]
+    fn quantiles_interpolate_between_values() {
+        assert_eq!(quantile(&[], 0.5), None);
+        assert_eq!(quantile(&[7.0], 0.0), Some(7.0));
+        assert_eq!(quantile(&[7.0], 0.95), Some(7.0));
+
+        let values = [10.0, 20.0, 30.0, 40.0];
+        assert_eq!(quantile(&values, 0.0), Some(10.0));
+        assert_eq!(quantile(&values, 0.5), Some(25.0));
+        assert_eq!(quantile(&values, 1.0), Some(40.0));
+        assert_eq!(quantile(&values, 2.0), Some(40.0));
+        assert!((quantile(&values, 0.95).unwrap() - 38.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn single_spike_is_dropped_but_a_lasting_slowdown_stays() {
+        let mut values: Vec<(DateTime<Utc>, f64)> = (0..11).map(|i| (at(i), 100.0)).collect();
+        values[5].1 = 5000.0;
+
+        let kept = reject_outliers(&values, 2.0, 5);
+        assert_eq!(kept.len(), 10);
+        assert!(
+            kept.iter()
+                .all(|(time, value)| *value == 100.0 && *time != at(5))
+        );
+
+        // From the middle on every lookup is slow, the neighbours agree so nothing goes
+        let slowdown: Vec<(DateTime<Utc>, f64)> = (0..20)
+            .map(|i| (at(i), if i < 10 { 100.0 } else { 900.0 }))
+            .collect();
+        assert_eq!(reject_outliers(&slowdown, 2.0, 5), slowdown);
+        assert!(reject_outliers(&[], 2.0, 5).is_empty());
+    }
+
+    #[test]
+    fn downsampling_fills_buckets_over_the_range() {
+        let values = [
+            (at(0), 1.0),
+            (at(1), 3.0),
+            (at(5), 10.0),
+            (at(10), 20.0),
+            // Outside the range
+            (at(11), 99.0),
+        ];
+
+        let points = downsample(&values, at(0), at(10), 5, mean);
+
+        let buckets: Vec<Option<f64>> = points.iter().map(|point| point.value).collect();
+        // The end timestamp goes to the last bucket, the empty buckets have no value
+        assert_eq!(buckets, [Some(2.0), None, Some(10.0), None, Some(20.0)]);
+        assert_eq!(points[0].timestamp, at(1));
+        assert_eq!(points[4].timestamp, at(9));
+
+        assert!(downsample(&values, at(10), at(0), 5, mean).is_empty());
+        assert!(downsample(&values, at(0), at(10), 0, mean).is_empty());
+    }
+
+    #[test]
+    fn interpolation_bridges_gaps_but_does_not_guess_the_edges() {
+        let points = [
+            point(0, None),
+            point(2, Some(10.0)),
+            point(4, None),
+            point(6, Some(30.0)),
+            point(8, None),
+        ];
+
+        let resampled = interpolate(&points, 9);
+
+        let values: Vec<Option<f64>> = resampled.iter().map(|point| point.value).collect();
+        assert_eq!(
+            values,
+            [
+                None,
+                None,
+                Some(10.0),
+                Some(15.0),
+                Some(20.0),
+                Some(25.0),
+                Some(30.0),
+                None,
+                None
+            ]
+        );
+        assert_eq!(resampled[0].timestamp, at(0));
+        assert_eq!(resampled[8].timestamp, at(8));
+    }
+
+    #[test]
+    fn interpolation_edge_cases() {
+        let points = [point(0, Some(5.0)), point(10, Some(15.0))];
+
+        assert_eq!(interpolate(&points, 1), [point(0, Some(5.0))]);
+        assert!(interpolate(&points, 0).is_empty());
+        assert!(interpolate(&[], 10).is_empty());
+        assert_eq!(
+            interpolate(&[point(0, None), point(10, None)], 3)
+                .iter()
+                .map(|point| point.value)
+                .collect::<Vec<_>>(),
+            [None, None, None]
+        );
+    }
+}
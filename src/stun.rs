@@ -0,0 +1,545 @@
+//! Minimal STUN (RFC 5389) client, sends binding requests and reads back the mapped address
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crate::{constants, dns, ip_check::IpFamily};
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const BINDING_ERROR: u16 = 0x0111;
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_ERROR_CODE: u16 = 0x0009;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// Everything that can go wrong while talking to a STUN server
+#[derive(Debug, Clone)]
+pub enum StunError {
+    /// The server couldn't be resolved or reached, or the socket failed
+    Io(String),
+    /// The answer couldn't be parsed or has no mapped address
+    Malformed(&'static str),
+    /// The server answered with an error response
+    Server(u16, String),
+}
+
+impl fmt::Display for StunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StunError::Io(e) => write!(f, "{}", e),
+            StunError::Malformed(e) => write!(f, "Malformed STUN message: {}", e),
+            StunError::Server(code, reason) => {
+                write!(f, "STUN server answered with error {} {}", code, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StunError {}
+
+impl From<std::io::Error> for StunError {
+    fn from(e: std::io::Error) -> Self {
+        StunError::Io(e.to_string())
+    }
+}
+
+/// How the NAT maps the local socket, worked out by comparing what several servers saw
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NatBehavior {
+    /// The mapped address is the local address, there is no NAT
+    NoNat,
+    /// Every server saw the same mapped address and port
+    EndpointIndependent,
+    /// The mapped address or port changed per server, the NAT is symmetric
+    Symmetric,
+    /// Only one server answered, so there is nothing to compare against
+    Unknown,
+}
+
+impl fmt::Display for NatBehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NatBehavior::NoNat => write!(f, "no NAT, the mapped address is the local address"),
+            NatBehavior::EndpointIndependent => {
+                write!(f, "not symmetric, the mapping is the same for every server")
+            }
+            NatBehavior::Symmetric => {
+                write!(f, "symmetric, the mapping changes for every server")
+            }
+            NatBehavior::Unknown => write!(f, "unknown, it takes two STUN servers to tell"),
+        }
+    }
+}
+
+/// What the STUN servers saw
+#[derive(Debug, Clone)]
+pub struct StunReport {
+    /// The mapped address and port each server answered with, in the order they were asked
+    pub mappings: Vec<(String, SocketAddr)>,
+    /// How the NAT behaves
+    pub nat: NatBehavior,
+}
+
+/// Builds a binding request
+///
+/// # Arguments
+/// * `transaction_id: &[u8; 12]` - The ID the answer has to carry
+///
+/// # Returns
+/// * `Vec<u8>` - The request in wire format
+pub fn build_binding_request(transaction_id: &[u8; 12]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(20);
+
+    msg.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    // No attributes
+    msg.extend_from_slice(&0u16.to_be_bytes());
+    msg.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    msg.extend_from_slice(transaction_id);
+
+    msg
+}
+
+/// Parses a binding response
+///
+/// # Arguments
+/// * `msg: &[u8]` - The response in wire format
+/// * `transaction_id: &[u8; 12]` - The ID of the request
+///
+/// # Returns
+/// * `Result<SocketAddr, StunError>` - The mapped address, XOR-MAPPED-ADDRESS is preferred
+pub fn parse_binding_response(
+    msg: &[u8],
+    transaction_id: &[u8; 12],
+) -> Result<SocketAddr, StunError> {
+    if msg.len() < 20 {
+        return Err(StunError::Malformed("message is shorter than the header"));
+    }
+
+    let msg_type = u16::from_be_bytes([msg[0], msg[1]]);
+    let len = u16::from_be_bytes([msg[2], msg[3]]) as usize;
+
+    if msg[4..8] != MAGIC_COOKIE.to_be_bytes() {
+        return Err(StunError::Malformed("missing magic cookie"));
+    }
+    if &msg[8..20] != transaction_id {
+        return Err(StunError::Malformed(
+            "transaction ID doesn't match the request",
+        ));
+    }
+
+    let attributes = msg
+        .get(20..20 + len)
+        .ok_or(StunError::Malformed("attributes run past the end"))?;
+
+    let mut mapped = None;
+    let mut xor_mapped = None;
+    let mut pos = 0;
+
+    while pos + 4 <= attributes.len() {
+        let attr_type = u16::from_be_bytes([attributes[pos], attributes[pos + 1]]);
+        let attr_len = u16::from_be_bytes([attributes[pos + 2], attributes[pos + 3]]) as usize;
+        let value = attributes
+            .get(pos + 4..pos + 4 + attr_len)
+            .ok_or(StunError::Malformed("attribute runs past the end"))?;
+
+        match attr_type {
+            ATTR_XOR_MAPPED_ADDRESS => xor_mapped = read_address(value, Some(transaction_id)),
+            ATTR_MAPPED_ADDRESS => mapped = read_address(value, None),
+            ATTR_ERROR_CODE if msg_type == BINDING_ERROR && value.len() >= 4 => {
+                let code = (value[2] & 0x07) as u16 * 100 + value[3] as u16;
+                let reason = String::from_utf8_lossy(&value[4..]).to_string();
+                return Err(StunError::Server(code, reason));
+            }
+            _ => {}
+        }
+
+        // Attributes are padded to 4 bytes
+        pos += 4 + attr_len.div_ceil(4) * 4;
+    }
+
+    if msg_type != BINDING_SUCCESS {
+        return Err(StunError::Malformed("not a binding success response"));
+    }
+
+    xor_mapped
+        .or(mapped)
+        .ok_or(StunError::Malformed("response has no mapped address"))
+}
+
+/// Reads a (XOR-)MAPPED-ADDRESS value, pass the transaction ID to undo the XOR
+fn read_address(value: &[u8], xor_with: Option<&[u8; 12]>) -> Option<SocketAddr> {
+    let cookie = MAGIC_COOKIE.to_be_bytes();
+    let mut port = u16::from_be_bytes([*value.get(2)?, *value.get(3)?]);
+
+    if xor_with.is_some() {
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+    }
+
+    let ip = match value.get(1)? {
+        0x01 => {
+            let mut octets: [u8; 4] = value.get(4..8)?.try_into().ok()?;
+            if xor_with.is_some() {
+                for (octet, key) in octets.iter_mut().zip(cookie) {
+                    *octet ^= key;
+                }
+            }
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        0x02 => {
+            let mut octets: [u8; 16] = value.get(4..20)?.try_into().ok()?;
+            if let Some(transaction_id) = xor_with {
+                let key = cookie.iter().chain(transaction_id.iter());
+                for (octet, key) in octets.iter_mut().zip(key) {
+                    *octet ^= key;
+                }
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Makes a transaction ID that is hard to guess
+fn new_transaction_id() -> [u8; 12] {
+    let mut id = [0u8; 12];
+
+    for chunk in id.chunks_mut(2) {
+        chunk.copy_from_slice(&dns::new_id().to_be_bytes());
+    }
+
+    id
+}
+
+/// Sends a binding request from the socket and waits for the answer, retrying once
+///
+/// # Arguments
+/// * `socket: &UdpSocket` - An unconnected socket, reused so every server sees the same local port
+/// * `server: SocketAddr` - The STUN server
+///
+/// # Returns
+/// * `Result<SocketAddr, StunError>` - The mapped address the server saw
+pub fn binding_request(socket: &UdpSocket, server: SocketAddr) -> Result<SocketAddr, StunError> {
+    let transaction_id = new_transaction_id();
+    let request = build_binding_request(&transaction_id);
+    let timeout = Duration::from_secs(constants::STUN_TIMEOUT_SECONDS);
+    let mut buf = [0u8; 1024];
+
+    for _ in 0..2 {
+        socket.send_to(&request, server)?;
+        let deadline = Instant::now() + timeout;
+
+        // Answers to earlier attempts or from other servers are skipped
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            socket.set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+
+            match socket.recv_from(&mut buf) {
+                // Short datagrams can't hold a header, they are skipped like strangers' answers
+                Ok((len, from)) if from == server && len >= 20 && buf[8..20] == transaction_id => {
+                    return parse_binding_response(&buf[..len], &transaction_id);
+                }
+                Ok(_) => continue,
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    Err(StunError::Io(format!("{} didn't answer", server)))
+}
+
+/// Asks every server for the mapped address of one local socket and works out the NAT behavior
+///
+/// # Arguments
+/// * `servers: &[String]` - STUN servers as `host[:port]`, port 3478 is the default
+/// * `family: IpFamily` - The family to ask over
+///
+/// # Returns
+/// * `Result<StunReport, StunError>` - Fails only if no server answered
+pub fn discover(servers: &[String], family: IpFamily) -> Result<StunReport, StunError> {
+    let bind: SocketAddr = match family {
+        IpFamily::V4 => "0.0.0.0:0".parse().unwrap(),
+        IpFamily::V6 => "[::]:0".parse().unwrap(),
+    };
+    let socket = UdpSocket::bind(bind)?;
+
+    let mut mappings = Vec::new();
+    let mut last_error = StunError::Io("No STUN servers are configured".to_string());
+    let mut local = None;
+
+    for server in servers {
+        let result = dns::resolve_host(server, 3478, family)
+            .map_err(StunError::from)
+            .and_then(|addr| {
+                // The local address the OS would pick for this server, used to spot "no NAT"
+                if local.is_none() {
+                    local = local_ip(bind, addr);
+                }
+                binding_request(&socket, addr)
+            });
+
+        match result {
+            Ok(mapped) => mappings.push((server.clone(), mapped)),
+            Err(e) => last_error = e,
+        }
+    }
+
+    let Some((_, first)) = mappings.first() else {
+        return Err(last_error);
+    };
+
+    let nat = if Some(first.ip()) == local {
+        NatBehavior::NoNat
+    } else if mappings.len() < 2 {
+        NatBehavior::Unknown
+    } else if mappings.iter().all(|(_, mapped)| mapped == first) {
+        NatBehavior::EndpointIndependent
+    } else {
+        NatBehavior::Symmetric
+    };
+
+    Ok(StunReport { mappings, nat })
+}
+
+/// Finds which local address the OS routes to the server from, nothing is sent
+fn local_ip(bind: SocketAddr, server: SocketAddr) -> Option<IpAddr> {
+    let probe = UdpSocket::bind(bind).ok()?;
+    probe.connect(server).ok()?;
+
+    probe.local_addr().ok().map(|addr| addr.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const ID: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+    /// Encodes an address attribute value, XORed with the transaction ID if one is given
+    fn address(addr: SocketAddr, xor_with: Option<&[u8]>) -> Vec<u8> {
+        let key: Vec<u8> = match xor_with {
+            Some(transaction_id) => MAGIC_COOKIE
+                .to_be_bytes()
+                .iter()
+                .chain(transaction_id)
+                .copied()
+                .collect(),
+            None => vec![0; 16],
+        };
+
+        let (family, octets) = match addr.ip() {
+            IpAddr::V4(ip) => (0x01, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (0x02, ip.octets().to_vec()),
+        };
+        let mut value = vec![0, family];
+        value
+            .extend_from_slice(&(addr.port() ^ u16::from_be_bytes([key[0], key[1]])).to_be_bytes());
+        value.extend(octets.iter().zip(&key).map(|(octet, key)| octet ^ key));
+        value
+    }
+
+    /// Builds a response with the attributes, padding each to 4 bytes
+    fn response(msg_type: u16, transaction_id: &[u8], attributes: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (attr_type, value) in attributes {
+            body.extend_from_slice(&attr_type.to_be_bytes());
+            body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            body.extend_from_slice(value);
+            body.resize(body.len().div_ceil(4) * 4, 0);
+        }
+
+        let mut msg = msg_type.to_be_bytes().to_vec();
+        msg.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        msg.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        msg.extend_from_slice(transaction_id);
+        msg.extend(body);
+        msg
+    }
+
+    /// Starts a responder on a loopback port, every request gets the datagrams `answer` returns
+    fn responder<F>(answer: F) -> String
+    where
+        F: Fn(&[u8]) -> Vec<Vec<u8>> + Send + 'static,
+    {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+                for datagram in answer(&buf[..len]) {
+                    socket.send_to(&datagram, peer).unwrap();
+                }
+            }
+        });
+
+        address
+    }
+
+    /// A responder that says the request came from `mapped`
+    fn mapping_to(mapped: &'static str) -> String {
+        responder(move |request| {
+            let id = &request[8..20];
+            vec![response(
+                BINDING_SUCCESS,
+                id,
+                &[(
+                    ATTR_XOR_MAPPED_ADDRESS,
+                    address(mapped.parse().unwrap(), Some(id)),
+                )],
+            )]
+        })
+    }
+
+    #[test]
+    fn reads_xor_mapped_ipv4_and_ipv6() {
+        for mapped in ["203.0.113.7:54321", "[2001:db8::7]:443"] {
+            let mapped: SocketAddr = mapped.parse().unwrap();
+            let msg = response(
+                BINDING_SUCCESS,
+                &ID,
+                &[(ATTR_XOR_MAPPED_ADDRESS, address(mapped, Some(&ID)))],
+            );
+
+            assert_eq!(parse_binding_response(&msg, &ID).unwrap(), mapped);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_mapped_address_and_prefers_xor() {
+        let plain: SocketAddr = "198.51.100.1:1000".parse().unwrap();
+        let xored: SocketAddr = "203.0.113.7:2000".parse().unwrap();
+
+        let only_plain = response(
+            BINDING_SUCCESS,
+            &ID,
+            &[(ATTR_MAPPED_ADDRESS, address(plain, None))],
+        );
+        assert_eq!(parse_binding_response(&only_plain, &ID).unwrap(), plain);
+
+        let both = response(
+            BINDING_SUCCESS,
+            &ID,
+            &[
+                (ATTR_MAPPED_ADDRESS, address(plain, None)),
+                (ATTR_XOR_MAPPED_ADDRESS, address(xored, Some(&ID))),
+            ],
+        );
+        assert_eq!(parse_binding_response(&both, &ID).unwrap(), xored);
+    }
+
+    #[test]
+    fn error_response_carries_code_and_reason() {
+        let mut value = vec![0, 0, 4, 20];
+        value.extend_from_slice(b"Unknown Attribute");
+        let msg = response(BINDING_ERROR, &ID, &[(ATTR_ERROR_CODE, value)]);
+
+        match parse_binding_response(&msg, &ID) {
+            Err(StunError::Server(code, reason)) => {
+                assert_eq!(code, 420);
+                assert_eq!(reason, "Unknown Attribute");
+            }
+            other => panic!("expected a server error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn short_and_cut_off_messages_are_malformed() {
+        let full = response(
+            BINDING_SUCCESS,
+            &ID,
+            &[(
+                ATTR_XOR_MAPPED_ADDRESS,
+                address("203.0.113.7:1".parse().unwrap(), Some(&ID)),
+            )],
+        );
+
+        for len in [0, 4, 7, 8, 19, 20, 24, full.len() - 1] {
+            assert!(
+                matches!(
+                    parse_binding_response(&full[..len], &ID),
+                    Err(StunError::Malformed(_))
+                ),
+                "{} bytes were accepted",
+                len
+            );
+        }
+        let mut other_id = full.clone();
+        other_id[19] ^= 0xFF;
+        assert!(parse_binding_response(&other_id, &ID).is_err());
+    }
+
+    #[test]
+    fn stray_short_datagrams_are_skipped() {
+        // Datagrams too short for a header arrive first, they used to be sliced past their end
+        let server = responder(|request| {
+            let id = &request[8..20];
+            vec![
+                vec![1, 2, 3],
+                request[..10].to_vec(),
+                response(
+                    BINDING_SUCCESS,
+                    id,
+                    &[(
+                        ATTR_XOR_MAPPED_ADDRESS,
+                        address("203.0.113.7:4000".parse().unwrap(), Some(id)),
+                    )],
+                ),
+            ]
+        });
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let mapped = binding_request(&socket, server.parse().unwrap()).unwrap();
+
+        assert_eq!(mapped, "203.0.113.7:4000".parse().unwrap());
+    }
+
+    #[test]
+    fn classifies_the_nat() {
+        let servers = |mappings: &[&'static str]| -> Vec<String> {
+            mappings.iter().map(|mapped| mapping_to(mapped)).collect()
+        };
+        let nat = |servers: Vec<String>| discover(&servers, IpFamily::V4).unwrap().nat;
+
+        assert_eq!(
+            nat(servers(&["203.0.113.7:4000", "203.0.113.7:4000"])),
+            NatBehavior::EndpointIndependent
+        );
+        assert_eq!(
+            nat(servers(&["203.0.113.7:4000", "203.0.113.7:4001"])),
+            NatBehavior::Symmetric
+        );
+        assert_eq!(nat(servers(&["203.0.113.7:4000"])), NatBehavior::Unknown);
+        // The mapped address is the one the socket sends from
+        assert_eq!(nat(servers(&["127.0.0.1:4000"])), NatBehavior::NoNat);
+    }
+
+    #[test]
+    fn no_answer_is_the_last_error() {
+        // The server answers with an error, so discover has no mapping at all
+        let server = responder(|request| {
+            let mut value = vec![0, 0, 5, 0];
+            value.extend_from_slice(b"Server Error");
+            vec![response(
+                BINDING_ERROR,
+                &request[8..20],
+                &[(ATTR_ERROR_CODE, value)],
+            )]
+        });
+
+        let error = discover(&[server], IpFamily::V4).unwrap_err();
+
+        assert!(matches!(error, StunError::Server(500, _)));
+    }
+}